@@ -3,7 +3,7 @@ set -euo pipefail
 
 # date at which the oldest wal file was last modified in epoch seconds
 echo "calculating age of oldest wal file"
-then_old=$(ls /re_dms/output_wal/*.wal -tr | head -n 1 | xargs stat -c '%Y')
+then_old=$(ls /re_dms/output_wal/*.wal* -tr | head -n 1 | xargs stat -c '%Y')
 
 # now epoch seconds
 now=$(date +%s)
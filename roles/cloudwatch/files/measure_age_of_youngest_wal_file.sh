@@ -3,7 +3,7 @@ set -euo pipefail
 
 # date at which the most recent wal file was last modified in epoch seconds
 echo "calculating age of youngest wal file"
-then_new=$(ls /re_dms/output_wal/*.wal -t | head -n 1 | xargs stat -c '%Y')
+then_new=$(ls /re_dms/output_wal/*.wal* -t | head -n 1 | xargs stat -c '%Y')
 
 # now epoch seconds
 now=$(date +%s)
@@ -2,7 +2,7 @@
 set -euo pipefail
 
 echo "calculating number_of_wal_files"
-number_of_wal_files=$(ls /re_dms/output_wal/*.wal | wc -l)
+number_of_wal_files=$(ls /re_dms/output_wal/*.wal* | wc -l)
 
 data="number_of_wal_files:$number_of_wal_files|g"
 echo $data
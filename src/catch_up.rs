@@ -0,0 +1,251 @@
+use lazy_static::lazy_static;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+#[allow(unused_imports)]
+use crate::{function, logger_debug, logger_error, logger_info, logger_panic, logger_warning};
+
+use crate::change_processing::{ChangeProcessing, ChangeProcessingResult};
+use crate::database_writer::StatsdWrapper;
+use crate::parser::{ParsedLine, Parser};
+use crate::shutdown_handler::ShutdownHandler;
+use crate::wal_archive::WalArchive;
+use crate::wal_file_manager::WalFileManager;
+use crate::wal_integrity::{open_validated_wal_file, WalIntegrityError, WalValidation};
+
+lazy_static! {
+    // how many backlog wal files we parse at once after an outage. 1 reprocesses them one at a time
+    static ref CATCH_UP_PARALLELISM: usize = std::env::var("CATCH_UP_PARALLELISM")
+        .unwrap_or("1".to_string())
+        .parse::<usize>()
+        .expect("CATCH_UP_PARALLELISM is not a valid integer");
+}
+
+// a backlog wal file, parsed and collected into csv files, waiting for the ones before it
+struct CaughtUpWalFile {
+    collector: ChangeProcessing,
+    outcome: Result<CaughtUp, CatchUpError>,
+}
+
+struct CaughtUp {
+    results: Vec<ChangeProcessingResult>,
+    validation: WalValidation,
+    elapsed_ms: u128,
+}
+
+enum CatchUpError {
+    Corrupted(WalIntegrityError),
+    Failed(String),
+    Interrupted,
+}
+
+// the backlog wal files after an outage are independent, apart from ddl,
+// so we parse and write the csv files for several of them at once.
+// results are sent on in wal file order, which keeps the per table order the database writer relies on.
+// each worker starts from what we knew about the tables' schemas when it started, so once a wal file
+// with ddl in it has been sent, the workers already running behind it are thrown away and run again.
+// the last wal file is left for the main loop, as it may still be being written to.
+// returns the last wal file caught up on, if any
+pub async fn catch_up(
+    wal_directory: &Path,
+    backlog: Vec<String>,
+    archive_unsealed: bool,
+    collector: &mut ChangeProcessing,
+    file_transmitter: &mut mpsc::Sender<ChangeProcessingResult>,
+) -> Result<Option<String>, ()> {
+    let parallelism = *CATCH_UP_PARALLELISM;
+    if parallelism <= 1 || backlog.is_empty() || ShutdownHandler::shutting_down() {
+        return Ok(None);
+    }
+    logger_info!(
+        None,
+        None,
+        &format!(
+            "catching_up wal_files:{} parallelism:{}",
+            backlog.len(),
+            parallelism
+        )
+    );
+    let statsd = StatsdWrapper::new();
+    let mut pending: VecDeque<String> = backlog.into();
+    let mut in_flight: VecDeque<(String, JoinHandle<CaughtUpWalFile>)> = VecDeque::new();
+    let mut last_caught_up = None;
+    loop {
+        while in_flight.len() < parallelism && !ShutdownHandler::shutting_down() {
+            let wal_path = match pending.pop_front() {
+                Some(wal_path) => wal_path,
+                None => break,
+            };
+            let wal_directory = wal_directory.to_path_buf();
+            let fork = collector.fork();
+            let worker_wal_path = wal_path.clone();
+            let handle = tokio::task::spawn_blocking(move || {
+                catch_up_wal_file(wal_directory, worker_wal_path, fork)
+            });
+            in_flight.push_back((wal_path, handle));
+        }
+        let (wal_path, handle) = match in_flight.pop_front() {
+            Some(in_flight_wal_file) => in_flight_wal_file,
+            None => break,
+        };
+        // a worker that panicked shuts us down like any other failure
+        let outcome = handle
+            .await
+            .map_err(|err| CatchUpError::Failed(format!("Error joining catch up worker: {}", err)))
+            .and_then(|caught_up_wal_file| {
+                let worker_collector = caught_up_wal_file.collector;
+                caught_up_wal_file
+                    .outcome
+                    .map(|caught_up| (caught_up, worker_collector))
+            });
+        let (caught_up, worker_collector) = match outcome {
+            Ok(outcome) => outcome,
+            Err(err) => {
+                // nothing after this one can go before it
+                discard_in_flight(in_flight).await;
+                return match err {
+                    CatchUpError::Corrupted(err) => {
+                        ShutdownHandler::register_messy_shutdown();
+                        logger_error!(
+                            None,
+                            None,
+                            &format!(
+                                "refusing_corrupted_wal_file path:{} error:{}",
+                                wal_path, err
+                            )
+                        );
+                        Err(())
+                    }
+                    CatchUpError::Failed(message) => {
+                        logger_error!(None, None, &format!("path:{} {}", wal_path, message));
+                        ShutdownHandler::register_clean_shutdown();
+                        Ok(last_caught_up)
+                    }
+                    CatchUpError::Interrupted => Ok(last_caught_up),
+                };
+            }
+        };
+        // sealed wal files were archived when they were sealed
+        if archive_unsealed && !caught_up.validation.sealed {
+            WalArchive::archive(Path::new(&wal_path));
+        }
+        let has_ddl = caught_up
+            .results
+            .iter()
+            .any(|result| matches!(result, ChangeProcessingResult::DdlChange(..)));
+        for result in caught_up.results {
+            file_transmitter
+                .send(result)
+                .await
+                .expect("Error sending caught up wal file to channel");
+        }
+        // picks up where the wal file left the tables' schemas.
+        // ours has no changes or wal file, so there's nothing to clean up
+        *collector = worker_collector;
+        // and removes the wal file if it had no changes
+        collector.register_wal_file(None);
+        logger_info!(
+            None,
+            None,
+            &format!(
+                "caught_up_wal_file path:{} elapsed_ms:{} has_ddl:{}",
+                wal_path, caught_up.elapsed_ms, has_ddl
+            )
+        );
+        statsd.timing(
+            "catch_up_wal_file",
+            caught_up.elapsed_ms as i64,
+            [if has_ddl { "ddl:true" } else { "ddl:false" }],
+        );
+        last_caught_up = Some(wal_path);
+        if has_ddl && !in_flight.is_empty() {
+            logger_info!(
+                None,
+                None,
+                &format!("catch_up_restarting_after_ddl wal_files:{}", in_flight.len())
+            );
+            // they started from the schemas before the ddl
+            for wal_path in discard_in_flight(std::mem::take(&mut in_flight))
+                .await
+                .into_iter()
+                .rev()
+            {
+                pending.push_front(wal_path);
+            }
+        }
+    }
+    Ok(last_caught_up)
+}
+
+// waits for them, as we can't stop a blocking task, then drops what they did.
+// their wal files stay where they are, as nothing removes a wal file until its changes are applied
+async fn discard_in_flight(
+    in_flight: VecDeque<(String, JoinHandle<CaughtUpWalFile>)>,
+) -> Vec<String> {
+    let mut wal_paths = vec![];
+    for (wal_path, handle) in in_flight {
+        // including a worker that panicked, as what it did is dropped either way
+        let _ = handle.await;
+        wal_paths.push(wal_path);
+    }
+    wal_paths
+}
+
+fn catch_up_wal_file(
+    wal_directory: PathBuf,
+    wal_path: String,
+    mut collector: ChangeProcessing,
+) -> CaughtUpWalFile {
+    let outcome = parse_and_collect(&wal_directory, &wal_path, &mut collector);
+    CaughtUpWalFile { collector, outcome }
+}
+
+fn parse_and_collect(
+    wal_directory: &Path,
+    wal_path: &str,
+    collector: &mut ChangeProcessing,
+) -> Result<CaughtUp, CatchUpError> {
+    let started = Instant::now();
+    // drops any incomplete transaction at the end, and refuses corrupted files
+    let mut lines =
+        open_validated_wal_file(Path::new(wal_path)).map_err(CatchUpError::Corrupted)?;
+    let mut wal_file_manager = WalFileManager::reprocess(wal_directory, wal_path.to_string());
+    let mut parser = Parser::new(true);
+    parser.register_wal_number(wal_file_manager.current_wal().file_number);
+    collector.register_wal_file(Some(wal_file_manager.current_wal()));
+    let mut results = vec![];
+    for line in lines.by_ref() {
+        let line = line.map_err(CatchUpError::Corrupted)?;
+        if ShutdownHandler::shutting_down() {
+            return Err(CatchUpError::Interrupted);
+        }
+        wal_file_manager.next_line(&line);
+        match parser.parse(&line) {
+            Ok(ParsedLine::ContinueParse) => {}
+            Ok(parsed_line) => match collector.add_change(parsed_line) {
+                Ok(changes) => results.extend(changes.into_iter().flatten()),
+                Err(err) => {
+                    return Err(CatchUpError::Failed(format!(
+                        "Error processing changes. Failed due to: {:?}",
+                        err
+                    )))
+                }
+            },
+            Err(err) => {
+                return Err(CatchUpError::Failed(format!(
+                    "Error parsing changes. Failed due to: {:?}",
+                    err
+                )))
+            }
+        }
+    }
+    results.extend(collector.drain_final_changes());
+    Ok(CaughtUp {
+        results,
+        validation: lines.validation().clone(),
+        elapsed_ms: started.elapsed().as_millis(),
+    })
+}
@@ -1,31 +1,140 @@
 use crate::parser::{
     ChangeKind, Column, ColumnInfo, ColumnName, ColumnType, ColumnValue, ParsedLine, ParsingError,
-    TableName,
+    SchemaAndTable, TableName,
 };
 use crate::targets_tables_column_names::{Table as TableFromTarget, TargetsTablesColumnNames};
 use crate::wal_file_manager::WalFile;
 use itertools::Itertools;
+use lazy_static::lazy_static;
 use std::collections::hash_map::Entry::{Occupied, Vacant};
 use std::collections::{BTreeMap, HashMap, HashSet};
 use std::{error::Error, fmt};
 
+use crate::checkpoints::{Checkpoint, Checkpoints};
+use crate::database_writer::StatsdWrapper;
 use crate::file_writer;
+use crate::replication_client::Lsn;
+use crate::row_filter::{self, RowFilterError};
+use crate::shutdown_handler::ShutdownHandler;
 use either::Either;
 
 #[allow(unused_imports)]
 use crate::{function, logger_debug, logger_error, logger_info, logger_panic};
 
+lazy_static! {
+    // source table that an event trigger writes ddl into, e.g. public.re_dms_ddl_audit
+    static ref DDL_AUDIT_TABLE: Option<String> = std::env::var("DDL_AUDIT_TABLE")
+        .ok()
+        .filter(|table_name| !table_name.is_empty());
+    static ref CONFLICT_POLICY: ConflictPolicy = std::env::var("CONFLICT_POLICY")
+        .map(|policy| ConflictPolicy::parse(&policy))
+        .unwrap_or(ConflictPolicy::Strict);
+    // comma separated schema.table:policy e.g. public.users:last_writer_wins,public.events:skip_and_log
+    static ref CONFLICT_POLICY_OVERRIDES: HashMap<String, ConflictPolicy> =
+        std::env::var("CONFLICT_POLICY_OVERRIDES")
+            .unwrap_or("".to_owned())
+            .split(',')
+            .filter(|table_and_policy| !table_and_policy.is_empty())
+            .map(|table_and_policy| {
+                let (table_name, policy) = table_and_policy
+                    .rsplit_once(':')
+                    .expect("CONFLICT_POLICY_OVERRIDES entries should be schema.table:policy");
+                (table_name.to_owned(), ConflictPolicy::parse(policy))
+            })
+            .collect();
+}
+
+// How we resolve a sequence of changes for a row that shouldn't happen.
+// These happen legitimately after a PITR restore or a replication slot reset.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum ConflictPolicy {
+    // the hardcoded rules: a second insert wins, anything else is an error
+    Strict,
+    // the newest change wins
+    LastWriterWins,
+    // keep what we already have and log the change we skipped
+    SkipAndLog,
+}
+
+impl ConflictPolicy {
+    fn parse(policy: &str) -> ConflictPolicy {
+        match policy {
+            "strict" => ConflictPolicy::Strict,
+            "last_writer_wins" => ConflictPolicy::LastWriterWins,
+            "skip_and_log" => ConflictPolicy::SkipAndLog,
+            unknown => panic!("Unknown conflict policy: {}", unknown),
+        }
+    }
+
+    fn for_table(table_name: &TableName) -> ConflictPolicy {
+        CONFLICT_POLICY_OVERRIDES
+            .get(table_name.as_str())
+            .copied()
+            .unwrap_or(*CONFLICT_POLICY)
+    }
+}
+
+impl fmt::Display for ConflictPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Strict => write!(f, "strict"),
+            Self::LastWriterWins => write!(f, "last_writer_wins"),
+            Self::SkipAndLog => write!(f, "skip_and_log"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum ConflictKind {
+    // with different data
+    InsertAfterInsert,
+    // with different data
+    InsertAfterUpdate,
+    UpdateAfterDelete,
+}
+
+impl fmt::Display for ConflictKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::InsertAfterInsert => write!(f, "insert_after_insert"),
+            Self::InsertAfterUpdate => write!(f, "insert_after_update"),
+            Self::UpdateAfterDelete => write!(f, "update_after_delete"),
+        }
+    }
+}
+
+// a conflict we resolved, for metrics and logging
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct Conflict {
+    kind: ConflictKind,
+    policy: ConflictPolicy,
+    table_name: TableName,
+    id: String,
+}
+
 #[derive(Debug, Eq, PartialEq, Hash, Clone)]
 pub enum DdlChange {
     AddColumn(ColumnInfo, TableName),
     RemoveColumn(ColumnInfo, TableName),
+    // old column name, new column name
+    RenameColumn(ColumnName, ColumnName, TableName),
+    // old table name, new table name
+    RenameTable(TableName, TableName),
+    DropTable(TableName),
+    CreateTable(TableName, Vec<ColumnInfo>),
 }
 
 impl DdlChange {
+    // NOTE: a rename is routed to the old table, so it is applied after any changes
+    // we've already sent for that table
     pub fn table_name(&self) -> TableName {
         match self {
             Self::AddColumn(_, table_name) => table_name.clone(),
             Self::RemoveColumn(_, table_name) => table_name.clone(),
+            Self::RenameColumn(_, _, table_name) => table_name.clone(),
+            Self::RenameTable(old_table_name, _) => old_table_name.clone(),
+            Self::DropTable(table_name) => table_name.clone(),
+            Self::CreateTable(table_name, _) => table_name.clone(),
         }
     }
 }
@@ -35,8 +144,137 @@ impl std::string::ToString for DdlChange {
         match self {
             Self::AddColumn(_, _) => "add_column".to_string(),
             Self::RemoveColumn(_, _) => "remove_column".to_string(),
+            Self::RenameColumn(_, _, _) => "rename_column".to_string(),
+            Self::RenameTable(_, _) => "rename_table".to_string(),
+            Self::DropTable(_) => "drop_table".to_string(),
+            Self::CreateTable(_, _) => "create_table".to_string(),
+        }
+    }
+}
+
+// A row from the ddl audit table. The event trigger on the source is expected to write
+// rows with the columns:
+// id, event, table_name, old_name, new_name, column_definitions
+// event is one of rename_column, rename_table, drop_table, create_table
+// table_name is schema qualified, and column_definitions is `name:type;name:type`
+#[derive(Debug)]
+struct DdlAuditRecord {
+    event: String,
+    table_name: TableName,
+    old_name: Option<String>,
+    new_name: Option<String>,
+    column_definitions: Option<String>,
+}
+
+impl DdlAuditRecord {
+    fn from_parsed_line(parsed_line: &ParsedLine) -> Result<DdlAuditRecord> {
+        let text_value = |column_name: &str| -> Option<String> {
+            parsed_line
+                .columns_for_changed_data()
+                .iter()
+                .find(|column| column.column_name() == column_name)
+                .and_then(|column| match column {
+                    Column::ChangedColumn {
+                        value: Some(ColumnValue::Text(text)),
+                        ..
+                    } => Some(text.clone()),
+                    _ => None,
+                })
+        };
+        let missing_column = |column_name: &str| ChangeProcessingError {
+            message: format!("ddl audit row is missing {}", column_name),
+            parsed_line: Some(parsed_line.clone()),
+            source_line: None,
+        };
+        let table_name = text_value("table_name").ok_or_else(|| missing_column("table_name"))?;
+        if !table_name.contains('.') {
+            return Err(ChangeProcessingError {
+                message: format!(
+                    "ddl audit table_name is not schema qualified: {}",
+                    table_name
+                ),
+                parsed_line: Some(parsed_line.clone()),
+                source_line: None,
+            });
+        }
+        Ok(DdlAuditRecord {
+            event: text_value("event").ok_or_else(|| missing_column("event"))?,
+            table_name: TableName::new(table_name),
+            old_name: text_value("old_name"),
+            new_name: text_value("new_name"),
+            column_definitions: text_value("column_definitions"),
+        })
+    }
+
+    fn ddl_change(&self) -> Result<DdlChange> {
+        let required = |value: &Option<String>, name: &str| {
+            value.clone().ok_or_else(|| ChangeProcessingError {
+                message: format!("ddl audit {} event is missing {}", self.event, name),
+                parsed_line: None,
+                source_line: None,
+            })
+        };
+        match self.event.as_str() {
+            "rename_column" => Ok(DdlChange::RenameColumn(
+                ColumnName::new(required(&self.old_name, "old_name")?),
+                ColumnName::new(required(&self.new_name, "new_name")?),
+                self.table_name.clone(),
+            )),
+            "rename_table" => {
+                // ALTER TABLE ... RENAME TO can't move a table between schemas
+                let (schema_name, _) = self.table_name.original_schema_and_table_name();
+                let new_table_name =
+                    format!("{}.{}", schema_name, required(&self.new_name, "new_name")?);
+                Ok(DdlChange::RenameTable(
+                    self.table_name.clone(),
+                    TableName::new(new_table_name),
+                ))
+            }
+            "drop_table" => Ok(DdlChange::DropTable(self.table_name.clone())),
+            "create_table" => {
+                let columns = Self::parse_column_definitions(&required(
+                    &self.column_definitions,
+                    "column_definitions",
+                )?)?;
+                Ok(DdlChange::CreateTable(self.table_name.clone(), columns))
+            }
+            unknown => Err(ChangeProcessingError {
+                message: format!("unknown ddl audit event: {}", unknown),
+                parsed_line: None,
+                source_line: None,
+            }),
         }
     }
+
+    // there's no escaping, so names and types can't have a `:` or `;` in them.
+    // Anything we'd have to guess at is refused, rather than creating the wrong table
+    fn parse_column_definitions(column_definitions: &str) -> Result<Vec<ColumnInfo>> {
+        let unparseable = |reason: &str| ChangeProcessingError {
+            message: format!(
+                "unparseable column_definitions ({}): {}",
+                reason, column_definitions
+            ),
+            parsed_line: None,
+            source_line: None,
+        };
+        let mut columns: Vec<ColumnInfo> = vec![];
+        for definition in column_definitions.split(';') {
+            let (name, column_type) = match definition.split(':').collect::<Vec<_>>()[..] {
+                [name, column_type] => (name, column_type),
+                _ => return Err(unparseable(&format!("not name:type {:?}", definition))),
+            };
+            for part in [name, column_type] {
+                if part.is_empty() || part.trim() != part {
+                    return Err(unparseable(&format!("empty or padded {:?}", definition)));
+                }
+            }
+            if columns.iter().any(|column| column.name.as_str() == name) {
+                return Err(unparseable(&format!("duplicate column {}", name)));
+            }
+            columns.push(ColumnInfo::new(name, column_type));
+        }
+        Ok(columns)
+    }
 }
 
 #[derive(Debug)]
@@ -57,6 +295,16 @@ impl fmt::Display for ChangeProcessingError {
     }
 }
 
+impl From<RowFilterError> for ChangeProcessingError {
+    fn from(err: RowFilterError) -> ChangeProcessingError {
+        ChangeProcessingError {
+            source_line: None,
+            message: err.message,
+            parsed_line: None,
+        }
+    }
+}
+
 impl From<ParsingError> for ChangeProcessingError {
     fn from(err: ParsingError) -> ChangeProcessingError {
         ChangeProcessingError {
@@ -72,6 +320,11 @@ pub type Result<T> = std::result::Result<T, ChangeProcessingError>;
 #[derive(Debug, Eq, PartialEq)]
 struct ChangeSet {
     changes: Option<ParsedLine>,
+    // the row may not be in the target, so an update is applied as a delete and insert
+    replaces_row: bool,
+    // an update after an update that didn't match the row filter in this batch, so the row
+    // may be moving into the filter, and missing from the target
+    moved_into_filter: bool,
 }
 
 #[derive(Debug)]
@@ -99,19 +352,111 @@ impl ChangeProcessingResult {
 
 impl ChangeSet {
     fn new() -> ChangeSet {
-        ChangeSet { changes: None }
+        ChangeSet {
+            changes: None,
+            replaces_row: false,
+            moved_into_filter: false,
+        }
     }
     // batch apply enabled
-    fn add_change(&mut self, new_change: ParsedLine) -> Result<()> {
-        self.changes = match self.changes {
-            Some(ParsedLine::ChangedData { kind, .. }) => match kind {
-                ChangeKind::Insert => self.handle_insert_subsequent(new_change)?,
-                ChangeKind::Update => self.handle_update_subsequent(new_change)?,
-                ChangeKind::Delete => self.handle_delete_subsequent(new_change)?,
+    fn add_change(
+        &mut self,
+        new_change: ParsedLine,
+        policy: ConflictPolicy,
+        row_filtered: bool,
+    ) -> Result<Option<ConflictKind>> {
+        let is_update = matches!(
+            new_change,
+            ParsedLine::ChangedData {
+                kind: ChangeKind::Update,
+                ..
+            }
+        );
+        if row_filtered && is_update {
+            // we can't tell whether the row matched the filter before, and so is in the target
+            self.replaces_row = true;
+            if self.is_row_moving_back_into_filter(&new_change) {
+                self.moved_into_filter = true;
+                self.changes = Some(new_change);
+                return Ok(None);
+            }
+        }
+        let conflict = self.conflict_with(&new_change);
+        self.changes = match (conflict, policy) {
+            (Some(_), ConflictPolicy::SkipAndLog) => self.changes.take(),
+            (Some(ConflictKind::InsertAfterUpdate), ConflictPolicy::LastWriterWins)
+            | (Some(ConflictKind::UpdateAfterDelete), ConflictPolicy::LastWriterWins) => {
+                // the row may or may not be in the target, so this is an update that replaces it
+                // either way (unless it has unchanged toast columns)
+                self.replaces_row = true;
+                if let ParsedLine::ChangedData {
+                    columns,
+                    table_name,
+                    ..
+                } = new_change
+                {
+                    Some(ParsedLine::ChangedData {
+                        columns,
+                        kind: ChangeKind::Update,
+                        table_name,
+                    })
+                } else {
+                    None
+                }
+            }
+            _ => match self.changes {
+                Some(ParsedLine::ChangedData { kind, .. }) => match kind {
+                    ChangeKind::Insert => self.handle_insert_subsequent(new_change)?,
+                    ChangeKind::Update => self.handle_update_subsequent(new_change)?,
+                    ChangeKind::Delete => self.handle_delete_subsequent(new_change)?,
+                },
+                _ => Some(new_change),
             },
-            _ => Some(new_change),
         };
-        Ok(())
+        Ok(conflict)
+    }
+
+    // updates that don't match a row filter become deletes, so an update after a delete
+    // is the row matching again, which replaces whatever's in the target
+    fn is_row_moving_back_into_filter(&self, new_change: &ParsedLine) -> bool {
+        matches!(
+            (&self.changes, new_change),
+            (
+                Some(ParsedLine::ChangedData {
+                    kind: ChangeKind::Delete,
+                    ..
+                }),
+                ParsedLine::ChangedData {
+                    kind: ChangeKind::Update,
+                    ..
+                },
+            )
+        )
+    }
+
+    fn conflict_with(&self, new_change: &ParsedLine) -> Option<ConflictKind> {
+        if let (
+            Some(ParsedLine::ChangedData {
+                kind: old_kind,
+                columns: old_columns,
+                ..
+            }),
+            ParsedLine::ChangedData { kind, columns, .. },
+        ) = (&self.changes, new_change)
+        {
+            match (old_kind, kind) {
+                (ChangeKind::Insert, ChangeKind::Insert) if old_columns != columns => {
+                    Some(ConflictKind::InsertAfterInsert)
+                }
+                (ChangeKind::Update, ChangeKind::Insert) if old_columns != columns => {
+                    Some(ConflictKind::InsertAfterUpdate)
+                }
+                (ChangeKind::Delete, ChangeKind::Update) => Some(ConflictKind::UpdateAfterDelete),
+                _ => None,
+            }
+        } else {
+            None
+        }
     }
 
     fn handle_insert_subsequent(&self, new_change: ParsedLine) -> Result<Option<ParsedLine>> {
@@ -316,15 +661,27 @@ impl ChangeSet {
 // BTreeMap, because we want to traverse the indices in order
 // when we write them out to files, as this is how it's efficient to load things into redshift.
 // id is the sort key
+// Consolidated tenant tables are keyed by the source schema as well, since ids clash between tenants
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, Eq, PartialEq)]
 enum ChangeSetWithColumnType {
     IntColumnType(BTreeMap<i64, ChangeSet>),
     UuidColumnType(BTreeMap<String, ChangeSet>),
+    TenantIntColumnType(BTreeMap<(String, i64), ChangeSet>),
+    TenantUuidColumnType(BTreeMap<(String, String), ChangeSet>),
 }
 
 impl ChangeSetWithColumnType {
-    fn new(value: &ColumnValue) -> ChangeSetWithColumnType {
+    fn new(value: &ColumnValue, consolidated_tenant_table: bool) -> ChangeSetWithColumnType {
         match value {
+            ColumnValue::Integer(_) if consolidated_tenant_table => {
+                let btree = BTreeMap::<(String, i64), ChangeSet>::new();
+                ChangeSetWithColumnType::TenantIntColumnType(btree)
+            }
+            ColumnValue::Text(_) if consolidated_tenant_table => {
+                let btree = BTreeMap::<(String, String), ChangeSet>::new();
+                ChangeSetWithColumnType::TenantUuidColumnType(btree)
+            }
             ColumnValue::Integer(_) => {
                 let btree = BTreeMap::<i64, ChangeSet>::new();
                 ChangeSetWithColumnType::IntColumnType(btree)
@@ -343,8 +700,18 @@ impl ChangeSetWithColumnType {
     }
     fn values(&self) -> impl Iterator<Item = &ChangeSet> {
         match self {
-            ChangeSetWithColumnType::IntColumnType(btree) => Either::Left(btree.values()),
-            ChangeSetWithColumnType::UuidColumnType(btree) => Either::Right(btree.values()),
+            ChangeSetWithColumnType::IntColumnType(btree) => {
+                Either::Left(Either::Left(btree.values()))
+            }
+            ChangeSetWithColumnType::UuidColumnType(btree) => {
+                Either::Left(Either::Right(btree.values()))
+            }
+            ChangeSetWithColumnType::TenantIntColumnType(btree) => {
+                Either::Right(Either::Left(btree.values()))
+            }
+            ChangeSetWithColumnType::TenantUuidColumnType(btree) => {
+                Either::Right(Either::Right(btree.values()))
+            }
         }
     }
 
@@ -352,6 +719,8 @@ impl ChangeSetWithColumnType {
         match self {
             ChangeSetWithColumnType::IntColumnType(btree) => btree.len(),
             ChangeSetWithColumnType::UuidColumnType(btree) => btree.len(),
+            ChangeSetWithColumnType::TenantIntColumnType(btree) => btree.len(),
+            ChangeSetWithColumnType::TenantUuidColumnType(btree) => btree.len(),
         }
     }
 
@@ -372,6 +741,14 @@ impl ChangeSetWithColumnType {
                 let btree = BTreeMap::<String, ChangeSet>::new();
                 ChangeSetWithColumnType::UuidColumnType(btree)
             }
+            ChangeSetWithColumnType::TenantIntColumnType(..) => {
+                let btree = BTreeMap::<(String, i64), ChangeSet>::new();
+                ChangeSetWithColumnType::TenantIntColumnType(btree)
+            }
+            ChangeSetWithColumnType::TenantUuidColumnType(..) => {
+                let btree = BTreeMap::<(String, String), ChangeSet>::new();
+                ChangeSetWithColumnType::TenantUuidColumnType(btree)
+            }
         }
     }
 }
@@ -391,13 +768,23 @@ struct TableHolder {
 }
 
 impl Table {
+    fn empty_clone(&self) -> Table {
+        Table {
+            changeset: self.changeset.empty_clone(),
+            column_info: self.column_info.clone(),
+            table_name: self.table_name.clone(),
+            column_info_from_target: self.column_info_from_target.clone(),
+        }
+    }
+
     fn new(
         parsed_line: &ParsedLine,
         targets_tables_column_names: &TargetsTablesColumnNames,
     ) -> Result<Table> {
         if let ParsedLine::ChangedData { table_name, .. } = parsed_line {
             let id_column = parsed_line.find_id_column()?.column_value_unwrap();
-            let changeset = ChangeSetWithColumnType::new(id_column);
+            let changeset =
+                ChangeSetWithColumnType::new(id_column, parsed_line.find_source_schema().is_some());
             let column_info = None; // Don't trust the column info from the first parsed line as there might have been schema changes already
             let table_name = table_name.clone();
             let column_info_from_target =
@@ -420,6 +807,7 @@ impl Table {
     fn add_change(
         &mut self,
         parsed_line: ParsedLine,
+        conflicts: &mut Vec<Conflict>,
     ) -> Result<Option<(Table, Option<Vec<DdlChange>>)>> {
         if self.has_ddl_changes(&parsed_line) {
             // if we have ddl changes, send the table data off now, then send the ddl changes, then apply the change
@@ -430,12 +818,12 @@ impl Table {
 
             // time_to_swap_tables is never true immediately after we add the first new change here
             // so we safely don't check it
-            self.add_change_to_changeset(parsed_line)?;
+            conflicts.extend(self.add_change_to_changeset(parsed_line)?);
 
             Ok(Some((returned_table, Some(ddl_changes))))
         } else {
             // no ddl changes, add the line as normal
-            self.add_change_to_changeset(parsed_line)?;
+            conflicts.extend(self.add_change_to_changeset(parsed_line)?);
             Ok(None)
         }
     }
@@ -453,30 +841,68 @@ impl Table {
         }
     }
 
-    fn add_change_to_changeset(&mut self, parsed_line: ParsedLine) -> Result<()> {
+    fn add_change_to_changeset(&mut self, parsed_line: ParsedLine) -> Result<Option<Conflict>> {
         self.update_column_info_if_unset(&parsed_line);
-        if let ParsedLine::ChangedData { .. } = parsed_line {
+        let policy = ConflictPolicy::for_table(&self.table_name);
+        let row_filtered = row_filter::has_row_filters(&self.table_name);
+        let conflict_kind = if let ParsedLine::ChangedData { .. } = parsed_line {
             let parsed_line_id = parsed_line.find_id_column()?;
-            match parsed_line_id.column_value_unwrap() {
-                ColumnValue::Text(string) => {
+            let source_schema = parsed_line.find_source_schema().map(str::to_string);
+            match (parsed_line_id.column_value_unwrap(), source_schema) {
+                (ColumnValue::Text(string), Some(source_schema)) => {
+                    if let ChangeSetWithColumnType::TenantUuidColumnType(ref mut changeset) =
+                        self.changeset
+                    {
+                        let id = format!("{}.{}", source_schema, string);
+                        changeset
+                            .entry((source_schema, string.clone()))
+                            .or_insert_with(ChangeSet::new)
+                            .add_change(parsed_line, policy, row_filtered)?
+                            .map(|kind| (kind, id))
+                    } else {
+                        return Err(self.key_mismatch(&parsed_line));
+                    }
+                }
+                (ColumnValue::Integer(int), Some(source_schema)) => {
+                    if let ChangeSetWithColumnType::TenantIntColumnType(ref mut changeset) =
+                        self.changeset
+                    {
+                        let id = format!("{}.{}", source_schema, int);
+                        changeset
+                            .entry((source_schema, *int))
+                            .or_insert_with(ChangeSet::new)
+                            .add_change(parsed_line, policy, row_filtered)?
+                            .map(|kind| (kind, id))
+                    } else {
+                        return Err(self.key_mismatch(&parsed_line));
+                    }
+                }
+                (ColumnValue::Text(string), None) => {
                     if let ChangeSetWithColumnType::UuidColumnType(ref mut changeset) =
                         self.changeset
                     {
                         let cloned = string.clone();
                         changeset
-                            .entry(cloned)
+                            .entry(cloned.clone())
                             .or_insert_with(|| ChangeSet::new())
-                            .add_change(parsed_line)?
+                            .add_change(parsed_line, policy, row_filtered)?
+                            .map(|kind| (kind, cloned))
+                    } else {
+                        return Err(self.key_mismatch(&parsed_line));
                     }
                 }
-                ColumnValue::Integer(int) => {
+                (ColumnValue::Integer(int), None) => {
                     if let ChangeSetWithColumnType::IntColumnType(ref mut changeset) =
                         self.changeset
                     {
+                        let id = *int;
                         changeset
-                            .entry(*int)
+                            .entry(id)
                             .or_insert_with(|| ChangeSet::new())
-                            .add_change(parsed_line)?
+                            .add_change(parsed_line, policy, row_filtered)?
+                            .map(|kind| (kind, id.to_string()))
+                    } else {
+                        return Err(self.key_mismatch(&parsed_line));
                     }
                 }
                 _ => {
@@ -486,15 +912,30 @@ impl Table {
                         source_line: None,
                     })
                 }
-            };
+            }
         } else {
             return Err(ChangeProcessingError {
                 message: "No changed data present".to_string(),
                 parsed_line: Some(parsed_line.clone()),
                 source_line: None,
             });
+        };
+        Ok(conflict_kind.map(|(kind, id)| Conflict {
+            kind,
+            policy,
+            table_name: self.table_name.clone(),
+            id,
+        }))
+    }
+
+    // the changeset is keyed like the table's first change, e.g. with the source schema
+    // for consolidated tenant tables, so every change needs the same key
+    fn key_mismatch(&self, parsed_line: &ParsedLine) -> ChangeProcessingError {
+        ChangeProcessingError {
+            message: format!("change isn't keyed like the rest of {}", self.table_name),
+            parsed_line: Some(parsed_line.clone()),
+            source_line: None,
         }
-        Ok(())
     }
 
     fn update_column_info_if_unset(&mut self, parsed_line: &ParsedLine) {
@@ -603,6 +1044,35 @@ impl Table {
         }
     }
 
+    // keep our cached schema in line with a rename, so the new name isn't seen as a drop and an add
+    fn rename_column(&mut self, old_column_name: &ColumnName, new_column_name: &ColumnName) {
+        if let Some(column_info) = self.column_info.as_mut() {
+            let old_column_info = column_info
+                .iter()
+                .find(|info| &info.name == old_column_name)
+                .cloned();
+            if let Some(old_column_info) = old_column_info {
+                column_info.remove(&old_column_info);
+                column_info.insert(ColumnInfo {
+                    name: new_column_name.clone(),
+                    column_type: old_column_info.column_type,
+                });
+            }
+        }
+        if let Some(target_table) = self.column_info_from_target.as_mut() {
+            target_table.column_info = target_table
+                .column_info
+                .drain()
+                .map(|mut info| {
+                    if &info.name == old_column_name {
+                        info.name = new_column_name.clone();
+                    }
+                    info
+                })
+                .collect();
+        }
+    }
+
     fn get_stats(&self) -> (usize, usize) {
         let number_of_ids = self.changeset.len();
         let number_of_changes = self.changeset.values().fold(0, |acc, value| {
@@ -621,15 +1091,20 @@ impl TableHolder {
         &mut self,
         parsed_line: ParsedLine,
         targets_tables_column_names: &TargetsTablesColumnNames,
+        conflicts: &mut Vec<Conflict>,
     ) -> Result<Option<(Table, Option<Vec<DdlChange>>)>> {
         if let ParsedLine::ChangedData { ref table_name, .. } = parsed_line {
             // these are cheap since this is an interned string
             let entry = self.tables.entry(table_name.clone());
             match entry {
-                Occupied(mut entry_value) => Ok(entry_value.get_mut().add_change(parsed_line)?),
+                Occupied(mut entry_value) => {
+                    Ok(entry_value.get_mut().add_change(parsed_line, conflicts)?)
+                }
                 Vacant(entry_value) => {
                     let new_table = Table::new(&parsed_line, targets_tables_column_names)?;
-                    Ok(entry_value.insert(new_table).add_change(parsed_line)?)
+                    Ok(entry_value
+                        .insert(new_table)
+                        .add_change(parsed_line, conflicts)?)
                 }
             }
         } else {
@@ -650,11 +1125,155 @@ impl TableHolder {
     }
 }
 
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+struct RowsByKind {
+    inserts: usize,
+    updates: usize,
+    deletes: usize,
+}
+
+impl RowsByKind {
+    fn add(&mut self, kind: ChangeKind) {
+        match kind {
+            ChangeKind::Insert => self.inserts += 1,
+            ChangeKind::Update => self.updates += 1,
+            ChangeKind::Delete => self.deletes += 1,
+        }
+    }
+}
+
+// what we saw for a table in a batch, and what we're sending on after collapsing it
+#[derive(Debug, Eq, PartialEq)]
+struct TableBatchStats {
+    rows_before_collapse: RowsByKind,
+    rows_after_collapse: RowsByKind,
+    distinct_ids: usize,
+    update_files: usize,
+    csv_bytes: u64,
+    // updates that may be moving rows into the row filter, but have unchanged toast columns, so
+    // they don't have the whole row to insert
+    partial_rows_moved_into_filter: usize,
+}
+
+impl TableBatchStats {
+    fn new(
+        table: &Table,
+        rows_before_collapse: RowsByKind,
+        file_writer: &mut file_writer::FileWriter,
+    ) -> TableBatchStats {
+        let (distinct_ids, _changes) = table.get_stats();
+        let mut rows_after_collapse = RowsByKind::default();
+        let mut partial_rows_moved_into_filter = 0;
+        table.changeset.values().for_each(|record| {
+            if let Some(ParsedLine::ChangedData { kind, columns, .. }) = &record.changes {
+                rows_after_collapse.add(*kind);
+                if record.moved_into_filter
+                    && *kind == ChangeKind::Update
+                    && columns.iter().any(Column::is_unchanged_toast_column)
+                {
+                    partial_rows_moved_into_filter += 1;
+                }
+            }
+        });
+        TableBatchStats {
+            rows_before_collapse,
+            rows_after_collapse,
+            distinct_ids,
+            update_files: file_writer.update_files.len(),
+            csv_bytes: file_writer.csv_bytes(),
+            partial_rows_moved_into_filter,
+        }
+    }
+
+    fn emit(&self, statsd: &StatsdWrapper, table_name: &TableName, wal_file_number: u64) {
+        let tags = [
+            format!("table_name:{}", table_name),
+            format!("wal_file:{}", wal_file_number),
+        ];
+        let with_kind = |kind: ChangeKind| {
+            let mut tags_with_kind = tags.to_vec();
+            tags_with_kind.push(format!("kind:{}", kind.to_string()));
+            tags_with_kind
+        };
+        for (kind, before, after) in [
+            (
+                ChangeKind::Insert,
+                self.rows_before_collapse.inserts,
+                self.rows_after_collapse.inserts,
+            ),
+            (
+                ChangeKind::Update,
+                self.rows_before_collapse.updates,
+                self.rows_after_collapse.updates,
+            ),
+            (
+                ChangeKind::Delete,
+                self.rows_before_collapse.deletes,
+                self.rows_after_collapse.deletes,
+            ),
+        ] {
+            statsd.count("batch_rows_before_collapse", before as i64, with_kind(kind));
+            statsd.count("batch_rows_after_collapse", after as i64, with_kind(kind));
+        }
+        statsd.count("batch_distinct_ids", self.distinct_ids as i64, &tags);
+        statsd.count("batch_update_files", self.update_files as i64, &tags);
+        statsd.count("batch_csv_bytes", self.csv_bytes as i64, &tags);
+        if self.partial_rows_moved_into_filter > 0 {
+            // these only update rows that are there, so any missing from the target stay missing
+            statsd.count(
+                "partial_rows_moved_into_filter",
+                self.partial_rows_moved_into_filter as i64,
+                &tags,
+            );
+            logger_error!(
+                Some(wal_file_number),
+                Some(table_name),
+                &format!(
+                    "partial_rows_moved_into_filter rows:{} (updates with unchanged toast columns can't insert rows missing from the target, backfill the table)",
+                    self.partial_rows_moved_into_filter
+                )
+            );
+        }
+        logger_info!(
+            Some(wal_file_number),
+            Some(table_name),
+            &format!(
+                "drained_final_changes:{} inserts_before_collapse:{} updates_before_collapse:{} deletes_before_collapse:{} inserts:{} updates:{} deletes:{} distinct_ids:{} update_files:{} csv_bytes:{}",
+                table_name,
+                self.rows_before_collapse.inserts,
+                self.rows_before_collapse.updates,
+                self.rows_before_collapse.deletes,
+                self.rows_after_collapse.inserts,
+                self.rows_after_collapse.updates,
+                self.rows_after_collapse.deletes,
+                self.distinct_ids,
+                self.update_files,
+                self.csv_bytes
+            )
+        );
+    }
+}
+
 // single threaded f'now
 pub struct ChangeProcessing {
     table_holder: TableHolder,
     associated_wal_file: Option<WalFile>,
     targets_tables_column_names: TargetsTablesColumnNames,
+    // rows we've been given for each table since we last wrote its files
+    rows_before_collapse: HashMap<TableName, RowsByKind>,
+    statsd: StatsdWrapper,
+    // what's already applied to the target, from a previous run
+    checkpoints: Checkpoints,
+    last_commit: Option<Commit>,
+    // the lsn of the commit we're about to be given, when streaming
+    next_commit_lsn: Option<Lsn>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Commit {
+    xid: i64,
+    commit_timestamp: Option<String>,
+    lsn: Option<Lsn>,
 }
 
 impl ChangeProcessing {
@@ -664,9 +1283,43 @@ impl ChangeProcessing {
             table_holder: TableHolder { tables: hash_map },
             associated_wal_file: None,
             targets_tables_column_names: targets_tables_column_names,
+            rows_before_collapse: HashMap::new(),
+            statsd: StatsdWrapper::new(),
+            checkpoints: Checkpoints::default(),
+            last_commit: None,
+            next_commit_lsn: None,
         }
     }
 
+    // what we know about the tables, without any of their changes,
+    // to process a later wal file alongside this one
+    pub fn fork(&self) -> ChangeProcessing {
+        let tables = self
+            .table_holder
+            .tables
+            .iter()
+            .map(|(table_name, table)| (table_name.clone(), table.empty_clone()))
+            .collect();
+        ChangeProcessing {
+            table_holder: TableHolder { tables },
+            associated_wal_file: None,
+            targets_tables_column_names: self.targets_tables_column_names.clone(),
+            rows_before_collapse: HashMap::new(),
+            statsd: StatsdWrapper::new(),
+            checkpoints: self.checkpoints.clone(),
+            last_commit: self.last_commit.clone(),
+            next_commit_lsn: None,
+        }
+    }
+
+    pub fn register_checkpoints(&mut self, checkpoints: Checkpoints) {
+        self.checkpoints = checkpoints;
+    }
+
+    pub fn register_commit_lsn(&mut self, commit_lsn: Lsn) {
+        self.next_commit_lsn = Some(commit_lsn);
+    }
+
     // notice this is a move of the wal file
     pub fn register_wal_file(&mut self, associated_wal_file: Option<WalFile>) {
         // if there are no changes,
@@ -686,77 +1339,296 @@ impl ChangeProcessing {
         &mut self,
         parsed_line: ParsedLine,
     ) -> Result<Option<Vec<ChangeProcessingResult>>> {
+        self.record_in_manifest(&parsed_line);
         match parsed_line {
-            ParsedLine::Begin(_)
-            | ParsedLine::Commit(_)
-            | ParsedLine::PgRcvlogicalMsg(_)
-            | ParsedLine::Truncate => Ok(None),
+            ParsedLine::Commit(xid, commit_timestamp) => {
+                self.last_commit = Some(Commit {
+                    xid,
+                    commit_timestamp,
+                    lsn: self.next_commit_lsn.take(),
+                });
+                Ok(None)
+            }
+            ParsedLine::Begin(_) | ParsedLine::PgRcvlogicalMsg(_) | ParsedLine::Truncate => {
+                Ok(None)
+            }
             ParsedLine::ContinueParse => Ok(None), // need to be exhaustive
+            ParsedLine::ChangedData { ref table_name, .. }
+                if Self::is_ddl_audit_table(table_name) =>
+            {
+                self.handle_ddl_audit_change(parsed_line)
+            }
             ParsedLine::ChangedData { .. } => {
-                // map here maps over the option
-                // NOTE: this means that we must return a table if we want to return a ddl result
-                Ok(self
-                    .table_holder
-                    .add_change(parsed_line, &self.targets_tables_column_names)?
-                    .map(|(returned_table, maybe_ddl_changes)| {
-                        let mut start_vec = vec![ChangeProcessingResult::TableChanges(
-                            Self::write_files_for_table(
-                                returned_table,
-                                self.associated_wal_file
-                                    .clone()
-                                    .expect("Error: Trying to write files with no wal file?"),
-                            ),
-                        )];
-                        if let Some(ddl_changes) = maybe_ddl_changes {
-                            for ddl_change in ddl_changes {
-                                start_vec.push(ChangeProcessingResult::DdlChange(
-                                    ddl_change,
-                                    self.associated_wal_file
-                                        .clone()
-                                        .expect("Unable to find wal_file for ddl_change"),
-                                ))
-                            }
-                        }
-                        start_vec
-                    }))
+                // filtered rows never make it into our tables
+                match row_filter::apply_row_filters(parsed_line)? {
+                    Some(filtered_line) => self.add_changed_data(filtered_line),
+                    None => Ok(None),
+                }
             }
         }
     }
 
-    /// Get statistics for all tables. Used for debugging and testing.
-    #[allow(dead_code)] // Used in tests, intentionally part of public API for debugging
-    pub fn get_stats(&self) -> HashMap<&TableName, usize> {
-        self.table_holder
-            .tables
+    // every row in the wal file, before any are filtered out
+    fn record_in_manifest(&self, parsed_line: &ParsedLine) {
+        if let Some(wal_file) = &self.associated_wal_file {
+            match parsed_line {
+                ParsedLine::Commit(xid, commit_timestamp) => {
+                    wal_file.record_in_manifest(|manifest| {
+                        manifest.add_commit(*xid, commit_timestamp.as_deref())
+                    })
+                }
+                ParsedLine::ChangedData {
+                    table_name, kind, ..
+                } => wal_file.record_in_manifest(|manifest| manifest.add_row(table_name, *kind)),
+                _ => {}
+            }
+        }
+    }
+
+    fn add_changed_data(
+        &mut self,
+        parsed_line: ParsedLine,
+    ) -> Result<Option<Vec<ChangeProcessingResult>>> {
+        let (table_name, kind) = match &parsed_line {
+            ParsedLine::ChangedData {
+                table_name, kind, ..
+            } => (table_name.clone(), *kind),
+            _ => panic!("add_changed_data called on non-changed data"),
+        };
+        let mut conflicts = vec![];
+        // NOTE: this means that we must return a table if we want to return a ddl result
+        let result = self
+            .table_holder
+            .add_change(
+                parsed_line,
+                &self.targets_tables_column_names,
+                &mut conflicts,
+            )?
+            .map(|(returned_table, maybe_ddl_changes)| {
+                let mut start_vec: Vec<ChangeProcessingResult> = self
+                    .write_files_for_table(
+                        returned_table,
+                        self.associated_wal_file
+                            .clone()
+                            .expect("Error: Trying to write files with no wal file?"),
+                    )
+                    .map(|(file_writer, _stats)| ChangeProcessingResult::TableChanges(file_writer))
+                    .into_iter()
+                    .collect();
+                if let Some(ddl_changes) = maybe_ddl_changes {
+                    for ddl_change in ddl_changes {
+                        start_vec.push(ChangeProcessingResult::DdlChange(
+                            ddl_change,
+                            self.associated_wal_file
+                                .clone()
+                                .expect("Unable to find wal_file for ddl_change"),
+                        ))
+                    }
+                }
+                start_vec
+            });
+        // counted after any returned table, since this change is part of the next batch
+        self.rows_before_collapse
+            .entry(table_name)
+            .or_default()
+            .add(kind);
+        conflicts
             .iter()
-            .map(|(table_name, table)| (table_name, table.get_stats().0))
-            .collect()
+            .for_each(|conflict| self.report_conflict(conflict));
+        Ok(result)
+    }
+
+    fn report_conflict(&self, conflict: &Conflict) {
+        logger_info!(
+            self.associated_wal_file
+                .as_ref()
+                .map(|wal_file| wal_file.file_number),
+            Some(&conflict.table_name),
+            &format!(
+                "resolved_conflict:{} policy:{} id:{}",
+                conflict.kind, conflict.policy, conflict.id
+            )
+        );
+        self.statsd.count(
+            "change_conflicts",
+            1,
+            &[
+                format!("table_name:{}", conflict.table_name),
+                format!("conflict:{}", conflict.kind),
+                format!("policy:{}", conflict.policy),
+            ],
+        );
+    }
+
+    fn is_ddl_audit_table(table_name: &TableName) -> bool {
+        DDL_AUDIT_TABLE.as_deref() == Some(table_name.as_str())
+    }
+
+    // rows in the ddl audit table are turned into explicit ddl changes rather than replicated.
+    // Any changes we're holding for the affected table are sent first, so they're applied
+    // against the schema they were made with.
+    fn handle_ddl_audit_change(
+        &mut self,
+        parsed_line: ParsedLine,
+    ) -> Result<Option<Vec<ChangeProcessingResult>>> {
+        if let ParsedLine::ChangedData { kind, .. } = parsed_line {
+            if kind != ChangeKind::Insert {
+                // we only care about new events, tidying up the audit table is fine
+                return Ok(None);
+            }
+        }
+        let ddl_change = DdlAuditRecord::from_parsed_line(&parsed_line)?.ddl_change()?;
+        let wal_file = self
+            .associated_wal_file
+            .clone()
+            .expect("Unable to find wal_file for ddl_change");
+        logger_info!(
+            Some(wal_file.file_number),
+            Some(&ddl_change.table_name()),
+            &format!("ddl_audit_change:{:?}", ddl_change)
+        );
+        let mut results = vec![];
+        match &ddl_change {
+            DdlChange::RenameColumn(old_column_name, new_column_name, table_name) => {
+                if let Some(table) = self.table_holder.tables.get_mut(table_name) {
+                    let returned_table = table.reset_and_return_table_data();
+                    table.rename_column(old_column_name, new_column_name);
+                    if returned_table.len() > 0 {
+                        results.extend(
+                            self.write_files_for_table(returned_table, wal_file.clone())
+                                .map(|(file_writer, _stats)| {
+                                    ChangeProcessingResult::TableChanges(file_writer)
+                                }),
+                        );
+                    }
+                }
+                self.targets_tables_column_names.rename_column(
+                    table_name,
+                    old_column_name,
+                    new_column_name,
+                );
+            }
+            DdlChange::RenameTable(old_table_name, new_table_name) => {
+                if let Some(mut table) = self.table_holder.tables.remove(old_table_name) {
+                    if table.len() > 0 {
+                        let returned_table = table.reset_and_return_table_data();
+                        results.extend(
+                            self.write_files_for_table(returned_table, wal_file.clone())
+                                .map(|(file_writer, _stats)| {
+                                    ChangeProcessingResult::TableChanges(file_writer)
+                                }),
+                        );
+                    }
+                    table.table_name = new_table_name.clone();
+                    self.table_holder
+                        .tables
+                        .insert(new_table_name.clone(), table);
+                }
+                self.targets_tables_column_names
+                    .rename_table(old_table_name, new_table_name);
+            }
+            DdlChange::DropTable(table_name) => {
+                // no point loading changes into a table we're about to drop
+                if let Some(table) = self.table_holder.tables.remove(table_name) {
+                    logger_info!(
+                        Some(wal_file.file_number),
+                        Some(table_name),
+                        &format!("discarding_changes_for_dropped_table:{}", table.len())
+                    );
+                }
+                self.rows_before_collapse.remove(table_name);
+                self.targets_tables_column_names.remove_table(table_name);
+            }
+            DdlChange::CreateTable(table_name, columns) => {
+                self.targets_tables_column_names.add_table(
+                    table_name,
+                    columns.iter().map(|column| column.name.clone()).collect(),
+                );
+            }
+            // these are inferred from the stream, never from the audit table
+            DdlChange::AddColumn(..) | DdlChange::RemoveColumn(..) => {}
+        }
+        results.push(ChangeProcessingResult::DdlChange(ddl_change, wal_file));
+        Ok(Some(results))
     }
 
-    /// Print statistics for all tables. Used for debugging and testing.
-    #[allow(dead_code)] // Used in tests, intentionally part of public API for debugging
-    pub fn print_stats(&self) {
+    /// Get statistics for all tables. Used for testing, the batch metrics are in TableBatchStats.
+    #[cfg(test)]
+    pub fn get_stats(&self) -> HashMap<&TableName, usize> {
         self.table_holder
             .tables
             .iter()
-            .for_each(|(table_name, table)| {
-                let (ids, _changes) = table.get_stats();
-                println!("{} {}", ids, table_name)
-            });
+            .map(|(table_name, table)| (table_name, table.get_stats().0))
+            .collect()
     }
 
+    // None if a previous run already applied this batch,
+    // or we couldn't create its files (and are shutting down).
+    // the stats are returned as well as emitted, so they can be checked
     fn write_files_for_table(
+        &mut self,
         table: Table,
-        associated_wal_file: WalFile,
-    ) -> file_writer::FileWriter {
-        let table_name = table.table_name;
-        let mut file_writer = file_writer::FileWriter::new(table_name.clone(), associated_wal_file);
+        mut associated_wal_file: WalFile,
+    ) -> Option<(file_writer::FileWriter, TableBatchStats)> {
+        let table_name = table.table_name.clone();
+        let wal_file_number = associated_wal_file.file_number;
+        if self.checkpoints.is_applied(&table_name, wal_file_number) {
+            let rows_before_collapse = self
+                .rows_before_collapse
+                .remove(&table_name)
+                .unwrap_or_default();
+            let rows = rows_before_collapse.inserts
+                + rows_before_collapse.updates
+                + rows_before_collapse.deletes;
+            if rows > 0 {
+                logger_info!(
+                    Some(wal_file_number),
+                    Some(&table_name),
+                    &format!("skipping_applied_batch rows:{}", rows)
+                );
+            }
+            return None;
+        }
+        let mut file_writer =
+            match file_writer::FileWriter::new(table_name.clone(), associated_wal_file.clone()) {
+                Ok(file_writer) => file_writer,
+                Err(err) => {
+                    logger_error!(
+                        Some(wal_file_number),
+                        Some(&table_name),
+                        &format!("file_create_failed error:{}", err)
+                    );
+                    // the wal file is kept, to reprocess this batch
+                    associated_wal_file.register_error();
+                    ShutdownHandler::register_messy_shutdown();
+                    return None;
+                }
+            };
         table.changeset.values().for_each(|record| {
             if let Some(change) = &record.changes {
-                file_writer.add_change(change);
+                file_writer.add_change(change, record.replaces_row);
             };
         });
-        file_writer
+        let rows_before_collapse = self
+            .rows_before_collapse
+            .remove(&table_name)
+            .unwrap_or_default();
+        let stats = TableBatchStats::new(&table, rows_before_collapse, &mut file_writer);
+        stats.emit(&self.statsd, &table_name, wal_file_number);
+        Some((file_writer, stats))
+    }
+
+    fn checkpoint_for(&self, file_writer: &file_writer::FileWriter) -> Checkpoint {
+        Checkpoint {
+            table_name: file_writer.table_name.clone(),
+            wal_file_number: file_writer.wal_file.file_number,
+            xid: self.last_commit.as_ref().map(|commit| commit.xid),
+            commit_timestamp: self
+                .last_commit
+                .as_ref()
+                .and_then(|commit| commit.commit_timestamp.clone()),
+            lsn: self.last_commit.as_ref().and_then(|commit| commit.lsn),
+        }
     }
 
     // this empties every table from the changeset,
@@ -766,20 +1638,26 @@ impl ChangeProcessing {
     // schema info which we use for ddl changes
     pub fn drain_final_changes(&mut self) -> Vec<ChangeProcessingResult> {
         let maybe_associated_wal_file = self.associated_wal_file.clone();
-        // error if associated_wal_file is null
-        let resulting_vec = self
+        let returned_tables: Vec<Table> = self
             .table_holder
             .tables
-            .iter_mut()
-            .map(|(_table_name, table)| {
+            .values_mut()
+            .map(|table| table.reset_and_return_table_data())
+            .collect();
+        // error if associated_wal_file is null
+        let resulting_vec = returned_tables
+            .into_iter()
+            .filter_map(|returned_table| {
                 // need to clone again because this is in a loop
-                let file_writer = Self::write_files_for_table(
-                    table.reset_and_return_table_data(), // this
+                let (mut file_writer, _stats) = self.write_files_for_table(
+                    returned_table,
                     maybe_associated_wal_file
                         .clone()
                         .expect("Error: trying to write tables with no wal file"),
-                );
-                ChangeProcessingResult::TableChanges(file_writer)
+                )?;
+                // the table's last batch for this wal file, so it finishes the wal file
+                file_writer.checkpoint = Some(self.checkpoint_for(&file_writer));
+                Some(ChangeProcessingResult::TableChanges(file_writer))
             })
             .collect();
         logger_info!(
@@ -838,8 +1716,15 @@ mod tests {
     #[ctor::ctor]
     fn create_tmp_directory() {
         std::fs::create_dir_all(TESTING_PATH).unwrap();
+        std::env::set_var("DDL_AUDIT_TABLE", DDL_AUDIT_TABLE_NAME);
+        std::env::set_var(
+            "CONFLICT_POLICY_OVERRIDES",
+            "public.last_writer_wins_table:last_writer_wins,public.skip_and_log_table:skip_and_log",
+        );
     }
 
+    const DDL_AUDIT_TABLE_NAME: &str = "public.re_dms_ddl_audit";
+
     // TODO stub filesystem properly
     const TESTING_PATH: &str = "/tmp/wal_change_processing_testing";
 
@@ -851,6 +1736,60 @@ mod tests {
         )
     }
 
+    fn ddl_audit_row(kind: ChangeKind, values: Vec<(&str, Option<&str>)>) -> ParsedLine {
+        let mut columns = vec![Column::ChangedColumn {
+            column_info: ColumnInfo::new("id", "bigint"),
+            value: Some(ColumnValue::Integer(1)),
+        }];
+        for (name, value) in values {
+            columns.push(Column::ChangedColumn {
+                column_info: ColumnInfo::new(name, "text"),
+                value: value.map(|value| ColumnValue::Text(value.to_string())),
+            });
+        }
+        ParsedLine::ChangedData {
+            kind,
+            table_name: TableName::new(DDL_AUDIT_TABLE_NAME.to_string()),
+            columns,
+        }
+    }
+
+    fn insert_with_columns(table_name: &TableName, columns: Vec<(&str, i64)>) -> ParsedLine {
+        change_with_columns(ChangeKind::Insert, table_name, columns)
+    }
+
+    fn change_with_columns(
+        kind: ChangeKind,
+        table_name: &TableName,
+        columns: Vec<(&str, i64)>,
+    ) -> ParsedLine {
+        ParsedLine::ChangedData {
+            kind,
+            table_name: table_name.clone(),
+            columns: columns
+                .into_iter()
+                .map(|(name, value)| Column::ChangedColumn {
+                    column_info: ColumnInfo::new(name, "bigint"),
+                    value: Some(ColumnValue::Integer(value)),
+                })
+                .collect(),
+        }
+    }
+
+    fn pending_change(
+        change_processing: &ChangeProcessing,
+        table_name: &TableName,
+        id: i64,
+    ) -> Option<ParsedLine> {
+        if let ChangeSetWithColumnType::IntColumnType(btree) =
+            &change_processing.table_holder.tables[table_name].changeset
+        {
+            btree[&id].changes.clone()
+        } else {
+            panic!("expected an integer id")
+        }
+    }
+
     fn clear_testing_directory() {
         // clear directory
         let directory_path = PathBuf::from(TESTING_PATH);
@@ -1514,6 +2453,8 @@ mod tests {
                     table_name: table_name.clone(),
                     kind: ChangeKind::Insert,
                 }),
+                replaces_row: false,
+                moved_into_filter: false,
             },
         );
         let expected_change_set_1 = ChangeSetWithColumnType::IntColumnType(expected_changes_1);
@@ -1564,6 +2505,8 @@ mod tests {
                     table_name: table_name.clone(),
                     kind: ChangeKind::Insert,
                 }),
+                replaces_row: false,
+                moved_into_filter: false,
             },
         );
         let expected_change_set_2 = ChangeSetWithColumnType::IntColumnType(expected_changes_2);
@@ -1591,7 +2534,14 @@ mod tests {
             .add_change(change_3)
             .expect("Failed processing changes");
         let mut expected_changes_3 = BTreeMap::<i64, ChangeSet>::new();
-        expected_changes_3.insert(1, ChangeSet { changes: None });
+        expected_changes_3.insert(
+            1,
+            ChangeSet {
+                changes: None,
+                replaces_row: false,
+                moved_into_filter: false,
+            },
+        );
         let expected_change_set_3 = ChangeSetWithColumnType::IntColumnType(expected_changes_3);
         let expected_table_holder_3 = TableHolder {
             tables: hashmap!(table_name.clone() => Table {
@@ -1856,6 +2806,8 @@ mod tests {
                     table_name: table_name.clone(),
                     kind: ChangeKind::Update,
                 }),
+                replaces_row: false,
+                moved_into_filter: false,
             },
         );
         let expected_change_set_1 = ChangeSetWithColumnType::IntColumnType(expected_changes_1);
@@ -1914,6 +2866,8 @@ mod tests {
                     table_name: table_name.clone(),
                     kind: ChangeKind::Update,
                 }),
+                replaces_row: false,
+                moved_into_filter: false,
             },
         );
         let expected_change_set_1 = ChangeSetWithColumnType::IntColumnType(expected_changes_1);
@@ -1964,6 +2918,8 @@ mod tests {
                     table_name: table_name.clone(),
                     kind: ChangeKind::Update,
                 }),
+                replaces_row: false,
+                moved_into_filter: false,
             },
         );
         let expected_change_set_2 = ChangeSetWithColumnType::IntColumnType(expected_changes_2);
@@ -2002,6 +2958,8 @@ mod tests {
                     table_name: table_name.clone(),
                     kind: ChangeKind::Delete,
                 }),
+                replaces_row: false,
+                moved_into_filter: false,
             },
         );
         let expected_change_set_3 = ChangeSetWithColumnType::IntColumnType(expected_changes_3);
@@ -2085,6 +3043,8 @@ mod tests {
                     table_name: table_name.clone(),
                     kind: ChangeKind::Insert,
                 }),
+                replaces_row: false,
+                moved_into_filter: false,
             },
         );
         let expected_change_set_1 = ChangeSetWithColumnType::IntColumnType(expected_changes_1);
@@ -2134,6 +3094,8 @@ mod tests {
                     table_name: table_name.clone(),
                     kind: ChangeKind::Insert,
                 }),
+                replaces_row: false,
+                moved_into_filter: false,
             },
         );
         let expected_change_set_2 = ChangeSetWithColumnType::IntColumnType(expected_changes_2);
@@ -2193,6 +3155,8 @@ mod tests {
                     table_name: table_name.clone(),
                     kind: ChangeKind::Update,
                 }),
+                replaces_row: false,
+                moved_into_filter: false,
             },
         );
         let expected_change_set_1 = ChangeSetWithColumnType::IntColumnType(expected_changes_1);
@@ -2242,6 +3206,8 @@ mod tests {
                     table_name: table_name.clone(),
                     kind: ChangeKind::Update,
                 }),
+                replaces_row: false,
+                moved_into_filter: false,
             },
         );
         let expected_change_set_2 = ChangeSetWithColumnType::IntColumnType(expected_changes_2);
@@ -2299,6 +3265,8 @@ mod tests {
                     table_name: table_name.clone(),
                     kind: ChangeKind::Update,
                 }),
+                replaces_row: false,
+                moved_into_filter: false,
             },
         );
         let expected_change_set_1 = ChangeSetWithColumnType::IntColumnType(expected_changes_1);
@@ -2347,6 +3315,8 @@ mod tests {
                     table_name: table_name.clone(),
                     kind: ChangeKind::Update,
                 }),
+                replaces_row: false,
+                moved_into_filter: false,
             },
         );
         let expected_change_set_2 = ChangeSetWithColumnType::IntColumnType(expected_changes_2);
@@ -2360,4 +3330,585 @@ mod tests {
         assert_eq!(change_processing.table_holder, expected_table_holder_2);
         assert!(result_2.is_none());
     }
+
+    #[test]
+    fn ddl_audit_rename_column_is_not_a_remove_and_add() {
+        clear_testing_directory();
+        let table_name = TableName::new("public.foobar".to_string());
+        let mut tables_columns_names_map = HashMap::new();
+        tables_columns_names_map.insert(
+            TableName::new("foobar".to_string()),
+            hashset!(
+                ColumnName::new("id".to_string()),
+                ColumnName::new("old_name".to_string())
+            ),
+        );
+        let mut change_processing =
+            ChangeProcessing::new(TargetsTablesColumnNames::from_map(tables_columns_names_map));
+        change_processing.register_wal_file(Some(new_wal_file()));
+
+        let first_result = change_processing
+            .add_change(insert_with_columns(
+                &table_name,
+                vec![("id", 1), ("old_name", 1)],
+            ))
+            .expect("Failed processing changes");
+        assert!(first_result.is_none());
+
+        let mut rename_result = change_processing
+            .add_change(ddl_audit_row(
+                ChangeKind::Insert,
+                vec![
+                    ("event", Some("rename_column")),
+                    ("table_name", Some("public.foobar")),
+                    ("old_name", Some("old_name")),
+                    ("new_name", Some("new_name")),
+                    ("column_definitions", None),
+                ],
+            ))
+            .expect("Failed processing changes")
+            .expect("Expected a ddl change");
+        // the pending change for the table, then the rename
+        assert_eq!(rename_result.len(), 2);
+        assert!(matches!(
+            rename_result.remove(0),
+            ChangeProcessingResult::TableChanges(..)
+        ));
+        if let ChangeProcessingResult::DdlChange(
+            DdlChange::RenameColumn(old_column_name, new_column_name, ddl_table_name),
+            _,
+        ) = rename_result.remove(0)
+        {
+            assert_eq!(old_column_name, ColumnName::new("old_name".to_string()));
+            assert_eq!(new_column_name, ColumnName::new("new_name".to_string()));
+            assert_eq!(ddl_table_name, table_name);
+        } else {
+            panic!("doesn't match rename_column");
+        }
+        // the audit table itself isn't replicated
+        assert_eq!(change_processing.get_stats(), hashmap!(&table_name => 0));
+
+        // a change using the new name doesn't look like ddl
+        let second_result = change_processing
+            .add_change(insert_with_columns(
+                &table_name,
+                vec![("id", 2), ("new_name", 1)],
+            ))
+            .expect("Failed processing changes");
+        assert!(second_result.is_none());
+        assert_eq!(change_processing.get_stats(), hashmap!(&table_name => 1));
+    }
+
+    #[test]
+    fn ddl_audit_rename_table_moves_pending_table() {
+        clear_testing_directory();
+        let table_name = TableName::new("public.foobar".to_string());
+        let new_table_name = TableName::new("public.barfoo".to_string());
+        let mut change_processing = ChangeProcessing::new(TargetsTablesColumnNames::new());
+        change_processing.register_wal_file(Some(new_wal_file()));
+        change_processing
+            .add_change(insert_with_columns(&table_name, vec![("id", 1)]))
+            .expect("Failed processing changes");
+
+        let mut rename_result = change_processing
+            .add_change(ddl_audit_row(
+                ChangeKind::Insert,
+                vec![
+                    ("event", Some("rename_table")),
+                    ("table_name", Some("public.foobar")),
+                    ("new_name", Some("barfoo")),
+                ],
+            ))
+            .expect("Failed processing changes")
+            .expect("Expected a ddl change");
+        assert_eq!(rename_result.len(), 2);
+        assert!(matches!(
+            rename_result.remove(0),
+            ChangeProcessingResult::TableChanges(..)
+        ));
+        assert!(matches!(
+            rename_result.remove(0),
+            ChangeProcessingResult::DdlChange(DdlChange::RenameTable(ref old, ref new), _)
+                if old == &table_name && new == &new_table_name
+        ));
+        assert_eq!(
+            change_processing.get_stats(),
+            hashmap!(&new_table_name => 0)
+        );
+    }
+
+    #[test]
+    fn ddl_audit_drop_table_discards_pending_changes() {
+        clear_testing_directory();
+        let table_name = TableName::new("public.foobar".to_string());
+        let mut change_processing = ChangeProcessing::new(TargetsTablesColumnNames::new());
+        change_processing.register_wal_file(Some(new_wal_file()));
+        change_processing
+            .add_change(insert_with_columns(&table_name, vec![("id", 1)]))
+            .expect("Failed processing changes");
+
+        let mut drop_result = change_processing
+            .add_change(ddl_audit_row(
+                ChangeKind::Insert,
+                vec![
+                    ("event", Some("drop_table")),
+                    ("table_name", Some("public.foobar")),
+                ],
+            ))
+            .expect("Failed processing changes")
+            .expect("Expected a ddl change");
+        assert_eq!(drop_result.len(), 1);
+        assert!(matches!(
+            drop_result.remove(0),
+            ChangeProcessingResult::DdlChange(DdlChange::DropTable(ref dropped), _)
+                if dropped == &table_name
+        ));
+        assert_eq!(change_processing.get_stats(), hashmap!());
+    }
+
+    #[test]
+    fn column_definitions_that_dont_parse_are_refused() {
+        assert_eq!(
+            DdlAuditRecord::parse_column_definitions("id:bigint;tags:text[]").unwrap(),
+            vec![
+                ColumnInfo::new("id", "bigint"),
+                ColumnInfo::new("tags", "text[]")
+            ]
+        );
+        for column_definitions in [
+            "",
+            "id:bigint;",
+            "id:bigint;;name:text",
+            "id:bigint;name",
+            "id:bigint;na:me:text",
+            "id:bigint;:text",
+            "id:bigint;name:",
+            "id:bigint; name:text",
+            "id:bigint;id:text",
+        ] {
+            assert!(
+                DdlAuditRecord::parse_column_definitions(column_definitions).is_err(),
+                "{:?} parsed",
+                column_definitions
+            );
+        }
+    }
+
+    #[test]
+    fn ddl_audit_create_table_and_ignored_rows() {
+        clear_testing_directory();
+        let mut change_processing = ChangeProcessing::new(TargetsTablesColumnNames::new());
+        change_processing.register_wal_file(Some(new_wal_file()));
+
+        let mut create_result = change_processing
+            .add_change(ddl_audit_row(
+                ChangeKind::Insert,
+                vec![
+                    ("event", Some("create_table")),
+                    ("table_name", Some("public.foobar")),
+                    ("column_definitions", Some("id:bigint;name:text")),
+                ],
+            ))
+            .expect("Failed processing changes")
+            .expect("Expected a ddl change");
+        if let ChangeProcessingResult::DdlChange(DdlChange::CreateTable(table_name, columns), _) =
+            create_result.remove(0)
+        {
+            assert_eq!(table_name, TableName::new("public.foobar".to_string()));
+            assert_eq!(
+                columns,
+                vec![
+                    ColumnInfo::new("id", "bigint"),
+                    ColumnInfo::new("name", "text")
+                ]
+            );
+        } else {
+            panic!("doesn't match create_table");
+        }
+
+        // cleaning up the audit table does nothing
+        let delete_result = change_processing
+            .add_change(ddl_audit_row(ChangeKind::Delete, vec![]))
+            .expect("Failed processing changes");
+        assert!(delete_result.is_none());
+
+        // unqualified table names are refused
+        assert!(change_processing
+            .add_change(ddl_audit_row(
+                ChangeKind::Insert,
+                vec![
+                    ("event", Some("drop_table")),
+                    ("table_name", Some("foobar"))
+                ],
+            ))
+            .is_err());
+    }
+
+    #[test]
+    fn table_batch_stats_before_and_after_collapse() {
+        clear_testing_directory();
+        let table_name = TableName::new("public.foobar".to_string());
+        let mut change_processing = ChangeProcessing::new(TargetsTablesColumnNames::new());
+        change_processing.register_wal_file(Some(new_wal_file()));
+        for parsed_line in [
+            change_with_columns(ChangeKind::Insert, &table_name, vec![("id", 1), ("foo", 1)]),
+            change_with_columns(ChangeKind::Update, &table_name, vec![("id", 1), ("foo", 2)]),
+            change_with_columns(ChangeKind::Update, &table_name, vec![("id", 2), ("foo", 2)]),
+            change_with_columns(ChangeKind::Delete, &table_name, vec![("id", 3)]),
+        ] {
+            change_processing
+                .add_change(parsed_line)
+                .expect("Failed processing changes");
+        }
+        let table = change_processing
+            .table_holder
+            .tables
+            .get_mut(&table_name)
+            .unwrap()
+            .reset_and_return_table_data();
+        let wal_file = change_processing.associated_wal_file.clone().unwrap();
+        let (_file_writer, stats) = change_processing
+            .write_files_for_table(table, wal_file)
+            .unwrap();
+        assert_eq!(
+            stats.rows_before_collapse,
+            RowsByKind {
+                inserts: 1,
+                updates: 2,
+                deletes: 1
+            }
+        );
+        assert_eq!(
+            stats.rows_after_collapse,
+            RowsByKind {
+                inserts: 1,
+                updates: 1,
+                deletes: 1
+            }
+        );
+        assert_eq!(stats.distinct_ids, 3);
+        assert_eq!(stats.update_files, 1);
+        // header and a row for each of the insert, update and delete files
+        assert_eq!(
+            stats.csv_bytes,
+            ("id,foo\n1,2\n".len() * 2 + "id\n3\n".len()) as u64
+        );
+    }
+
+    // updates to a row in a filtered table, as they come out of the row filter
+    fn row_filtered_changeset(tenant_ids: &[i64]) -> ChangeSet {
+        let row_filters = row_filter::RowFilters::parse("public.users:tenant_id = 5").unwrap();
+        let table_name = TableName::new("public.users".to_string());
+        let mut changeset = ChangeSet::new();
+        for tenant_id in tenant_ids {
+            let update = change_with_columns(
+                ChangeKind::Update,
+                &table_name,
+                vec![("id", 1), ("tenant_id", *tenant_id)],
+            );
+            if let Some(change) = row_filters.apply(update).unwrap() {
+                changeset
+                    .add_change(change, ConflictPolicy::Strict, true)
+                    .expect("Failed processing changes");
+            }
+        }
+        changeset
+    }
+
+    #[test]
+    fn rows_moving_in_and_out_of_a_row_filter() {
+        let table_name = TableName::new("public.users".to_string());
+        // with the whole row, so it replaces the row whether or not it's in the target
+        let matching_update = change_with_columns(
+            ChangeKind::Update,
+            &table_name,
+            vec![("id", 1), ("tenant_id", 5)],
+        );
+        let delete = change_with_columns(ChangeKind::Delete, &table_name, vec![("id", 1)]);
+        assert_eq!(
+            row_filtered_changeset(&[5, 6, 5]).changes,
+            Some(matching_update.clone())
+        );
+        assert_eq!(
+            row_filtered_changeset(&[6, 5, 6]).changes,
+            Some(delete.clone())
+        );
+        let moved_in = row_filtered_changeset(&[6, 5]);
+        assert_eq!(moved_in.changes, Some(matching_update));
+        // as the row was deleted from the target
+        assert!(moved_in.replaces_row);
+        assert_eq!(row_filtered_changeset(&[5, 6]).changes, Some(delete));
+    }
+
+    #[test]
+    fn partial_rows_moved_into_a_row_filter_are_counted() {
+        clear_testing_directory();
+        let row_filters = row_filter::RowFilters::parse("public.users:tenant_id = 5").unwrap();
+        let table_name = TableName::new("public.users".to_string());
+        let update = |id: i64, tenant_id: i64, toasted: bool| {
+            let mut update = change_with_columns(
+                ChangeKind::Update,
+                &table_name,
+                vec![("id", id), ("tenant_id", tenant_id), ("bio", 1)],
+            );
+            if let ParsedLine::ChangedData { columns, .. } = &mut update {
+                if toasted {
+                    columns[2] = Column::UnchangedToastColumn {
+                        column_info: ColumnInfo::new("bio", "bigint"),
+                    };
+                }
+            }
+            row_filters.apply(update).unwrap().unwrap()
+        };
+        let mut changeset = BTreeMap::<i64, ChangeSet>::new();
+        for (id, tenant_id, toasted) in [
+            // moves in without the toast column
+            (1, 6, false),
+            (1, 5, true),
+            // moves in with the whole row
+            (2, 6, false),
+            (2, 5, false),
+            // already matched
+            (3, 5, true),
+        ] {
+            changeset
+                .entry(id)
+                .or_insert_with(ChangeSet::new)
+                .add_change(update(id, tenant_id, toasted), ConflictPolicy::Strict, true)
+                .expect("Failed processing changes");
+        }
+        let table = Table {
+            table_name: table_name.clone(),
+            column_info: None,
+            changeset: ChangeSetWithColumnType::IntColumnType(changeset),
+            column_info_from_target: None,
+        };
+        let mut change_processing = ChangeProcessing::new(TargetsTablesColumnNames::new());
+        let (_file_writer, stats) = change_processing
+            .write_files_for_table(table, new_wal_file())
+            .unwrap();
+        assert_eq!(stats.partial_rows_moved_into_filter, 1);
+    }
+
+    #[test]
+    fn conflict_policy_last_writer_wins() {
+        clear_testing_directory();
+        let table_name = TableName::new("public.last_writer_wins_table".to_string());
+        let mut change_processing = ChangeProcessing::new(TargetsTablesColumnNames::new());
+        change_processing.register_wal_file(Some(new_wal_file()));
+        let change = |kind: ChangeKind, id: i64, foo: i64| {
+            change_with_columns(kind, &table_name, vec![("id", id), ("foo", foo)])
+        };
+        for parsed_line in [
+            change(ChangeKind::Delete, 1, 1),
+            change(ChangeKind::Update, 1, 2),
+            change(ChangeKind::Update, 2, 1),
+            change(ChangeKind::Insert, 2, 2),
+            change(ChangeKind::Update, 3, 1),
+        ] {
+            change_processing
+                .add_change(parsed_line)
+                .expect("Failed processing changes");
+        }
+        // both end up as updates with the newest data
+        assert_eq!(
+            pending_change(&change_processing, &table_name, 1),
+            Some(change(ChangeKind::Update, 1, 2))
+        );
+        assert_eq!(
+            pending_change(&change_processing, &table_name, 2),
+            Some(change(ChangeKind::Update, 2, 2))
+        );
+        // which replace the rows, so they're inserted if they aren't in the target.
+        // the update without a conflict only updates the row that's there
+        let mut results = change_processing.drain_final_changes();
+        if let Some(ChangeProcessingResult::TableChanges(file_writer)) = results.pop() {
+            let mut update_files: Vec<(bool, u64)> = file_writer
+                .update_files
+                .values()
+                .map(|update_file| (update_file.replaces_rows, update_file.rows))
+                .collect();
+            update_files.sort();
+            assert_eq!(update_files, vec![(false, 1), (true, 2)]);
+        } else {
+            panic!("expected the table's changes")
+        }
+    }
+
+    #[test]
+    fn conflict_policy_skip_and_log() {
+        clear_testing_directory();
+        let table_name = TableName::new("public.skip_and_log_table".to_string());
+        let mut change_processing = ChangeProcessing::new(TargetsTablesColumnNames::new());
+        change_processing.register_wal_file(Some(new_wal_file()));
+        let change = |kind: ChangeKind, id: i64, foo: i64| {
+            change_with_columns(kind, &table_name, vec![("id", id), ("foo", foo)])
+        };
+        for parsed_line in [
+            change(ChangeKind::Delete, 1, 1),
+            change(ChangeKind::Update, 1, 2),
+            change(ChangeKind::Insert, 2, 1),
+            change(ChangeKind::Insert, 2, 2),
+            change(ChangeKind::Update, 3, 1),
+            change(ChangeKind::Insert, 3, 2),
+        ] {
+            change_processing
+                .add_change(parsed_line)
+                .expect("Failed processing changes");
+        }
+        // we keep what we had
+        assert_eq!(
+            pending_change(&change_processing, &table_name, 1),
+            Some(change(ChangeKind::Delete, 1, 1))
+        );
+        assert_eq!(
+            pending_change(&change_processing, &table_name, 2),
+            Some(change(ChangeKind::Insert, 2, 1))
+        );
+        assert_eq!(
+            pending_change(&change_processing, &table_name, 3),
+            Some(change(ChangeKind::Update, 3, 1))
+        );
+    }
+
+    #[test]
+    fn consolidated_tenant_table_keys_on_source_schema_and_id() {
+        clear_testing_directory();
+        let table_name = TableName::new("tenants.foobar".to_string());
+        let mut change_processing = ChangeProcessing::new(TargetsTablesColumnNames::new());
+        change_processing.register_wal_file(Some(new_wal_file()));
+        let change = |kind: ChangeKind, source_schema: &str, id: i64| ParsedLine::ChangedData {
+            kind,
+            table_name: table_name.clone(),
+            columns: vec![
+                Column::ChangedColumn {
+                    column_info: ColumnInfo::new(SOURCE_SCHEMA_COLUMN, "text"),
+                    value: Some(ColumnValue::Text(source_schema.to_string())),
+                },
+                Column::ChangedColumn {
+                    column_info: ColumnInfo::new("id", "bigint"),
+                    value: Some(ColumnValue::Integer(id)),
+                },
+            ],
+        };
+        for parsed_line in [
+            change(ChangeKind::Insert, "tenant_a", 1),
+            change(ChangeKind::Insert, "tenant_b", 1),
+            change(ChangeKind::Delete, "tenant_a", 1),
+        ] {
+            change_processing
+                .add_change(parsed_line)
+                .expect("Failed processing changes");
+        }
+        // the same id in two tenants doesn't collapse
+        assert_eq!(change_processing.get_stats(), hashmap! { &table_name => 2 });
+        if let ChangeSetWithColumnType::TenantIntColumnType(btree) =
+            &change_processing.table_holder.tables[&table_name].changeset
+        {
+            assert_eq!(btree[&("tenant_a".to_string(), 1)].changes, None);
+            assert_eq!(
+                btree[&("tenant_b".to_string(), 1)].changes,
+                Some(change(ChangeKind::Insert, "tenant_b", 1))
+            );
+        } else {
+            panic!("expected a consolidated tenant table")
+        }
+        // without the source schema we can't tell which tenant's row it is
+        assert!(change_processing
+            .add_change(change_with_columns(
+                ChangeKind::Delete,
+                &table_name,
+                vec![("id", 1)]
+            ))
+            .is_err());
+    }
+    #[test]
+    fn checkpointed_batches_are_skipped() {
+        clear_testing_directory();
+        let applied_table_name = TableName::new("public.applied".to_string());
+        let table_name = TableName::new("public.not_applied".to_string());
+        let mut change_processing = ChangeProcessing::new(TargetsTablesColumnNames::new());
+        change_processing.register_checkpoints(Checkpoints::from_checkpoints(vec![Checkpoint {
+            table_name: applied_table_name.clone(),
+            wal_file_number: 1,
+            xid: Some(1),
+            commit_timestamp: None,
+            lsn: None,
+        }]));
+        change_processing.register_wal_file(Some(new_wal_file()));
+        let insert = |table_name: &TableName| ParsedLine::ChangedData {
+            kind: ChangeKind::Insert,
+            table_name: table_name.clone(),
+            columns: vec![Column::ChangedColumn {
+                column_info: ColumnInfo::new("id", "bigint"),
+                value: Some(ColumnValue::Integer(1)),
+            }],
+        };
+        for parsed_line in [
+            ParsedLine::Begin(2),
+            insert(&applied_table_name),
+            insert(&table_name),
+            ParsedLine::Commit(2, Some("2024-01-01 10:00:00+00".to_string())),
+        ] {
+            change_processing
+                .add_change(parsed_line)
+                .expect("Failed processing changes");
+        }
+        let results = change_processing.drain_final_changes();
+        assert_eq!(results.len(), 1);
+        if let ChangeProcessingResult::TableChanges(file_writer) = &results[0] {
+            assert_eq!(
+                file_writer.checkpoint,
+                Some(Checkpoint {
+                    table_name: table_name.clone(),
+                    wal_file_number: 1,
+                    xid: Some(2),
+                    commit_timestamp: Some("2024-01-01 10:00:00+00".to_string()),
+                    lsn: None,
+                })
+            );
+        } else {
+            panic!("expected table changes")
+        }
+    }
+
+    #[test]
+    fn forks_keep_schemas_but_not_changes() {
+        clear_testing_directory();
+        let table_name = TableName::new("public.fork_table".to_string());
+        let mut tables_columns_names_map = HashMap::new();
+        tables_columns_names_map.insert(
+            TableName::new("fork_table".to_string()),
+            vec![ColumnInfo::new("id", "bigint").name].into_iter().collect(),
+        );
+        let mut change_processing =
+            ChangeProcessing::new(TargetsTablesColumnNames::from_map(tables_columns_names_map));
+        let wal_file = new_wal_file();
+        change_processing.register_wal_file(Some(wal_file.clone()));
+        let change = insert_with_columns(&table_name, vec![("id", 1), ("new_column", 1)]);
+        let results = change_processing
+            .add_change(change.clone())
+            .expect("Failed processing changes")
+            .unwrap();
+        assert!(results
+            .iter()
+            .any(|result| matches!(result, ChangeProcessingResult::DdlChange(..))));
+
+        let mut fork = change_processing.fork();
+        assert_eq!(fork.get_stats(), hashmap!(&table_name => 0));
+        assert_eq!(change_processing.get_stats(), hashmap!(&table_name => 1));
+        fork.register_wal_file(Some(wal_file));
+        // it already knows about the new column
+        assert!(fork
+            .add_change(change)
+            .expect("Failed processing changes")
+            .is_none());
+        assert_eq!(fork.get_stats(), hashmap!(&table_name => 1));
+        // removes the wal file
+        drop(results);
+        for collector in [&mut fork, &mut change_processing] {
+            drop(collector.drain_final_changes());
+            collector.register_wal_file(None);
+        }
+    }
 }
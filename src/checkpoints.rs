@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+#[allow(unused_imports)]
+use crate::{function, logger_debug, logger_error, logger_info, logger_panic, logger_warning};
+
+use crate::parser::TableName;
+use crate::replication_client::{format_lsn, Lsn};
+use crate::targets_tables_column_names::TargetsTablesColumnNames;
+
+fn checkpoints_table(target_schema: Option<&str>) -> String {
+    format!(
+        "\"{}\".\"re_dms_checkpoints\"",
+        target_schema.unwrap_or("public")
+    )
+}
+
+// The last wal file applied to the target for a table,
+// written in the same transaction as the last of the table's changes from that wal file.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Checkpoint {
+    pub table_name: TableName,
+    pub wal_file_number: u64,
+    // from the last commit in the wal file
+    pub xid: Option<i64>,
+    pub commit_timestamp: Option<String>,
+    // only when we're streaming, not reprocessing
+    pub lsn: Option<Lsn>,
+}
+
+#[derive(Debug)]
+pub enum CheckpointsError {
+    PoolError(deadpool_postgres::PoolError),
+    TokioError(tokio_postgres::Error),
+}
+
+impl fmt::Display for CheckpointsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckpointsError::PoolError(err) => write!(f, "Pool error: {}", err),
+            CheckpointsError::TokioError(err) => write!(f, "Tokio postgres error: {}", err),
+        }
+    }
+}
+
+impl Error for CheckpointsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CheckpointsError::PoolError(err) => Some(err),
+            CheckpointsError::TokioError(err) => Some(err),
+        }
+    }
+}
+
+fn quote_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+fn literal_or_null(value: Option<String>) -> String {
+    value
+        .map(|value| quote_literal(&value))
+        .unwrap_or_else(|| "null".to_string())
+}
+
+impl Checkpoint {
+    // redshift has no upsert. updated_at is set with the target's function for the time now
+    pub fn upsert_statements(
+        &self,
+        current_timestamp: &str,
+        target_schema: Option<&str>,
+    ) -> [String; 2] {
+        let checkpoints_table = checkpoints_table(target_schema);
+        [
+            format!(
+                "delete from {} where table_name = {}",
+                checkpoints_table,
+                quote_literal(&self.table_name)
+            ),
+            format!(
+                "insert into {} (table_name, wal_file_number, xid, commit_timestamp, lsn, updated_at) values ({}, {}, {}, {}, {}, {})",
+                checkpoints_table,
+                quote_literal(&self.table_name),
+                self.wal_file_number,
+                self.xid
+                    .map(|xid| xid.to_string())
+                    .unwrap_or_else(|| "null".to_string()),
+                literal_or_null(self.commit_timestamp.clone()),
+                literal_or_null(self.lsn.map(format_lsn)),
+                current_timestamp,
+            ),
+        ]
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Checkpoints {
+    checkpoints: HashMap<TableName, Checkpoint>,
+}
+
+impl Checkpoints {
+    #[cfg(test)]
+    pub fn from_checkpoints(checkpoints: Vec<Checkpoint>) -> Checkpoints {
+        Checkpoints {
+            checkpoints: checkpoints
+                .into_iter()
+                .map(|checkpoint| (checkpoint.table_name.clone(), checkpoint))
+                .collect(),
+        }
+    }
+
+    // creates the checkpoints table if it doesn't exist yet
+    pub async fn load(target_schema: Option<&str>) -> Result<Checkpoints, CheckpointsError> {
+        let checkpoints_table = checkpoints_table(target_schema);
+        let pool = TargetsTablesColumnNames::create_connection_pool();
+        let client = pool.get().await.map_err(CheckpointsError::PoolError)?;
+        client
+            .execute(
+                format!(
+                    "create table if not exists {} (table_name varchar(256) not null primary key, wal_file_number bigint not null, xid bigint, commit_timestamp timestamptz, lsn varchar(32), updated_at timestamp not null)",
+                    checkpoints_table
+                )
+                .as_str(),
+                &[],
+            )
+            .await
+            .map_err(CheckpointsError::TokioError)?;
+        let rows = client
+            .query(
+                format!(
+                    "select table_name, wal_file_number, xid, commit_timestamp::varchar, lsn from {}",
+                    checkpoints_table
+                )
+                .as_str(),
+                &[],
+            )
+            .await
+            .map_err(CheckpointsError::TokioError)?;
+        let checkpoints = rows
+            .into_iter()
+            .map(|row| {
+                let table_name = TableName::new(row.get::<_, String>(0));
+                let checkpoint = Checkpoint {
+                    table_name: table_name.clone(),
+                    wal_file_number: row.get::<_, i64>(1) as u64,
+                    xid: row.get(2),
+                    commit_timestamp: row.get(3),
+                    lsn: row
+                        .get::<_, Option<String>>(4)
+                        .as_deref()
+                        .and_then(parse_lsn),
+                };
+                (table_name, checkpoint)
+            })
+            .collect();
+        Ok(Checkpoints { checkpoints })
+    }
+
+    pub fn len(&self) -> usize {
+        self.checkpoints.len()
+    }
+
+    // batches up to and including the checkpointed wal file are already in the target
+    pub fn is_applied(&self, table_name: &TableName, wal_file_number: u64) -> bool {
+        self.checkpoints
+            .get(table_name)
+            .is_some_and(|checkpoint| wal_file_number <= checkpoint.wal_file_number)
+    }
+
+    // wal file numbers need to carry on from here, or we'd skip new changes
+    pub fn last_wal_file_number(&self) -> u64 {
+        self.checkpoints
+            .values()
+            .map(|checkpoint| checkpoint.wal_file_number)
+            .max()
+            .unwrap_or(0)
+    }
+
+    // wal files that are neither checkpointed nor on disk were lost.
+    // (or had no changes for any table, which we can't tell apart)
+    pub fn report_gap(&self, first_wal_file_number_on_disk: Option<u64>) {
+        let last_wal_file_number = self.last_wal_file_number();
+        match first_wal_file_number_on_disk {
+            Some(first_wal_file_number) if first_wal_file_number > last_wal_file_number + 1 => {
+                logger_warning!(
+                    None,
+                    None,
+                    &format!(
+                        "checkpoint_gap last_checkpointed_wal_file:{} first_wal_file_on_disk:{}",
+                        last_wal_file_number, first_wal_file_number
+                    )
+                );
+            }
+            _ => {
+                logger_info!(
+                    None,
+                    None,
+                    &format!(
+                        "checkpoints:{} last_checkpointed_wal_file:{} first_wal_file_on_disk:{:?}",
+                        self.len(),
+                        last_wal_file_number,
+                        first_wal_file_number_on_disk
+                    )
+                );
+            }
+        }
+    }
+}
+
+fn parse_lsn(lsn: &str) -> Option<Lsn> {
+    let (high, low) = lsn.split_once('/')?;
+    let high = u64::from_str_radix(high, 16).ok()?;
+    let low = u64::from_str_radix(low, 16).ok()?;
+    Some((high << 32) | low)
+}
@@ -1,11 +1,16 @@
+use bytes::Bytes;
 use deadpool_postgres::{Client, GenericClient, ManagerConfig, Pool, RecyclingMethod, Runtime};
-use dogstatsd::{Client as StatsdClient, Options as StatsdOptions};
+use dogstatsd::{Client as StatsdClient, DogstatsdError, Options as StatsdOptions};
+use futures::SinkExt;
 use openssl::ssl::{SslConnector, SslMethod};
 use postgres_openssl::MakeTlsConnector;
 use serde::Deserialize;
 use std::error::Error;
 use std::fmt;
+use std::path::PathBuf;
+use std::sync::RwLock;
 use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
 use tokio::time::timeout;
 use tokio_postgres::error::Error as TokioPostgresError;
 use tokio_postgres::{CancelToken, Row};
@@ -17,8 +22,13 @@ use crate::{function, logger_debug, logger_error, logger_info, logger_panic, log
 
 use crate::change_processing::DdlChange;
 use crate::file_uploader::CleoS3File;
-use crate::parser::{ChangeKind, ColumnInfo, SchemaAndTable, TableName};
+use crate::file_writer::StagingFileFormat;
+use crate::parser::{
+    ChangeKind, ColumnInfo, ColumnName, SchemaAndTable, TableName, SOURCE_SCHEMA_COLUMN,
+};
+use crate::postgres_copy;
 use crate::shutdown_handler::ShutdownHandler;
+use crate::storage_backend::StorageLocation;
 use crate::targets_tables_column_names::TargetsTablesColumnNames;
 
 pub const DEFAULT_NUMERIC_PRECISION: i32 = 19; // 99_999_999_999.99999999
@@ -34,11 +44,44 @@ lazy_static! {
     );
     static ref STATSD_IP_AND_PORT: String =
         std::env::var("STATSD_IP_AND_PORT").unwrap_or("127.0.0.1:8125".to_string());
+    // row count mismatches are always logged and counted, this also fails the load on a copy mismatch
+    static ref FAIL_ON_ROW_COUNT_MISMATCH: bool = std::env::var("FAIL_ON_ROW_COUNT_MISMATCH")
+        .unwrap_or("false".to_string())
+        .parse::<bool>()
+        .expect("FAIL_ON_ROW_COUNT_MISMATCH is not a valid boolean");
+}
+
+// a local directory is loaded into postgres (e.g. to run the pipeline without an AWS account),
+// as redshift only copies from s3
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TargetDialect {
+    Redshift,
+    Postgres,
+}
+
+impl TargetDialect {
+    pub fn configured() -> TargetDialect {
+        match StorageLocation::configured() {
+            StorageLocation::S3 { .. } => TargetDialect::Redshift,
+            StorageLocation::Local(_) => TargetDialect::Postgres,
+        }
+    }
+
+    pub fn current_timestamp(&self) -> &'static str {
+        match self {
+            TargetDialect::Redshift => "getdate()",
+            TargetDialect::Postgres => "now()",
+        }
+    }
 }
 
 pub struct DatabaseWriter {
     connection_pool: Pool,
-    targets_tables_column_names: TargetsTablesColumnNames,
+    dialect: TargetDialect,
+    // behind a lock so ddl we apply can keep it up to date
+    targets_tables_column_names: RwLock<TargetsTablesColumnNames>,
+    // the schema we write every table to, otherwise each table's source schema
+    target_schema: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -57,6 +100,9 @@ pub enum DatabaseWriterError {
     PoolError(deadpool_postgres::PoolError),
     TokioError(tokio_postgres::Error),
     TimeoutError(tokio::time::error::Elapsed),
+    RowCountMismatch(String),
+    // reading a local file to copy it into postgres
+    IoError(PathBuf, std::io::Error),
 }
 
 impl fmt::Display for DatabaseWriterError {
@@ -71,6 +117,12 @@ impl fmt::Display for DatabaseWriterError {
             DatabaseWriterError::TimeoutError(err) => {
                 write!(f, "Query timeout error: {}", err)
             }
+            DatabaseWriterError::RowCountMismatch(message) => {
+                write!(f, "Row count mismatch: {}", message)
+            }
+            DatabaseWriterError::IoError(path, err) => {
+                write!(f, "Io error on {}: {}", path.display(), err)
+            }
         }
     }
 }
@@ -81,6 +133,8 @@ impl Error for DatabaseWriterError {
             DatabaseWriterError::PoolError(err) => Some(err),
             DatabaseWriterError::TokioError(err) => Some(err),
             DatabaseWriterError::TimeoutError(err) => Some(err),
+            DatabaseWriterError::RowCountMismatch(_) => None,
+            DatabaseWriterError::IoError(_, err) => Some(err),
         }
     }
 }
@@ -117,7 +171,43 @@ impl StatsdWrapper {
         S: Into<String>,
         T: AsRef<str>,
     {
-        self.statsd.timing(stat.into(), ms, tags).expect("Failed to send timing metric");
+        let stat = stat.into();
+        let result = self.statsd.timing(&stat, ms, tags);
+        Self::log_failed_send(&stat, result);
+    }
+
+    pub fn count<I, S, T>(&self, stat: S, count: i64, tags: I)
+    where
+        I: IntoIterator<Item = T>,
+        S: Into<String>,
+        T: AsRef<str>,
+    {
+        let stat = stat.into();
+        let result = self.statsd.count(&stat, count, tags);
+        Self::log_failed_send(&stat, result);
+    }
+
+    pub fn gauge<I, S, V, T>(&self, stat: S, value: V, tags: I)
+    where
+        I: IntoIterator<Item = T>,
+        S: Into<String>,
+        V: ToString,
+        T: AsRef<str>,
+    {
+        let stat = stat.into();
+        let result = self.statsd.gauge(&stat, value.to_string(), tags);
+        Self::log_failed_send(&stat, result);
+    }
+
+    // metrics are best effort, we keep replicating without them
+    fn log_failed_send(stat: &str, result: Result<(), DogstatsdError>) {
+        if let Err(err) = result {
+            logger_warning!(
+                None,
+                None,
+                &format!("failed_to_send_metric:{} error:{}", stat, err)
+            );
+        }
     }
 
 }
@@ -147,7 +237,7 @@ impl QueryExecution {
         client: &impl GenericClient,
         metric_name: &str,
         metric_tags: &[String],
-    ) -> Result<(), DatabaseWriterError> {
+    ) -> Result<u64, DatabaseWriterError> {
         let query_execution = client.execute(self.query.as_str(), &[]);
         let start = Instant::now();
         let timeout_result =
@@ -157,7 +247,7 @@ impl QueryExecution {
             .timing(metric_name, duration.as_millis() as i64, metric_tags);
         match timeout_result {
             Ok(query_result) => match query_result {
-                Ok(_) => Ok(()),
+                Ok(rows) => Ok(rows),
                 Err(err) => Err(err).map_err(DatabaseWriterError::TokioError),
             },
             Err(err) => {
@@ -187,6 +277,57 @@ impl QueryExecution {
         }
     }
 
+    // streams the rows into a copy from stdin, and returns how many were copied
+    pub async fn copy_in_with_timeout(
+        &self,
+        transaction: &tokio_postgres::Transaction<'_>,
+        mut receiver: mpsc::Receiver<postgres_copy::CopyChunk>,
+        metric_name: &str,
+        metric_tags: &[String],
+    ) -> Result<u64, DatabaseWriterError> {
+        let copy_in = async {
+            let sink = transaction
+                .copy_in::<_, Bytes>(self.query.as_str())
+                .await
+                .map_err(DatabaseWriterError::TokioError)?;
+            // dropping it part way through aborts the copy
+            futures::pin_mut!(sink);
+            while let Some(chunk) = receiver.recv().await {
+                let chunk = chunk.map_err(|(path, err)| DatabaseWriterError::IoError(path, err))?;
+                sink.send(chunk)
+                    .await
+                    .map_err(DatabaseWriterError::TokioError)?;
+            }
+            sink.finish().await.map_err(DatabaseWriterError::TokioError)
+        };
+        let start = Instant::now();
+        let timeout_result = timeout(*CLIENT_SIDE_DB_QUERY_TIMEOUT_IN_SECONDS, copy_in).await;
+        let duration = start.elapsed();
+        self.statsd
+            .timing(metric_name, duration.as_millis() as i64, metric_tags);
+        match timeout_result {
+            Ok(copy_result) => copy_result,
+            Err(err) => {
+                logger_error!(
+                    None,
+                    None,
+                    &format!("Copy timed out:{}, query: {}", err, self.query)
+                );
+                if let Err(cancel_err) = self.cancel().await {
+                    logger_warning!(
+                        None,
+                        None,
+                        &format!(
+                            "Failed to cancel query:{}, query: {}",
+                            cancel_err, self.query
+                        )
+                    );
+                }
+                Err(DatabaseWriterError::TimeoutError(err))
+            }
+        }
+    }
+
     pub async fn query_one_with_timeout(
         &self,
         client: &impl GenericClient,
@@ -233,9 +374,11 @@ impl QueryExecution {
 }
 
 impl DatabaseWriter {
-    pub async fn new() -> DatabaseWriter {
+    pub async fn new(target_schema: Option<String>) -> DatabaseWriter {
         let mut targets_tables_column_names = TargetsTablesColumnNames::new();
-        let result = targets_tables_column_names.refresh().await;
+        let result = targets_tables_column_names
+            .refresh(target_schema.as_deref())
+            .await;
         match result {
             Ok(_) => logger_info!(
                 None,
@@ -254,7 +397,9 @@ impl DatabaseWriter {
 
         DatabaseWriter {
             connection_pool: DatabaseWriter::create_connection_pool(),
-            targets_tables_column_names: targets_tables_column_names,
+            dialect: TargetDialect::configured(),
+            targets_tables_column_names: RwLock::new(targets_tables_column_names),
+            target_schema,
         }
     }
 
@@ -285,6 +430,16 @@ impl DatabaseWriter {
             DdlChange::RemoveColumn(column_info, table_name) => {
                 self.remove_column_statement(column_info, table_name)
             }
+            DdlChange::RenameColumn(old_column_name, new_column_name, table_name) => {
+                self.rename_column_statement(old_column_name, new_column_name, table_name)
+            }
+            DdlChange::RenameTable(old_table_name, new_table_name) => {
+                self.rename_table_statement(old_table_name, new_table_name)
+            }
+            DdlChange::DropTable(table_name) => self.drop_table_statement(table_name),
+            DdlChange::CreateTable(table_name, columns) => {
+                self.create_table_statement(table_name, columns)
+            }
         };
         let client = self
             .get_connection_from_pool(wal_file_number, &table_name)
@@ -302,12 +457,45 @@ impl DatabaseWriter {
         )
         .await?;
 
+        self.update_cache_after_ddl(ddl_change);
+
         Ok(())
     }
 
+    fn update_cache_after_ddl(&self, ddl_change: &DdlChange) {
+        let mut targets_tables_column_names = self
+            .targets_tables_column_names
+            .write()
+            .expect("targets_tables_column_names lock poisoned");
+        match ddl_change {
+            DdlChange::RenameColumn(old_column_name, new_column_name, table_name) => {
+                targets_tables_column_names.rename_column(
+                    table_name,
+                    old_column_name,
+                    new_column_name,
+                );
+            }
+            DdlChange::RenameTable(old_table_name, new_table_name) => {
+                targets_tables_column_names.rename_table(old_table_name, new_table_name);
+            }
+            DdlChange::DropTable(table_name) => {
+                targets_tables_column_names.remove_table(table_name);
+            }
+            DdlChange::CreateTable(table_name, columns) => {
+                targets_tables_column_names.add_table(
+                    table_name,
+                    columns.iter().map(|column| column.name.clone()).collect(),
+                );
+            }
+            // we only use the cache to skip existence checks, so column changes don't matter
+            DdlChange::AddColumn(..) | DdlChange::RemoveColumn(..) => {}
+        }
+    }
+
     fn add_column_statement(&self, column_info: &ColumnInfo, table_name: &TableName) -> String {
-        let (schema_name, just_table_name) = table_name.schema_and_table_name();
-        let column_name_and_type = self.column_and_type_for_column(column_info);
+        let (schema_name, just_table_name) =
+            table_name.schema_and_table_name(self.target_schema.as_deref());
+        let column_name_and_type = self.column_and_type_for_column(column_info, false);
         format!(
             "alter table \"{schema_name}\".\"{just_table_name}\" add column {column_name_and_type}",
             schema_name = &schema_name,
@@ -317,7 +505,8 @@ impl DatabaseWriter {
     }
 
     fn remove_column_statement(&self, column_info: &ColumnInfo, table_name: &TableName) -> String {
-        let (schema_name, just_table_name) = table_name.schema_and_table_name();
+        let (schema_name, just_table_name) =
+            table_name.schema_and_table_name(self.target_schema.as_deref());
         // TODO: foreign keys
         format!(
             "alter table \"{schema_name}\".\"{just_table_name}\" drop column \"{column_name}\"",
@@ -327,6 +516,64 @@ impl DatabaseWriter {
         )
     }
 
+    fn rename_column_statement(
+        &self,
+        old_column_name: &ColumnName,
+        new_column_name: &ColumnName,
+        table_name: &TableName,
+    ) -> String {
+        let (schema_name, just_table_name) =
+            table_name.schema_and_table_name(self.target_schema.as_deref());
+        format!(
+            "alter table \"{schema_name}\".\"{just_table_name}\" rename column \"{old_column_name}\" to \"{new_column_name}\"",
+            schema_name = &schema_name,
+            just_table_name = &just_table_name,
+            old_column_name = &old_column_name,
+            new_column_name = &new_column_name
+        )
+    }
+
+    fn rename_table_statement(
+        &self,
+        old_table_name: &TableName,
+        new_table_name: &TableName,
+    ) -> String {
+        let (schema_name, just_table_name) =
+            old_table_name.schema_and_table_name(self.target_schema.as_deref());
+        let (_, new_just_table_name) =
+            new_table_name.schema_and_table_name(self.target_schema.as_deref());
+        format!(
+            "alter table \"{schema_name}\".\"{just_table_name}\" rename to \"{new_just_table_name}\"",
+            schema_name = &schema_name,
+            just_table_name = &just_table_name,
+            new_just_table_name = &new_just_table_name
+        )
+    }
+
+    fn drop_table_statement(&self, table_name: &TableName) -> String {
+        let (schema_name, just_table_name) =
+            table_name.schema_and_table_name(self.target_schema.as_deref());
+        format!(
+            "drop table if exists \"{schema_name}\".\"{just_table_name}\"",
+            schema_name = &schema_name,
+            just_table_name = &just_table_name
+        )
+    }
+
+    // for tables created from the stream and from the ddl audit table.
+    // No distkey, so redshift picks the distribution. The staging tables we merge from are
+    // on every node (DISTSTYLE ALL), so the merges don't have to redistribute either way
+    fn create_table_statement(&self, table_name: &TableName, columns: &[ColumnInfo]) -> String {
+        let (schema_name, just_table_name) =
+            table_name.schema_and_table_name(self.target_schema.as_deref());
+        format!(
+            "create table if not exists \"{schema_name}\".\"{just_table_name}\" ({columns})",
+            schema_name = &schema_name,
+            just_table_name = &just_table_name,
+            columns = self.values_description_for_table(columns)
+        )
+    }
+
     async fn get_connection_from_pool(
         &self,
         wal_file_number: u64,
@@ -374,7 +621,8 @@ impl DatabaseWriter {
 
         let transaction = client.transaction().await.expect("Failed to initialize a db transaction.");
         let cancel_token = &transaction.cancel_token();
-        let (schema_name, just_table_name) = table_name.schema_and_table_name();
+        let (schema_name, just_table_name) =
+            table_name.schema_and_table_name(self.target_schema.as_deref());
         assert!(!table_name.contains('"'));
         let staging_name = self.staging_name(s3_file);
         let return_early = self
@@ -391,17 +639,39 @@ impl DatabaseWriter {
             &just_table_name,
         );
 
-        let iam_role =
-            env::var("IAM_ROLE").expect("Unable to find IAM_ROLE");
         let column_list = self.column_name_list(&s3_file.columns);
-        // no gzip
-        let copy_to_staging_table = format!(
-            "copy \"{staging_name}\" ({column_list}) from '{remote_filepath}' IAM_ROLE '{iam_role}' GZIP CSV TRUNCATECOLUMNS IGNOREHEADER 1 DELIMITER ',' NULL as '\\0' compupdate off statupdate off",
-            staging_name = &staging_name,
-            column_list = &column_list,
-            remote_filepath = &remote_filepath,
-            iam_role = &iam_role,
-        );
+        let copy_to_staging_table = match self.dialect {
+            TargetDialect::Redshift => {
+                let format_options = match s3_file.format {
+                    // GZIP, ZSTD, BZIP2 or nothing, to match how the file was compressed
+                    StagingFileFormat::Csv => format!(
+                        "{} CSV TRUNCATECOLUMNS IGNOREHEADER 1 DELIMITER ',' NULL as '\\0'",
+                        s3_file.compression.copy_option()
+                    )
+                    .trim_start()
+                    .to_owned(),
+                    // typed, so there's no escaping or null byte to deal with
+                    StagingFileFormat::Parquet => "FORMAT AS PARQUET".to_owned(),
+                };
+                // a file split into parts is copied from its manifest, so the parts load in parallel
+                let manifest = if s3_file.is_copy_manifest() {
+                    " MANIFEST"
+                } else {
+                    ""
+                };
+                format!(
+                    "copy \"{staging_name}\" ({column_list}) from '{remote_filepath}' IAM_ROLE '{iam_role}'{manifest} {format_options} compupdate off statupdate off",
+                    staging_name = &staging_name,
+                    column_list = &column_list,
+                    remote_filepath = &remote_filepath,
+                    iam_role = env::var("IAM_ROLE").expect("Unable to find IAM_ROLE"),
+                    manifest = manifest,
+                    format_options = format_options,
+                )
+            }
+            // we stream the rows from the local files ourselves
+            TargetDialect::Postgres => postgres_copy::copy_statement(&staging_name, &column_list),
+        };
 
         let data_migration_query_string = self.query_for_change_kind(
             kind,
@@ -409,6 +679,15 @@ impl DatabaseWriter {
             just_table_name.as_ref(),
             schema_name.as_ref(),
             &s3_file.columns,
+            s3_file.replaces_rows,
+        );
+        let replaced_rows_query_string = self.query_for_replaced_rows(
+            kind,
+            staging_name.as_ref(),
+            just_table_name.as_ref(),
+            schema_name.as_ref(),
+            &s3_file.columns,
+            s3_file.replaces_rows,
         );
         let drop_staging_table = format!("drop table if exists {}", &staging_name);
 
@@ -436,20 +715,51 @@ impl DatabaseWriter {
         )
         .await?;
 
-        let result = self
-            .execute_single_query(
-                &transaction,
-                cancel_token,
-                copy_to_staging_table.as_str(),
-                "copy_to_staging_table",
-                &kind.to_string(),
-                &remote_filepath,
-                table_name.clone(),
-                wal_file_number,
-            )
-            .await;
-        match result {
-            Ok(..) => {}
+        let result = match self.dialect {
+            TargetDialect::Redshift => {
+                self.execute_single_query(
+                    &transaction,
+                    cancel_token,
+                    copy_to_staging_table.as_str(),
+                    "copy_to_staging_table",
+                    &kind.to_string(),
+                    &remote_filepath,
+                    table_name.clone(),
+                    wal_file_number,
+                )
+                .await
+            }
+            TargetDialect::Postgres => {
+                let receiver = postgres_copy::spawn_reader(
+                    s3_file
+                        .part_remote_paths()
+                        .into_iter()
+                        .map(PathBuf::from)
+                        .collect(),
+                    s3_file.compression,
+                    s3_file.columns.clone(),
+                );
+                QueryExecution::new(cancel_token, copy_to_staging_table.clone())
+                    .copy_in_with_timeout(
+                        &transaction,
+                        receiver,
+                        "copy_to_staging_table",
+                        metric_tags,
+                    )
+                    .await
+            }
+        };
+        let copied_rows = match result {
+            Ok(rows) => rows,
+            // e.g. the local file's gone, which retrying may sort out
+            Err(err @ DatabaseWriterError::IoError(..)) => {
+                logger_error!(
+                    Some(wal_file_number),
+                    Some(table_name),
+                    &format!("copy_to_staging_table_got_error:{}", err)
+                );
+                Err(err)?
+            }
             Err(err) => {
                 if let DatabaseWriterError::TokioError(tokio_error) = err {
                     // https://github.com/sfackler/rust-postgres/blob/master/tokio-postgres/src/error/mod.rs
@@ -477,18 +787,57 @@ impl DatabaseWriter {
                     )
                 }
             }
+        };
+        // postgres tells us how many rows it copied when it's done
+        let copied_rows = match self.dialect {
+            TargetDialect::Redshift => {
+                let copied_rows: i64 =
+                    QueryExecution::new(cancel_token, "select pg_last_copy_count()".to_owned())
+                        .query_one_with_timeout(&transaction, "last_copy_count", metric_tags, &[])
+                        .await?
+                        .get(0);
+                copied_rows as u64
+            }
+            TargetDialect::Postgres => copied_rows,
+        };
+        self.check_row_count(
+            s3_file,
+            "copy",
+            copied_rows,
+            *FAIL_ON_ROW_COUNT_MISMATCH,
+            &statsd,
+            metric_tags,
+        )?;
+
+        if let Some(replaced_rows_query_string) = replaced_rows_query_string {
+            self.execute_single_query(
+                &transaction,
+                cancel_token,
+                replaced_rows_query_string.as_str(),
+                "delete_replaced_rows",
+                &kind.to_string(),
+                &remote_filepath,
+                table_name.clone(),
+                wal_file_number,
+            )
+            .await?;
         }
-        self.execute_single_query(
-            &transaction,
-            cancel_token,
-            data_migration_query_string.as_str(),
-            "apply_changes_to_real_table",
-            &kind.to_string(),
-            &remote_filepath,
-            table_name.clone(),
-            wal_file_number,
-        )
-        .await?;
+
+        let merged_rows = self
+            .execute_single_query(
+                &transaction,
+                cancel_token,
+                data_migration_query_string.as_str(),
+                "apply_changes_to_real_table",
+                &kind.to_string(),
+                &remote_filepath,
+                table_name.clone(),
+                wal_file_number,
+            )
+            .await?;
+        // reprocessing or replaying a file legitimately merges fewer rows (they're already
+        // there, or already gone), so this is only ever reported
+        self.check_row_count(s3_file, "merge", merged_rows, false, &statsd, metric_tags)?;
 
         self.execute_single_query(
             &transaction,
@@ -502,6 +851,29 @@ impl DatabaseWriter {
         )
         .await?;
 
+        // in the same transaction, so the checkpoint is only there if the data is
+        if let Some(checkpoint) = &s3_file.checkpoint {
+            for upsert_checkpoint in checkpoint
+                .upsert_statements(
+                    self.dialect.current_timestamp(),
+                    self.target_schema.as_deref(),
+                )
+                .iter()
+            {
+                self.execute_single_query(
+                    &transaction,
+                    cancel_token,
+                    upsert_checkpoint.as_str(),
+                    "update_checkpoint",
+                    &kind.to_string(),
+                    &remote_filepath,
+                    table_name.clone(),
+                    wal_file_number,
+                )
+                .await?;
+            }
+        }
+
         let start_commit = Instant::now();
         transaction.commit().await.expect("Failed to commit the db transaction");
         let duration_commit = start_commit.elapsed();
@@ -515,7 +887,10 @@ impl DatabaseWriter {
         logger_info!(
             Some(wal_file_number),
             Some(&table_name),
-            &format!("finished_importing:{}", &remote_filepath)
+            &format!(
+                "finished_importing:{} rows:{} content_hash:{:08x}",
+                &remote_filepath, s3_file.rows, s3_file.content_hash
+            )
         );
 
         s3_file.wal_file.maybe_remove_wal_file();
@@ -525,6 +900,47 @@ impl DatabaseWriter {
         Ok(())
     }
 
+    // the copy and the not exists insert can lose rows without an error,
+    // so the rows copied and merged are checked against the rows written to the file
+    fn check_row_count(
+        &self,
+        s3_file: &CleoS3File,
+        step: &str,
+        rows: u64,
+        fail_on_mismatch: bool,
+        statsd: &StatsdWrapper,
+        metric_tags: &[String],
+    ) -> Result<(), DatabaseWriterError> {
+        statsd.count(format!("{}_rows", step), rows as i64, metric_tags);
+        if rows == s3_file.rows {
+            return Ok(());
+        }
+        statsd.count(format!("{}_row_count_mismatch", step), 1, metric_tags);
+        let message = format!(
+            "{}_row_count_mismatch:{} file_rows:{} rows:{} content_hash:{:08x}",
+            step,
+            s3_file.remote_path(),
+            s3_file.rows,
+            rows,
+            s3_file.content_hash
+        );
+        if fail_on_mismatch {
+            logger_error!(
+                Some(s3_file.wal_file.file_number),
+                Some(&s3_file.table_name),
+                &message
+            );
+            Err(DatabaseWriterError::RowCountMismatch(message))
+        } else {
+            logger_warning!(
+                Some(s3_file.wal_file.file_number),
+                Some(&s3_file.table_name),
+                &message
+            );
+            Ok(())
+        }
+    }
+
     async fn execute_single_query(
         &self,
         client: &impl GenericClient,
@@ -535,7 +951,7 @@ impl DatabaseWriter {
         remote_filepath: &str,
         table_name: TableName,
         wal_file_number: u64,
-    ) -> Result<(), DatabaseWriterError> {
+    ) -> Result<u64, DatabaseWriterError> {
         let log_tag = &format!("{}:{}", action_name, remote_filepath);
         logger_info!(
             Some(wal_file_number),
@@ -560,13 +976,13 @@ impl DatabaseWriter {
             .execute_with_timeout(client, action_name, metric_tags)
             .await;
         match result {
-            Ok(..) => {
+            Ok(rows) => {
                 logger_info!(
                     Some(wal_file_number),
                     Some(&table_name),
-                    &format!("successfully_executed:{}", log_tag)
+                    &format!("successfully_executed:{} rows:{}", log_tag, rows)
                 );
-                Ok(())
+                Ok(rows)
             }
             Err(err) => {
                 logger_error!(
@@ -582,6 +998,8 @@ impl DatabaseWriter {
     fn table_exists_in_cache(&self, table_name_with_schema: &TableName) -> bool {
         match self
             .targets_tables_column_names
+            .read()
+            .expect("targets_tables_column_names lock poisoned")
             .get_by_name(table_name_with_schema)
         {
             Some(..) => true,
@@ -597,7 +1015,9 @@ impl DatabaseWriter {
         database_client: &impl GenericClient,
         cancel_token: &CancelToken,
     ) -> Result<bool, DatabaseWriterError> {
-        let (schema_name, just_table_name) = s3_file.table_name.schema_and_table_name();
+        let (schema_name, just_table_name) = s3_file
+            .table_name
+            .schema_and_table_name(self.target_schema.as_deref());
         let table_name = s3_file.table_name.clone();
         let wal_file_number = s3_file.wal_file.file_number;
 
@@ -673,13 +1093,8 @@ impl DatabaseWriter {
                 "creating_table_that_doesnt_exist"
             );
 
-            // TODO: distkey
-            let create_table_query = format!(
-                "create table \"{schema_name}\".\"{just_table_name}\" ({columns})",
-                schema_name = schema_name,
-                just_table_name = just_table_name,
-                columns = self.values_description_for_table(&s3_file.columns)
-            );
+            let create_table_query =
+                self.create_table_statement(&s3_file.table_name, &s3_file.columns);
 
             self.execute_single_query(
                 database_client,
@@ -723,53 +1138,97 @@ impl DatabaseWriter {
         schema_name: &str,
         table_name: &str,
     ) -> String {
+        let (insert_table_options, table_options) = match self.dialect {
+            TargetDialect::Redshift => (" DISTSTYLE ALL sortkey(id)", " DISTSTYLE ALL"),
+            TargetDialect::Postgres => ("", ""),
+        };
         match kind {
             ChangeKind::Insert => {
                 format!(
-                    "create temp table \"{}\" DISTSTYLE ALL sortkey(id) as (SELECT * FROM \"{}\".\"{}\" where false)",
-                    &staging_name, &schema_name, &table_name
+                    "create temp table \"{}\"{} as (SELECT * FROM \"{}\".\"{}\" where false)",
+                    &staging_name, insert_table_options, &schema_name, &table_name
                 )
             }
             ChangeKind::Delete => {
                 format!(
-                    "create temp table \"{}\" ({}) DISTSTYLE ALL",
+                    "create temp table \"{}\" ({}){}",
                     &staging_name,
-                    self.values_description_for_table(columns)
+                    self.values_description_for_table(columns),
+                    table_options
                 )
             }
             ChangeKind::Update => {
                 format!(
-                    "create temp table \"{}\" ({}) DISTSTYLE ALL",
+                    "create temp table \"{}\" ({}){}",
                     &staging_name,
-                    self.values_description_for_table(columns)
+                    self.values_description_for_table(columns),
+                    table_options
                 )
             }
         }
     }
 
-    fn values_description_for_table(&self, columns: &Vec<ColumnInfo>) -> String {
-        columns
+    fn values_description_for_table(&self, columns: &[ColumnInfo]) -> String {
+        let consolidated_tenant_table = Self::is_consolidated_tenant_table(columns);
+        let column_descriptions = columns
             .iter()
-            .map(|x| self.column_and_type_for_column(x))
+            .map(|x| self.column_and_type_for_column(x, consolidated_tenant_table))
             .collect::<Vec<_>>()
-            .join(",")
+            .join(",");
+        if consolidated_tenant_table {
+            format!(
+                "{},primary key (\"{}\", \"id\")",
+                column_descriptions, SOURCE_SCHEMA_COLUMN
+            )
+        } else {
+            column_descriptions
+        }
     }
 
     // NOTE: if you have a column named "tag" it needs to be surrounded by quotes
     // NOTE: you also need to remove quotes from the column name
-    fn column_and_type_for_column(&self, column_info: &ColumnInfo) -> String {
+    fn column_and_type_for_column(
+        &self,
+        column_info: &ColumnInfo,
+        consolidated_tenant_table: bool,
+    ) -> String {
+        let sortkey = match self.dialect {
+            TargetDialect::Redshift => " sortkey",
+            TargetDialect::Postgres => "",
+        };
         format!(
             "\"{column_name}\" {column_type}{constraints}",
             column_name = column_info.column_name().replace("\"", ""),
             column_type = self.column_type_mapping(column_info.column_type()).as_str(),
-            constraints = if column_info.is_id_column() {
-                " sortkey primary key not null"
+            constraints = if column_info.is_id_column() && consolidated_tenant_table {
+                // the primary key is on the source schema and id together
+                format!("{} not null", sortkey)
+            } else if column_info.is_id_column() {
+                format!("{} primary key not null", sortkey)
+            } else if column_info.is_source_schema_column() {
+                " not null".to_owned()
             } else {
-                ""
+                "".to_owned()
             }
         )
     }
 
+    // consolidated tenant tables have a _source_schema column, which is part of the key
+    fn is_consolidated_tenant_table(columns: &[ColumnInfo]) -> bool {
+        columns.iter().any(|x| x.is_source_schema_column())
+    }
+
+    fn key_condition(&self, columns: &[ColumnInfo]) -> String {
+        if Self::is_consolidated_tenant_table(columns) {
+            format!(
+                "s.id = t.id and s.\"{column}\" = t.\"{column}\"",
+                column = SOURCE_SCHEMA_COLUMN
+            )
+        } else {
+            "s.id = t.id".to_string()
+        }
+    }
+
     fn column_name_list(&self, columns: &Vec<ColumnInfo>) -> String {
         columns
             .iter()
@@ -778,6 +1237,29 @@ impl DatabaseWriter {
             .join(",")
     }
 
+    // updates to rows that may not be in the target (see ChangeSet) replace the row,
+    // so we delete what's there, then insert them
+    fn query_for_replaced_rows(
+        &self,
+        kind: &ChangeKind,
+        staging_name: &str,
+        table_name: &str,
+        schema_name: &str,
+        columns: &[ColumnInfo],
+        replaces_rows: bool,
+    ) -> Option<String> {
+        match kind {
+            ChangeKind::Update if replaces_rows => Some(format!(
+                "delete from \"{schema_name}\".\"{table_name}\" t using \"{staging_name}\" s where {key_condition}",
+                schema_name = &schema_name,
+                table_name = &table_name,
+                staging_name = &staging_name,
+                key_condition = self.key_condition(columns)
+            )),
+            _ => None,
+        }
+    }
+
     fn query_for_change_kind(
         &self,
         kind: &ChangeKind,
@@ -785,6 +1267,7 @@ impl DatabaseWriter {
         table_name: &str,
         schema_name: &str,
         columns: &Vec<ColumnInfo>,
+        replaces_rows: bool,
     ) -> String {
         match kind {
             ChangeKind::Insert => {
@@ -793,10 +1276,20 @@ impl DatabaseWriter {
                     select s.* from \"{staging_name}\" s
                     where not exists (
                         select 1 from \"{schema_name}\".\"{table_name}\" t
-                        where s.id = t.id)",
+                        where {key_condition})",
                     schema_name = &schema_name,
                     table_name = &table_name,
-                    staging_name = &staging_name
+                    staging_name = &staging_name,
+                    key_condition = self.key_condition(columns)
+                )
+            }
+            ChangeKind::Delete if Self::is_consolidated_tenant_table(columns) => {
+                format!(
+                    "delete from \"{schema_name}\".\"{table_name}\" t using \"{staging_name}\" s where {key_condition}",
+                    schema_name = &schema_name,
+                    table_name = &table_name,
+                    staging_name = &staging_name,
+                    key_condition = self.key_condition(columns)
                 )
             }
             ChangeKind::Delete => {
@@ -807,19 +1300,30 @@ impl DatabaseWriter {
                     staging_name=&staging_name
                 )
             }
+            ChangeKind::Update if replaces_rows => {
+                // the rows were deleted by query_for_replaced_rows
+                format!(
+                    "insert into \"{schema_name}\".\"{table_name}\" ({column_list}) select {column_list} from \"{staging_name}\"",
+                    schema_name = &schema_name,
+                    table_name = &table_name,
+                    column_list = self.column_name_list(columns),
+                    staging_name = &staging_name
+                )
+            }
             ChangeKind::Update => {
                 // Don't update the id column
                 format!(
                     "
                     update \"{schema_name}\".\"{table_name}\" t
                     set {columns_to_update} from \"{staging_name}\" s
-                    where t.id = s.id
+                    where {key_condition}
                     ",
                     schema_name = &schema_name,
                     table_name = &table_name,
+                    key_condition = self.key_condition(columns),
                     columns_to_update = columns
                         .iter()
-                        .filter(|x| !x.is_id_column())
+                        .filter(|x| !x.is_id_column() && !x.is_source_schema_column())
                         .map(|x| x.column_name().replace("\"", ""))
                         .map(|x| format!("\"{}\" = s.\"{}\"", x, x))
                         .collect::<Vec<_>>()
@@ -859,3 +1363,244 @@ impl DatabaseWriter {
         return_type.to_string()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::staging_compression::StagingCompression;
+    use tokio_postgres::NoTls;
+
+    // the pool only connects when it's used
+    fn database_writer_for(url: String) -> DatabaseWriter {
+        let mut cfg = deadpool_postgres::Config::new();
+        cfg.url = Some(url);
+        DatabaseWriter {
+            connection_pool: cfg
+                .create_pool(Some(Runtime::Tokio1), NoTls)
+                .expect("Unable to build test database connection pool"),
+            dialect: TargetDialect::Postgres,
+            targets_tables_column_names: RwLock::new(TargetsTablesColumnNames::new()),
+            target_schema: None,
+        }
+    }
+
+    // for the tests that run queries against postgres
+    fn test_database_writer() -> DatabaseWriter {
+        database_writer_for(env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL is not set"))
+    }
+
+    #[test]
+    fn only_updates_that_replace_rows_are_deleted_and_inserted() {
+        let database_writer = database_writer_for("postgres://localhost/unused".to_string());
+        let columns = vec![
+            ColumnInfo::new("id", "bigint"),
+            ColumnInfo::new("foo", "bigint"),
+        ];
+        let query_for = |kind: &ChangeKind, replaces_rows: bool| {
+            (
+                database_writer.query_for_replaced_rows(
+                    kind,
+                    "staging",
+                    "table",
+                    "public",
+                    &columns,
+                    replaces_rows,
+                ),
+                database_writer.query_for_change_kind(
+                    kind,
+                    "staging",
+                    "table",
+                    "public",
+                    &columns,
+                    replaces_rows,
+                ),
+            )
+        };
+        let (replaced_rows, merge) = query_for(&ChangeKind::Update, true);
+        assert!(replaced_rows
+            .expect("expected the replaced rows to be deleted")
+            .starts_with("delete from \"public\".\"table\" t using \"staging\" s where "));
+        assert_eq!(
+            merge,
+            "insert into \"public\".\"table\" (\"id\",\"foo\") select \"id\",\"foo\" from \"staging\""
+        );
+        let (replaced_rows, merge) = query_for(&ChangeKind::Update, false);
+        assert_eq!(replaced_rows, None);
+        assert!(merge.trim().starts_with("update \"public\".\"table\" t"));
+        assert!(merge.contains("set \"foo\" = s.\"foo\" from \"staging\" s"));
+        // only updates replace rows
+        for kind in [ChangeKind::Insert, ChangeKind::Delete] {
+            assert_eq!(query_for(&kind, true), query_for(&kind, false));
+            assert_eq!(query_for(&kind, true).0, None);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a postgres database in TEST_DATABASE_URL"]
+    fn updates_with_whole_rows_replace_rows_missing_from_the_target() {
+        let database_writer = test_database_writer();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let client = database_writer.connection_pool.get().await.unwrap();
+            client
+                .batch_execute(
+                    "drop table if exists public.replaced_rows_test;
+                    create table public.replaced_rows_test (id bigint, foo bigint);
+                    insert into public.replaced_rows_test values (1, 1);
+                    create temp table replaced_rows_staging (id bigint, foo bigint);
+                    insert into replaced_rows_staging values (1, 2), (2, 2);",
+                )
+                .await
+                .unwrap();
+            let columns = vec![
+                ColumnInfo::new("id", "bigint"),
+                ColumnInfo::new("foo", "bigint"),
+            ];
+            let replaced_rows = database_writer
+                .query_for_replaced_rows(
+                    &ChangeKind::Update,
+                    "replaced_rows_staging",
+                    "replaced_rows_test",
+                    "public",
+                    &columns,
+                    true,
+                )
+                .unwrap();
+            assert_eq!(
+                client.execute(replaced_rows.as_str(), &[]).await.unwrap(),
+                1
+            );
+            let merge = database_writer.query_for_change_kind(
+                &ChangeKind::Update,
+                "replaced_rows_staging",
+                "replaced_rows_test",
+                "public",
+                &columns,
+                true,
+            );
+            assert_eq!(client.execute(merge.as_str(), &[]).await.unwrap(), 2);
+            let rows = client
+                .query(
+                    "select id, foo from public.replaced_rows_test order by id",
+                    &[],
+                )
+                .await
+                .unwrap()
+                .iter()
+                .map(|row| (row.get::<_, i64>(0), row.get::<_, i64>(1)))
+                .collect::<Vec<_>>();
+            // the row that wasn't there is inserted
+            assert_eq!(rows, vec![(1, 2), (2, 2)]);
+            client
+                .batch_execute("drop table public.replaced_rows_test")
+                .await
+                .unwrap();
+        });
+    }
+
+    #[test]
+    #[ignore = "needs a postgres database in TEST_DATABASE_URL"]
+    fn local_files_are_copied_and_merged_into_postgres() {
+        let database_writer = test_database_writer();
+        if env::var("CLIENT_SIDE_DB_QUERY_TIMEOUT_IN_SECONDS").is_err() {
+            env::set_var("CLIENT_SIDE_DB_QUERY_TIMEOUT_IN_SECONDS", "60");
+        }
+        let directory = PathBuf::from("/tmp/re_dms_local_copy_test");
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("public.local_copy_test_insert.csv.gz");
+        let mut encoder = flate2::write::GzEncoder::new(
+            std::fs::File::create(&path).unwrap(),
+            flate2::Compression::default(),
+        );
+        std::io::Write::write_all(&mut encoder, b"id,name,amount\n1,\"a\tb\",1.5\n2,\0,\n")
+            .unwrap();
+        encoder.finish().unwrap();
+        let table_name = TableName::new("public.local_copy_test".to_string());
+        let columns = vec![
+            ColumnInfo::new("id", "bigint"),
+            ColumnInfo::new("name", "text"),
+            ColumnInfo::new("amount", "numeric"),
+        ];
+        let staging_name = "local_copy_test_insert_staging";
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let mut client = database_writer.connection_pool.get().await.unwrap();
+            client
+                .batch_execute("drop table if exists public.local_copy_test")
+                .await
+                .unwrap();
+            client
+                .batch_execute(&database_writer.create_table_statement(&table_name, &columns))
+                .await
+                .unwrap();
+            client
+                .batch_execute("insert into public.local_copy_test values (3, 'c', 3)")
+                .await
+                .unwrap();
+            let transaction = client.transaction().await.unwrap();
+            transaction
+                .batch_execute(&database_writer.query_for_create_staging_table(
+                    &ChangeKind::Insert,
+                    &columns,
+                    staging_name,
+                    "public",
+                    "local_copy_test",
+                ))
+                .await
+                .unwrap();
+            let receiver = postgres_copy::spawn_reader(
+                vec![path],
+                StagingCompression::Gzip { level: 6 },
+                columns.clone(),
+            );
+            let copy_statement = postgres_copy::copy_statement(
+                staging_name,
+                &database_writer.column_name_list(&columns),
+            );
+            let copied_rows = QueryExecution::new(&transaction.cancel_token(), copy_statement)
+                .copy_in_with_timeout(&transaction, receiver, "copy_to_staging_table", &[])
+                .await
+                .unwrap();
+            assert_eq!(copied_rows, 2);
+            let merge = database_writer.query_for_change_kind(
+                &ChangeKind::Insert,
+                staging_name,
+                "local_copy_test",
+                "public",
+                &columns,
+                true,
+            );
+            assert_eq!(transaction.execute(merge.as_str(), &[]).await.unwrap(), 2);
+            transaction.commit().await.unwrap();
+            let rows = client
+                .query(
+                    "select id, name, amount::text from public.local_copy_test order by id",
+                    &[],
+                )
+                .await
+                .unwrap()
+                .iter()
+                .map(|row| {
+                    (
+                        row.get::<_, i64>(0),
+                        row.get::<_, Option<String>>(1),
+                        row.get::<_, Option<String>>(2),
+                    )
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(
+                rows,
+                vec![
+                    (1, Some("a\tb".to_string()), Some("1.50000000".to_string())),
+                    // nulls in the file are nulls
+                    (2, None, None),
+                    (3, Some("c".to_string()), Some("3.00000000".to_string())),
+                ]
+            );
+            client
+                .batch_execute("drop table public.local_copy_test")
+                .await
+                .unwrap();
+        });
+    }
+}
@@ -1,3 +1,4 @@
+use backoff::Error as BackoffError;
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::mpsc;
@@ -5,13 +6,15 @@ use tokio::sync::mpsc;
 #[allow(unused_imports)]
 use crate::{function, logger_debug, logger_error, logger_info, logger_panic};
 
-use crate::database_writer::DatabaseWriter;
+use crate::change_processing::DdlChange;
+use crate::database_writer::{DatabaseWriter, DatabaseWriterError};
 use crate::exponential_backoff::*;
 use crate::file_uploader_threads::{
     GenericTableThread, GenericTableThreadSplitter, UploaderStageResult, DEFAULT_CHANNEL_SIZE,
 };
 use crate::parser::TableName;
 use crate::shutdown_handler::ShutdownHandler;
+use crate::wal_manifest::CsvFileStatus;
 
 // manages the thread-per-table and the fanout
 pub type DatabaseTableThread = GenericTableThread<UploaderStageResult>;
@@ -20,8 +23,8 @@ pub type DatabaseTableThread = GenericTableThread<UploaderStageResult>;
 pub type DatabaseWriterThreads = GenericTableThreadSplitter<DatabaseWriter, UploaderStageResult>;
 
 impl DatabaseWriterThreads {
-    pub async fn new() -> DatabaseWriterThreads {
-        let shared_resource = Arc::new(DatabaseWriter::new().await);
+    pub async fn new(target_schema: Option<String>) -> DatabaseWriterThreads {
+        let shared_resource = Arc::new(DatabaseWriter::new(target_schema).await);
         let table_streams = HashMap::new();
         DatabaseWriterThreads {
             shared_resource,
@@ -31,14 +34,39 @@ impl DatabaseWriterThreads {
 
     pub fn spawn_database_writer_stream(
         receiver: mpsc::Receiver<UploaderStageResult>,
+        target_schema: Option<String>,
     ) -> tokio::task::JoinHandle<()> {
-        tokio::spawn(DatabaseWriterThreads::database_uploader_stream(receiver))
+        tokio::spawn(DatabaseWriterThreads::database_uploader_stream(
+            receiver,
+            target_schema,
+        ))
     }
 
-    pub async fn database_uploader_stream(mut receiver: mpsc::Receiver<UploaderStageResult>) {
-        let mut database_uploader_stream = DatabaseWriterThreads::new().await;
+    pub async fn database_uploader_stream(
+        mut receiver: mpsc::Receiver<UploaderStageResult>,
+        target_schema: Option<String>,
+    ) {
+        let mut database_uploader_stream = DatabaseWriterThreads::new(target_schema).await;
         loop {
             let received = receiver.recv().await;
+            // anything under the new name comes after the rename, so it can't create the
+            // new table before the rename does. Both tasks finish what they have first,
+            // and then the rename is applied before we send anything else
+            let renamed_table_names = match &received {
+                Some(UploaderStageResult::DdlChange(
+                    DdlChange::RenameTable(old_table_name, new_table_name),
+                    _,
+                )) => Some((old_table_name.clone(), new_table_name.clone())),
+                _ => None,
+            };
+            if let Some((old_table_name, new_table_name)) = &renamed_table_names {
+                database_uploader_stream
+                    .drain_table_thread(old_table_name)
+                    .await;
+                database_uploader_stream
+                    .drain_table_thread(new_table_name)
+                    .await;
+            }
             if let Some(s3_file) = received {
                 let table_name = s3_file.table_name();
                 let current_table_name = table_name.clone();
@@ -57,6 +85,11 @@ impl DatabaseWriterThreads {
                         }
                     }
                 }
+                if let Some((old_table_name, _)) = &renamed_table_names {
+                    database_uploader_stream
+                        .drain_table_thread(old_table_name)
+                        .await;
+                }
             } else {
                 logger_info!(None, None, "main_channel_hung_up");
                 database_uploader_stream.join_all_table_threads().await;
@@ -125,12 +158,20 @@ impl DatabaseWriterThreads {
                             // it an Fn at the expense of this clone)
                             // it took me a _loooooong_ time to grok all of that.
                             let mut mutable_s3_file = (*cleo_s3_file).clone();
-                            uploader.apply_s3_changes(&mut mutable_s3_file).await?;
+                            uploader
+                                .apply_s3_changes(&mut mutable_s3_file)
+                                .await
+                                .map_err(|err| match err {
+                                    // loading it again gets the same rows
+                                    DatabaseWriterError::RowCountMismatch(..) => {
+                                        BackoffError::permanent(err)
+                                    }
+                                    _ => BackoffError::transient(err),
+                                })?;
                         }
                         UploaderStageResult::DdlChange(ddl_change, wal_file) => {
-                            // I don't think we need to handle a ddl change being the last
-                            // change in a wal file, since it relies on a difference, so there must be a line after it
-                            // so we don't need to call maybe_remove_wal_file();
+                            // the wal file is cleaned up once we're done with it below,
+                            // as audit ddl can be the last change in a wal file
                             uploader
                                 .handle_ddl(&ddl_change, wal_file.file_number)
                                 .await?;
@@ -138,6 +179,17 @@ impl DatabaseWriterThreads {
                     };
                     Ok(())
                 }).await;
+                let load_status = match backoff_result {
+                    Ok(..) => CsvFileStatus::Loaded,
+                    Err(..) => CsvFileStatus::Failed,
+                };
+                if let UploaderStageResult::S3File(cleo_s3_file) = uploader_stage_result {
+                    wal_file.update_manifest(|manifest| {
+                        for part_remote_filename in cleo_s3_file.part_remote_filenames.iter() {
+                            manifest.csv_file_loaded(part_remote_filename, load_status)
+                        }
+                    });
+                }
                 match backoff_result {
                     Ok(..) => {
                         // need to clean up our wal file
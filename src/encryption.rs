@@ -0,0 +1,481 @@
+use lazy_static::lazy_static;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::hkdf;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+#[allow(unused_imports)]
+use crate::{function, logger_debug, logger_error, logger_info, logger_panic, logger_warning};
+
+lazy_static! {
+    // 32 bytes, hex encoded. From a file, or the env. Files are written in plaintext if neither is set
+    static ref ENCRYPTION_KEY: Option<MasterKey> = std::env::var("ENCRYPTION_KEY_FILE")
+        .ok()
+        .filter(|path| !path.is_empty())
+        .map(|path| {
+            std::fs::read_to_string(&path)
+                .unwrap_or_else(|err| panic!("Unable to read ENCRYPTION_KEY_FILE {}: {}", path, err))
+        })
+        .or_else(|| std::env::var("ENCRYPTION_KEY").ok().filter(|key| !key.is_empty()))
+        .map(|key| MasterKey::parse(&key));
+}
+
+// encrypted files start with this, then the salt, then the frames. e.g.
+// re_dms:aes256gcm:1 <32 byte salt> <u32 length><ciphertext and tag> <u32 length><ciphertext and tag> ...
+// the last frame of a finished file has FINAL_FRAME set in its length, and is authenticated as
+// the last, so a file cut short can't pass for a whole one
+const MAGIC: &[u8] = b"re_dms:aes256gcm:1";
+const SALT_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+const FINAL_FRAME: u32 = 0x8000_0000;
+// plaintext is sealed into a frame on every flush, or once we've this much
+const MAX_FRAME_LEN: usize = 64 * 1024;
+
+#[derive(Clone)]
+pub struct MasterKey([u8; 32]);
+
+impl MasterKey {
+    fn parse(key: &str) -> MasterKey {
+        let bytes = hex::decode(key.trim()).expect("Encryption key is not valid hex");
+        MasterKey(
+            bytes
+                .try_into()
+                .expect("Encryption key should be 32 bytes (64 hex characters)"),
+        )
+    }
+
+    // each file gets its own key, so frame numbers can be the nonces
+    fn file_key(&self, salt: &[u8]) -> LessSafeKey {
+        let file_key: UnboundKey = hkdf::Salt::new(hkdf::HKDF_SHA256, salt)
+            .extract(&self.0)
+            .expand(&[b"re_dms file"], &AES_256_GCM)
+            .expect("Error deriving file encryption key")
+            .into();
+        LessSafeKey::new(file_key)
+    }
+}
+
+fn nonce(frame: u64) -> Nonce {
+    let mut nonce = [0; NONCE_LEN];
+    nonce[NONCE_LEN - 8..].copy_from_slice(&frame.to_be_bytes());
+    Nonce::assume_unique_for_key(nonce)
+}
+
+fn aad(final_frame: bool) -> Aad<[u8; 1]> {
+    Aad::from([final_frame as u8])
+}
+
+struct Sealer {
+    key: LessSafeKey,
+    salt: [u8; SALT_LEN],
+    frames: u64,
+    written_header: bool,
+}
+
+// encrypts everything written to it with the configured key, or passes it straight through.
+// a flush seals what we have into a frame, so everything up to the last flush can be read back
+// after a crash
+pub struct EncryptingWriter<W: Write> {
+    inner: W,
+    sealer: Option<Sealer>,
+    plaintext: Vec<u8>,
+}
+
+impl<W: Write> std::fmt::Debug for EncryptingWriter<W>
+where
+    W: std::fmt::Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncryptingWriter")
+            .field("inner", &self.inner)
+            .field("encrypted", &self.sealer.is_some())
+            .finish()
+    }
+}
+
+impl<W: Write> EncryptingWriter<W> {
+    pub fn new(inner: W) -> EncryptingWriter<W> {
+        Self::with_key(inner, ENCRYPTION_KEY.as_ref())
+    }
+
+    fn with_key(inner: W, master_key: Option<&MasterKey>) -> EncryptingWriter<W> {
+        let sealer = master_key.map(|master_key| {
+            let mut salt = [0; SALT_LEN];
+            SystemRandom::new()
+                .fill(&mut salt)
+                .expect("Error generating encryption salt");
+            Sealer {
+                key: master_key.file_key(&salt),
+                salt,
+                frames: 0,
+                written_header: false,
+            }
+        });
+        EncryptingWriter {
+            inner,
+            sealer,
+            plaintext: vec![],
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    // seals anything left into the final frame. nothing more can be written after it
+    pub fn finish(mut self) -> io::Result<W> {
+        let written_header = self
+            .sealer
+            .as_ref()
+            .is_some_and(|sealer| sealer.written_header);
+        if written_header || !self.plaintext.is_empty() {
+            self.write_frame(true)?;
+        }
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    fn seal_frame(&mut self) -> io::Result<()> {
+        if self.plaintext.is_empty() {
+            return Ok(());
+        }
+        self.write_frame(false)
+    }
+
+    fn write_frame(&mut self, final_frame: bool) -> io::Result<()> {
+        let sealer = match &mut self.sealer {
+            Some(sealer) => sealer,
+            None => return Ok(()),
+        };
+        // nothing's written until there's something to encrypt, so an empty file stays empty
+        if !sealer.written_header {
+            self.inner.write_all(MAGIC)?;
+            self.inner.write_all(&sealer.salt)?;
+            sealer.written_header = true;
+        }
+        let mut frame = std::mem::take(&mut self.plaintext);
+        sealer
+            .key
+            .seal_in_place_append_tag(nonce(sealer.frames), aad(final_frame), &mut frame)
+            .map_err(|_| io::Error::other("Error encrypting frame"))?;
+        sealer.frames += 1;
+        let mut length = frame.len() as u32;
+        if final_frame {
+            length |= FINAL_FRAME;
+        }
+        self.inner.write_all(&length.to_be_bytes())?;
+        self.inner.write_all(&frame)
+    }
+}
+
+impl<W: Write> Write for EncryptingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.sealer.is_none() {
+            return self.inner.write(buf);
+        }
+        let written = buf.len().min(MAX_FRAME_LEN - self.plaintext.len());
+        self.plaintext.extend_from_slice(&buf[..written]);
+        if self.plaintext.len() == MAX_FRAME_LEN {
+            self.seal_frame()?;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.seal_frame()?;
+        self.inner.flush()
+    }
+}
+
+struct DecryptingReader<R: Read> {
+    inner: R,
+    key: LessSafeKey,
+    frames: u64,
+    plaintext: Vec<u8>,
+    position: usize,
+    // only for a wal file we may still have been writing when we stopped
+    allow_truncated: bool,
+    finished: bool,
+}
+
+impl<R: Read> DecryptingReader<R> {
+    // false at the end of the file
+    fn open_frame(&mut self) -> io::Result<bool> {
+        if self.finished {
+            return Ok(false);
+        }
+        let mut length = [0; 4];
+        if !self.read_all_or_nothing(&mut length)? {
+            return Ok(false);
+        }
+        let length = u32::from_be_bytes(length);
+        let final_frame = length & FINAL_FRAME != 0;
+        let length = (length & !FINAL_FRAME) as usize;
+        if !(TAG_LEN..=MAX_FRAME_LEN + TAG_LEN).contains(&length) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid encrypted frame length {}", length),
+            ));
+        }
+        let mut frame = vec![0; length];
+        if !self.read_all_or_nothing(&mut frame)? {
+            return Ok(false);
+        }
+        let plaintext_len = self
+            .key
+            .open_in_place(nonce(self.frames), aad(final_frame), &mut frame)
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Encrypted frame {} failed authentication", self.frames),
+                )
+            })?
+            .len();
+        frame.truncate(plaintext_len);
+        self.frames += 1;
+        self.plaintext = frame;
+        self.position = 0;
+        if final_frame {
+            self.finished = true;
+            if read_up_to(&mut self.inner, &mut [0])? != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Data after the final encrypted frame",
+                ));
+            }
+        }
+        Ok(true)
+    }
+
+    // false if we're at the end. The end of a file without its final frame is only expected of a
+    // wal file we stopped part way through writing (e.g. we crashed), where everything
+    // before it was flushed
+    fn read_all_or_nothing(&mut self, buf: &mut [u8]) -> io::Result<bool> {
+        let read = read_up_to(&mut self.inner, buf)?;
+        if read == buf.len() {
+            return Ok(true);
+        }
+        if !self.allow_truncated {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "Encrypted file ends without its final frame, after frame {}",
+                    self.frames
+                ),
+            ));
+        }
+        logger_warning!(
+            None,
+            None,
+            &format!(
+                "encrypted_file_truncated frames:{} bytes:{}",
+                self.frames, read
+            )
+        );
+        Ok(false)
+    }
+}
+
+impl<R: Read> Read for DecryptingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.plaintext.len() {
+            if !self.open_frame()? {
+                return Ok(0);
+            }
+        }
+        let read = buf.len().min(self.plaintext.len() - self.position);
+        buf[..read].copy_from_slice(&self.plaintext[self.position..self.position + read]);
+        self.position += read;
+        Ok(read)
+    }
+}
+
+fn read_up_to<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(read)
+}
+
+// reads encrypted and plaintext files alike, whatever we're configured with now.
+// encrypted files have to have been finished
+pub fn decrypting_reader<R: Read + 'static>(inner: R) -> io::Result<Box<dyn Read>> {
+    decrypting_reader_with_key(inner, ENCRYPTION_KEY.as_ref(), false)
+}
+
+// for a wal file we may not have finished writing, which is read up to its last flush
+pub fn decrypting_reader_allowing_truncation<R: Read + 'static>(
+    inner: R,
+) -> io::Result<Box<dyn Read>> {
+    decrypting_reader_with_key(inner, ENCRYPTION_KEY.as_ref(), true)
+}
+
+fn decrypting_reader_with_key<R: Read + 'static>(
+    mut inner: R,
+    master_key: Option<&MasterKey>,
+    allow_truncated: bool,
+) -> io::Result<Box<dyn Read>> {
+    let mut magic = vec![0; MAGIC.len()];
+    let read = read_up_to(&mut inner, &mut magic)?;
+    if read < MAGIC.len() || magic != MAGIC {
+        magic.truncate(read);
+        return Ok(Box::new(io::Cursor::new(magic).chain(inner)));
+    }
+    let master_key = master_key.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "File is encrypted, but ENCRYPTION_KEY_FILE or ENCRYPTION_KEY is not set",
+        )
+    })?;
+    let mut salt = [0; SALT_LEN];
+    if read_up_to(&mut inner, &mut salt)? != SALT_LEN {
+        if allow_truncated {
+            logger_warning!(None, None, "encrypted_file_truncated frames:0 bytes:0");
+            return Ok(Box::new(io::empty()));
+        }
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Encrypted file ends before its salt",
+        ));
+    }
+    Ok(Box::new(DecryptingReader {
+        inner,
+        key: master_key.file_key(&salt),
+        frames: 0,
+        plaintext: vec![],
+        position: 0,
+        allow_truncated,
+        finished: false,
+    }))
+}
+
+pub fn is_encrypted(path: &Path) -> io::Result<bool> {
+    let mut magic = vec![0; MAGIC.len()];
+    let read = read_up_to(&mut File::open(path)?, &mut magic)?;
+    Ok(read == MAGIC.len() && magic == MAGIC)
+}
+
+pub fn read_to_end(path: &Path) -> io::Result<Vec<u8>> {
+    let mut contents = vec![];
+    decrypting_reader(File::open(path)?)?.read_to_end(&mut contents)?;
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> MasterKey {
+        MasterKey::parse(&"2a".repeat(32))
+    }
+
+    fn read(ciphertext: Vec<u8>, master_key: Option<&MasterKey>) -> io::Result<Vec<u8>> {
+        read_allowing_truncation(ciphertext, master_key, false)
+    }
+
+    fn read_allowing_truncation(
+        ciphertext: Vec<u8>,
+        master_key: Option<&MasterKey>,
+        allow_truncated: bool,
+    ) -> io::Result<Vec<u8>> {
+        let mut plaintext = vec![];
+        decrypting_reader_with_key(io::Cursor::new(ciphertext), master_key, allow_truncated)?
+            .read_to_end(&mut plaintext)?;
+        Ok(plaintext)
+    }
+
+    #[test]
+    fn encrypted_files_read_back_once_finished() {
+        let mut writer = EncryptingWriter::with_key(vec![], Some(&key()));
+        writer.write_all(b"BEGIN 1\n").unwrap();
+        writer.flush().unwrap();
+        writer.write_all(&vec![b'x'; MAX_FRAME_LEN + 1]).unwrap();
+        let ciphertext = writer.finish().unwrap();
+        assert!(ciphertext.starts_with(MAGIC));
+        assert!(!ciphertext.windows(8).any(|window| window == b"BEGIN 1\n"));
+        let plaintext = read(ciphertext.clone(), Some(&key())).unwrap();
+        assert_eq!(plaintext.len(), 8 + MAX_FRAME_LEN + 1);
+        assert!(plaintext.starts_with(b"BEGIN 1\n"));
+
+        // a file cut short isn't a whole one, even at a frame boundary
+        let truncated = ciphertext[..ciphertext.len() - 5].to_vec();
+        assert_eq!(
+            read(truncated, Some(&key())).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+        let final_frame_len = 4 + 1 + TAG_LEN;
+        let without_final_frame = ciphertext[..ciphertext.len() - final_frame_len].to_vec();
+        assert_eq!(
+            read(without_final_frame, Some(&key())).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+        let mut appended = ciphertext.clone();
+        appended.extend_from_slice(&ciphertext[MAGIC.len() + SALT_LEN..]);
+        assert_eq!(
+            read(appended, Some(&key())).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        let mut tampered = ciphertext.clone();
+        tampered[MAGIC.len() + SALT_LEN + 6] ^= 1;
+        assert_eq!(
+            read(tampered, Some(&key())).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert!(read(ciphertext, None).is_err());
+    }
+
+    #[test]
+    fn unfinished_wal_files_read_back_up_to_the_last_flush() {
+        let mut writer = EncryptingWriter::with_key(vec![], Some(&key()));
+        writer.write_all(b"BEGIN 1\n").unwrap();
+        writer.flush().unwrap();
+        writer.write_all(b"COMMIT 1\n").unwrap();
+        writer.flush().unwrap();
+        // a crash part way through writing the next frame
+        writer.write_all(b"BEGIN 2\n").unwrap();
+        writer.flush().unwrap();
+        let ciphertext = writer.get_ref().clone();
+        let truncated = ciphertext[..ciphertext.len() - 5].to_vec();
+        assert_eq!(
+            read_allowing_truncation(truncated.clone(), Some(&key()), true).unwrap(),
+            b"BEGIN 1\nCOMMIT 1\n"
+        );
+        assert!(read(truncated, Some(&key())).is_err());
+        assert_eq!(
+            read_allowing_truncation(ciphertext[..MAGIC.len() + 5].to_vec(), Some(&key()), true)
+                .unwrap(),
+            b""
+        );
+
+        // the final frame of a finished one is still checked
+        let mut finished = writer.finish().unwrap();
+        let last = finished.len() - 1;
+        finished[last] ^= 1;
+        assert_eq!(
+            read_allowing_truncation(finished, Some(&key()), true)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn plaintext_files_pass_through() {
+        let mut writer = EncryptingWriter::with_key(vec![], None);
+        writer.write_all(b"BEGIN 1\n").unwrap();
+        let plaintext = writer.finish().unwrap();
+        assert_eq!(plaintext, b"BEGIN 1\n");
+        assert_eq!(read(plaintext.clone(), Some(&key())).unwrap(), plaintext);
+        assert_eq!(read(b"re".to_vec(), None).unwrap(), b"re");
+    }
+}
@@ -1,21 +1,73 @@
 use backoff::Error as BackoffError;
 use lazy_static::lazy_static;
-use aws_sdk_s3::Client as S3Client;
-use aws_sdk_s3::Error as S3Error;
-use aws_sdk_s3::primitives::ByteStream;
+use std::error::Error;
+use std::fmt;
 
 #[allow(unused_imports)]
 use crate::{function, logger_debug, logger_error, logger_info, logger_panic, logger_warning};
 
+use crate::checkpoints::Checkpoint;
 use crate::exponential_backoff::*;
-use crate::file_writer::{FileStruct, FileWriter};
+use crate::file_writer::{FileStruct, FileWriter, FileWriterError, StagingFileFormat};
 use crate::parser::{ChangeKind, ColumnInfo, TableName};
 use crate::shutdown_handler::ShutdownHandler;
+use crate::staging_compression::StagingCompression;
+use crate::storage_backend::{StorageBackend, StorageError, StorageLocation};
 use crate::wal_file_manager;
 use crate::wal_file_manager::WalFile;
 
 pub struct FileUploader {
-    s3_client: S3Client,
+    storage_backend: Box<dyn StorageBackend>,
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug)]
+pub enum FileUploaderError {
+    StorageError(StorageError),
+    FileWriterError(FileWriterError),
+}
+
+impl fmt::Display for FileUploaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileUploaderError::StorageError(err) => {
+                write!(f, "Storage error: {}", err)
+            }
+            FileUploaderError::FileWriterError(err) => {
+                write!(f, "File writer error: {}", err)
+            }
+        }
+    }
+}
+
+impl Error for FileUploaderError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FileUploaderError::StorageError(err) => Some(err),
+            FileUploaderError::FileWriterError(err) => Some(err),
+        }
+    }
+}
+
+impl From<StorageError> for FileUploaderError {
+    fn from(err: StorageError) -> FileUploaderError {
+        FileUploaderError::StorageError(err)
+    }
+}
+
+impl From<FileWriterError> for FileUploaderError {
+    fn from(err: FileWriterError) -> FileUploaderError {
+        FileUploaderError::FileWriterError(err)
+    }
+}
+
+// retried if it might go away by itself
+fn backoff_error(err: StorageError) -> BackoffError<FileUploaderError> {
+    if err.is_transient() {
+        BackoffError::transient(err.into())
+    } else {
+        BackoffError::permanent(err.into())
+    }
 }
 
 // little bag of data
@@ -25,110 +77,98 @@ pub struct CleoS3File {
     pub kind: ChangeKind,
     pub table_name: TableName,
     pub columns: Vec<ColumnInfo>,
+    pub format: StagingFileFormat,
+    pub compression: StagingCompression,
+    // when a file is split into parts, remote_filename is the copy manifest listing these
+    pub part_remote_filenames: Vec<String>,
+    // uploaded bytes, which copy manifests need for parquet files
+    pub content_length: u64,
+    // rows in the whole file (all of its parts), and a hash of them, to check what's loaded
+    pub rows: u64,
+    pub content_hash: u32,
+    // updates to rows that may not be in the target, applied as a delete and insert
+    pub replaces_rows: bool,
     pub wal_file: wal_file_manager::WalFile,
+    // only on the last file of a table's batch for a wal file
+    pub checkpoint: Option<Checkpoint>,
 }
 impl CleoS3File {
     pub fn remote_path(&self) -> String {
-        "s3://".to_owned() + BUCKET_NAME.as_ref() + "/" + self.remote_filename.as_ref()
+        StorageLocation::configured().url(&self.remote_filename)
+    }
+
+    pub fn is_copy_manifest(&self) -> bool {
+        self.part_remote_filenames.len() > 1
+    }
+
+    // the file, or its parts if it's copied from a manifest
+    pub fn part_remote_paths(&self) -> Vec<String> {
+        if self.is_copy_manifest() {
+            self.part_remote_filenames
+                .iter()
+                .map(|part_remote_filename| StorageLocation::configured().url(part_remote_filename))
+                .collect()
+        } else {
+            vec![self.remote_path()]
+        }
     }
 }
 lazy_static! {
-    static ref BUCKET_NAME: String =
-        std::env::var("BUCKET_NAME").expect("BUCKET_NAME env is not set");
     static ref BUCKET_FOLDER: String =
         std::env::var("BUCKET_FOLDER").expect("BUCKET_FOLDER env is not set");
-    static ref AWS_REGION: String = {
-        let region = std::env::var("AWS_REGION").unwrap_or_else(|_| "us-east-1".to_string());
-        let valid_regions = [
-            "us-east-1", "us-east-2", "us-west-1", "us-west-2",
-            "ca-central-1",
-            "eu-west-1", "eu-west-2", "eu-west-3", "eu-central-1",
-            "ap-northeast-1", "ap-northeast-2", "ap-northeast-3",
-            "ap-southeast-1", "ap-southeast-2", "ap-south-1",
-            "sa-east-1",
-        ];
-        if !valid_regions.contains(&region.as_str()) {
-            logger_warning!(None, None, &format!("Invalid AWS region: {}. Defaulting to us-east-1", region));
-            "us-east-1".to_string()
-        } else {
-            region
-        }
-    };
 }
 
 impl FileUploader {
     pub async fn new() -> FileUploader {
-        logger_info!(None, None, &format!("Initializing S3 client with region: {}", AWS_REGION.as_str()));
-        
-        let region = aws_config::Region::new(AWS_REGION.to_string());
-        let config = aws_config::defaults(aws_config::BehaviorVersion::latest())
-            .region(region)
-            .load()
-            .await;
-        let s3_client = S3Client::new(&config);
-        
-        FileUploader {
-            s3_client,
-        }
+        let storage_backend = StorageLocation::configured().backend().await;
+
+        FileUploader { storage_backend }
     }
     pub async fn upload_to_s3(
         &self,
         wal_file: &wal_file_manager::WalFile,
         file_name: &str,
         file_struct: &FileStruct,
-    ) -> Result<CleoS3File, BackoffError<S3Error>> {
+    ) -> Result<CleoS3File, BackoffError<FileUploaderError>> {
         // info!("copying file {}", file_name);
         let local_filename = file_name;
         let remote_filename = BUCKET_FOLDER.to_owned() + file_name;
         // info!("remote key {}", remote_filename);
         // async
         // info!("{}", local_filename);
-        let meta = ::std::fs::metadata(local_filename).unwrap();
         let file_path = std::path::Path::new(local_filename);
-        let byte_stream_result = ByteStream::from_path(file_path).await;
-        match byte_stream_result {
-            Ok(byte_stream) => {
-                logger_debug!(
+        match self
+            .storage_backend
+            .put_file(&remote_filename, file_path)
+            .await
+        {
+            Ok(file_length) => {
+                logger_info!(
                     Some(wal_file.file_number),
                     Some(&file_struct.table_name),
-                    &format!("file_length:{} file_name:{}", meta.len(), file_name)
+                    &format!(
+                        "uploaded_file:{} file_length:{}",
+                        remote_filename, file_length
+                    )
                 );
-                
-                let maybe_uploaded = self.s3_client
-                    .put_object()
-                    .bucket(BUCKET_NAME.as_str())
-                    .key(&remote_filename)
-                    .content_length(meta.len() as i64)
-                    .body(byte_stream)
-                    .send()
-                    .await;
-                
-                match maybe_uploaded {
-                    Ok(_result) => {
-                        logger_info!(
-                            Some(wal_file.file_number),
-                            Some(&file_struct.table_name),
-                            &format!("uploaded_file:{}", remote_filename)
-                        );
-                    }
-                    Err(result) => {
-                        // Log the specific S3 error details
-                        logger_error!(
-                            Some(wal_file.file_number),
-                            Some(&file_struct.table_name),
-                            &format!("S3 upload error: {:?} for file: {}", result, remote_filename)
-                        );
-                        // treat s3 errors as transient
-                        return Err(BackoffError::transient(result.into()));
-                    }
-                }
+                wal_file.update_manifest(|manifest| {
+                    manifest.csv_file_uploaded(file_path, &remote_filename)
+                });
                 if let Some(columns) = &file_struct.columns {
                     Ok(CleoS3File {
                         remote_filename: remote_filename.clone(),
                         kind: file_struct.kind,
                         table_name: file_struct.table_name.clone(),
                         columns: columns.clone(),
+                        format: file_struct.format,
+                        compression: file_struct.compression,
+                        part_remote_filenames: vec![remote_filename.clone()],
+                        content_length: file_length,
+                        rows: file_struct.rows,
+                        content_hash: file_struct.content_hash(),
+                        replaces_rows: file_struct.replaces_rows,
                         wal_file: (*wal_file).clone(),
+                        checkpoint: None,
                     })
                 } else {
                     // logic error
@@ -136,16 +176,22 @@ impl FileUploader {
                 }
             }
             Err(err) => {
-                // bail early for local file disk errors
-                // Is this right? should be retry reading from disk?
-                panic!("Error reading file from disk {:?} {:?}", file_name, err);
+                logger_error!(
+                    Some(wal_file.file_number),
+                    Some(&file_struct.table_name),
+                    &format!("file_upload_error file:{} error:{}", remote_filename, err)
+                );
+                Err(backoff_error(err))
             }
         }
     }
 
     // does all of these concurrently
     // consumes the file_writer
-    pub async fn upload_table_to_s3(&self, mut file_writer: FileWriter) -> Vec<CleoS3File> {
+    pub async fn upload_table_to_s3(
+        &self,
+        mut file_writer: FileWriter,
+    ) -> Result<Vec<CleoS3File>, FileUploaderError> {
         let mut upload_files_vec = vec![];
         let insert_file = &file_writer.insert_file;
         let deletes_file = &file_writer.delete_file;
@@ -162,10 +208,7 @@ impl FileUploader {
         let s3_file_results = upload_files_vec
             .iter_mut()
             .filter(|(_wal_file, file)| file.exists())
-            .map(|(wal_file, file)| async move {
-                self.upload_to_s3_with_backoff(wal_file, file.file_name.to_str().unwrap(), &file)
-                    .await
-            })
+            .map(|(wal_file, file)| async move { self.upload_parts_to_s3(wal_file, file).await })
             .collect::<Vec<_>>();
         let cleo_s3_files = futures::future::join_all(s3_file_results).await;
         // just make sure we drop any dangling references to wal_files before we try and maybe_remove it
@@ -173,11 +216,105 @@ impl FileUploader {
         // if we don't have any cleo s3 files... first off, bit weird that we sent a file writer here
         // but secondly, we'd need to clean up the wal file
         file_writer.wal_file.maybe_remove_wal_file();
-        if cleo_s3_files.iter().any(Result::is_err) {
-            vec![]
-        } else {
-            cleo_s3_files.into_iter().filter_map(Result::ok).collect()
+        let mut cleo_s3_files = cleo_s3_files
+            .into_iter()
+            .collect::<Result<Vec<CleoS3File>, FileUploaderError>>()?;
+        // files are applied in order, so the last one finishes the batch
+        if let Some(last_s3_file) = cleo_s3_files.last_mut() {
+            last_s3_file.checkpoint = file_writer.checkpoint.take();
+        }
+        Ok(cleo_s3_files)
+    }
+
+    // files split into parts have their parts uploaded concurrently, and a copy manifest listing them
+    async fn upload_parts_to_s3(
+        &self,
+        wal_file: &mut wal_file_manager::WalFile,
+        file_struct: &FileStruct,
+    ) -> Result<CleoS3File, FileUploaderError> {
+        if file_struct.part_file_names.len() == 1 {
+            return self
+                .upload_to_s3_with_backoff(
+                    wal_file,
+                    file_struct.file_name.to_str().unwrap(),
+                    file_struct,
+                )
+                .await;
+        }
+        let part_uploads = file_struct
+            .part_file_names
+            .iter()
+            .map(|part_file_name| {
+                let mut wal_file = wal_file.clone();
+                async move {
+                    self.upload_to_s3_with_backoff(
+                        &mut wal_file,
+                        part_file_name.to_str().unwrap(),
+                        file_struct,
+                    )
+                    .await
+                }
+            })
+            .collect::<Vec<_>>();
+        let parts = futures::future::join_all(part_uploads)
+            .await
+            .into_iter()
+            .collect::<Result<Vec<CleoS3File>, _>>()?;
+
+        let copy_manifest = serde_json::json!({
+            "entries": parts
+                .iter()
+                .map(|part| serde_json::json!({
+                    "url": part.remote_path(),
+                    "mandatory": true,
+                    "meta": { "content_length": part.content_length },
+                }))
+                .collect::<Vec<_>>()
+        })
+        .to_string();
+        let remote_filename = BUCKET_FOLDER.to_owned()
+            + file_struct
+                .file_name
+                .to_str()
+                .unwrap()
+                .strip_suffix(file_struct.extension())
+                .expect("File name doesn't have its extension")
+            + ".manifest";
+        let result = retry(default_exponential_backoff(), || async {
+            self.storage_backend
+                .put_bytes(&remote_filename, copy_manifest.clone().into_bytes())
+                .await
+                .map_err(backoff_error)
+        })
+        .await;
+        if let Err(err) = result {
+            wal_file.register_error();
+            ShutdownHandler::register_messy_shutdown();
+            logger_error!(
+                Some(wal_file.file_number),
+                Some(&file_struct.table_name),
+                &format!(
+                    "copy_manifest_upload_failed file:{} error:{}",
+                    remote_filename, err
+                )
+            );
+            return Err(err);
         }
+        logger_info!(
+            Some(wal_file.file_number),
+            Some(&file_struct.table_name),
+            &format!(
+                "uploaded_copy_manifest:{} parts:{}",
+                remote_filename,
+                parts.len()
+            )
+        );
+        let mut s3_file = parts[0].clone();
+        s3_file.content_length = copy_manifest.len() as u64;
+        s3_file.part_remote_filenames =
+            parts.into_iter().map(|part| part.remote_filename).collect();
+        s3_file.remote_filename = remote_filename;
+        Ok(s3_file)
     }
 
     pub async fn upload_to_s3_with_backoff(
@@ -185,7 +322,7 @@ impl FileUploader {
         wal_file: &mut wal_file_manager::WalFile,
         file_name: &str,
         file_struct: &FileStruct,
-    ) -> Result<CleoS3File, BackoffError<S3Error>> {
+    ) -> Result<CleoS3File, FileUploaderError> {
         // for simplicity, this
         let result = retry(default_exponential_backoff(), || async { self.upload_to_s3(wal_file, file_name, file_struct).await }).await;
         match result {
@@ -193,13 +330,16 @@ impl FileUploader {
             Err(err) => {
                 // belt and bracers, this won't get deleted
                 wal_file.register_error();
+                wal_file.update_manifest(|manifest| {
+                    manifest.csv_file_upload_failed(std::path::Path::new(file_name))
+                });
                 ShutdownHandler::register_messy_shutdown();
                 logger_error!(
                     Some(wal_file.file_number),
                     Some(&file_struct.table_name),
                     &format!("file_upload_failed file:{} error:{}", file_name, err)
                 );
-                Err(err)?
+                Err(err)
             }
         }
     }
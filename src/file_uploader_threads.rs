@@ -84,6 +84,22 @@ impl<SharedResource, ChannelType> GenericTableThreadSplitter<SharedResource, Cha
         logger_info!(None, None, "join_handles_finished_waiting");
     }
 
+    // waits for everything already sent to the table's task, and removes it.
+    // the next thing sent for the table starts a new one
+    pub async fn drain_table_thread(&mut self, table_name: &TableName) {
+        if let Some(mut table_thread) = self.table_streams.remove(table_name) {
+            if let Some(join_handle) = table_thread.drop_sender_and_return_join_handle() {
+                if let Err(err) = join_handle.await {
+                    logger_error!(
+                        None,
+                        Some(table_name),
+                        &format!("table_thread_join_failed:{:?}", err)
+                    );
+                }
+            }
+        }
+    }
+
     pub fn get_shared_resource(&self) -> Arc<SharedResource> {
         // create new reference counted pointer
         self.shared_resource.clone()
@@ -127,7 +143,31 @@ impl FileUploaderThreads {
         let mut file_uploader_stream = FileUploaderThreads::new().await;
         loop {
             let received = receiver.recv().await;
-            if let Some(file_writer) = received {
+            if let Some(change_processing::ChangeProcessingResult::DdlChange(
+                ddl_change @ change_processing::DdlChange::RenameTable(..),
+                wal_file,
+            )) = received
+            {
+                // changes under the new name are uploaded by another task, so both tasks
+                // finish what they have before the rename is passed on, and anything
+                // for the new name is passed on after it
+                if let change_processing::DdlChange::RenameTable(old_table_name, new_table_name) =
+                    &ddl_change
+                {
+                    file_uploader_stream
+                        .drain_table_thread(old_table_name)
+                        .await;
+                    file_uploader_stream
+                        .drain_table_thread(new_table_name)
+                        .await;
+                }
+                result_sender
+                    .send(UploaderStageResult::DdlChange(ddl_change, wal_file))
+                    .await
+                    .expect(
+                        "Unable to send rename_table from file_uploader_stream to database writer",
+                    );
+            } else if let Some(file_writer) = received {
                 let table_name = file_writer.table_name();
                 let sender = file_uploader_stream.get_sender(table_name.clone(), &result_sender);
                 if let Some(ref mut inner_sender) = sender.sender {
@@ -207,8 +247,27 @@ impl FileUploaderThreads {
                 last_table_name = Some(table_name);
                 match change {
                     change_processing::ChangeProcessingResult::TableChanges(mut file_writer) => {
-                        file_writer.flush_all();
-                        let s3_files = uploader.upload_table_to_s3(file_writer).await;
+                        let s3_files = match file_writer.flush_all() {
+                            Ok(()) => uploader.upload_table_to_s3(file_writer).await,
+                            Err(err) => {
+                                // the wal file is kept, to reprocess this batch
+                                file_writer.wal_file.register_error();
+                                Err(err.into())
+                            }
+                        };
+                        let s3_files = match s3_files {
+                            Ok(s3_files) => s3_files,
+                            Err(err) => {
+                                logger_error!(
+                                    last_wal_number,
+                                    last_table_name.as_deref(),
+                                    &format!("file_uploader_failed error:{}", err)
+                                );
+                                // later batches for the table can't be applied without this one
+                                ShutdownHandler::register_messy_shutdown();
+                                continue;
+                            }
+                        };
                         for s3_file in s3_files {
                             let result_change = UploaderStageResult::S3File(s3_file);
                             result_sender.send(result_change).await.expect(
@@ -236,3 +295,63 @@ impl FileUploaderThreads {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    // a task that's slow to apply each change, like a copy into redshift
+    fn spawn_recording_thread(applied: Arc<Mutex<Vec<String>>>) -> GenericTableThread<String> {
+        let (sender, mut receiver) = mpsc::channel::<String>(DEFAULT_CHANNEL_SIZE);
+        let join_handle = tokio::spawn(async move {
+            while let Some(change) = receiver.recv().await {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                applied.lock().unwrap().push(change);
+            }
+        });
+        GenericTableThread {
+            sender: Some(sender),
+            join_handle: Some(join_handle),
+        }
+    }
+
+    #[test]
+    fn draining_both_tables_orders_a_rename_after_their_changes() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let applied = Arc::new(Mutex::new(vec![]));
+            let old_table_name = TableName::new("public.old_name".to_string());
+            let new_table_name = TableName::new("public.new_name".to_string());
+            let mut splitter: GenericTableThreadSplitter<(), String> = GenericTableThreadSplitter {
+                shared_resource: Arc::new(()),
+                table_streams: HashMap::new(),
+            };
+            for table_name in [&old_table_name, &new_table_name] {
+                let table_thread = spawn_recording_thread(applied.clone());
+                for change in 0..3 {
+                    table_thread
+                        .sender
+                        .as_ref()
+                        .unwrap()
+                        .send(format!("{}:{}", table_name, change))
+                        .await
+                        .unwrap();
+                }
+                splitter
+                    .table_streams
+                    .insert(table_name.clone(), table_thread);
+            }
+
+            splitter.drain_table_thread(&old_table_name).await;
+            splitter.drain_table_thread(&new_table_name).await;
+            applied.lock().unwrap().push("rename".to_string());
+
+            assert!(splitter.table_streams.is_empty());
+            let applied = applied.lock().unwrap();
+            assert_eq!(applied.len(), 7);
+            assert_eq!(applied.last().unwrap(), "rename");
+        });
+    }
+}
@@ -1,16 +1,24 @@
 use glob::glob;
+use std::error::Error;
+use std::fmt;
 use std::fs;
+use std::io::Write;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
-use crate::parser::{ChangeKind, ColumnInfo, ColumnTypeEnum, ParsedLine, TableName};
+use crate::checkpoints::Checkpoint;
+use crate::encryption::EncryptingWriter;
+use crate::parquet_writer::ParquetWriter;
+use crate::parser::{ChangeKind, ColumnInfo, ColumnTypeEnum, ColumnValue, ParsedLine, TableName};
+use crate::staging_compression::{CompressingWriter, StagingCompression};
+use crate::storage_backend::StorageLocation;
 use crate::wal_file_manager;
 use std::collections::HashMap; //{ HashMap, BTreeMap, HashSet };
 
 use itertools::Itertools;
-
-use flate2::write::GzEncoder;
-use flate2::Compression;
+use lazy_static::lazy_static;
 
 #[allow(unused_imports)]
 use crate::{function, logger_debug, logger_error, logger_info, logger_panic};
@@ -21,23 +29,139 @@ use crate::{function, logger_debug, logger_error, logger_info, logger_panic};
 pub struct FileWriter {
     directory: PathBuf,
     pub insert_file: FileStruct,
-    pub update_files: HashMap<String, FileStruct>,
+    // by the columns in them, and whether they replace their rows
+    pub update_files: HashMap<(String, bool), FileStruct>,
     pub delete_file: FileStruct,
     pub table_name: TableName,
     pub wal_file: wal_file_manager::WalFile,
+    // set when this is the table's last batch for the wal file
+    pub checkpoint: Option<Checkpoint>,
+    // the first error writing a change, returned when we flush
+    error: Option<FileWriterError>,
 }
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug)]
-enum CsvWriter {
+pub enum FileWriterError {
+    IoError(PathBuf, std::io::Error),
+    CsvError(PathBuf, csv::Error),
+    ParquetError(PathBuf, parquet::errors::ParquetError),
+    GlobError(glob::GlobError),
+    PatternError(glob::PatternError),
+}
+
+impl fmt::Display for FileWriterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileWriterError::IoError(file_name, err) => {
+                write!(f, "Io error on {:?}: {}", file_name, err)
+            }
+            FileWriterError::CsvError(file_name, err) => {
+                write!(f, "Csv error on {:?}: {}", file_name, err)
+            }
+            FileWriterError::ParquetError(file_name, err) => {
+                write!(f, "Parquet error on {:?}: {}", file_name, err)
+            }
+            FileWriterError::GlobError(err) => {
+                write!(f, "Glob error: {}", err)
+            }
+            FileWriterError::PatternError(err) => {
+                write!(f, "Glob pattern error: {}", err)
+            }
+        }
+    }
+}
+
+impl Error for FileWriterError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FileWriterError::IoError(_, err) => Some(err),
+            FileWriterError::CsvError(_, err) => Some(err),
+            FileWriterError::ParquetError(_, err) => Some(err),
+            FileWriterError::GlobError(err) => Some(err),
+            FileWriterError::PatternError(err) => Some(err),
+        }
+    }
+}
+
+impl From<glob::GlobError> for FileWriterError {
+    fn from(err: glob::GlobError) -> FileWriterError {
+        FileWriterError::GlobError(err)
+    }
+}
+
+impl From<glob::PatternError> for FileWriterError {
+    fn from(err: glob::PatternError) -> FileWriterError {
+        FileWriterError::PatternError(err)
+    }
+}
+
+type Result<T> = std::result::Result<T, FileWriterError>;
+
+// counts the (uncompressed) csv bytes that go through it, for our metrics
+#[derive(Debug)]
+struct CountingWriter<W: Write> {
+    inner: W,
+    bytes_written: Arc<AtomicU64>,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.bytes_written
+            .fetch_add(written as u64, Ordering::Relaxed);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+lazy_static! {
+    static ref STAGING_FILE_FORMAT: StagingFileFormat =
+        match std::env::var("STAGING_FILE_FORMAT").as_deref() {
+            Err(_) | Ok("") | Ok("csv") => StagingFileFormat::Csv,
+            // we copy files in a local directory into postgres ourselves, and it can't read parquet
+            Ok("parquet") if matches!(StorageLocation::configured(), StorageLocation::Local(_)) => {
+                panic!("STAGING_FILE_FORMAT parquet can't be used with STORAGE_BACKEND local")
+            }
+            Ok("parquet") => StagingFileFormat::Parquet,
+            Ok(format) => panic!("Unknown STAGING_FILE_FORMAT: {}", format),
+        };
+    // a file is split into parts once it gets bigger than these, so redshift can load them in parallel
+    static ref STAGING_FILE_MAX_BYTES: Option<u64> =
+        std::env::var("STAGING_FILE_MAX_BYTES").ok().filter(|bytes| !bytes.is_empty()).map(|bytes| {
+            bytes.parse::<u64>()
+                .expect("STAGING_FILE_MAX_BYTES is not a valid integer")
+        });
+    static ref STAGING_FILE_MAX_ROWS: Option<u64> =
+        std::env::var("STAGING_FILE_MAX_ROWS").ok().filter(|rows| !rows.is_empty()).map(|rows| {
+            rows.parse::<u64>()
+                .expect("STAGING_FILE_MAX_ROWS is not a valid integer")
+        });
+}
+
+// what we write the files we copy into redshift as
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StagingFileFormat {
+    Csv,
+    Parquet,
+}
+
+#[derive(Debug)]
+enum StagingFileWriter {
     Uninitialized,
-    ReadyToWrite(csv::Writer<flate2::write::GzEncoder<fs::File>>),
+    Csv(csv::Writer<CountingWriter<CompressingWriter>>),
+    // parquet compresses itself
+    Parquet(ParquetWriter<CountingWriter<EncryptingWriter<fs::File>>>),
     Finished,
 }
 
-impl CsvWriter {
+impl StagingFileWriter {
     pub fn is_some(&self) -> bool {
         match self {
-            CsvWriter::Uninitialized => false,
+            StagingFileWriter::Uninitialized => false,
             _ => true,
         }
     }
@@ -45,76 +169,138 @@ impl CsvWriter {
         !self.is_some()
     }
     // move
-    pub fn flush_and_close(&mut self) {
+    fn flush_and_close(&mut self, file_name: &Path) -> Result<()> {
         if self.is_some() {
-            let new_value = CsvWriter::Finished;
+            let new_value = StagingFileWriter::Finished;
             let old_value = std::mem::replace(self, new_value);
-            if let CsvWriter::ReadyToWrite(writer) = old_value {
-                writer
-                    .into_inner()
-                    .map(|gzip| gzip.finish().expect("Error finishing gzip"))
-                    .expect("Error unwrapping gzip encoder from csv writer");
+            let io_error = |err| FileWriterError::IoError(file_name.to_path_buf(), err);
+            match old_value {
+                StagingFileWriter::Csv(writer) => {
+                    writer
+                        .into_inner()
+                        .map_err(|err| {
+                            io_error(std::io::Error::new(
+                                err.error().kind(),
+                                err.error().to_string(),
+                            ))
+                        })?
+                        .inner
+                        .finish()
+                        .map_err(io_error)?
+                        .finish()
+                        .map_err(io_error)?;
+                }
+                StagingFileWriter::Parquet(writer) => {
+                    writer
+                        .finish()
+                        .map_err(|err| FileWriterError::ParquetError(file_name.to_path_buf(), err))?
+                        .inner
+                        .finish()
+                        .map_err(io_error)?;
+                }
+                _ => {}
             }
         }
+        Ok(())
     }
 }
 
 #[derive(Debug)]
 pub struct FileStruct {
     pub file_name: PathBuf,
+    // file_name, then any parts we've split it into
+    pub part_file_names: Vec<PathBuf>,
     pub table_name: TableName,
     pub kind: ChangeKind,
     pub columns: Option<Vec<ColumnInfo>>,
-    file: CsvWriter,
+    pub format: StagingFileFormat,
+    // parquet files are compressed by the parquet writer
+    pub compression: StagingCompression,
+    file: StagingFileWriter,
     written_header: bool,
+    csv_bytes: Arc<AtomicU64>,
+    // across all of the parts, to check against what's loaded
+    pub rows: u64,
+    // updates to rows that may not be in the target, applied as a delete and insert.
+    // only with every column, as updates leave out unchanged toast columns
+    pub replaces_rows: bool,
+    content_hasher: crc32fast::Hasher,
+    part_rows: u64,
+    part_start_bytes: u64,
+    max_part_rows: Option<u64>,
+    max_part_bytes: Option<u64>,
 }
 
 impl FileStruct {
-    pub fn new(directory_name: &Path, kind: ChangeKind, table_name: TableName) -> FileStruct {
-        let new_file_name = Self::new_file_name(directory_name, kind, table_name.as_str());
+    pub fn new(
+        directory_name: &Path,
+        kind: ChangeKind,
+        table_name: TableName,
+    ) -> Result<FileStruct> {
+        let format = *STAGING_FILE_FORMAT;
+        let compression = StagingCompression::configured();
+        let new_file_name = Self::new_file_name(
+            directory_name,
+            kind,
+            table_name.as_str(),
+            Self::extension_for(format, compression),
+        )?;
         let file_struct = FileStruct {
             file_name: new_file_name.to_path_buf(),
-            file: CsvWriter::Uninitialized,
+            part_file_names: vec![new_file_name.to_path_buf()],
+            format,
+            compression,
+            file: StagingFileWriter::Uninitialized,
             kind: kind,
             table_name: table_name.clone(),
             written_header: false,
             columns: None,
+            csv_bytes: Arc::new(AtomicU64::new(0)),
+            rows: 0,
+            replaces_rows: false,
+            content_hasher: crc32fast::Hasher::new(),
+            part_rows: 0,
+            part_start_bytes: 0,
+            max_part_rows: *STAGING_FILE_MAX_ROWS,
+            max_part_bytes: *STAGING_FILE_MAX_BYTES,
         };
         // we touch the file when we create the struct to create the file
-        let _file = fs::File::create(new_file_name.as_path()).expect("Error creating file");
-        file_struct
+        let _file = fs::File::create(new_file_name.as_path())
+            .map_err(|err| FileWriterError::IoError(new_file_name.clone(), err))?;
+        Ok(file_struct)
     }
 
     // creates a new filename of the sort directory/n_table_name_inserts.csv.gz
-    // where n is a number
+    // (or whichever extension) where n is a number
     // TODO: do we just want to save the number and be passing it in somewhere
     // I'm not super happy with thrashing our directory tree?
-    fn new_file_name(directory_name: &Path, kind: ChangeKind, table_name: &str) -> PathBuf {
+    fn new_file_name(
+        directory_name: &Path,
+        kind: ChangeKind,
+        table_name: &str,
+        extension: &str,
+    ) -> Result<PathBuf> {
         let the_file_glob_pattern =
-            ["*", table_name, kind.to_string().as_str()].join("_") + ".csv.gz";
+            ["*", table_name, kind.to_string().as_str()].join("_") + extension;
         let the_glob_pattern = directory_name.join(the_file_glob_pattern);
 
         let current_file_number = glob(the_glob_pattern.to_str().expect(
             "Error turning glob pattern to string. Probably non-UTF8 characters in the directory names?",
-        ))
-        .expect("Error running glob on directory")
+        ))?
         .map(|file_path| {
-            match file_path {
-                Ok(path) => {
-                    let file_name = path.file_name().expect("Error getting file_name");
-                    // if it's not UTF-8 it can crash
-                    let file_name_str = file_name.to_str().expect("Error turning file_name to string");
-                    // filename is number_stuff.
-                    let (file_number_str, _) = file_name_str.split_once('_').expect("Error, no _ in filename so can't parse it");
-                    let file_number: i32 = file_number_str.parse::<i32>().expect("Error can't parse file number to i32");
-                    file_number
-                }
-
-                // if the path matched but was unreadable,
-                // thereby preventing its contents from matching
-                Err(_e) => panic!("Unreadable filepath. What did you do?"),
-            }
+            // if the path matched but was unreadable,
+            // thereby preventing its contents from matching
+            let path = file_path?;
+            let file_name = path.file_name().expect("Error getting file_name");
+            // if it's not UTF-8 it can crash
+            let file_name_str = file_name.to_str().expect("Error turning file_name to string");
+            // filename is number_stuff.
+            let (file_number_str, _) = file_name_str.split_once('_').expect("Error, no _ in filename so can't parse it");
+            let file_number: i32 = file_number_str.parse::<i32>().expect("Error can't parse file number to i32");
+            Ok(file_number)
         })
+        .collect::<Result<Vec<i32>>>()?
+        .into_iter()
         .max()
         .unwrap_or(0);
         let new_file_number = current_file_number + 1;
@@ -124,9 +310,9 @@ impl FileStruct {
             kind.to_string().as_str(),
         ]
         .join("_")
-            + ".csv.gz";
+            + extension;
         let the_new_file_name_and_directory = directory_name.join(the_new_file_name);
-        the_new_file_name_and_directory
+        Ok(the_new_file_name_and_directory)
     }
 
     // the file only has data in it if we've written the header
@@ -134,82 +320,228 @@ impl FileStruct {
         self.written_header
     }
 
-    fn create_writer(&mut self) {
-        let file = fs::File::create(self.file_name.as_path())
-            .expect("Unable to create file in file writer");
-        let writer = GzEncoder::new(file, Compression::default());
-        let csv_writer = csv::WriterBuilder::new().from_writer(writer);
-        self.file = CsvWriter::ReadyToWrite(csv_writer);
+    fn extension_for(format: StagingFileFormat, compression: StagingCompression) -> &'static str {
+        match format {
+            StagingFileFormat::Csv => compression.extension(),
+            StagingFileFormat::Parquet => ".parquet",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        Self::extension_for(self.format, self.compression)
+    }
+
+    // directory/n_table_name_inserts.part2.csv.gz for the second part
+    fn part_file_name(&self, part: usize) -> PathBuf {
+        let file_name = self.file_name.to_str().expect("Unprintable file name");
+        let without_extension = file_name
+            .strip_suffix(self.extension())
+            .expect("File name doesn't have its extension");
+        PathBuf::from(format!(
+            "{}.part{}{}",
+            without_extension,
+            part,
+            self.extension()
+        ))
+    }
+
+    fn current_part_file_name(&self) -> &Path {
+        self.part_file_names
+            .last()
+            .expect("There's always at least one part")
+    }
+
+    fn part_is_full(&self) -> bool {
+        let part_bytes = self.csv_bytes.load(Ordering::Relaxed) - self.part_start_bytes;
+        self.max_part_rows
+            .is_some_and(|max_rows| self.part_rows >= max_rows)
+            || self
+                .max_part_bytes
+                .is_some_and(|max_bytes| part_bytes >= max_bytes)
+    }
+
+    fn start_next_part(&mut self) -> Result<()> {
+        self.flush_and_close()?;
+        let part_file_name = self.part_file_name(self.part_file_names.len() + 1);
+        self.part_file_names.push(part_file_name);
+        self.part_rows = 0;
+        self.part_start_bytes = self.csv_bytes.load(Ordering::Relaxed);
+        self.create_writer()?;
+        self.write_part_header()
+    }
+
+    fn io_error(&self, err: std::io::Error) -> FileWriterError {
+        FileWriterError::IoError(self.current_part_file_name().to_path_buf(), err)
     }
 
-    fn write_header(&mut self, change: &ParsedLine) {
+    fn create_writer(&mut self) -> Result<()> {
+        match self.format {
+            StagingFileFormat::Csv => {
+                let file = fs::File::create(self.current_part_file_name())
+                    .map_err(|err| self.io_error(err))?;
+                let writer = CountingWriter {
+                    inner: self.compression.writer(EncryptingWriter::new(file)),
+                    bytes_written: self.csv_bytes.clone(),
+                };
+                self.file = StagingFileWriter::Csv(csv::WriterBuilder::new().from_writer(writer));
+            }
+            // the parquet writer needs the columns for its schema, so it's created with the header
+            StagingFileFormat::Parquet => {}
+        }
+        Ok(())
+    }
+
+    fn write_header(&mut self, change: &ParsedLine) -> Result<()> {
         if !self.written_header {
-            if let CsvWriter::ReadyToWrite(_file) = &mut self.file {
-                if let ParsedLine::ChangedData { columns, .. } = change {
-                    let changed_column_info: Vec<ColumnInfo> = columns
+            if let ParsedLine::ChangedData { columns, .. } = change {
+                let changed_column_info: Vec<ColumnInfo> = columns
+                    .iter()
+                    .filter(|x| x.is_changed_data_column())
+                    .map(|x| x.column_info().clone())
+                    .collect();
+                self.columns = Some(changed_column_info);
+                self.write_part_header()?;
+            }
+            self.written_header = true;
+        }
+        Ok(())
+    }
+
+    // every part gets its own header
+    fn write_part_header(&mut self) -> Result<()> {
+        let columns = self
+            .columns
+            .clone()
+            .expect("columns not initialized before writing the header");
+        match self.format {
+            StagingFileFormat::Csv => {
+                let strings: Vec<&str> = columns.iter().map(|x| x.column_name()).collect();
+                self.write(&strings)?;
+            }
+            StagingFileFormat::Parquet => {
+                let file = fs::File::create(self.current_part_file_name())
+                    .map_err(|err| self.io_error(err))?;
+                let writer = CountingWriter {
+                    inner: EncryptingWriter::new(file),
+                    bytes_written: self.csv_bytes.clone(),
+                };
+                let parquet_writer =
+                    ParquetWriter::new(writer, &columns).map_err(|err| self.parquet_error(err))?;
+                self.file = StagingFileWriter::Parquet(parquet_writer);
+            }
+        }
+        Ok(())
+    }
+
+    fn parquet_error(&self, err: parquet::errors::ParquetError) -> FileWriterError {
+        FileWriterError::ParquetError(self.current_part_file_name().to_path_buf(), err)
+    }
+
+    fn write_line(&mut self, change: &ParsedLine) -> Result<()> {
+        self.write_header(change)?;
+        self.rows += 1;
+        self.part_rows += 1;
+        if let ParsedLine::ChangedData { columns, .. } = change {
+            match &mut self.file {
+                StagingFileWriter::Csv(_file) => {
+                    // need to own these strings
+                    let strings: Vec<String> = columns
+                        .iter()
+                        .filter(|x| x.is_changed_data_column())
+                        .map(|x| {
+                            if let Some(value) = x.column_value_for_changed_column() {
+                                value.to_string_truncated()
+                            } else {
+                                match x.column_info().column_type_enum() {
+                                    ColumnTypeEnum::Text => "\0".to_owned(),
+                                    _ => "".to_owned(),
+                                }
+                            } // remember null byte as nulls
+                        })
+                        .collect();
+                    self.hash_row(&strings);
+                    self.write(&strings)?;
+                }
+                // nulls are nulls in parquet
+                StagingFileWriter::Parquet(file) => {
+                    let values: Vec<Option<&ColumnValue>> = columns
                         .iter()
                         .filter(|x| x.is_changed_data_column())
-                        .map(|x| x.column_info().clone())
+                        .map(|x| x.column_value_for_changed_column())
                         .collect();
-                    let strings: Vec<&str> = changed_column_info
+                    let strings: Vec<String> = values
                         .iter()
-                        .map(|x| x.column_name())
+                        .map(|value| {
+                            value.map_or_else(|| "\0".to_owned(), |value| value.to_string())
+                        })
                         .collect();
-                    self.write(&strings);
-                    self.columns = Some(changed_column_info);
+                    let result = file.write_row(values);
+                    result.map_err(|err| self.parquet_error(err))?;
+                    self.hash_row(&strings);
                 }
+                _ => {}
             }
-            self.written_header = true;
         }
+        Ok(())
     }
 
-    fn write_line(&mut self, change: &ParsedLine) {
-        self.write_header(change);
-        if let CsvWriter::ReadyToWrite(_file) = &mut self.file {
-            if let ParsedLine::ChangedData { columns, .. } = change {
-                // need to own these strings
-                let strings: Vec<String> = columns
-                    .iter()
-                    .filter(|x| x.is_changed_data_column())
-                    .map(|x| {
-                        if let Some(value) = x.column_value_for_changed_column() {
-                            value.to_string_truncated()
-                        } else {
-                            match x.column_info().column_type_enum() {
-                                ColumnTypeEnum::Text => "\0".to_owned(),
-                                _ => "".to_owned(),
-                            }
-                        } // remember null byte as nulls
-                    })
-                    .collect();
-                self.write(&strings);
-            }
+    // of the rows as they're written, so a file can be traced to what was loaded from it
+    fn hash_row(&mut self, strings: &[String]) {
+        for string in strings {
+            self.content_hasher.update(string.as_bytes());
+            // so moving bytes between columns changes the hash
+            self.content_hasher.update(&[0x1f]);
         }
+        self.content_hasher.update(&[0x1e]);
+    }
+
+    pub fn content_hash(&self) -> u32 {
+        self.content_hasher.clone().finalize()
     }
 
-    fn write<I, T>(&mut self, string: I)
+    fn write<I, T>(&mut self, string: I) -> Result<()>
     where
         I: IntoIterator<Item = T>,
         T: AsRef<[u8]>,
     {
-        if let CsvWriter::ReadyToWrite(file) = &mut self.file {
-            // TODO handle error
-            file.write_record(string).expect("failed to write file");
+        if let StagingFileWriter::Csv(file) = &mut self.file {
+            let result = file.write_record(string);
+            result.map_err(|err| {
+                FileWriterError::CsvError(self.current_part_file_name().to_path_buf(), err)
+            })
         } else {
             panic!("tried to write to file before creating it");
         }
     }
-    fn add_change(&mut self, change: &ParsedLine) {
+    fn add_change(&mut self, change: &ParsedLine) -> Result<()> {
         if self.file.is_none() {
-            self.create_writer();
+            self.create_writer()?;
+        } else if self.part_is_full() {
+            self.start_next_part()?;
         }
         self.write_line(change)
     }
 
+    pub fn flush_and_close(&mut self) -> Result<()> {
+        let file_name = self.current_part_file_name().to_path_buf();
+        self.file.flush_and_close(&file_name)
+    }
+
     // check whether the internal file has been initialised
     pub fn is_some(&self) -> bool {
         self.file.is_some()
     }
+
+    // uncompressed bytes written so far.
+    // parquet files count their compressed bytes, once each row group is written
+    pub fn csv_bytes(&mut self) -> u64 {
+        if let StagingFileWriter::Csv(writer) = &mut self.file {
+            // the csv writer buffers, so push everything through to be counted.
+            // if that fails, it fails again when the file is closed
+            let _ = writer.flush();
+        }
+        self.csv_bytes.load(Ordering::Relaxed)
+    }
 }
 
 // TODO: write iterator over files
@@ -217,43 +549,55 @@ impl FileWriter {
     pub fn new(
         table_name: TableName,
         associated_wal_file: wal_file_manager::WalFile,
-    ) -> FileWriter {
+    ) -> Result<FileWriter> {
         let directory = associated_wal_file.path_for_wal_directory();
         let owned_directory = directory.clone().to_owned();
-        FileWriter {
+        Ok(FileWriter {
             directory: owned_directory,
             insert_file: FileStruct::new(
                 directory.as_path(),
                 ChangeKind::Insert,
                 table_name.clone(),
-            ),
+            )?,
             update_files: HashMap::new(),
             delete_file: FileStruct::new(
                 directory.as_path(),
                 ChangeKind::Delete,
                 table_name.clone(),
-            ),
+            )?,
             table_name: table_name,
             wal_file: associated_wal_file,
-        }
+            checkpoint: None,
+            error: None,
+        })
     }
-    pub fn add_change(&mut self, change: &ParsedLine) {
+    // once a change fails to be written we stop writing,
+    // and the error is returned by flush_all
+    pub fn add_change(&mut self, change: &ParsedLine, replaces_row: bool) {
+        if self.error.is_some() {
+            return;
+        }
         if let ParsedLine::ChangedData { kind, .. } = change {
-            match kind {
-                ChangeKind::Insert => {
-                    self.insert_file.add_change(change);
-                }
-                ChangeKind::Update => {
-                    self.add_change_to_update_file(change);
-                }
-                ChangeKind::Delete => {
-                    self.delete_file.add_change(change);
-                }
+            let result = match kind {
+                ChangeKind::Insert => self.insert_file.add_change(change),
+                ChangeKind::Update => self.add_change_to_update_file(change, replaces_row),
+                ChangeKind::Delete => self.delete_file.add_change(change),
+            };
+            if let Err(err) = result {
+                logger_error!(
+                    Some(self.wal_file.file_number),
+                    Some(&self.table_name),
+                    &format!("file_write_failed error:{}", err)
+                );
+                self.error = Some(err);
             }
         }
     }
-    pub fn flush_all(&mut self) {
-        self.insert_file.file.flush_and_close();
+    pub fn flush_all(&mut self) -> Result<()> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+        self.insert_file.flush_and_close()?;
         if self.insert_file.is_some() {
             logger_info!(
                 Some(self.wal_file.file_number),
@@ -268,7 +612,7 @@ impl FileWriter {
             )
         }
         for x in self.update_files.values_mut() {
-            x.file.flush_and_close();
+            x.flush_and_close()?;
             if x.is_some() {
                 logger_info!(
                     Some(self.wal_file.file_number),
@@ -280,7 +624,7 @@ impl FileWriter {
                 )
             }
         }
-        self.delete_file.file.flush_and_close();
+        self.delete_file.flush_and_close()?;
         if self.delete_file.is_some() {
             logger_info!(
                 Some(self.wal_file.file_number),
@@ -294,32 +638,137 @@ impl FileWriter {
                 )
             )
         }
+        self.record_csv_files_in_manifest();
+        Ok(())
+    }
+
+    fn record_csv_files_in_manifest(&self) {
+        let csv_files: Vec<&FileStruct> = std::iter::once(&self.insert_file)
+            .chain(self.update_files.values())
+            .chain(std::iter::once(&self.delete_file))
+            .filter(|file| file.exists())
+            .collect();
+        if csv_files.is_empty() {
+            return;
+        }
+        self.wal_file.update_manifest(|manifest| {
+            for file in csv_files {
+                for part_file_name in file.part_file_names.iter() {
+                    manifest.add_csv_file(&self.table_name, file.kind, part_file_name);
+                }
+            }
+        });
+    }
+
+    pub fn csv_bytes(&mut self) -> u64 {
+        self.insert_file.csv_bytes()
+            + self
+                .update_files
+                .values_mut()
+                .map(|file| file.csv_bytes())
+                .sum::<u64>()
+            + self.delete_file.csv_bytes()
     }
 
     // update_files is a hash of our column names to our File
-    fn add_change_to_update_file(&mut self, change: &ParsedLine) {
-        let update_key: String = change
-            .columns_for_changed_data()
+    fn add_change_to_update_file(&mut self, change: &ParsedLine, replaces_row: bool) -> Result<()> {
+        let columns = change.columns_for_changed_data();
+        let update_columns: String = columns
             .iter()
             .filter(|x| x.is_changed_data_column())
             .map(|x| x.column_name())
             .sorted()
             .join(",");
+        // without the whole row we can only update the row that's there
+        let replaces_row = replaces_row && columns.iter().all(|x| x.is_changed_data_column());
+        let update_key = (update_columns, replaces_row);
         // let number_of_updates_that_exist = self.update_files.len();
         let cloned_directory = self.directory.clone();
         if let ParsedLine::ChangedData { table_name, .. } = change {
-            self.update_files
-                .entry(update_key)
-                .or_insert_with(|| {
-                    FileStruct::new(
+            let update_file = match self.update_files.entry(update_key) {
+                std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+                std::collections::hash_map::Entry::Vacant(entry) => {
+                    let mut update_file = FileStruct::new(
                         cloned_directory.as_path(),
                         ChangeKind::Update,
                         table_name.clone(),
-                    )
-                })
-                .add_change(change);
+                    )?;
+                    update_file.replaces_rows = replaces_row;
+                    entry.insert(update_file)
+                }
+            };
+            update_file.add_change(change)
         } else {
             panic!("non changed data passed to add_change_to_update_file")
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    const TESTING_PATH: &str = "/tmp/file_writer_testing";
+
+    #[test]
+    fn files_are_split_into_parts_with_their_own_header() {
+        let directory = Path::new(TESTING_PATH);
+        let _ = fs::remove_dir_all(directory);
+        fs::create_dir_all(directory).unwrap();
+        let table_name = TableName::new("public.foobar".to_string());
+        let mut file_struct = FileStruct::new(directory, ChangeKind::Insert, table_name).unwrap();
+        file_struct.format = StagingFileFormat::Csv;
+        file_struct.compression = StagingCompression::Gzip { level: 6 };
+        file_struct.max_part_rows = Some(2);
+        let mut parser = Parser::new(true);
+        for id in 1..=3 {
+            let line = format!("table public.foobar: INSERT: id[bigint]:{}", id);
+            file_struct
+                .add_change(&parser.parse(&line).unwrap())
+                .unwrap();
+        }
+        file_struct.flush_and_close().unwrap();
+
+        assert_eq!(
+            file_struct.part_file_names,
+            vec![
+                directory.join("1_public.foobar_insert.csv.gz"),
+                directory.join("1_public.foobar_insert.part2.csv.gz"),
+            ]
+        );
+        let contents: Vec<String> = file_struct
+            .part_file_names
+            .iter()
+            .map(|part_file_name| {
+                let mut contents = String::new();
+                GzDecoder::new(fs::File::open(part_file_name).unwrap())
+                    .read_to_string(&mut contents)
+                    .unwrap();
+                contents
+            })
+            .collect();
+        assert_eq!(contents, vec!["id\n1\n2\n", "id\n3\n"]);
+        assert_eq!(file_struct.rows, 3);
+        assert_eq!(file_struct.content_hash(), {
+            let mut hasher = crc32fast::Hasher::new();
+            hasher.update(b"1\x1f\x1e2\x1f\x1e3\x1f\x1e");
+            hasher.finalize()
+        });
+    }
+
+    #[test]
+    fn io_errors_are_returned_rather_than_panicking() {
+        let directory = Path::new(TESTING_PATH).join("not_there");
+        let _ = fs::remove_dir_all(&directory);
+        let table_name = TableName::new("public.foobar".to_string());
+        match FileStruct::new(&directory, ChangeKind::Insert, table_name) {
+            Err(FileWriterError::IoError(file_name, _err)) => {
+                assert_eq!(file_name, directory.join("1_public.foobar_insert.csv.gz"))
+            }
+            other => panic!("expected an io error, got {:?}", other),
+        }
+    }
+}
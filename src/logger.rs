@@ -118,7 +118,7 @@ impl Logger {
         table_name: Option<&String>,
         tag: &str,
         message: &str,
-    ) {
+    ) -> ! {
         panic!("{}", Self::structured_format(
             wal_number, table_name, tag, message
         ));
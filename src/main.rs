@@ -1,35 +1,53 @@
 #![deny(warnings)]
 
-use clap::{App, Arg};
-use either::Either;
+use clap::{App, Arg, ArgMatches};
 use glob::{glob_with, MatchOptions};
 use lazy_static::lazy_static;
-use std::convert::TryInto;
-use std::fs::File;
-use std::io::{self, BufRead, BufReader};
-use std::path::PathBuf;
-use std::process::{Command, Stdio};
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use dotenv::dotenv;
+use futures::FutureExt;
 
 use tokio::sync::mpsc;
 
+mod catch_up;
 mod change_processing;
+mod checkpoints;
 mod database_writer;
 mod database_writer_threads;
+mod encryption;
 mod exponential_backoff;
 mod file_uploader;
 mod file_uploader_threads;
 mod file_writer;
 mod logger;
+mod parquet_writer;
 mod parser;
+mod postgres_copy;
+mod replication_client;
+mod row_filter;
 mod shutdown_handler;
+mod staging_compression;
+mod storage_backend;
 mod targets_tables_column_names;
+mod wal_archive;
+mod wal_disk_space;
 mod wal_file_manager;
+mod wal_fsync;
+mod wal_integrity;
+mod wal_manifest;
+mod wal_retention;
+mod wal_toolkit;
 
 use file_uploader_threads::DEFAULT_CHANNEL_SIZE;
+use replication_client::{Lsn, ReplicationClient, ReplicationLine, ReplicationProgress};
 use shutdown_handler::{RuntimeType, ShutdownHandler};
+use wal_archive::WalArchive;
 use wal_file_manager::WalFile;
+use wal_integrity::WalLines;
+use wal_retention::WalRetention;
 #[cfg(feature = "with_sentry")]
 use crate::logger::init_sentry;
 
@@ -39,19 +57,40 @@ extern crate sentry;
 lazy_static! {
     static ref OUTPUT_WAL_DIRECTORY: String =
         std::env::var("OUTPUT_WAL_DIRECTORY").expect("OUTPUT_WAL_DIRECTORY env is not set");
-    static ref PG_RECVLOGICAL_PATH: String =
-        std::env::var("PG_RECVLOGICAL_PATH").expect("PG_RECVLOGICAL_PATH env is not set");
-    static ref REPLICATION_SLOT: String =
-        std::env::var("REPLICATION_SLOT").expect("REPLICATION_SLOT env is not set");
-    static ref SOURCE_CONNECTION_STRING: String =
-        std::env::var("SOURCE_CONNECTION_STRING").expect("SOURCE_CONNECTION_STRING env is not set");
+    static ref TARGET_SCHEMA_NAME: Option<String> = std::env::var("TARGET_SCHEMA_NAME").ok();
 }
 
 #[derive(Debug, Clone)]
 enum InputType {
     Stdin,
     Wal(String),
-    PgRcvlogical,
+    Replication,
+}
+
+enum InputLines {
+    Reader(Box<WalLines>),
+    Stdin(mpsc::Receiver<io::Result<String>>),
+    Replication(mpsc::Receiver<ReplicationLine>),
+}
+
+impl InputLines {
+    // only lines from replication have a commit position
+    async fn next_line(&mut self) -> Option<io::Result<(String, Option<Lsn>)>> {
+        match self {
+            InputLines::Reader(lines) => lines.next().map(|line| {
+                line.map(|line| (line, None))
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+            }),
+            InputLines::Stdin(receiver) => receiver
+                .recv()
+                .await
+                .map(|line| line.map(|line| (line, None))),
+            InputLines::Replication(receiver) => receiver
+                .recv()
+                .await
+                .map(|line| Ok((line.line, line.commit_lsn))),
+        }
+    }
 }
 
 fn panic_if_messy_shutdown() -> Result<(), ()> {
@@ -122,9 +161,94 @@ async fn main_impl() -> Result<(),()> {
     dotenv().ok();
     env_logger::init();
 
+    let arg_matches = cli_args();
+    // looking after wal files, rather than replicating
+    if let Some(wal_arg_matches) = arg_matches.subcommand_matches("wal") {
+        return wal_toolkit::run(wal_arg_matches).map_err(|err| {
+            logger_error!(None, None, &format!("wal_toolkit_failed error:{}", err));
+        });
+    }
+    let read_from_stdin = arg_matches.is_present("read_from_stdin");
+    let replay = Replay::from_args(&arg_matches);
+    // replays go to their own directory, so they don't mix with what we're streaming
+    let wal_directory = match &replay {
+        Some(replay) => {
+            let replay_directory = Path::new(OUTPUT_WAL_DIRECTORY.as_str()).join("replay");
+            // the wal files we've kept since processing them, then anything older from the archive
+            let retained = match WalRetention::fetch(
+                Path::new(OUTPUT_WAL_DIRECTORY.as_str()),
+                replay.first,
+                replay.last,
+                replay_directory.as_path(),
+            ) {
+                Ok(retained) => retained,
+                Err(err) => {
+                    logger_error!(
+                        None,
+                        None,
+                        &format!("fetching_retained_wal_files_failed error:{}", err)
+                    );
+                    return Err(());
+                }
+            };
+            let archived = if retained.is_empty() || WalArchive::configured() {
+                WalArchive::fetch(
+                    replay.first,
+                    replay.last,
+                    replay_directory.as_path(),
+                    &retained,
+                )
+                .await
+            } else {
+                Ok(0)
+            };
+            match archived.map(|archived| archived + retained.len()) {
+                Ok(0) => {
+                    logger_error!(
+                        None,
+                        None,
+                        &format!(
+                            "no_archived_wal_files_to_replay first:{:X} last:{:X}",
+                            replay.first, replay.last
+                        )
+                    );
+                    return Err(());
+                }
+                Ok(fetched) => logger_info!(
+                    None,
+                    None,
+                    &format!(
+                        "replaying_archived_wal_files:{} target_schema:{}",
+                        fetched, replay.target_schema
+                    )
+                ),
+                Err(err) => {
+                    logger_error!(
+                        None,
+                        None,
+                        &format!("fetching_archived_wal_files_failed error:{}", err)
+                    );
+                    return Err(());
+                }
+            }
+            replay_directory
+        }
+        None => {
+            WalArchive::archive_pending(Path::new(OUTPUT_WAL_DIRECTORY.as_str()));
+            PathBuf::from(OUTPUT_WAL_DIRECTORY.clone())
+        }
+    };
+
+    // replays go to the schema they're given
+    let target_schema = match &replay {
+        Some(replay) => Some(replay.target_schema.clone()),
+        None => TARGET_SCHEMA_NAME.clone(),
+    };
     let mut targets_tables_column_names =
         targets_tables_column_names::TargetsTablesColumnNames::new();
-    let result = targets_tables_column_names.refresh().await;
+    let result = targets_tables_column_names
+        .refresh(target_schema.as_deref())
+        .await;
     match result {
         Ok(_) => logger_info!(
             None,
@@ -140,8 +264,23 @@ async fn main_impl() -> Result<(),()> {
             &format!("Failed to fetch column names from target DB: {:?}", msg)
         ),
     };
+    let checkpoints = match checkpoints::Checkpoints::load(target_schema.as_deref()).await {
+        Ok(checkpoints) => checkpoints,
+        Err(msg) => logger_panic!(
+            None,
+            None,
+            &format!("Failed to fetch checkpoints from target DB: {:?}", msg)
+        ),
+    };
+    checkpoints.report_gap(
+        wal_file_manager::WalFileManager::first_wal_filenumber_on_filesystem(
+            wal_directory.as_path(),
+        ),
+    );
+    let last_applied_wal_file_number = checkpoints.last_wal_file_number();
     let mut parser = parser::Parser::new(true);
     let mut collector = change_processing::ChangeProcessing::new(targets_tables_column_names);
+    collector.register_checkpoints(checkpoints);
     // initialize our channels
     let (mut file_transmitter, file_receiver) =
         mpsc::channel::<change_processing::ChangeProcessingResult>(DEFAULT_CHANNEL_SIZE);
@@ -157,39 +296,54 @@ async fn main_impl() -> Result<(),()> {
     let database_writer_threads_join_handle =
         database_writer_threads::DatabaseWriterThreads::spawn_database_writer_stream(
             database_receiver,
+            target_schema,
         );
 
-    let mut child_process_guard = ChildGuard(None);
-    let mut wal_file_manager;
+    let mut replication_client_handle = None;
+    // none if we stopped before reading any input, e.g. shutting down while catching up
+    let mut final_wal_file_manager = None;
     let mut previous_input_type = None;
     let mut preprocessing_manager = PreprocessingManager::new();
     loop {
-        // need to define this at this level so it lives long enough
-        let stdin = io::stdin();
-        let locked_stdin = stdin.lock();
-
-        let input_type = input_type(previous_input_type);
+        let input_type = input_type(
+            previous_input_type,
+            read_from_stdin,
+            wal_directory.as_path(),
+        );
         previous_input_type = Some(input_type.clone());
-        let buffered_reader = if let InputType::PgRcvlogical = input_type {
-            let (process, bufreader) = get_buffered_reader_process();
-
-            let process_id = process
-                .id()
-                .try_into()
-                .expect("pid that's greater than i32::MAX");
-            // register it to the childguard, so it gets shutdown in the event of a panic
-            child_process_guard.0 = Some(process);
-            ShutdownHandler::register_shutdown_handler(RuntimeType::from_pid(process_id));
-            // how to term the child process
-            Either::Right(bufreader)
-        } else {
-            let reader: Box<dyn BufRead> = match &input_type {
-                InputType::Stdin => {
-                    logger_info!(None, None, "Reading from stdin");
-                    ShutdownHandler::register_shutdown_handler(RuntimeType::Stdin);
-                    Box::new(locked_stdin)
+        // works through a backlog of wal files in parallel, leaving the last of them for below
+        if let InputType::Wal(wal_path) = &input_type {
+            ShutdownHandler::register_shutdown_handler(RuntimeType::File);
+            let mut backlog = vec![wal_path.clone()];
+            backlog.extend(wal_files_after(wal_directory.as_path(), Some(wal_path)));
+            backlog.pop();
+            let caught_up = catch_up::catch_up(
+                wal_directory.as_path(),
+                backlog,
+                replay.is_none(),
+                &mut collector,
+                &mut file_transmitter,
+            )
+            .await?;
+            if let Some(last_caught_up) = caught_up {
+                previous_input_type = Some(InputType::Wal(last_caught_up));
+                if ShutdownHandler::should_break_main_loop() {
+                    break;
                 }
-
+                continue;
+            }
+        }
+        let mut input_lines = if let InputType::Replication = input_type {
+            ShutdownHandler::register_shutdown_handler(RuntimeType::Replication);
+            let (handle, receiver) = ReplicationClient::spawn();
+            replication_client_handle = Some(handle);
+            InputLines::Replication(receiver)
+        } else if let InputType::Stdin = input_type {
+            logger_info!(None, None, "Reading from stdin");
+            ShutdownHandler::register_shutdown_handler(RuntimeType::Stdin);
+            InputLines::Stdin(spawn_stdin_reader())
+        } else {
+            let lines: WalLines = match &input_type {
                 InputType::Wal(wal_path) => {
                     logger_info!(
                         None,
@@ -197,34 +351,99 @@ async fn main_impl() -> Result<(),()> {
                         &format!("Reading from existing WAL: {}", wal_path)
                     );
                     ShutdownHandler::register_shutdown_handler(RuntimeType::File);
-                    Box::new(BufReader::new(
-                        File::open(wal_path)
-                            .expect(&format!("Unable to open existing WAL at {}", wal_path)),
-                    ))
+                    // drops any incomplete transaction at the end, and refuses corrupted ones
+                    match wal_integrity::open_validated_wal_file(Path::new(wal_path)) {
+                        Ok(lines) => {
+                            // sealed wal files were archived when they were sealed
+                            if replay.is_none() && !wal_file_manager::is_sealed(Path::new(wal_path))
+                            {
+                                WalArchive::archive(Path::new(wal_path));
+                            }
+                            lines
+                        }
+                        Err(err) => {
+                            ShutdownHandler::register_messy_shutdown();
+                            logger_error!(
+                                None,
+                                None,
+                                &format!(
+                                    "refusing_corrupted_wal_file path:{} error:{}",
+                                    wal_path, err
+                                )
+                            );
+                            return Err(());
+                        }
+                    }
                 }
-                InputType::PgRcvlogical => {
-                    panic!("Should never have gotten here as PgRcvlogical is handled separately")
+                InputType::Stdin | InputType::Replication => {
+                    panic!("Should never have gotten here as Stdin and Replication are handled separately")
                 }
             };
-            Either::Left(reader)
+            InputLines::Reader(Box::new(lines))
         };
 
-        wal_file_manager = match &input_type {
+        let wal_file_manager = final_wal_file_manager.insert(match &input_type {
             InputType::Wal(file_path) => wal_file_manager::WalFileManager::reprocess(
-                PathBuf::from(OUTPUT_WAL_DIRECTORY.clone()).as_path(),
+                wal_directory.as_path(),
                 file_path.clone(),
             ),
-            _ => wal_file_manager::WalFileManager::new(
-                PathBuf::from(OUTPUT_WAL_DIRECTORY.clone()).as_path(),
+            _ => wal_file_manager::WalFileManager::new_after(
+                wal_directory.as_path(),
+                last_applied_wal_file_number,
             ),
-        };
+        });
 
         collector.register_wal_file(Some(wal_file_manager.current_wal()));
         // for logging
         parser.register_wal_number(wal_file_manager.current_wal().file_number);
 
-        for line in buffered_reader.lines() {
-            if let Ok(ip) = line {
+        loop {
+            let line = match input_lines.next_line().now_or_never() {
+                Some(line) => line,
+                None => {
+                    // we've caught up with the input, so nothing else is about to be committed
+                    wal_file_manager.sync_caught_up();
+                    // swaps the wal file when the stream goes quiet, so its changes are applied
+                    // without waiting for the next commit
+                    let time_until_idle_swap = wal_file_manager.time_until_idle_swap();
+                    let time_until_sync = wal_file_manager.time_until_sync();
+                    tokio::select! {
+                        line = input_lines.next_line() => line,
+                        _ = sleep_for(time_until_idle_swap) => {
+                            if let Some(wal_file) = wal_file_manager.swap_idle_wal() {
+                                handle_swapped_wal(
+                                    wal_file,
+                                    &mut preprocessing_manager,
+                                    &mut collector,
+                                    &mut parser,
+                                    &mut file_transmitter,
+                                )
+                                .await;
+                            }
+                            continue;
+                        }
+                        _ = sleep_for(time_until_sync) => {
+                            wal_file_manager.sync_if_due();
+                            continue;
+                        }
+                    }
+                }
+            };
+            let line = match line {
+                Some(line) => line,
+                None => break,
+            };
+            if let Ok((ip, commit_lsn)) = line {
+                // holds this line while the wal disk is filling up, so the source keeps the rest
+                wal_file_manager.wait_for_disk_space().await;
+                if let Some(commit_lsn) = commit_lsn {
+                    // before next_line, which may swap to the next wal file after this commit
+                    ReplicationProgress::register_commit(
+                        wal_file_manager.current_wal().file_number,
+                        commit_lsn,
+                    );
+                    collector.register_commit_lsn(commit_lsn);
+                }
                 let wal_file_manager_result = wal_file_manager.next_line(&ip);
                 let shutting_down = ShutdownHandler::shutting_down();
                 if shutting_down {
@@ -283,15 +502,27 @@ async fn main_impl() -> Result<(),()> {
 
                 if let wal_file_manager::WalLineResult::SwapWal(wal_file) = wal_file_manager_result
                 {
-                    if !preprocessing_manager.preprocessing_halted() {
-                        // drain the collector of all it's tables, and send to file transmitter
-                        drain_collector_and_transmit(&mut collector, &mut file_transmitter).await;
-                        collector.register_wal_file(Some(wal_file.clone()));
-                        parser.register_wal_number(wal_file.file_number);
-                    } else {
-                        preprocessing_manager.preserve_wal_file_for_reprocessing(wal_file);
-                    }
+                    handle_swapped_wal(
+                        wal_file,
+                        &mut preprocessing_manager,
+                        &mut collector,
+                        &mut parser,
+                        &mut file_transmitter,
+                    )
+                    .await;
                 }
+            } else if let Err(err) = line {
+                // the rest of the input can't be applied without it
+                ShutdownHandler::register_messy_shutdown();
+                let message = match &input_type {
+                    InputType::Wal(wal_path) => format!(
+                        "refusing_corrupted_wal_file path:{} error:{}",
+                        wal_path, err
+                    ),
+                    _ => format!("unable_to_read_input error:{}", err),
+                };
+                logger_error!(None, None, &message);
+                break;
             }
         }
 
@@ -309,7 +540,7 @@ async fn main_impl() -> Result<(),()> {
 
         drain_collector_and_transmit(&mut collector, &mut file_transmitter).await;
 
-        if let InputType::Wal(_) = input_type {
+        if let InputType::Wal(wal_path) = &input_type {
             let shutting_down = ShutdownHandler::shutting_down();
             if shutting_down {
                 if ShutdownHandler::should_break_main_loop() {
@@ -318,6 +549,11 @@ async fn main_impl() -> Result<(),()> {
                     continue;
                 }
             }
+            // a replay stops after the archived wal files, rather than streaming
+            if replay.is_some() && next_wal_file(wal_directory.as_path(), Some(wal_path)).is_none()
+            {
+                break;
+            }
         } else {
             break;
         }
@@ -337,7 +573,15 @@ async fn main_impl() -> Result<(),()> {
     // remove wal file from collector
     collector.register_wal_file(None);
     // clean up wal file in manager it should be the last one now.
-    wal_file_manager.clean_up_final_wal_file();
+    if let Some(wal_file_manager) = final_wal_file_manager.as_mut() {
+        wal_file_manager.clean_up_final_wal_file();
+    }
+
+    // now everything's applied we can confirm the final position to postgres
+    if let Some(replication_client_handle) = replication_client_handle {
+        replication_client_handle.finish().await;
+    }
+    WalArchive::finish().await;
 
     ShutdownHandler::log_shutdown_status();
 
@@ -345,6 +589,45 @@ async fn main_impl() -> Result<(),()> {
     Result::Ok(())
 }
 
+async fn handle_swapped_wal(
+    wal_file: WalFile,
+    preprocessing_manager: &mut PreprocessingManager,
+    collector: &mut change_processing::ChangeProcessing,
+    parser: &mut parser::Parser,
+    file_transmitter: &mut mpsc::Sender<change_processing::ChangeProcessingResult>,
+) {
+    if !preprocessing_manager.preprocessing_halted() {
+        // drain the collector of all it's tables, and send to file transmitter
+        drain_collector_and_transmit(collector, file_transmitter).await;
+        collector.register_wal_file(Some(wal_file.clone()));
+        parser.register_wal_number(wal_file.file_number);
+    } else {
+        preprocessing_manager.preserve_wal_file_for_reprocessing(wal_file);
+    }
+}
+
+// never wakes for None
+async fn sleep_for(duration: Option<Duration>) {
+    match duration {
+        Some(duration) => tokio::time::sleep(duration).await,
+        None => std::future::pending().await,
+    }
+}
+
+// reads stdin on a thread of its own, as it blocks,
+// so the main loop can still swap the wal file while it's quiet
+fn spawn_stdin_reader() -> mpsc::Receiver<io::Result<String>> {
+    let (sender, receiver) = mpsc::channel(DEFAULT_CHANNEL_SIZE);
+    std::thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            if sender.blocking_send(line).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
 async fn drain_collector_and_transmit(
     collector: &mut change_processing::ChangeProcessing,
     transmitter: &mut mpsc::Sender<change_processing::ChangeProcessingResult>,
@@ -358,72 +641,33 @@ async fn drain_collector_and_transmit(
     }
 }
 
-fn get_buffered_reader_process() -> (std::process::Child, BufReader<std::process::ChildStdout>) {
-    let mut child = Command::new(PG_RECVLOGICAL_PATH.clone())
-        .args(&[
-            "--create-slot",
-            "--start",
-            "--if-not-exists",
-            "--fsync-interval=0",
-            "--file=-",
-            "--plugin=test_decoding",
-            &format!("--slot={}", *REPLICATION_SLOT),
-            &format!("--dbname={}", *SOURCE_CONNECTION_STRING),
-        ])
-        .stdin(Stdio::null())
-        .stderr(Stdio::inherit())
-        .stdout(Stdio::piped())
-        .spawn()
-        .expect("Failed to execute pg_recvlogical");
-    let stdout = child
-        .stdout
-        .take() // take allows us to avoid partially moving the child
-        .expect("Failed to get stdout for pg_recvlogical");
-    (child, BufReader::new(stdout))
+struct Replay {
+    first: u64,
+    last: u64,
+    target_schema: String,
 }
 
-// https://stackoverflow.com/questions/30538004/how-do-i-ensure-that-a-spawned-child-process-is-killed-if-my-app-panics
-
-struct ChildGuard(Option<std::process::Child>);
-
-// I'm not sure if this is strictly needed.
-// we abort on panic, and if we panic, even without this code we get:
-// Command terminated by signal 6
-// still, this code feels correct so I'm including it.
-impl Drop for ChildGuard {
-    fn drop(&mut self) {
-        match &mut self.0 {
-            Some(process) => match process.kill() {
-                Err(e) => match e.kind() {
-                    std::io::ErrorKind::InvalidInput => {
-                        logger_info!(
-                            None,
-                            None,
-                            &format!(
-                                "Child process already killed during child guard dropping: {}",
-                                e
-                            )
-                        )
-                    }
-                    _unknown_error_kind => {
-                        logger_error!(None, None, &format!("Could not kill child process: {}", e))
-                    }
-                },
-                Ok(_) => logger_info!(None, None, "Successfully killed child process"),
-            },
-            None => {
-                logger_info!(
-                    None,
-                    None,
-                    "Child guard dropped with nothing registered to it."
-                )
-            }
-        }
+impl Replay {
+    fn from_args(arg_matches: &ArgMatches) -> Option<Replay> {
+        let wal_file_number = |name: &str| {
+            arg_matches.value_of(name).map(|number| {
+                u64::from_str_radix(number, 16)
+                    .unwrap_or_else(|_| panic!("--{} is not a hex wal file number", name))
+            })
+        };
+        Some(Replay {
+            first: wal_file_number("replay-from")?,
+            last: wal_file_number("replay-to").unwrap_or(u64::MAX),
+            target_schema: arg_matches
+                .value_of("target-schema")
+                .expect("--target-schema is required to replay")
+                .to_string(),
+        })
     }
 }
 
-fn input_type(previous_input_type: Option<InputType>) -> InputType {
-    let arg_matches = App::new("re_dms")
+fn cli_args() -> ArgMatches<'static> {
+    App::new("re_dms")
         .version("0.1")
         .author("MeetCleo. <team@meetcleo.com>")
         .about("replication from postgres to redshift")
@@ -433,43 +677,90 @@ fn input_type(previous_input_type: Option<InputType>) -> InputType {
                 .long("stdin")
                 .help("Makes the process read from stdin instead of starting a subprocess"),
         )
-        .get_matches();
+        .arg(
+            Arg::with_name("replay-from")
+                .long("replay-from")
+                .takes_value(true)
+                .requires("target-schema")
+                .conflicts_with("read_from_stdin")
+                .help("Replays archived wal files from this wal file number (hex), instead of streaming"),
+        )
+        .arg(
+            Arg::with_name("replay-to")
+                .long("replay-to")
+                .takes_value(true)
+                .requires("replay-from")
+                .help("The last archived wal file number (hex) to replay"),
+        )
+        .arg(
+            Arg::with_name("target-schema")
+                .long("target-schema")
+                .takes_value(true)
+                .requires("replay-from")
+                .help("The target schema to replay into"),
+        )
+        .subcommand(wal_toolkit::subcommand())
+        .get_matches()
+}
 
-    if arg_matches.is_present("read_from_stdin") {
+fn input_type(
+    previous_input_type: Option<InputType>,
+    read_from_stdin: bool,
+    wal_directory: &Path,
+) -> InputType {
+    if read_from_stdin {
         InputType::Stdin
     } else {
-        let options = MatchOptions {
-            case_sensitive: false,
-            ..Default::default()
+        let previous_wal_file = match &previous_input_type {
+            Some(InputType::Wal(previous_wal_file)) => Some(previous_wal_file.as_str()),
+            _ => None,
         };
-
-        let existing_wals = glob_with(&format!("{}/*.wal", *OUTPUT_WAL_DIRECTORY), options)
-            .expect("Unable to check for existing WAL files")
-            .filter_map(|file_path| match file_path {
-                Ok(path) => {
-                    let filename = path
-                        .to_str()
-                        .expect("Invalid UTF filename for WAL file")
-                        .to_string();
-                    if let Some(InputType::Wal(previous_wal_file)) = previous_input_type.clone() {
-                        if filename > previous_wal_file {
-                            Some(filename)
-                        } else {
-                            None
-                        }
-                    } else {
-                        Some(filename)
-                    }
-                }
-                Err(_e) => panic!("unreadable path. What did you do?"),
-            });
-
-        let earliest_wal = existing_wals.min();
-
-        if let Some(path) = earliest_wal {
+        if let Some(path) = next_wal_file(wal_directory, previous_wal_file) {
             InputType::Wal(path)
         } else {
-            InputType::PgRcvlogical
+            InputType::Replication
         }
     }
 }
+
+// the earliest wal file after the previous one
+fn next_wal_file(wal_directory: &Path, previous_wal_file: Option<&str>) -> Option<String> {
+    wal_files_after(wal_directory, previous_wal_file)
+        .into_iter()
+        .next()
+}
+
+// in order
+fn wal_files_after(wal_directory: &Path, previous_wal_file: Option<&str>) -> Vec<String> {
+    let options = MatchOptions {
+        case_sensitive: false,
+        ..Default::default()
+    };
+
+    // compressed or not
+    let existing_wals = glob_with(
+        &format!(
+            "{}/*.wal*",
+            wal_directory.to_str().expect("Invalid UTF wal directory")
+        ),
+        options,
+    )
+    .expect("Unable to check for existing WAL files")
+    .filter_map(|file_path| match file_path {
+        Ok(path) => {
+            let filename = path
+                .to_str()
+                .expect("Invalid UTF filename for WAL file")
+                .to_string();
+            match previous_wal_file {
+                Some(previous_wal_file) if filename.as_str() <= previous_wal_file => None,
+                _ => Some(filename),
+            }
+        }
+        Err(_e) => panic!("unreadable path. What did you do?"),
+    });
+
+    let mut existing_wals: Vec<String> = existing_wals.collect();
+    existing_wals.sort();
+    existing_wals
+}
@@ -0,0 +1,422 @@
+use bigdecimal::BigDecimal;
+use chrono::{NaiveDate, NaiveDateTime};
+use num_bigint::Sign;
+use parquet::basic::{Compression, LogicalType, Repetition, TimeUnit, Type as PhysicalType};
+use parquet::column::writer::ColumnWriter;
+use parquet::data_type::{ByteArray, FixedLenByteArray};
+use parquet::errors::Result;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::format::MicroSeconds;
+use parquet::schema::types::Type;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::database_writer::{DEFAULT_NUMERIC_PRECISION, DEFAULT_NUMERIC_SCALE};
+use crate::parser::{ColumnInfo, ColumnTypeEnum, ColumnValue};
+
+// rows are buffered in memory, and written out as a row group once we have this many
+const ROW_GROUP_SIZE: usize = 100_000;
+// the fewest bytes that hold a signed DEFAULT_NUMERIC_PRECISION digit number
+const DECIMAL_BYTE_LENGTH: usize = 9;
+
+// the parquet type each column is written as. These need to match the staging table's column types,
+// as redshift won't convert between them when it copies parquet
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ParquetColumnType {
+    Boolean,
+    Int32,
+    Int64,
+    Double,
+    Decimal,
+    Date,
+    Timestamp { is_adjusted_to_utc: bool },
+    Text,
+}
+
+impl ParquetColumnType {
+    fn for_column(column: &ColumnInfo) -> ParquetColumnType {
+        match column.column_type_enum() {
+            ColumnTypeEnum::Boolean => ParquetColumnType::Boolean,
+            ColumnTypeEnum::Integer => match column.column_type() {
+                "bigint" => ParquetColumnType::Int64,
+                _ => ParquetColumnType::Int32,
+            },
+            ColumnTypeEnum::Numeric => ParquetColumnType::Double,
+            ColumnTypeEnum::RoundingNumeric => ParquetColumnType::Decimal,
+            ColumnTypeEnum::Timestamp => match column.column_type() {
+                "date" => ParquetColumnType::Date,
+                "timestamp with time zone" => ParquetColumnType::Timestamp {
+                    is_adjusted_to_utc: true,
+                },
+                _ => ParquetColumnType::Timestamp {
+                    is_adjusted_to_utc: false,
+                },
+            },
+            ColumnTypeEnum::Text | ColumnTypeEnum::Oid | ColumnTypeEnum::StringEnumType => {
+                ParquetColumnType::Text
+            }
+        }
+    }
+
+    fn schema_type(&self, column_name: &str) -> Result<Type> {
+        let builder = match self {
+            ParquetColumnType::Boolean => {
+                Type::primitive_type_builder(column_name, PhysicalType::BOOLEAN)
+            }
+            ParquetColumnType::Int32 => {
+                Type::primitive_type_builder(column_name, PhysicalType::INT32)
+            }
+            ParquetColumnType::Int64 => {
+                Type::primitive_type_builder(column_name, PhysicalType::INT64)
+            }
+            ParquetColumnType::Double => {
+                Type::primitive_type_builder(column_name, PhysicalType::DOUBLE)
+            }
+            ParquetColumnType::Decimal => {
+                Type::primitive_type_builder(column_name, PhysicalType::FIXED_LEN_BYTE_ARRAY)
+                    .with_length(DECIMAL_BYTE_LENGTH as i32)
+                    .with_logical_type(Some(LogicalType::Decimal {
+                        scale: DEFAULT_NUMERIC_SCALE,
+                        precision: DEFAULT_NUMERIC_PRECISION,
+                    }))
+                    .with_precision(DEFAULT_NUMERIC_PRECISION)
+                    .with_scale(DEFAULT_NUMERIC_SCALE)
+            }
+            ParquetColumnType::Date => {
+                Type::primitive_type_builder(column_name, PhysicalType::INT32)
+                    .with_logical_type(Some(LogicalType::Date))
+            }
+            ParquetColumnType::Timestamp { is_adjusted_to_utc } => {
+                Type::primitive_type_builder(column_name, PhysicalType::INT64).with_logical_type(
+                    Some(LogicalType::Timestamp {
+                        is_adjusted_to_u_t_c: *is_adjusted_to_utc,
+                        unit: TimeUnit::MICROS(MicroSeconds {}),
+                    }),
+                )
+            }
+            ParquetColumnType::Text => {
+                Type::primitive_type_builder(column_name, PhysicalType::BYTE_ARRAY)
+                    .with_logical_type(Some(LogicalType::String))
+            }
+        };
+        // every column is nullable, as the staging tables are
+        builder.with_repetition(Repetition::OPTIONAL).build()
+    }
+}
+
+// the values for one column of the row group we're building up
+enum ColumnValues {
+    Boolean(Vec<bool>),
+    Int32(Vec<i32>),
+    Int64(Vec<i64>),
+    Double(Vec<f64>),
+    FixedLenByteArray(Vec<FixedLenByteArray>),
+    ByteArray(Vec<ByteArray>),
+}
+
+impl ColumnValues {
+    fn new(column_type: ParquetColumnType) -> ColumnValues {
+        match column_type {
+            ParquetColumnType::Boolean => ColumnValues::Boolean(vec![]),
+            ParquetColumnType::Int32 | ParquetColumnType::Date => ColumnValues::Int32(vec![]),
+            ParquetColumnType::Int64 | ParquetColumnType::Timestamp { .. } => {
+                ColumnValues::Int64(vec![])
+            }
+            ParquetColumnType::Double => ColumnValues::Double(vec![]),
+            ParquetColumnType::Decimal => ColumnValues::FixedLenByteArray(vec![]),
+            ParquetColumnType::Text => ColumnValues::ByteArray(vec![]),
+        }
+    }
+}
+
+struct ParquetColumn {
+    column_type: ParquetColumnType,
+    values: ColumnValues,
+    // 0 for a null, 1 for a value
+    definition_levels: Vec<i16>,
+}
+
+impl ParquetColumn {
+    fn push(&mut self, value: Option<&ColumnValue>) {
+        let value = match value {
+            Some(value) => value,
+            None => {
+                self.definition_levels.push(0);
+                return;
+            }
+        };
+        self.definition_levels.push(1);
+        match (&mut self.values, self.column_type) {
+            (ColumnValues::Boolean(values), _) => values.push(match value {
+                ColumnValue::Boolean(boolean) => *boolean,
+                _ => panic!("Expected a boolean value, got {:?}", value),
+            }),
+            (ColumnValues::Int32(values), ParquetColumnType::Date) => {
+                values.push(days_since_epoch(&value.to_string()))
+            }
+            (ColumnValues::Int32(values), _) => values.push(match value {
+                ColumnValue::Integer(integer) => i32::try_from(*integer)
+                    .unwrap_or_else(|_| panic!("Integer {} doesn't fit in 32 bits", integer)),
+                _ => panic!("Expected an integer value, got {:?}", value),
+            }),
+            (ColumnValues::Int64(values), ParquetColumnType::Timestamp { is_adjusted_to_utc }) => {
+                values.push(micros_since_epoch(&value.to_string(), is_adjusted_to_utc))
+            }
+            (ColumnValues::Int64(values), _) => values.push(match value {
+                ColumnValue::Integer(integer) => *integer,
+                _ => panic!("Expected an integer value, got {:?}", value),
+            }),
+            (ColumnValues::Double(values), _) => {
+                let string = value.to_string();
+                values.push(
+                    string
+                        .parse::<f64>()
+                        .unwrap_or_else(|_| panic!("Double unable to be parsed: {}", string)),
+                )
+            }
+            (ColumnValues::FixedLenByteArray(values), _) => {
+                values.push(decimal_bytes(&value.to_string()).into())
+            }
+            (ColumnValues::ByteArray(values), _) => {
+                values.push(value.to_string_truncated().into_bytes().into())
+            }
+        }
+    }
+
+    fn write(&mut self, column_writer: &mut ColumnWriter) -> Result<()> {
+        let definition_levels = Some(self.definition_levels.as_slice());
+        match (&mut self.values, column_writer) {
+            (ColumnValues::Boolean(values), ColumnWriter::BoolColumnWriter(writer)) => {
+                writer.write_batch(values, definition_levels, None)?;
+                values.clear();
+            }
+            (ColumnValues::Int32(values), ColumnWriter::Int32ColumnWriter(writer)) => {
+                writer.write_batch(values, definition_levels, None)?;
+                values.clear();
+            }
+            (ColumnValues::Int64(values), ColumnWriter::Int64ColumnWriter(writer)) => {
+                writer.write_batch(values, definition_levels, None)?;
+                values.clear();
+            }
+            (ColumnValues::Double(values), ColumnWriter::DoubleColumnWriter(writer)) => {
+                writer.write_batch(values, definition_levels, None)?;
+                values.clear();
+            }
+            (
+                ColumnValues::FixedLenByteArray(values),
+                ColumnWriter::FixedLenByteArrayColumnWriter(writer),
+            ) => {
+                writer.write_batch(values, definition_levels, None)?;
+                values.clear();
+            }
+            (ColumnValues::ByteArray(values), ColumnWriter::ByteArrayColumnWriter(writer)) => {
+                writer.write_batch(values, definition_levels, None)?;
+                values.clear();
+            }
+            _ => panic!("Column writer doesn't match the schema"),
+        }
+        self.definition_levels.clear();
+        Ok(())
+    }
+}
+
+// the value is already rounded to our precision and scale by to_string
+fn decimal_bytes(string: &str) -> Vec<u8> {
+    let big_decimal = BigDecimal::from_str(string)
+        .unwrap_or_else(|_| panic!("BigDecimal unable to be parsed: {}", string));
+    let (unscaled, _scale) = big_decimal
+        .with_scale(DEFAULT_NUMERIC_SCALE as i64)
+        .as_bigint_and_exponent();
+    let bytes = unscaled.to_signed_bytes_be();
+    // sign extended out to the fixed length
+    let padding = if unscaled.sign() == Sign::Minus {
+        0xff
+    } else {
+        0
+    };
+    let mut fixed_length = vec![padding; DECIMAL_BYTE_LENGTH - bytes.len()];
+    fixed_length.extend(bytes);
+    fixed_length
+}
+
+fn days_since_epoch(string: &str) -> i32 {
+    let date = NaiveDate::parse_from_str(string, "%Y-%m-%d")
+        .unwrap_or_else(|_| panic!("Date unable to be parsed: {}", string));
+    let epoch = NaiveDate::from_ymd_opt(1970, 1, 1).expect("Epoch is a valid date");
+    date.signed_duration_since(epoch).num_days() as i32
+}
+
+// postgres gives us e.g. 2021-01-01 10:00:00.123456, with an offset of +00, +05:30 or +00:01:15 for time zones
+fn micros_since_epoch(string: &str, has_offset: bool) -> i64 {
+    let (date_time, offset_seconds) = if has_offset {
+        // the offset sign is the first one after the date
+        let offset_start = string[10..]
+            .find(['+', '-'])
+            .map(|index| index + 10)
+            .unwrap_or_else(|| panic!("Timestamp has no offset: {}", string));
+        let (date_time, offset) = string.split_at(offset_start);
+        (date_time, offset_seconds(offset))
+    } else {
+        (string, 0)
+    };
+    let date_time = NaiveDateTime::parse_from_str(date_time, "%Y-%m-%d %H:%M:%S%.f")
+        .unwrap_or_else(|_| panic!("Timestamp unable to be parsed: {}", string));
+    date_time.and_utc().timestamp_micros() - offset_seconds * 1_000_000
+}
+
+fn offset_seconds(offset: &str) -> i64 {
+    let sign = if offset.starts_with('-') { -1 } else { 1 };
+    let seconds: i64 = offset[1..]
+        .split(':')
+        .zip([60 * 60, 60, 1])
+        .map(|(part, seconds)| {
+            part.parse::<i64>()
+                .unwrap_or_else(|_| panic!("Timestamp offset unable to be parsed: {}", offset))
+                * seconds
+        })
+        .sum();
+    sign * seconds
+}
+
+// writes a parquet file a row at a time, typed by the columns' postgres types
+pub struct ParquetWriter<W: Write + Send> {
+    writer: SerializedFileWriter<W>,
+    columns: Vec<ParquetColumn>,
+    buffered_rows: usize,
+}
+
+impl<W: Write + Send> fmt::Debug for ParquetWriter<W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ParquetWriter")
+            .field("writer", &self.writer)
+            .field("buffered_rows", &self.buffered_rows)
+            .finish()
+    }
+}
+
+impl<W: Write + Send> ParquetWriter<W> {
+    pub fn new(inner: W, columns: &[ColumnInfo]) -> Result<ParquetWriter<W>> {
+        let column_types: Vec<ParquetColumnType> =
+            columns.iter().map(ParquetColumnType::for_column).collect();
+        let fields = columns
+            .iter()
+            .zip(column_types.iter())
+            .map(|(column, column_type)| {
+                column_type
+                    .schema_type(&column.column_name().replace("\"", ""))
+                    .map(Arc::new)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let schema = Type::group_type_builder("schema")
+            .with_fields(fields)
+            .build()?;
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+        Ok(ParquetWriter {
+            writer: SerializedFileWriter::new(inner, Arc::new(schema), Arc::new(properties))?,
+            columns: column_types
+                .into_iter()
+                .map(|column_type| ParquetColumn {
+                    column_type,
+                    values: ColumnValues::new(column_type),
+                    definition_levels: vec![],
+                })
+                .collect(),
+            buffered_rows: 0,
+        })
+    }
+
+    pub fn write_row(&mut self, row: Vec<Option<&ColumnValue>>) -> Result<()> {
+        assert_eq!(row.len(), self.columns.len());
+        for (column, value) in self.columns.iter_mut().zip(row) {
+            column.push(value);
+        }
+        self.buffered_rows += 1;
+        if self.buffered_rows >= ROW_GROUP_SIZE {
+            self.write_row_group()?;
+        }
+        Ok(())
+    }
+
+    fn write_row_group(&mut self) -> Result<()> {
+        let mut row_group_writer = self.writer.next_row_group()?;
+        for column in self.columns.iter_mut() {
+            let mut column_writer = row_group_writer
+                .next_column()?
+                .expect("Fewer parquet columns than in the schema");
+            column.write(column_writer.untyped())?;
+            column_writer.close()?;
+        }
+        row_group_writer.close()?;
+        self.buffered_rows = 0;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<W> {
+        if self.buffered_rows > 0 {
+            self.write_row_group()?;
+        }
+        self.writer.into_inner()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_are_converted_to_parquet_types() {
+        assert_eq!(days_since_epoch("1970-01-02"), 1);
+        assert_eq!(
+            micros_since_epoch("1970-01-01 00:00:01.5", false),
+            1_500_000
+        );
+        assert_eq!(
+            micros_since_epoch("1970-01-01 01:00:01+01", true),
+            1_000_000
+        );
+        assert_eq!(
+            micros_since_epoch("1970-01-01 00:00:00-00:30", true),
+            30 * 60 * 1_000_000
+        );
+        // 1.5 with a scale of 8 is 150000000 unscaled
+        assert_eq!(
+            decimal_bytes("1.50000000"),
+            vec![0, 0, 0, 0, 0, 0x08, 0xf0, 0xd1, 0x80]
+        );
+        assert_eq!(decimal_bytes("-0.00000001"), vec![0xff; 9]);
+    }
+
+    #[test]
+    fn rows_are_written_as_typed_columns() {
+        use parquet::file::reader::{FileReader, SerializedFileReader};
+
+        let columns = vec![
+            ColumnInfo::new("id", "bigint"),
+            ColumnInfo::new("name", "text"),
+            ColumnInfo::new("created_at", "timestamp without time zone"),
+        ];
+        let mut writer = ParquetWriter::new(vec![], &columns).unwrap();
+        let id = ColumnValue::Integer(1);
+        let name = ColumnValue::Text("a, \"quoted\" name".to_owned());
+        let created_at = ColumnValue::Text("2021-01-01 10:00:00".to_owned());
+        writer
+            .write_row(vec![Some(&id), Some(&name), Some(&created_at)])
+            .unwrap();
+        writer.write_row(vec![Some(&id), None, None]).unwrap();
+        let bytes = writer.finish().unwrap();
+
+        let reader = SerializedFileReader::new(bytes::Bytes::from(bytes)).unwrap();
+        let metadata = reader.metadata().file_metadata();
+        assert_eq!(metadata.num_rows(), 2);
+        let schema = metadata.schema_descr();
+        assert_eq!(schema.column(0).physical_type(), PhysicalType::INT64);
+        assert_eq!(schema.column(1).physical_type(), PhysicalType::BYTE_ARRAY);
+        assert_eq!(schema.column(2).physical_type(), PhysicalType::INT64);
+    }
+}
@@ -24,6 +24,9 @@ pub type TableName = ArcIntern<String>;
 pub type ColumnName = ArcIntern<String>;
 pub type ColumnType = ArcIntern<String>;
 
+// added to consolidated tenant tables, part of the key along with id
+pub const SOURCE_SCHEMA_COLUMN: &str = "_source_schema";
+
 // https://docs.aws.amazon.com/redshift/latest/dg/r_Character_types.html
 const REDSHIFT_MAX_COLUMN_SIZE: usize = 65535;
 
@@ -31,9 +34,11 @@ lazy_static! {
     // leave these as unwrap
     static ref TABLE_BLACKLIST: Vec<String> = env::var("TABLE_BLACKLIST").unwrap_or("".to_owned()).split(",").map(|x| x.to_owned()).collect();
     static ref SCHEMA_BLACKLIST: Vec<String> = env::var("SCHEMA_BLACKLIST").unwrap_or("".to_owned()).split(",").map(|x| x.to_owned()).collect();
-    static ref TARGET_SCHEMA_NAME: Option<String> = env::var("TARGET_SCHEMA_NAME").ok();
     static ref PARTITION_SUFFIX_REGEXP: Option<Regex> = env::var("PARTITION_SUFFIX_REGEXP").map(|s| Regex::new(&s).expect("Failed to parse partition suffix regexp")).ok();
     static ref ARRAY_STRING: String = "array".to_string();
+    // source schemas matching this are consolidated into one table per table name, in TENANT_CONSOLIDATED_SCHEMA
+    static ref TENANT_SCHEMA_REGEXP: Option<Regex> = env::var("TENANT_SCHEMA_REGEXP").ok().filter(|s| !s.is_empty()).map(|s| Regex::new(&s).expect("Failed to parse tenant schema regexp"));
+    static ref TENANT_CONSOLIDATED_SCHEMA: String = env::var("TENANT_CONSOLIDATED_SCHEMA").expect("TENANT_CONSOLIDATED_SCHEMA env is not set, it's needed for TENANT_SCHEMA_REGEXP");
 
     // 99_999_999_999.99999999
     static ref MAX_NUMERIC_VALUE: String = "9".repeat(
@@ -47,7 +52,7 @@ lazy_static! {
 
 // for tablename
 pub trait SchemaAndTable {
-    fn schema_and_table_name(&self) -> (&str, &str);
+    fn schema_and_table_name<'a>(&'a self, target_schema: Option<&'a str>) -> (&'a str, &'a str);
     fn original_schema_and_table_name(&self) -> (&str, &str);
 }
 
@@ -58,17 +63,55 @@ fn departition_table_name(table_name: &str) -> Cow<'_, str> {
     }
 }
 
+// tenant_a.users -> tenants.users with a _source_schema column of tenant_a
+fn consolidate_tenant_schema(parsed_line: ParsedLine) -> ParsedLine {
+    match (&*TENANT_SCHEMA_REGEXP, parsed_line) {
+        (
+            Some(tenant_schema_regexp),
+            ParsedLine::ChangedData {
+                table_name,
+                kind,
+                columns,
+            },
+        ) => {
+            let (schema_name, just_table_name) = table_name.original_schema_and_table_name();
+            if !tenant_schema_regexp.is_match(schema_name) {
+                return ParsedLine::ChangedData {
+                    table_name,
+                    kind,
+                    columns,
+                };
+            }
+            let source_schema_column = Column::ChangedColumn {
+                column_info: ColumnInfo::new(SOURCE_SCHEMA_COLUMN, "text"),
+                value: Some(ColumnValue::Text(schema_name.to_string())),
+            };
+            ParsedLine::ChangedData {
+                table_name: TableName::new(format!(
+                    "{}.{}",
+                    *TENANT_CONSOLIDATED_SCHEMA, just_table_name
+                )),
+                kind,
+                columns: std::iter::once(source_schema_column)
+                    .chain(columns)
+                    .collect(),
+            }
+        }
+        (_, parsed_line) => parsed_line,
+    }
+}
+
 // schema.table_name
 // we assume a valid table name, so unwrap
 impl SchemaAndTable for TableName {
-    // NOTE: this gives the DESTINATION target schema name.
+    // NOTE: this gives the DESTINATION target schema name (the source one without a target schema).
     // which could be really f-ing confusing if you don't expect that.
-    fn schema_and_table_name(&self) -> (&str, &str) {
+    fn schema_and_table_name<'a>(&'a self, target_schema: Option<&'a str>) -> (&'a str, &'a str) {
         let result = self.split_once('.').expect(&format!(
             "can't split schema and table name. No `.` character: {}",
             self
         ));
-        match &*TARGET_SCHEMA_NAME {
+        match target_schema {
             None => result,
             Some(schema_name) => (schema_name, result.1),
         }
@@ -265,6 +308,9 @@ impl ColumnInfo {
     pub fn is_id_column(&self) -> bool {
         self.name.as_ref() == "id"
     }
+    pub fn is_source_schema_column(&self) -> bool {
+        self.name.as_ref() == SOURCE_SCHEMA_COLUMN
+    }
 }
 
 impl PartialEq for ColumnInfo {
@@ -354,8 +400,8 @@ impl std::string::ToString for ChangeKind {
 pub enum ParsedLine {
     // int is xid
     Begin(i64),
-    // int is xid
-    Commit(i64),
+    // int is xid, and the commit timestamp if the slot includes it
+    Commit(i64, Option<String>),
     ChangedData {
         columns: Vec<Column>,
         table_name: TableName,
@@ -387,6 +433,20 @@ impl ParsedLine {
         }
     }
 
+    // only present for consolidated tenant tables
+    pub fn find_source_schema(&self) -> Option<&str> {
+        self.columns_for_changed_data()
+            .iter()
+            .find(|column| column.column_info().is_source_schema_column())
+            .and_then(|column| match column {
+                Column::ChangedColumn {
+                    value: Some(ColumnValue::Text(source_schema)),
+                    ..
+                } => Some(source_schema.as_str()),
+                _ => None,
+            })
+    }
+
     pub fn column_info_set(&self) -> Option<HashSet<ColumnInfo>> {
         match self {
             ParsedLine::ChangedData { columns, kind, .. } => {
@@ -640,18 +700,27 @@ impl Parser {
 
     fn parse_commit(&self, string: &str) -> Result<ParsedLine> {
         if self.config.include_xids {
-            // "COMMIT 1234"
+            // "COMMIT 1234" or "COMMIT 1234 (at 2024-01-01 00:00:00.000000+00)"
             const SIZE_OF_COMMIT_TAG: usize = "COMMIT ".len();
             let rest_of_string = &string[SIZE_OF_COMMIT_TAG..string.len()];
-            // "BEGIN 1234"
-            match rest_of_string.parse() {
+            let (xid_string, commit_timestamp) = match rest_of_string.split_once(' ') {
+                Some((xid_string, timestamp_string)) => (
+                    xid_string,
+                    timestamp_string
+                        .strip_prefix("(at ")
+                        .and_then(|timestamp| timestamp.strip_suffix(')'))
+                        .map(str::to_string),
+                ),
+                None => (rest_of_string, None),
+            };
+            match xid_string.parse() {
                 Ok(xid) => {
                     logger_debug!(
                         self.parse_state.wal_file_number,
                         None,
                         &format!("xid:{}", xid)
                     );
-                    Ok(ParsedLine::Commit(xid))
+                    Ok(ParsedLine::Commit(xid, commit_timestamp))
                 }
                 Err(inner_message) => Err(ParsingError {
                     line: string.to_string(),
@@ -659,7 +728,7 @@ impl Parser {
                 }),
             }
         } else {
-            Ok(ParsedLine::Commit(0))
+            Ok(ParsedLine::Commit(0, None))
         }
     }
 
@@ -686,7 +755,7 @@ impl Parser {
 
         // TODO: split early here for truncate columns
 
-        let kind = self.parse_kind(kind_string);
+        let kind = self.parse_kind(kind_string, string)?;
 
         // + 2 for colon + space
         fail_parse_if_unequal(
@@ -736,12 +805,15 @@ impl Parser {
         }
     }
 
-    fn parse_kind(&self, string: &str) -> ChangeKind {
+    fn parse_kind(&self, string: &str, line: &str) -> Result<ChangeKind> {
         match string {
-            "INSERT" => ChangeKind::Insert,
-            "UPDATE" => ChangeKind::Update,
-            "DELETE" => ChangeKind::Delete,
-            _ => panic!("Unknown change kind: {}", string),
+            "INSERT" => Ok(ChangeKind::Insert),
+            "UPDATE" => Ok(ChangeKind::Update),
+            "DELETE" => Ok(ChangeKind::Delete),
+            _ => Err(ParsingError {
+                message: format!("Unknown change kind: {}", string),
+                line: line.to_string(),
+            }),
         }
     }
 
@@ -977,7 +1049,7 @@ impl Parser {
                 // handle newlines in our blacklisted schemas
                 ParsedLine::ContinueParse
             } else {
-                changed_data
+                consolidate_tenant_schema(changed_data)
             }
         };
         logger_debug!(
@@ -1074,6 +1146,47 @@ mod tests {
             "partman,data_science,sch_repcloud,sch_repdrop,sch_repnew,private",
         );
         std::env::set_var("PARTITION_SUFFIX_REGEXP", r"_p\d{4}w\d{1,2}\z");
+        std::env::set_var("TENANT_SCHEMA_REGEXP", r"\Atenant_");
+        std::env::set_var("TENANT_CONSOLIDATED_SCHEMA", "tenants");
+    }
+
+    #[test]
+    fn parse_commit_with_timestamp() {
+        let mut parser = Parser::new(true);
+        let line = "COMMIT 1234 (at 2024-01-01 10:00:00.123456+00)";
+        assert_eq!(
+            parser.parse(&line.to_string()).expect("failed parsing"),
+            ParsedLine::Commit(1234, Some("2024-01-01 10:00:00.123456+00".to_string()))
+        );
+    }
+
+    #[test]
+    fn tenant_schemas_are_consolidated() {
+        let mut parser = Parser::new(true);
+        let line = "table tenant_a.users: DELETE: id[bigint]:123";
+        let result = parser.parse(&line.to_string()).expect("failed parsing");
+        assert_eq!(
+            result,
+            ParsedLine::ChangedData {
+                table_name: TableName::new("tenants.users".to_string()),
+                kind: ChangeKind::Delete,
+                columns: vec![
+                    Column::ChangedColumn {
+                        column_info: ColumnInfo::new(SOURCE_SCHEMA_COLUMN, "text"),
+                        value: Some(ColumnValue::Text("tenant_a".to_string())),
+                    },
+                    Column::ChangedColumn {
+                        column_info: ColumnInfo::new("id", "bigint"),
+                        value: Some(ColumnValue::Integer(123)),
+                    },
+                ],
+            }
+        );
+        assert_eq!(result.find_source_schema(), Some("tenant_a"));
+
+        let line = "table public.users: DELETE: id[bigint]:123";
+        let result = parser.parse(&line.to_string()).expect("failed parsing");
+        assert_eq!(result.find_source_schema(), None);
     }
 
     #[test]
@@ -1085,10 +1198,24 @@ mod tests {
             ParsedLine::ChangedData { table_name, .. } => table_name,
             _ => panic!("tried to find table name of non changed_data"),
         };
-        let actual = table_name.schema_and_table_name().1.to_string();
+        let actual = table_name.schema_and_table_name(None).1.to_string();
         assert_eq!(actual, "webhooks_incoming_webhooks".to_string());
     }
 
+    #[test]
+    fn target_schema_replaces_the_source_schema() {
+        let table_name = TableName::new("public.users".to_string());
+        assert_eq!(table_name.schema_and_table_name(None), ("public", "users"));
+        assert_eq!(
+            table_name.schema_and_table_name(Some("replay")),
+            ("replay", "users")
+        );
+        assert_eq!(
+            table_name.original_schema_and_table_name(),
+            ("public", "users")
+        );
+    }
+
     #[test]
     fn table_blacklist_works_as_expected() {
         let mut parser = Parser::new(true);
@@ -1343,7 +1470,7 @@ mod tests {
                 ],
                 table_name: ArcIntern::new("public.transactions".to_string()),
                 kind: ChangeKind::Update },
-            ParsedLine::Commit(11989965),
+            ParsedLine::Commit(11989965, None),
             ParsedLine::Begin(4220773504),
             ParsedLine::ChangedData { columns: vec![
                 Column::ChangedColumn { column_info: ColumnInfo::new("id".to_string(), "integer".to_string()), value: Some(ColumnValue::Integer(1111111)) },
@@ -1404,7 +1531,7 @@ mod tests {
                 Column::ChangedColumn { column_info: ColumnInfo::new("last_messenger_request_at".to_string(), "timestamp without time zone".to_string()), value: None }],
                 table_name: ArcIntern::new("public.users".to_string()),
                 kind: ChangeKind::Update },
-            ParsedLine::Commit(4220773504),
+            ParsedLine::Commit(4220773504, None),
             ParsedLine::Begin(4220773503),
             ParsedLine::ChangedData { columns: vec![
                 Column::ChangedColumn { column_info: ColumnInfo::new("id".to_string(), "uuid".to_string()), value: Some(ColumnValue::Text("188101f7-1c30-44c9-88e5-1be3b024470e".to_string())) },
@@ -1414,7 +1541,7 @@ mod tests {
                 Column::ChangedColumn { column_info: ColumnInfo::new("closed_at".to_string(), "timestamp without time zone".to_string()), value: None }],
                 table_name: ArcIntern::new("public.app_sessions".to_string()),
                 kind: ChangeKind::Insert },
-            ParsedLine::Commit(4220773503),
+            ParsedLine::Commit(4220773503, None),
             ParsedLine::Begin(4220773509),
             ParsedLine::ChangedData { columns: vec![
                 Column::ChangedColumn { column_info: ColumnInfo::new("id".to_string(), "bigint".to_string()), value: Some(ColumnValue::Integer(474344529)) },
@@ -1433,7 +1560,7 @@ mod tests {
                 Column::ChangedColumn { column_info: ColumnInfo::new("exception_message".to_string(), "character varying".to_string()), value: None }],
                 table_name: ArcIntern::new("public.webhooks_incoming_webhooks".to_string()),
                 kind: ChangeKind::Insert },
-            ParsedLine::Commit(4220773509),
+            ParsedLine::Commit(4220773509, None),
             ParsedLine::Begin(4220773508),
             ParsedLine::ChangedData { columns: vec![
                 Column::ChangedColumn { column_info: ColumnInfo::new("id".to_string(), "integer".to_string()), value: Some(ColumnValue::Integer(508629076)) },
@@ -1445,7 +1572,7 @@ mod tests {
                 Column::ChangedColumn { column_info: ColumnInfo::new("visitor_id".to_string(), "uuid".to_string()), value: None }],
                 table_name: ArcIntern::new("public.interactions".to_string()),
                 kind: ChangeKind::Insert },
-            ParsedLine::Commit(4220773508),
+            ParsedLine::Commit(4220773508, None),
             ParsedLine::Begin(4220773511),
             ParsedLine::ChangedData { columns: vec![
                 Column::ChangedColumn { column_info: ColumnInfo::new("id".to_string(), "uuid".to_string()), value: Some(ColumnValue::Text("5fe0cb5c-d92b-46ef-84bf-c02018ff19ca".to_string())) },
@@ -1462,15 +1589,15 @@ mod tests {
                 Column::ChangedColumn { column_info: ColumnInfo::new("updated_at".to_string(), "timestamp without time zone".to_string()), value: Some(ColumnValue::Text("2020-11-27 15:35:28.55719".to_string())) }],
                 table_name: ArcIntern::new("public.notification_sending_logs".to_string()),
                 kind: ChangeKind::Update },
-            ParsedLine::Commit(4220773511),
+            ParsedLine::Commit(4220773511, None),
             ParsedLine::Begin(4220773599),
             ParsedLine::ChangedData { columns: vec![],
                 table_name: ArcIntern::new("public.smart_insight_admin_conditions".to_string()),
                 kind: ChangeKind::Delete },
-            ParsedLine::Commit(4220773599),
+            ParsedLine::Commit(4220773599, None),
             ParsedLine::Begin(4220773600),
             ParsedLine::Truncate,
-            ParsedLine::Commit(4220773600),
+            ParsedLine::Commit(4220773600, None),
             ]));
     }
 
@@ -1510,7 +1637,7 @@ mod tests {
                     table_name: ArcIntern::new("public.foobar".to_string()),
                     kind: ChangeKind::Insert
                 },
-                ParsedLine::Commit(3970124255)
+                ParsedLine::Commit(3970124255, None)
             ]
         ))
     }
@@ -1551,7 +1678,7 @@ mod tests {
                         table_name: ArcIntern::new("public.foobar".to_string()),
                         kind: ChangeKind::Insert
                     },
-                    ParsedLine::Commit(3970124255)
+                    ParsedLine::Commit(3970124255, None)
                 ]
             )
         );
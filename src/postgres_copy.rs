@@ -0,0 +1,128 @@
+use bytes::Bytes;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use tokio::sync::mpsc;
+
+use crate::parser::{ColumnInfo, ColumnTypeEnum};
+use crate::staging_compression::StagingCompression;
+
+// redshift copies our csv files itself. postgres (what a local directory is loaded into)
+// can only copy files on its own server, and can't decompress them, so we stream their
+// rows to it instead, in its text format
+
+// how much we send at a time
+const CHUNK_BYTES: usize = 1024 * 1024;
+
+pub type CopyChunk = Result<Bytes, (PathBuf, io::Error)>;
+
+pub fn copy_statement(staging_name: &str, column_list: &str) -> String {
+    format!("copy \"{}\" ({}) from stdin", staging_name, column_list)
+}
+
+// reads the files on a blocking thread, sending their rows on as we go.
+// stops once the receiver is dropped
+pub fn spawn_reader(
+    paths: Vec<PathBuf>,
+    compression: StagingCompression,
+    columns: Vec<ColumnInfo>,
+) -> mpsc::Receiver<CopyChunk> {
+    let (sender, receiver) = mpsc::channel(4);
+    tokio::task::spawn_blocking(move || {
+        for path in paths {
+            let result = File::open(&path)
+                .and_then(|file| compression.reader(file))
+                .and_then(|reader| {
+                    write_rows(reader, &columns, |chunk| {
+                        sender.blocking_send(Ok(chunk)).is_ok()
+                    })
+                });
+            match result {
+                Ok(true) => {}
+                Ok(false) => return,
+                Err(err) => {
+                    let _ = sender.blocking_send(Err((path, err)));
+                    return;
+                }
+            }
+        }
+    });
+    receiver
+}
+
+// our csv files have a header, and nulls as a null byte (or nothing, for columns that
+// aren't text). false if the rows couldn't be sent
+fn write_rows<R: Read>(
+    reader: R,
+    columns: &[ColumnInfo],
+    mut send: impl FnMut(Bytes) -> bool,
+) -> io::Result<bool> {
+    let mut csv_reader = csv::ReaderBuilder::new()
+        .has_headers(true)
+        .from_reader(reader);
+    let mut buffer = Vec::with_capacity(CHUNK_BYTES);
+    for record in csv_reader.byte_records() {
+        let record = record?;
+        if record.len() != columns.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Row has {} columns, expected {}",
+                    record.len(),
+                    columns.len()
+                ),
+            ));
+        }
+        for (index, (field, column)) in record.iter().zip(columns).enumerate() {
+            if index > 0 {
+                buffer.push(b'\t');
+            }
+            let is_text = matches!(column.column_type_enum(), ColumnTypeEnum::Text);
+            if field == b"\0" || (field.is_empty() && !is_text) {
+                buffer.extend_from_slice(b"\\N");
+                continue;
+            }
+            for byte in field {
+                match byte {
+                    b'\\' => buffer.extend_from_slice(b"\\\\"),
+                    b'\t' => buffer.extend_from_slice(b"\\t"),
+                    b'\n' => buffer.extend_from_slice(b"\\n"),
+                    b'\r' => buffer.extend_from_slice(b"\\r"),
+                    byte => buffer.push(*byte),
+                }
+            }
+        }
+        buffer.push(b'\n');
+        if buffer.len() >= CHUNK_BYTES && !send(Bytes::from(std::mem::take(&mut buffer))) {
+            return Ok(false);
+        }
+    }
+    Ok(buffer.is_empty() || send(Bytes::from(buffer)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_rows_are_written_in_the_text_format() {
+        let columns = vec![
+            ColumnInfo::new("id", "bigint"),
+            ColumnInfo::new("name", "text"),
+            ColumnInfo::new("amount", "numeric"),
+        ];
+        let csv = "id,name,amount\n1,\"a\tb\nc\\d\",1.5\n2,\0,\n3,\"\",2\n";
+        let mut written = vec![];
+        let sent = write_rows(csv.as_bytes(), &columns, |chunk| {
+            written.extend_from_slice(&chunk);
+            true
+        })
+        .unwrap();
+        assert!(sent);
+        assert_eq!(
+            String::from_utf8(written).unwrap(),
+            "1\ta\\tb\\nc\\\\d\t1.5\n2\t\\N\t\\N\n3\t\t2\n"
+        );
+        assert!(write_rows("id\n1\n".as_bytes(), &columns, |_| true).is_err());
+    }
+}
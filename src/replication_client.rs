@@ -0,0 +1,837 @@
+use backoff::backoff::Backoff;
+use bytes::{Buf, BufMut, BytesMut};
+use fallible_iterator::FallibleIterator;
+use lazy_static::lazy_static;
+use openssl::ssl::{SslConnector, SslMethod};
+use postgres_protocol::authentication::md5_hash;
+use postgres_protocol::authentication::sasl::{ChannelBinding, ScramSha256};
+use postgres_protocol::message::backend::{ErrorResponseBody, Message};
+use postgres_protocol::message::frontend;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Interval;
+use tokio_openssl::SslStream;
+use tokio_postgres::config::{Host, SslMode};
+
+#[allow(unused_imports)]
+use crate::{function, logger_debug, logger_error, logger_info, logger_panic};
+
+use crate::exponential_backoff::{default_exponential_backoff, ExponentialBackoff};
+use crate::file_uploader_threads::DEFAULT_CHANNEL_SIZE;
+use crate::shutdown_handler::ShutdownHandler;
+
+lazy_static! {
+    static ref REPLICATION_SLOT: String =
+        std::env::var("REPLICATION_SLOT").expect("REPLICATION_SLOT env is not set");
+    static ref SOURCE_CONNECTION_STRING: String =
+        std::env::var("SOURCE_CONNECTION_STRING").expect("SOURCE_CONNECTION_STRING env is not set");
+    static ref REPLICATION_STATUS_INTERVAL_SECONDS: u64 =
+        std::env::var("REPLICATION_STATUS_INTERVAL_SECONDS")
+            .unwrap_or("10".to_string())
+            .parse::<u64>()
+            .expect("REPLICATION_STATUS_INTERVAL_SECONDS is not a valid integer");
+    static ref REPLICATION_PROGRESS: Mutex<ReplicationProgress> =
+        Mutex::new(ReplicationProgress::default());
+}
+
+// a position in the source's wal
+pub type Lsn = u64;
+
+pub fn format_lsn(lsn: Lsn) -> String {
+    format!("{:X}/{:X}", lsn >> 32, lsn as u32)
+}
+
+// microseconds from the unix epoch to the postgres epoch (2000-01-01)
+const POSTGRES_EPOCH_MICROS: i64 = 946_684_800_000_000;
+
+// postgres' error code when the replication slot already exists
+const DUPLICATE_OBJECT: &str = "42710";
+
+// a single line of test_decoding output.
+// commit lines carry the position we confirm to postgres once they've been applied
+#[derive(Debug, PartialEq)]
+pub struct ReplicationLine {
+    pub line: String,
+    pub commit_lsn: Option<Lsn>,
+}
+
+#[derive(Debug)]
+pub enum ReplicationClientError {
+    Io(std::io::Error),
+    Config(tokio_postgres::Error),
+    Tls(openssl::error::ErrorStack),
+    TlsHandshake(openssl::ssl::Error),
+    Server { code: String, message: String },
+    Protocol(String),
+}
+
+impl fmt::Display for ReplicationClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplicationClientError::Io(err) => write!(f, "Io error: {}", err),
+            ReplicationClientError::Config(err) => {
+                write!(f, "Invalid SOURCE_CONNECTION_STRING: {}", err)
+            }
+            ReplicationClientError::Tls(err) => write!(f, "Tls error: {}", err),
+            ReplicationClientError::TlsHandshake(err) => write!(f, "Tls handshake error: {}", err),
+            ReplicationClientError::Server { code, message } => {
+                write!(f, "Server error {}: {}", code, message)
+            }
+            ReplicationClientError::Protocol(message) => write!(f, "Protocol error: {}", message),
+        }
+    }
+}
+
+impl Error for ReplicationClientError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ReplicationClientError::Io(err) => Some(err),
+            ReplicationClientError::Config(err) => Some(err),
+            ReplicationClientError::Tls(err) => Some(err),
+            ReplicationClientError::TlsHandshake(err) => Some(err),
+            ReplicationClientError::Server { .. } | ReplicationClientError::Protocol(..) => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ReplicationClientError {
+    fn from(err: std::io::Error) -> Self {
+        ReplicationClientError::Io(err)
+    }
+}
+
+impl From<openssl::error::ErrorStack> for ReplicationClientError {
+    fn from(err: openssl::error::ErrorStack) -> Self {
+        ReplicationClientError::Tls(err)
+    }
+}
+
+impl From<openssl::ssl::Error> for ReplicationClientError {
+    fn from(err: openssl::ssl::Error) -> Self {
+        ReplicationClientError::TlsHandshake(err)
+    }
+}
+
+impl From<&ErrorResponseBody> for ReplicationClientError {
+    fn from(body: &ErrorResponseBody) -> Self {
+        let mut code = String::new();
+        let mut message = String::new();
+        let mut fields = body.fields();
+        while let Ok(Some(field)) = fields.next() {
+            match field.type_() {
+                b'C' => code = field.value().to_string(),
+                b'M' => message = field.value().to_string(),
+                _ => {}
+            }
+        }
+        ReplicationClientError::Server { code, message }
+    }
+}
+
+type Result<T> = std::result::Result<T, ReplicationClientError>;
+
+// Which commits ended up in which of our wal files.
+// A position is only confirmed to postgres once every wal file up to it has been applied,
+// as wal files for different tables finish out of order.
+#[derive(Debug, Default)]
+pub struct ReplicationProgress {
+    wal_files: BTreeMap<u64, WalFileProgress>,
+    applied_lsn: Lsn,
+}
+
+#[derive(Debug, Default)]
+struct WalFileProgress {
+    last_commit_lsn: Option<Lsn>,
+    applied: bool,
+}
+
+impl ReplicationProgress {
+    pub fn register_commit(wal_file_number: u64, lsn: Lsn) {
+        REPLICATION_PROGRESS
+            .lock()
+            .unwrap()
+            .add_commit(wal_file_number, lsn);
+    }
+
+    pub fn register_applied_wal_file(wal_file_number: u64) {
+        REPLICATION_PROGRESS
+            .lock()
+            .unwrap()
+            .mark_applied(wal_file_number);
+    }
+
+    pub fn current_applied_lsn() -> Lsn {
+        REPLICATION_PROGRESS.lock().unwrap().applied_lsn
+    }
+
+    fn add_commit(&mut self, wal_file_number: u64, lsn: Lsn) {
+        self.wal_files
+            .entry(wal_file_number)
+            .or_default()
+            .last_commit_lsn = Some(lsn);
+    }
+
+    fn mark_applied(&mut self, wal_file_number: u64) {
+        self.wal_files.entry(wal_file_number).or_default().applied = true;
+        while let Some(entry) = self.wal_files.first_entry() {
+            if !entry.get().applied {
+                break;
+            }
+            if let Some(lsn) = entry.remove().last_commit_lsn {
+                self.applied_lsn = self.applied_lsn.max(lsn);
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum ReplicationMessage {
+    XLogData {
+        wal_start: Lsn,
+        wal_end: Lsn,
+        data: Vec<u8>,
+    },
+    Keepalive {
+        wal_end: Lsn,
+        reply_requested: bool,
+    },
+}
+
+impl ReplicationMessage {
+    // https://www.postgresql.org/docs/current/protocol-replication.html
+    fn parse(data: &[u8]) -> Result<ReplicationMessage> {
+        let mut buf = data;
+        match buf.first() {
+            Some(b'w') if buf.len() >= 25 => {
+                buf.advance(1);
+                let wal_start = buf.get_u64();
+                let wal_end = buf.get_u64();
+                let _send_time = buf.get_i64();
+                Ok(ReplicationMessage::XLogData {
+                    wal_start,
+                    wal_end,
+                    data: buf.to_vec(),
+                })
+            }
+            Some(b'k') if buf.len() >= 18 => {
+                buf.advance(1);
+                let wal_end = buf.get_u64();
+                let _send_time = buf.get_i64();
+                Ok(ReplicationMessage::Keepalive {
+                    wal_end,
+                    reply_requested: buf.get_u8() == 1,
+                })
+            }
+            _ => Err(ReplicationClientError::Protocol(format!(
+                "unexpected replication message:{:?}",
+                data
+            ))),
+        }
+    }
+}
+
+// pg_recvlogical wrote each message followed by a newline, and we read that line by line,
+// so do the same thing for messages containing newlines (e.g. in text columns)
+fn message_lines(message: &str) -> impl Iterator<Item = &str> {
+    message
+        .split('\n')
+        .map(|line| line.strip_suffix('\r').unwrap_or(line))
+}
+
+fn standby_status_update(written_lsn: Lsn, flushed_lsn: Lsn, buf: &mut BytesMut) -> Result<()> {
+    let now_micros = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is before the unix epoch")
+        .as_micros() as i64;
+    let mut body = BytesMut::new();
+    body.put_u8(b'r');
+    body.put_u64(written_lsn);
+    body.put_u64(flushed_lsn);
+    // applied
+    body.put_u64(flushed_lsn);
+    body.put_i64(now_micros - POSTGRES_EPOCH_MICROS);
+    // don't ask for a reply
+    body.put_u8(0);
+    frontend::CopyData::new(body)?.write(buf);
+    Ok(())
+}
+
+trait ReplicationStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> ReplicationStream for T {}
+
+// a replication connection to the source, we only speak the parts of the protocol we need
+struct Connection {
+    stream: Box<dyn ReplicationStream>,
+    read_buffer: BytesMut,
+}
+
+impl Connection {
+    async fn connect(connection_string: &str) -> Result<Connection> {
+        let config = connection_string
+            .parse::<tokio_postgres::Config>()
+            .map_err(ReplicationClientError::Config)?;
+        let port = config.get_ports().first().copied().unwrap_or(5432);
+        let stream: Box<dyn ReplicationStream> = match config.get_hosts().first() {
+            Some(Host::Tcp(hostname)) => {
+                let stream = TcpStream::connect((hostname.as_str(), port)).await?;
+                Self::negotiate_tls(stream, hostname, config.get_ssl_mode()).await?
+            }
+            Some(Host::Unix(path)) => {
+                Box::new(UnixStream::connect(path.join(format!(".s.PGSQL.{}", port))).await?)
+            }
+            None => {
+                return Err(ReplicationClientError::Protocol(
+                    "no host in SOURCE_CONNECTION_STRING".to_string(),
+                ))
+            }
+        };
+        let mut connection = Connection {
+            stream,
+            read_buffer: BytesMut::new(),
+        };
+        connection.startup(&config).await?;
+        Ok(connection)
+    }
+
+    async fn negotiate_tls(
+        mut stream: TcpStream,
+        hostname: &str,
+        ssl_mode: SslMode,
+    ) -> Result<Box<dyn ReplicationStream>> {
+        if let SslMode::Disable = ssl_mode {
+            return Ok(Box::new(stream));
+        }
+        let mut buf = BytesMut::new();
+        frontend::ssl_request(&mut buf);
+        stream.write_all(&buf).await?;
+        let mut response = [0u8];
+        stream.read_exact(&mut response).await?;
+        if response[0] == b'S' {
+            let connector = SslConnector::builder(SslMethod::tls())?.build();
+            let ssl = connector.configure()?.into_ssl(hostname)?;
+            let mut tls_stream = SslStream::new(ssl, stream)?;
+            Pin::new(&mut tls_stream).connect().await?;
+            Ok(Box::new(tls_stream))
+        } else if let SslMode::Require = ssl_mode {
+            Err(ReplicationClientError::Protocol(
+                "the source doesn't support ssl, but sslmode is require".to_string(),
+            ))
+        } else {
+            Ok(Box::new(stream))
+        }
+    }
+
+    async fn startup(&mut self, config: &tokio_postgres::Config) -> Result<()> {
+        let user = config.get_user().ok_or_else(|| {
+            ReplicationClientError::Protocol("no user in SOURCE_CONNECTION_STRING".to_string())
+        })?;
+        let dbname = config.get_dbname().unwrap_or(user);
+        let mut buf = BytesMut::new();
+        frontend::startup_message(
+            [
+                ("user", user),
+                ("database", dbname),
+                ("replication", "database"),
+                ("application_name", "re_dms"),
+            ],
+            &mut buf,
+        )?;
+        self.write(&buf).await?;
+        self.authenticate(user, config.get_password()).await?;
+        loop {
+            match self.read_message().await? {
+                Message::ReadyForQuery(..) => return Ok(()),
+                Message::ErrorResponse(body) => return Err((&body).into()),
+                _ => {}
+            }
+        }
+    }
+
+    async fn authenticate(&mut self, user: &str, password: Option<&[u8]>) -> Result<()> {
+        let password = || {
+            password.ok_or_else(|| {
+                ReplicationClientError::Protocol(
+                    "the source needs a password, but there's none in SOURCE_CONNECTION_STRING"
+                        .to_string(),
+                )
+            })
+        };
+        let mut scram = None;
+        loop {
+            let mut buf = BytesMut::new();
+            match self.read_message().await? {
+                Message::AuthenticationOk => return Ok(()),
+                Message::AuthenticationCleartextPassword => {
+                    frontend::password_message(password()?, &mut buf)?;
+                }
+                Message::AuthenticationMd5Password(body) => {
+                    let hash = md5_hash(user.as_bytes(), password()?, body.salt());
+                    frontend::password_message(hash.as_bytes(), &mut buf)?;
+                }
+                Message::AuthenticationSasl(body) => {
+                    let mut mechanisms = body.mechanisms();
+                    let mut supports_scram = false;
+                    while let Some(mechanism) = mechanisms.next()? {
+                        supports_scram |= mechanism == "SCRAM-SHA-256";
+                    }
+                    if !supports_scram {
+                        return Err(ReplicationClientError::Protocol(
+                            "the source doesn't support SCRAM-SHA-256".to_string(),
+                        ));
+                    }
+                    let new_scram = ScramSha256::new(password()?, ChannelBinding::unsupported());
+                    frontend::sasl_initial_response(
+                        "SCRAM-SHA-256",
+                        new_scram.message(),
+                        &mut buf,
+                    )?;
+                    scram = Some(new_scram);
+                }
+                Message::AuthenticationSaslContinue(body) => {
+                    let scram = scram.as_mut().ok_or_else(|| {
+                        ReplicationClientError::Protocol("unexpected sasl continue".to_string())
+                    })?;
+                    scram.update(body.data())?;
+                    frontend::sasl_response(scram.message(), &mut buf)?;
+                }
+                Message::AuthenticationSaslFinal(body) => {
+                    let scram = scram.as_mut().ok_or_else(|| {
+                        ReplicationClientError::Protocol("unexpected sasl final".to_string())
+                    })?;
+                    scram.finish(body.data())?;
+                }
+                Message::ErrorResponse(body) => return Err((&body).into()),
+                _ => {
+                    return Err(ReplicationClientError::Protocol(
+                        "unsupported authentication method".to_string(),
+                    ))
+                }
+            }
+            if !buf.is_empty() {
+                self.write(&buf).await?;
+            }
+        }
+    }
+
+    // runs a query, ignoring any rows it returns
+    async fn simple_query(&mut self, query: &str) -> Result<()> {
+        let mut buf = BytesMut::new();
+        frontend::query(query, &mut buf)?;
+        self.write(&buf).await?;
+        let mut result = Ok(());
+        loop {
+            match self.read_message().await? {
+                Message::ReadyForQuery(..) => return result,
+                Message::ErrorResponse(body) => result = Err((&body).into()),
+                _ => {}
+            }
+        }
+    }
+
+    async fn create_slot_if_not_exists(&mut self) -> Result<()> {
+        let query = format!(
+            "CREATE_REPLICATION_SLOT \"{}\" LOGICAL test_decoding",
+            *REPLICATION_SLOT
+        );
+        match self.simple_query(&query).await {
+            Err(ReplicationClientError::Server { code, .. }) if code == DUPLICATE_OBJECT => Ok(()),
+            result => result,
+        }
+    }
+
+    // with an lsn of 0/0 postgres starts from what we last confirmed.
+    // commit timestamps go into our checkpoints
+    async fn start_replication(&mut self, lsn: Lsn) -> Result<()> {
+        let mut buf = BytesMut::new();
+        let query = format!(
+            "START_REPLICATION SLOT \"{}\" LOGICAL {} (\"include-timestamp\" 'on')",
+            *REPLICATION_SLOT,
+            format_lsn(lsn)
+        );
+        frontend::query(&query, &mut buf)?;
+        self.write(&buf).await?;
+        loop {
+            match self.read_message().await? {
+                Message::CopyOutResponse(..) => return Ok(()),
+                Message::ErrorResponse(body) => return Err((&body).into()),
+                _ => {}
+            }
+        }
+    }
+
+    // cancel safe, so it can be used in a select
+    async fn read_replication_message(&mut self) -> Result<ReplicationMessage> {
+        loop {
+            match self.read_message().await? {
+                Message::CopyData(body) => return ReplicationMessage::parse(body.data()),
+                Message::CopyDone => {
+                    return Err(ReplicationClientError::Protocol(
+                        "the source ended replication".to_string(),
+                    ))
+                }
+                Message::ErrorResponse(body) => return Err((&body).into()),
+                _ => {}
+            }
+        }
+    }
+
+    async fn read_message(&mut self) -> Result<Message> {
+        loop {
+            if self.read_buffer.first() == Some(&b'W') {
+                // postgres_protocol doesn't know about CopyBothResponse,
+                // it's the same as a CopyOutResponse apart from the tag.
+                self.read_buffer[0] = b'H';
+            }
+            if let Some(message) = Message::parse(&mut self.read_buffer)? {
+                return Ok(message);
+            }
+            if self.stream.read_buf(&mut self.read_buffer).await? == 0 {
+                return Err(ReplicationClientError::Protocol(
+                    "the source closed the connection".to_string(),
+                ));
+            }
+        }
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> Result<()> {
+        self.stream.write_all(buf).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    async fn close(mut self) {
+        let mut buf = BytesMut::new();
+        frontend::copy_done(&mut buf);
+        frontend::terminate(&mut buf);
+        if let Err(err) = self.write(&buf).await {
+            logger_error!(
+                None,
+                None,
+                &format!("error_closing_replication_connection:{}", err)
+            );
+        }
+    }
+}
+
+pub struct ReplicationClientHandle {
+    finish_sender: oneshot::Sender<()>,
+    join_handle: tokio::task::JoinHandle<()>,
+}
+
+impl ReplicationClientHandle {
+    // call once everything has been applied, to confirm the final position to postgres
+    pub async fn finish(self) {
+        // the client may already have given up
+        let _ = self.finish_sender.send(());
+        self.join_handle
+            .await
+            .expect("Error joining replication client");
+    }
+}
+
+// Streams test_decoding output from the replication slot.
+// We only confirm positions to postgres once they've been applied to the target,
+// so whatever hasn't been is streamed again after a restart.
+pub struct ReplicationClient {
+    // None once we're shutting down, after which we only keep confirming positions
+    sender: Option<mpsc::Sender<ReplicationLine>>,
+    // the end of the last transaction we passed on, which is where we resume from if we reconnect
+    last_forwarded_commit_lsn: Lsn,
+    // everything up to here has been passed on
+    received_lsn: Lsn,
+    // the furthest position we've confirmed while we had nothing waiting to be applied
+    idle_lsn: Lsn,
+    in_transaction: bool,
+    // if we reconnect part way through a transaction it's sent again from the start,
+    // so we skip as many messages as we've already passed on
+    forwarded_in_transaction: usize,
+    backoff: ExponentialBackoff,
+}
+
+impl ReplicationClient {
+    pub fn spawn() -> (ReplicationClientHandle, mpsc::Receiver<ReplicationLine>) {
+        let (sender, receiver) = mpsc::channel::<ReplicationLine>(DEFAULT_CHANNEL_SIZE);
+        let (finish_sender, finish_receiver) = oneshot::channel();
+        let client = ReplicationClient {
+            sender: Some(sender),
+            last_forwarded_commit_lsn: 0,
+            received_lsn: 0,
+            idle_lsn: 0,
+            in_transaction: false,
+            forwarded_in_transaction: 0,
+            backoff: default_exponential_backoff(),
+        };
+        let join_handle = tokio::spawn(client.run(finish_receiver));
+        (
+            ReplicationClientHandle {
+                finish_sender,
+                join_handle,
+            },
+            receiver,
+        )
+    }
+
+    async fn run(mut self, mut finish_receiver: oneshot::Receiver<()>) {
+        loop {
+            match self.stream(&mut finish_receiver).await {
+                Ok(()) => return,
+                Err(err) => {
+                    logger_error!(None, None, &format!("replication_client_error:{}", err));
+                }
+            }
+            match self.backoff.next_backoff() {
+                Some(duration) => {
+                    logger_info!(
+                        None,
+                        None,
+                        &format!("replication_client_reconnecting_in:{:?}", duration)
+                    );
+                    tokio::select! {
+                        _ = tokio::time::sleep(duration) => {}
+                        _ = &mut finish_receiver => return,
+                    }
+                }
+                None => {
+                    logger_error!(None, None, "replication_client_gave_up_reconnecting");
+                    self.sender = None;
+                    ShutdownHandler::register_messy_shutdown();
+                    return;
+                }
+            }
+        }
+    }
+
+    // returns Ok once we've been told to finish, errors mean we should reconnect
+    async fn stream(&mut self, finish_receiver: &mut oneshot::Receiver<()>) -> Result<()> {
+        let mut connection = Connection::connect(&SOURCE_CONNECTION_STRING).await?;
+        connection.create_slot_if_not_exists().await?;
+        connection
+            .start_replication(self.last_forwarded_commit_lsn)
+            .await?;
+        logger_info!(
+            None,
+            None,
+            &format!(
+                "started_replication slot:{} lsn:{}",
+                *REPLICATION_SLOT,
+                format_lsn(self.last_forwarded_commit_lsn)
+            )
+        );
+        self.backoff.reset();
+        let messages_to_skip = if self.in_transaction {
+            self.forwarded_in_transaction
+        } else {
+            0
+        };
+        let mut skipped_messages = 0;
+        let mut status_interval =
+            tokio::time::interval(Duration::from_secs(*REPLICATION_STATUS_INTERVAL_SECONDS));
+        loop {
+            tokio::select! {
+                message = connection.read_replication_message() => {
+                    match message? {
+                        ReplicationMessage::XLogData { wal_start, wal_end, data } => {
+                            if skipped_messages < messages_to_skip {
+                                skipped_messages += 1;
+                            } else {
+                                self.forward(
+                                    wal_start,
+                                    &String::from_utf8_lossy(&data),
+                                    &mut connection,
+                                    &mut status_interval,
+                                )
+                                .await?;
+                            }
+                            if self.sender.is_some() {
+                                self.received_lsn = self.received_lsn.max(wal_end);
+                            }
+                        }
+                        ReplicationMessage::Keepalive { wal_end, reply_requested } => {
+                            if self.sender.is_some() {
+                                self.received_lsn = self.received_lsn.max(wal_end);
+                            }
+                            if reply_requested {
+                                self.send_status(&mut connection).await?;
+                            }
+                        }
+                    }
+                }
+                _ = status_interval.tick() => {
+                    self.stop_forwarding_if_shutting_down();
+                    self.send_status(&mut connection).await?;
+                }
+                _ = &mut *finish_receiver => {
+                    self.send_status(&mut connection).await?;
+                    connection.close().await;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    async fn forward(
+        &mut self,
+        wal_start: Lsn,
+        message: &str,
+        connection: &mut Connection,
+        status_interval: &mut Interval,
+    ) -> Result<()> {
+        self.stop_forwarding_if_shutting_down();
+        let sender = match &self.sender {
+            Some(sender) => sender.clone(),
+            None => return Ok(()),
+        };
+        let is_commit = message.starts_with("COMMIT");
+        if message.starts_with("BEGIN") {
+            self.in_transaction = true;
+            self.forwarded_in_transaction = 0;
+        }
+        // for a commit this is the end of the transaction
+        let commit_lsn = if is_commit { Some(wal_start) } else { None };
+        for (index, line) in message_lines(message).enumerate() {
+            let replication_line = ReplicationLine {
+                line: line.to_string(),
+                commit_lsn: if index == 0 { commit_lsn } else { None },
+            };
+            // the main loop stops reading while the wal disk is filling up,
+            // so we keep sending our status meanwhile or the source times us out
+            let permit = loop {
+                tokio::select! {
+                    permit = sender.reserve() => break permit,
+                    _ = status_interval.tick() => {
+                        self.stop_forwarding_if_shutting_down();
+                        if self.sender.is_none() {
+                            return Ok(());
+                        }
+                        self.send_status(connection).await?;
+                    }
+                }
+            };
+            match permit {
+                Ok(permit) => permit.send(replication_line),
+                Err(_) => {
+                    logger_error!(None, None, "replication_lines_receiver_dropped");
+                    self.sender = None;
+                    return Ok(());
+                }
+            }
+        }
+        self.forwarded_in_transaction += 1;
+        if is_commit {
+            self.in_transaction = false;
+            self.forwarded_in_transaction = 0;
+            self.last_forwarded_commit_lsn = wal_start;
+        }
+        Ok(())
+    }
+
+    fn stop_forwarding_if_shutting_down(&mut self) {
+        if self.sender.is_some() && ShutdownHandler::shutting_down() {
+            logger_info!(None, None, "replication_client_stopped_forwarding");
+            // closes the channel, so the main loop finishes up
+            self.sender = None;
+        }
+    }
+
+    async fn send_status(&mut self, connection: &mut Connection) -> Result<()> {
+        let applied_lsn = ReplicationProgress::current_applied_lsn();
+        if self.sender.is_some()
+            && !self.in_transaction
+            && applied_lsn >= self.last_forwarded_commit_lsn
+        {
+            // everything we've passed on has been applied, so we can skip past
+            // anything the source has sent that wasn't for us
+            self.idle_lsn = self.idle_lsn.max(self.received_lsn);
+        }
+        let flushed_lsn = applied_lsn.max(self.idle_lsn);
+        let written_lsn = self.received_lsn.max(flushed_lsn);
+        logger_debug!(
+            None,
+            None,
+            &format!(
+                "replication_status written:{} flushed:{}",
+                format_lsn(written_lsn),
+                format_lsn(flushed_lsn)
+            )
+        );
+        let mut buf = BytesMut::new();
+        standby_status_update(written_lsn, flushed_lsn, &mut buf)?;
+        connection.write(&buf).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_waits_for_earlier_wal_files() {
+        let mut progress = ReplicationProgress::default();
+        progress.add_commit(1, 100);
+        progress.add_commit(1, 200);
+        progress.add_commit(2, 300);
+        progress.add_commit(3, 400);
+        progress.mark_applied(2);
+        assert_eq!(progress.applied_lsn, 0);
+        progress.mark_applied(1);
+        assert_eq!(progress.applied_lsn, 300);
+        // a reprocessed wal file has no commits of ours
+        progress.mark_applied(0);
+        assert_eq!(progress.applied_lsn, 300);
+        progress.mark_applied(3);
+        assert_eq!(progress.applied_lsn, 400);
+        assert!(progress.wal_files.is_empty());
+    }
+
+    #[test]
+    fn parse_replication_messages() {
+        let mut xlog_data = vec![b'w'];
+        xlog_data.extend_from_slice(&0x1_0000_0010u64.to_be_bytes());
+        xlog_data.extend_from_slice(&0x1_0000_0020u64.to_be_bytes());
+        xlog_data.extend_from_slice(&0i64.to_be_bytes());
+        xlog_data.extend_from_slice(b"COMMIT 1234");
+        assert_eq!(
+            ReplicationMessage::parse(&xlog_data).unwrap(),
+            ReplicationMessage::XLogData {
+                wal_start: 0x1_0000_0010,
+                wal_end: 0x1_0000_0020,
+                data: b"COMMIT 1234".to_vec(),
+            }
+        );
+
+        let mut keepalive = vec![b'k'];
+        keepalive.extend_from_slice(&0x20u64.to_be_bytes());
+        keepalive.extend_from_slice(&0i64.to_be_bytes());
+        keepalive.push(1);
+        assert_eq!(
+            ReplicationMessage::parse(&keepalive).unwrap(),
+            ReplicationMessage::Keepalive {
+                wal_end: 0x20,
+                reply_requested: true,
+            }
+        );
+
+        assert!(ReplicationMessage::parse(b"k").is_err());
+        assert_eq!(format_lsn(0x1_0000_0010), "1/10");
+    }
+
+    #[test]
+    fn messages_are_split_into_lines_like_pg_recvlogical() {
+        let message = "table public.foo: INSERT: id[bigint]:1 bar[text]:'a\r\nb\n'";
+        assert_eq!(
+            message_lines(message).collect::<Vec<_>>(),
+            [
+                "table public.foo: INSERT: id[bigint]:1 bar[text]:'a",
+                "b",
+                "'"
+            ]
+        );
+    }
+}
@@ -0,0 +1,455 @@
+use bigdecimal::BigDecimal;
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::{error::Error, fmt};
+
+use crate::parser::{ChangeKind, Column, ColumnValue, ParsedLine, TableName};
+
+#[allow(unused_imports)]
+use crate::{function, logger_debug, logger_error, logger_info, logger_panic};
+
+lazy_static! {
+    // semicolon separated schema.table:column op value
+    // e.g. public.users:tenant_id = 5;public.users:is_test != true;public.events:created_at >= 2024-01-01
+    static ref ROW_FILTERS: RowFilters = RowFilters::parse(
+        &std::env::var("ROW_FILTERS").unwrap_or("".to_owned())
+    )
+    .expect("Unable to parse ROW_FILTERS");
+    static ref PREDICATE_REGEX: Regex = Regex::new(
+        r"(?i)^\s*([^\s]+)\s+(is\s+not\s+null|is\s+null|not\s+in|in|!=|>=|<=|=|>|<)\s*(.*?)\s*$"
+    )
+    .unwrap();
+}
+
+#[derive(Debug)]
+pub struct RowFilterError {
+    pub message: String,
+}
+
+impl Error for RowFilterError {}
+
+impl fmt::Display for RowFilterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Failed to filter row due to: {}", self.message)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, RowFilterError>;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Operator {
+    Equal(String),
+    NotEqual(String),
+    In(Vec<String>),
+    NotIn(Vec<String>),
+    GreaterThan(String),
+    GreaterThanOrEqual(String),
+    LessThan(String),
+    LessThanOrEqual(String),
+    IsNull,
+    IsNotNull,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct Predicate {
+    column_name: String,
+    operator: Operator,
+}
+
+#[derive(Debug, Default, Eq, PartialEq)]
+pub(crate) struct RowFilters {
+    // all predicates for a table have to match
+    filters: HashMap<String, Vec<Predicate>>,
+}
+
+// strips optional single quotes, so values with spaces or commas can be given
+fn literal(value: &str) -> String {
+    let value = value.trim();
+    value
+        .strip_prefix('\'')
+        .and_then(|value| value.strip_suffix('\''))
+        .unwrap_or(value)
+        .to_string()
+}
+
+fn literal_list(value: &str) -> Result<Vec<String>> {
+    let inner = value
+        .trim()
+        .strip_prefix('(')
+        .and_then(|value| value.strip_suffix(')'))
+        .ok_or_else(|| RowFilterError {
+            message: format!("expected a list in brackets e.g. (1,2,3) got: {}", value),
+        })?;
+    Ok(inner.split(',').map(literal).collect())
+}
+
+impl Predicate {
+    fn parse(predicate: &str) -> Result<Predicate> {
+        let captures = PREDICATE_REGEX
+            .captures(predicate)
+            .ok_or_else(|| RowFilterError {
+                message: format!("unparseable predicate: {}", predicate),
+            })?;
+        let column_name = captures[1].to_string();
+        let value = &captures[3];
+        // collapse whitespace so `is  not null` works
+        let operator_string = captures[2]
+            .to_lowercase()
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ");
+        let operator = match operator_string.as_str() {
+            "is null" => Operator::IsNull,
+            "is not null" => Operator::IsNotNull,
+            "in" => Operator::In(literal_list(value)?),
+            "not in" => Operator::NotIn(literal_list(value)?),
+            "=" => Operator::Equal(literal(value)),
+            "!=" => Operator::NotEqual(literal(value)),
+            ">" => Operator::GreaterThan(literal(value)),
+            ">=" => Operator::GreaterThanOrEqual(literal(value)),
+            "<" => Operator::LessThan(literal(value)),
+            "<=" => Operator::LessThanOrEqual(literal(value)),
+            _ => unreachable!("regex only matches known operators"),
+        };
+        match operator {
+            Operator::IsNull | Operator::IsNotNull if !value.is_empty() => Err(RowFilterError {
+                message: format!("null checks don't take a value: {}", predicate),
+            }),
+            _ => Ok(Predicate {
+                column_name,
+                operator,
+            }),
+        }
+    }
+
+    // None when the value isn't in the change (an unchanged toast column), so we can't say
+    fn matches(&self, columns: &[Column]) -> Result<Option<bool>> {
+        let column = columns
+            .iter()
+            .find(|column| column.column_name() == self.column_name)
+            .ok_or_else(|| RowFilterError {
+                message: format!("filtered column {} is missing", self.column_name),
+            })?;
+        let value = match column {
+            Column::ChangedColumn { value, .. } => value.as_ref(),
+            Column::UnchangedToastColumn { .. } => return Ok(None),
+            Column::IncompleteColumn { .. } => {
+                return Err(RowFilterError {
+                    message: format!("filtered column {} is incomplete", self.column_name),
+                })
+            }
+        };
+        let value = match (value, &self.operator) {
+            (None, Operator::IsNull) => return Ok(Some(true)),
+            (None, _) => return Ok(Some(false)), // like sql, null doesn't compare to anything
+            (Some(_), Operator::IsNull) => return Ok(Some(false)),
+            (Some(_), Operator::IsNotNull) => return Ok(Some(true)),
+            (Some(value), _) => value,
+        };
+        let compare = |literal: &String| compare(value, literal);
+        let matched = match &self.operator {
+            Operator::Equal(literal) => compare(literal)? == Ordering::Equal,
+            Operator::NotEqual(literal) => compare(literal)? != Ordering::Equal,
+            Operator::In(literals) => literals
+                .iter()
+                .map(compare)
+                .collect::<Result<Vec<_>>>()?
+                .contains(&Ordering::Equal),
+            Operator::NotIn(literals) => !literals
+                .iter()
+                .map(compare)
+                .collect::<Result<Vec<_>>>()?
+                .contains(&Ordering::Equal),
+            Operator::GreaterThan(literal) => compare(literal)? == Ordering::Greater,
+            Operator::GreaterThanOrEqual(literal) => compare(literal)? != Ordering::Less,
+            Operator::LessThan(literal) => compare(literal)? == Ordering::Less,
+            Operator::LessThanOrEqual(literal) => compare(literal)? != Ordering::Greater,
+            Operator::IsNull | Operator::IsNotNull => unreachable!("handled above"),
+        };
+        Ok(Some(matched))
+    }
+}
+
+// compares in the type of the column, text (and timestamps) compare as strings
+fn compare(value: &ColumnValue, literal: &str) -> Result<Ordering> {
+    let unparseable = || RowFilterError {
+        message: format!("unable to compare {:?} with {}", value, literal),
+    };
+    match value {
+        ColumnValue::Integer(integer) => {
+            Ok(integer.cmp(&literal.parse::<i64>().map_err(|_| unparseable())?))
+        }
+        ColumnValue::Boolean(boolean) => {
+            Ok(boolean.cmp(&literal.parse::<bool>().map_err(|_| unparseable())?))
+        }
+        ColumnValue::Numeric(numeric) | ColumnValue::RoundingNumeric(numeric) => {
+            let numeric = BigDecimal::from_str(numeric).map_err(|_| unparseable())?;
+            let literal = BigDecimal::from_str(literal).map_err(|_| unparseable())?;
+            Ok(numeric.cmp(&literal))
+        }
+        ColumnValue::Text(text) => Ok(text.as_str().cmp(literal)),
+        ColumnValue::IncompleteText(_) | ColumnValue::UnchangedToast => Err(unparseable()),
+    }
+}
+
+impl RowFilters {
+    pub(crate) fn parse(row_filters: &str) -> Result<RowFilters> {
+        let mut filters: HashMap<String, Vec<Predicate>> = HashMap::new();
+        for table_and_predicate in row_filters
+            .split(';')
+            .filter(|table_and_predicate| !table_and_predicate.trim().is_empty())
+        {
+            let (table_name, predicate) =
+                table_and_predicate
+                    .split_once(':')
+                    .ok_or_else(|| RowFilterError {
+                        message: format!(
+                            "expected schema.table:predicate got: {}",
+                            table_and_predicate
+                        ),
+                    })?;
+            filters
+                .entry(table_name.trim().to_string())
+                .or_default()
+                .push(Predicate::parse(predicate)?);
+        }
+        Ok(RowFilters { filters })
+    }
+
+    pub(crate) fn apply(&self, parsed_line: ParsedLine) -> Result<Option<ParsedLine>> {
+        let predicates = match &parsed_line {
+            ParsedLine::ChangedData { table_name, .. } => {
+                match self.filters.get(table_name.as_str()) {
+                    Some(predicates) => predicates,
+                    None => return Ok(Some(parsed_line)),
+                }
+            }
+            _ => return Ok(Some(parsed_line)),
+        };
+        if let ParsedLine::ChangedData {
+            kind,
+            columns,
+            table_name,
+        } = &parsed_line
+        {
+            // deletes only have the id, and deleting a row we don't have is harmless
+            if *kind == ChangeKind::Delete {
+                return Ok(Some(parsed_line));
+            }
+            let mut matched = true;
+            for predicate in predicates {
+                // if we can't tell, we keep the row
+                if predicate.matches(columns)? == Some(false) {
+                    matched = false;
+                    break;
+                }
+            }
+            if matched {
+                return Ok(Some(parsed_line));
+            }
+            match kind {
+                ChangeKind::Insert => Ok(None),
+                // the row may have moved out of the filter, so make sure it's gone.
+                // with the whole key, like a delete from the stream
+                _ => {
+                    parsed_line.find_id_column().map_err(|err| RowFilterError {
+                        message: err.message,
+                    })?;
+                    Ok(Some(ParsedLine::ChangedData {
+                        kind: ChangeKind::Delete,
+                        table_name: table_name.clone(),
+                        columns: columns
+                            .iter()
+                            .filter(|column| {
+                                column.is_id_column()
+                                    || column.column_info().is_source_schema_column()
+                            })
+                            .cloned()
+                            .collect(),
+                    }))
+                }
+            }
+        } else {
+            Ok(Some(parsed_line))
+        }
+    }
+}
+
+pub fn has_row_filters(table_name: &TableName) -> bool {
+    ROW_FILTERS.filters.contains_key(table_name.as_str())
+}
+
+// returns the change to process, which may be a delete for an update that's no longer
+// in the filter, or None if it should be dropped
+pub fn apply_row_filters(parsed_line: ParsedLine) -> Result<Option<ParsedLine>> {
+    ROW_FILTERS.apply(parsed_line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{ColumnInfo, SOURCE_SCHEMA_COLUMN};
+
+    fn change(kind: ChangeKind, columns: Vec<Column>) -> ParsedLine {
+        ParsedLine::ChangedData {
+            kind,
+            table_name: TableName::new("public.users".to_string()),
+            columns,
+        }
+    }
+
+    fn integer_column(name: &str, value: Option<i64>) -> Column {
+        Column::ChangedColumn {
+            column_info: ColumnInfo::new(name, "bigint"),
+            value: value.map(ColumnValue::Integer),
+        }
+    }
+
+    fn text_column(name: &str, value: &str) -> Column {
+        Column::ChangedColumn {
+            column_info: ColumnInfo::new(name, "text"),
+            value: Some(ColumnValue::Text(value.to_string())),
+        }
+    }
+
+    #[test]
+    fn parses_predicates() {
+        let row_filters = RowFilters::parse(
+            "public.users:tenant_id in (1, 2,'3');public.users:deleted_at IS NOT NULL; public.events:created_at >= '2024-01-01 00:00:00'",
+        )
+        .unwrap();
+        assert_eq!(
+            row_filters.filters["public.users"],
+            vec![
+                Predicate {
+                    column_name: "tenant_id".to_string(),
+                    operator: Operator::In(vec!["1".to_string(), "2".to_string(), "3".to_string()])
+                },
+                Predicate {
+                    column_name: "deleted_at".to_string(),
+                    operator: Operator::IsNotNull
+                }
+            ]
+        );
+        assert_eq!(
+            row_filters.filters["public.events"],
+            vec![Predicate {
+                column_name: "created_at".to_string(),
+                operator: Operator::GreaterThanOrEqual("2024-01-01 00:00:00".to_string())
+            }]
+        );
+        assert!(RowFilters::parse("public.users:tenant_id ~ 1").is_err());
+        assert!(RowFilters::parse("public.users:tenant_id is null 1").is_err());
+        assert!(RowFilters::parse("tenant_id = 1").is_err());
+    }
+
+    #[test]
+    fn inserts_outside_the_filter_are_dropped() {
+        let row_filters =
+            RowFilters::parse("public.users:tenant_id = 5;public.users:email != test@example.com")
+                .unwrap();
+        let matching = change(
+            ChangeKind::Insert,
+            vec![
+                integer_column("id", Some(1)),
+                integer_column("tenant_id", Some(5)),
+                text_column("email", "user@example.com"),
+            ],
+        );
+        assert_eq!(row_filters.apply(matching.clone()).unwrap(), Some(matching));
+        let other_tenant = change(
+            ChangeKind::Insert,
+            vec![
+                integer_column("id", Some(1)),
+                integer_column("tenant_id", Some(6)),
+                text_column("email", "user@example.com"),
+            ],
+        );
+        assert_eq!(row_filters.apply(other_tenant).unwrap(), None);
+        let null_tenant = change(
+            ChangeKind::Insert,
+            vec![
+                integer_column("id", Some(1)),
+                integer_column("tenant_id", None),
+                text_column("email", "user@example.com"),
+            ],
+        );
+        assert_eq!(row_filters.apply(null_tenant).unwrap(), None);
+    }
+
+    #[test]
+    fn updates_outside_the_filter_become_deletes() {
+        let row_filters = RowFilters::parse("public.users:tenant_id < 5").unwrap();
+        let update = change(
+            ChangeKind::Update,
+            vec![
+                integer_column("id", Some(1)),
+                integer_column("tenant_id", Some(6)),
+            ],
+        );
+        assert_eq!(
+            row_filters.apply(update).unwrap(),
+            Some(change(
+                ChangeKind::Delete,
+                vec![integer_column("id", Some(1))]
+            ))
+        );
+        let delete = change(ChangeKind::Delete, vec![integer_column("id", Some(1))]);
+        assert_eq!(row_filters.apply(delete.clone()).unwrap(), Some(delete));
+        // we can't tell for unchanged toast columns, so keep them
+        let toasted_update = change(
+            ChangeKind::Update,
+            vec![
+                integer_column("id", Some(1)),
+                Column::UnchangedToastColumn {
+                    column_info: ColumnInfo::new("tenant_id", "bigint"),
+                },
+            ],
+        );
+        assert_eq!(
+            row_filters.apply(toasted_update.clone()).unwrap(),
+            Some(toasted_update)
+        );
+    }
+
+    #[test]
+    fn deletes_for_consolidated_tenant_tables_keep_the_source_schema() {
+        let row_filters = RowFilters::parse("public.users:tenant_id < 5").unwrap();
+        let update = change(
+            ChangeKind::Update,
+            vec![
+                text_column(SOURCE_SCHEMA_COLUMN, "tenant_a"),
+                integer_column("id", Some(1)),
+                integer_column("tenant_id", Some(6)),
+            ],
+        );
+        assert_eq!(
+            row_filters.apply(update).unwrap(),
+            Some(change(
+                ChangeKind::Delete,
+                vec![
+                    text_column(SOURCE_SCHEMA_COLUMN, "tenant_a"),
+                    integer_column("id", Some(1))
+                ]
+            ))
+        );
+    }
+
+    #[test]
+    fn missing_or_uncomparable_columns_are_errors() {
+        let row_filters = RowFilters::parse("public.users:tenant_id = five").unwrap();
+        let insert = change(
+            ChangeKind::Insert,
+            vec![
+                integer_column("id", Some(1)),
+                integer_column("tenant_id", Some(5)),
+            ],
+        );
+        assert!(row_filters.apply(insert).is_err());
+        let missing_column = change(ChangeKind::Insert, vec![integer_column("id", Some(1))]);
+        assert!(row_filters.apply(missing_column).is_err());
+    }
+}
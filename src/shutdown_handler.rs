@@ -3,8 +3,6 @@ use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 use std::sync::Mutex;
 
-use nix::sys::signal::{self, Signal};
-use nix::unistd::Pid;
 use signal_hook::consts::signal::*;
 use signal_hook::iterator::Signals;
 
@@ -24,22 +22,17 @@ pub struct ShutdownHandler {
 
 pub enum RuntimeType {
     Stdin,
-    Process(Pid),
+    Replication,
     File,
 }
 impl RuntimeType {
-    pub fn from_pid(id: i32) -> RuntimeType {
-        RuntimeType::Process(Pid::from_raw(id))
-    }
     pub fn no_child(&self) -> bool {
         matches!(self, RuntimeType::Stdin) || matches!(self, RuntimeType::File)
     }
     pub fn run_shutdown(&self) {
         match self {
-            Self::Process(pid) => {
-                // https://stackoverflow.com/questions/49210815/how-do-i-send-a-signal-to-a-child-subprocess
-                logger_info!(None, None, &format!("killing_child_process:{}", pid));
-                signal::kill(pid.clone(), Signal::SIGTERM).unwrap();
+            Self::Replication => {
+                // No-Op, the replication client stops passing on changes once it sees we're shutting down
             }
             Self::Stdin => {
                 // No-Op
@@ -0,0 +1,208 @@
+use bzip2::read::BzDecoder;
+use bzip2::write::BzEncoder;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use lazy_static::lazy_static;
+use std::fs::File;
+use std::io::{Read, Write};
+
+use crate::encryption::EncryptingWriter;
+
+lazy_static! {
+    static ref STAGING_COMPRESSION: StagingCompression = StagingCompression::parse(
+        &std::env::var("STAGING_COMPRESSION").unwrap_or_default(),
+        std::env::var("STAGING_COMPRESSION_LEVEL")
+            .ok()
+            .filter(|level| !level.is_empty())
+            .map(|level| {
+                level
+                    .parse::<u32>()
+                    .expect("STAGING_COMPRESSION_LEVEL is not a valid integer")
+            }),
+        std::env::var("STAGING_COMPRESSION_THREADS")
+            .ok()
+            .filter(|threads| !threads.is_empty())
+            .map(|threads| {
+                threads
+                    .parse::<u32>()
+                    .expect("STAGING_COMPRESSION_THREADS is not a valid integer")
+            })
+            .unwrap_or(1),
+    );
+}
+
+// how the csv files we copy into redshift are compressed
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum StagingCompression {
+    None,
+    Gzip { level: u32 },
+    // compressed on this many threads, when it's more than 1
+    Zstd { level: u32, threads: u32 },
+    Bzip2 { level: u32 },
+}
+
+impl StagingCompression {
+    pub fn configured() -> StagingCompression {
+        *STAGING_COMPRESSION
+    }
+
+    fn parse(compression: &str, level: Option<u32>, threads: u32) -> StagingCompression {
+        let staging_compression = match compression {
+            "gzip" | "" => StagingCompression::Gzip {
+                level: level.unwrap_or(6),
+            },
+            "zstd" => StagingCompression::Zstd {
+                level: level.unwrap_or(3),
+                threads,
+            },
+            "bzip2" => StagingCompression::Bzip2 {
+                level: level.unwrap_or(6),
+            },
+            "none" => StagingCompression::None,
+            unknown => panic!("Unknown staging compression: {}", unknown),
+        };
+        let valid_levels = match staging_compression {
+            StagingCompression::None => 0..=0,
+            StagingCompression::Gzip { .. } => 0..=9,
+            StagingCompression::Zstd { .. } => 1..=22,
+            StagingCompression::Bzip2 { .. } => 1..=9,
+        };
+        if let Some(level) = level {
+            assert!(
+                valid_levels.contains(&level),
+                "STAGING_COMPRESSION_LEVEL {} is out of range for {}",
+                level,
+                compression
+            );
+        }
+        staging_compression
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            StagingCompression::None => ".csv",
+            StagingCompression::Gzip { .. } => ".csv.gz",
+            StagingCompression::Zstd { .. } => ".csv.zst",
+            StagingCompression::Bzip2 { .. } => ".csv.bz2",
+        }
+    }
+
+    // so copy knows how to read the file
+    pub fn copy_option(&self) -> &'static str {
+        match self {
+            StagingCompression::None => "",
+            StagingCompression::Gzip { .. } => "GZIP",
+            StagingCompression::Zstd { .. } => "ZSTD",
+            StagingCompression::Bzip2 { .. } => "BZIP2",
+        }
+    }
+
+    pub fn writer(&self, file: EncryptingWriter<File>) -> CompressingWriter {
+        match self {
+            StagingCompression::None => CompressingWriter::None(file),
+            StagingCompression::Gzip { level } => {
+                CompressingWriter::Gzip(GzEncoder::new(file, flate2::Compression::new(*level)))
+            }
+            StagingCompression::Zstd { level, threads } => {
+                let mut encoder =
+                    zstd::Encoder::new(file, *level as i32).expect("Error creating zstd encoder");
+                if *threads > 1 {
+                    encoder
+                        .multithread(*threads)
+                        .expect("Error setting zstd threads");
+                }
+                CompressingWriter::Zstd(encoder)
+            }
+            StagingCompression::Bzip2 { level } => {
+                CompressingWriter::Bzip2(BzEncoder::new(file, bzip2::Compression::new(*level)))
+            }
+        }
+    }
+
+    // for the files we copy into postgres ourselves, as it can't decompress them
+    pub fn reader<R: Read + 'static>(&self, file: R) -> std::io::Result<Box<dyn Read>> {
+        Ok(match self {
+            StagingCompression::None => Box::new(file),
+            StagingCompression::Gzip { .. } => Box::new(GzDecoder::new(file)),
+            StagingCompression::Zstd { .. } => Box::new(zstd::Decoder::new(file)?),
+            StagingCompression::Bzip2 { .. } => Box::new(BzDecoder::new(file)),
+        })
+    }
+}
+
+// compressed, then encrypted
+pub enum CompressingWriter {
+    None(EncryptingWriter<File>),
+    Gzip(GzEncoder<EncryptingWriter<File>>),
+    Zstd(zstd::Encoder<'static, EncryptingWriter<File>>),
+    Bzip2(BzEncoder<EncryptingWriter<File>>),
+}
+
+impl CompressingWriter {
+    // ends the compressed stream
+    pub fn finish(self) -> std::io::Result<EncryptingWriter<File>> {
+        match self {
+            CompressingWriter::None(file) => Ok(file),
+            CompressingWriter::Gzip(encoder) => encoder.finish(),
+            CompressingWriter::Zstd(encoder) => encoder.finish(),
+            CompressingWriter::Bzip2(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl std::fmt::Debug for CompressingWriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompressingWriter::None(file) => write!(f, "None({:?})", file),
+            CompressingWriter::Gzip(encoder) => write!(f, "Gzip({:?})", encoder.get_ref()),
+            CompressingWriter::Zstd(encoder) => write!(f, "Zstd({:?})", encoder.get_ref()),
+            CompressingWriter::Bzip2(encoder) => write!(f, "Bzip2({:?})", encoder.get_ref()),
+        }
+    }
+}
+
+impl Write for CompressingWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            CompressingWriter::None(file) => file.write(buf),
+            CompressingWriter::Gzip(encoder) => encoder.write(buf),
+            CompressingWriter::Zstd(encoder) => encoder.write(buf),
+            CompressingWriter::Bzip2(encoder) => encoder.write(buf),
+        }
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            CompressingWriter::None(file) => file.flush(),
+            CompressingWriter::Gzip(encoder) => encoder.flush(),
+            CompressingWriter::Zstd(encoder) => encoder.flush(),
+            CompressingWriter::Bzip2(encoder) => encoder.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compression_is_parsed_with_its_default_level() {
+        assert_eq!(
+            StagingCompression::parse("", None, 1),
+            StagingCompression::Gzip { level: 6 }
+        );
+        assert_eq!(
+            StagingCompression::parse("zstd", Some(19), 4),
+            StagingCompression::Zstd {
+                level: 19,
+                threads: 4
+            }
+        );
+        assert_eq!(StagingCompression::parse("none", None, 1).copy_option(), "");
+    }
+
+    #[test]
+    #[should_panic(expected = "out of range")]
+    fn compression_levels_are_checked() {
+        StagingCompression::parse("gzip", Some(12), 1);
+    }
+}
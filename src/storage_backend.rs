@@ -0,0 +1,635 @@
+use async_trait::async_trait;
+use aws_sdk_s3::operation::create_multipart_upload::builders::CreateMultipartUploadFluentBuilder;
+use aws_sdk_s3::operation::put_object::builders::PutObjectFluentBuilder;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{
+    ChecksumAlgorithm, CompletedMultipartUpload, CompletedPart, ServerSideEncryption,
+};
+use aws_sdk_s3::Client as S3Client;
+use aws_sdk_s3::Error as S3Error;
+use backoff::Error as BackoffError;
+use base64::prelude::*;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use lazy_static::lazy_static;
+use md5::{Digest, Md5};
+use std::cell::Cell;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::mpsc;
+
+#[allow(unused_imports)]
+use crate::{function, logger_debug, logger_error, logger_info, logger_panic, logger_warning};
+
+use crate::encryption;
+use crate::exponential_backoff::*;
+
+lazy_static! {
+    static ref STORAGE_LOCATION: StorageLocation = StorageLocation::parse(
+        &std::env::var("STORAGE_BACKEND").unwrap_or_default(),
+        || std::env::var("BUCKET_NAME").expect("BUCKET_NAME env is not set"),
+        || std::env::var("LOCAL_STORAGE_DIRECTORY")
+            .expect("LOCAL_STORAGE_DIRECTORY env is not set"),
+    );
+    // files bigger than this are uploaded in parts, a part at a time
+    static ref S3_MULTIPART_THRESHOLD_BYTES: u64 = env_u64("S3_MULTIPART_THRESHOLD_BYTES", 100_000_000);
+    static ref S3_MULTIPART_PART_BYTES: u64 = env_u64("S3_MULTIPART_PART_BYTES", 50_000_000);
+    static ref S3_MULTIPART_CONCURRENCY: usize = env_u64("S3_MULTIPART_CONCURRENCY", 4) as usize;
+    // uploads are encrypted with s3's own keys, or with this kms key if it's set
+    static ref S3_SSE_KMS_KEY_ID: Option<String> = std::env::var("S3_SSE_KMS_KEY_ID")
+        .ok()
+        .filter(|kms_key_id| !kms_key_id.is_empty());
+    static ref AWS_REGION: String = {
+        let region = std::env::var("AWS_REGION").unwrap_or_else(|_| "us-east-1".to_string());
+        let valid_regions = [
+            "us-east-1", "us-east-2", "us-west-1", "us-west-2",
+            "ca-central-1",
+            "eu-west-1", "eu-west-2", "eu-west-3", "eu-central-1",
+            "ap-northeast-1", "ap-northeast-2", "ap-northeast-3",
+            "ap-southeast-1", "ap-southeast-2", "ap-south-1",
+            "sa-east-1",
+        ];
+        if !valid_regions.contains(&region.as_str()) {
+            logger_warning!(None, None, &format!("Invalid AWS region: {}. Defaulting to us-east-1", region));
+            "us-east-1".to_string()
+        } else {
+            region
+        }
+    };
+}
+
+fn env_u64(name: &str, default: u64) -> u64 {
+    std::env::var(name)
+        .ok()
+        .filter(|value| !value.is_empty())
+        .map(|value| {
+            value
+                .parse::<u64>()
+                .unwrap_or_else(|_| panic!("{} is not a valid integer", name))
+        })
+        .unwrap_or(default)
+}
+
+// s3 won't take parts smaller than this (other than the last), or more of them than this
+const S3_MIN_PART_BYTES: u64 = 5 * 1024 * 1024;
+const S3_MAX_PARTS: u64 = 10_000;
+
+// bigger parts than we asked for if it's the only way to fit the file in
+fn part_bytes(configured_part_bytes: u64, file_length: u64) -> u64 {
+    configured_part_bytes
+        .max(S3_MIN_PART_BYTES)
+        .max(file_length.div_ceil(S3_MAX_PARTS))
+}
+
+// where the files we copy into the target are kept.
+// keys are laid out the same whichever it is
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum StorageLocation {
+    S3 { bucket: String },
+    // read by the target from its own filesystem, e.g. a mounted path
+    Local(PathBuf),
+}
+
+impl StorageLocation {
+    pub fn configured() -> &'static StorageLocation {
+        &STORAGE_LOCATION
+    }
+
+    fn parse(
+        backend: &str,
+        bucket: impl FnOnce() -> String,
+        directory: impl FnOnce() -> String,
+    ) -> StorageLocation {
+        match backend {
+            "s3" | "" => StorageLocation::S3 { bucket: bucket() },
+            "local" => StorageLocation::Local(PathBuf::from(directory())),
+            unknown => panic!("Unknown storage backend: {}", unknown),
+        }
+    }
+
+    // what the target copies from
+    pub fn url(&self, key: &str) -> String {
+        match self {
+            StorageLocation::S3 { bucket } => format!("s3://{}/{}", bucket, key),
+            StorageLocation::Local(directory) => directory.join(key).to_string_lossy().into_owned(),
+        }
+    }
+
+    pub async fn backend(&self) -> Box<dyn StorageBackend> {
+        match self {
+            StorageLocation::S3 { bucket } => Box::new(S3Storage {
+                s3_client: S3Storage::new_s3_client().await,
+                bucket: bucket.clone(),
+            }),
+            StorageLocation::Local(directory) => Box::new(LocalStorage {
+                directory: directory.clone(),
+            }),
+        }
+    }
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug)]
+pub enum StorageError {
+    // reading the local file, or writing it to a local directory
+    IoError(PathBuf, io::Error),
+    S3Error(S3Error),
+}
+
+impl StorageError {
+    pub fn is_transient(&self) -> bool {
+        match self {
+            StorageError::IoError(_, err) => is_transient_io_error(err),
+            // treat s3 errors as transient
+            StorageError::S3Error(_) => true,
+        }
+    }
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::IoError(path, err) => {
+                write!(f, "Io error on {}: {}", path.display(), err)
+            }
+            StorageError::S3Error(err) => write!(f, "S3 error: {}", err),
+        }
+    }
+}
+
+impl Error for StorageError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            StorageError::IoError(_, err) => Some(err),
+            StorageError::S3Error(err) => Some(err),
+        }
+    }
+}
+
+impl From<S3Error> for StorageError {
+    fn from(err: S3Error) -> StorageError {
+        StorageError::S3Error(err)
+    }
+}
+
+// running out of file descriptors or being interrupted goes away by itself,
+// anything else reading or writing the file won't
+fn is_transient_io_error(err: &io::Error) -> bool {
+    // ENFILE and EMFILE
+    matches!(err.raw_os_error(), Some(23) | Some(24))
+        || matches!(
+            err.kind(),
+            io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        )
+}
+
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    // encrypted files are stored decrypted, so the target can read them.
+    // returns how many bytes were stored
+    async fn put_file(&self, key: &str, file_path: &Path) -> Result<u64, StorageError>;
+
+    async fn put_bytes(&self, key: &str, bytes: Vec<u8>) -> Result<(), StorageError>;
+}
+
+pub struct S3Storage {
+    s3_client: S3Client,
+    bucket: String,
+}
+
+impl S3Storage {
+    // also used to archive wal files
+    pub async fn new_s3_client() -> S3Client {
+        logger_info!(
+            None,
+            None,
+            &format!(
+                "Initializing S3 client with region: {}",
+                AWS_REGION.as_str()
+            )
+        );
+
+        let region = aws_config::Region::new(AWS_REGION.to_string());
+        let config = aws_config::defaults(aws_config::BehaviorVersion::latest())
+            .region(region)
+            .load()
+            .await;
+        S3Client::new(&config)
+    }
+
+    // everything we put in s3 is encrypted there too.
+    // also used to archive wal files
+    pub fn with_server_side_encryption(
+        put_object: PutObjectFluentBuilder,
+    ) -> PutObjectFluentBuilder {
+        match S3_SSE_KMS_KEY_ID.as_ref() {
+            Some(kms_key_id) => put_object
+                .server_side_encryption(ServerSideEncryption::AwsKms)
+                .ssekms_key_id(kms_key_id),
+            None => put_object.server_side_encryption(ServerSideEncryption::Aes256),
+        }
+    }
+
+    fn multipart_with_server_side_encryption(
+        create_multipart_upload: CreateMultipartUploadFluentBuilder,
+    ) -> CreateMultipartUploadFluentBuilder {
+        match S3_SSE_KMS_KEY_ID.as_ref() {
+            Some(kms_key_id) => create_multipart_upload
+                .server_side_encryption(ServerSideEncryption::AwsKms)
+                .ssekms_key_id(kms_key_id),
+            None => create_multipart_upload.server_side_encryption(ServerSideEncryption::Aes256),
+        }
+    }
+
+    // s3 checks the body against it, as well as the crc32c the sdk sends
+    fn content_md5(bytes: &[u8]) -> String {
+        BASE64_STANDARD.encode(Md5::digest(bytes))
+    }
+
+    async fn put_object(
+        &self,
+        key: &str,
+        byte_stream: ByteStream,
+        file_length: u64,
+        content_md5: Option<String>,
+    ) -> Result<(), StorageError> {
+        Self::with_server_side_encryption(self.s3_client.put_object())
+            .bucket(&self.bucket)
+            .key(key)
+            .content_length(file_length as i64)
+            .set_content_md5(content_md5)
+            .checksum_algorithm(ChecksumAlgorithm::Crc32C)
+            .body(byte_stream)
+            .send()
+            .await
+            .map_err(S3Error::from)?;
+        Ok(())
+    }
+
+    // the file is read a part at a time (decrypting it if it's encrypted),
+    // and the parts are uploaded concurrently, each retried by itself
+    async fn put_multipart(
+        &self,
+        key: &str,
+        file_path: &Path,
+        file_length: u64,
+    ) -> Result<u64, StorageError> {
+        let part_bytes = part_bytes(*S3_MULTIPART_PART_BYTES, file_length);
+        let upload_id =
+            Self::multipart_with_server_side_encryption(self.s3_client.create_multipart_upload())
+                .bucket(&self.bucket)
+                .key(key)
+                .checksum_algorithm(ChecksumAlgorithm::Crc32C)
+                .send()
+                .await
+                .map_err(S3Error::from)?
+                .upload_id()
+                .expect("s3 didn't return a multipart upload id")
+                .to_owned();
+        logger_info!(
+            None,
+            None,
+            &format!(
+                "multipart_upload_started:{} file_length:{} part_bytes:{}",
+                key, file_length, part_bytes
+            )
+        );
+        let result = self
+            .upload_parts(key, &upload_id, file_path, file_length, part_bytes)
+            .await;
+        match result {
+            Ok((parts, uploaded_bytes)) => {
+                self.s3_client
+                    .complete_multipart_upload()
+                    .bucket(&self.bucket)
+                    .key(key)
+                    .upload_id(&upload_id)
+                    .multipart_upload(
+                        CompletedMultipartUpload::builder()
+                            .set_parts(Some(parts))
+                            .build(),
+                    )
+                    .send()
+                    .await
+                    .map_err(S3Error::from)?;
+                Ok(uploaded_bytes)
+            }
+            Err(err) => {
+                // otherwise s3 keeps (and charges for) the parts we did upload
+                let aborted = self
+                    .s3_client
+                    .abort_multipart_upload()
+                    .bucket(&self.bucket)
+                    .key(key)
+                    .upload_id(&upload_id)
+                    .send()
+                    .await;
+                if let Err(abort_err) = aborted {
+                    logger_warning!(
+                        None,
+                        None,
+                        &format!(
+                            "multipart_upload_abort_failed:{} error:{}",
+                            key,
+                            S3Error::from(abort_err)
+                        )
+                    );
+                }
+                Err(err)
+            }
+        }
+    }
+
+    async fn upload_parts(
+        &self,
+        key: &str,
+        upload_id: &str,
+        file_path: &Path,
+        file_length: u64,
+        part_bytes: u64,
+    ) -> Result<(Vec<CompletedPart>, u64), StorageError> {
+        // read on a blocking thread, at most a part per upload ahead of the uploads.
+        // each part comes with how much of the file it took, as encrypted files are bigger
+        // on disk than what we upload
+        let (sender, receiver) =
+            mpsc::channel::<io::Result<(Vec<u8>, u64)>>(*S3_MULTIPART_CONCURRENCY);
+        let reader_path = file_path.to_path_buf();
+        let reader = tokio::task::spawn_blocking(move || {
+            let file_bytes_read = Rc::new(Cell::new(0));
+            let result = File::open(&reader_path)
+                .map(|file| CountingReader {
+                    inner: file,
+                    bytes_read: file_bytes_read.clone(),
+                })
+                .and_then(encryption::decrypting_reader)
+                .and_then(|reader| {
+                    let mut reader = reader.take(u64::MAX);
+                    let mut sent_file_bytes = 0;
+                    loop {
+                        let mut part = Vec::with_capacity(part_bytes as usize);
+                        reader.set_limit(part_bytes);
+                        reader.read_to_end(&mut part)?;
+                        let part_file_bytes = file_bytes_read.get() - sent_file_bytes;
+                        sent_file_bytes += part_file_bytes;
+                        // done, or the uploads have stopped so there's no one to send it to
+                        if part.is_empty()
+                            || sender.blocking_send(Ok((part, part_file_bytes))).is_err()
+                        {
+                            return Ok(());
+                        }
+                    }
+                });
+            if let Err(err) = result {
+                let _ = sender.blocking_send(Err(err));
+            }
+        });
+
+        let uploaded_bytes = AtomicU64::new(0);
+        let uploaded_file_bytes = AtomicU64::new(0);
+        let logged_tenths = AtomicU64::new(0);
+        let parts = stream::unfold(receiver, |mut receiver| async {
+            receiver.recv().await.map(|part| (part, receiver))
+        })
+        .enumerate()
+        .map(|(index, part)| {
+            let uploaded_bytes = &uploaded_bytes;
+            let uploaded_file_bytes = &uploaded_file_bytes;
+            let logged_tenths = &logged_tenths;
+            async move {
+                let (part, part_file_bytes) =
+                    part.map_err(|err| StorageError::IoError(file_path.to_path_buf(), err))?;
+                let part_length = part.len() as u64;
+                let completed_part = self
+                    .upload_part(key, upload_id, index as i32 + 1, part)
+                    .await?;
+                let uploaded =
+                    uploaded_bytes.fetch_add(part_length, Ordering::Relaxed) + part_length;
+                let uploaded_file = uploaded_file_bytes
+                    .fetch_add(part_file_bytes, Ordering::Relaxed)
+                    + part_file_bytes;
+                // every 10%, so very large files show they're getting somewhere
+                let tenths = (uploaded_file * 10 / file_length.max(1)).min(10);
+                if logged_tenths.fetch_max(tenths, Ordering::Relaxed) < tenths {
+                    logger_info!(
+                        None,
+                        None,
+                        &format!(
+                            "multipart_upload_progress:{} percent:{} uploaded_bytes:{}",
+                            key,
+                            tenths * 10,
+                            uploaded
+                        )
+                    );
+                }
+                Ok::<CompletedPart, StorageError>(completed_part)
+            }
+        })
+        .buffer_unordered(*S3_MULTIPART_CONCURRENCY)
+        .try_collect::<Vec<CompletedPart>>()
+        .await;
+        // dropping the parts stream stops the reader if we gave up part way through
+        let joined = reader
+            .await
+            .map_err(|err| StorageError::IoError(file_path.to_path_buf(), io::Error::other(err)));
+        let mut parts = parts?;
+        joined?;
+        parts.sort_by_key(|part| part.part_number());
+        Ok((parts, uploaded_bytes.load(Ordering::Relaxed)))
+    }
+
+    async fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number: i32,
+        part: Vec<u8>,
+    ) -> Result<CompletedPart, StorageError> {
+        let content_md5 = Self::content_md5(&part);
+        let uploaded = retry(default_exponential_backoff(), || async {
+            self.s3_client
+                .upload_part()
+                .bucket(&self.bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .part_number(part_number)
+                .content_length(part.len() as i64)
+                .content_md5(&content_md5)
+                .checksum_algorithm(ChecksumAlgorithm::Crc32C)
+                .body(ByteStream::from(part.clone()))
+                .send()
+                .await
+                .map_err(|err| BackoffError::transient(StorageError::S3Error(err.into())))
+        })
+        .await?;
+        Ok(CompletedPart::builder()
+            .part_number(part_number)
+            .e_tag(
+                uploaded
+                    .e_tag()
+                    .expect("s3 didn't return an etag for the part"),
+            )
+            .set_checksum_crc32_c(uploaded.checksum_crc32_c().map(str::to_owned))
+            .build())
+    }
+}
+
+#[async_trait]
+impl StorageBackend for S3Storage {
+    async fn put_file(&self, key: &str, file_path: &Path) -> Result<u64, StorageError> {
+        let io_error = |err| StorageError::IoError(file_path.to_path_buf(), err);
+        let file_length = fs::metadata(file_path).map_err(io_error)?.len();
+        if file_length > *S3_MULTIPART_THRESHOLD_BYTES {
+            return self.put_multipart(key, file_path, file_length).await;
+        }
+        // encrypted csv files are decrypted in memory, so they're never on disk in plaintext.
+        // others are streamed from the file, once we've read it for its md5
+        let reader_path = file_path.to_path_buf();
+        let (decrypted, content_md5) = tokio::task::spawn_blocking(move || {
+            if encryption::is_encrypted(&reader_path)? {
+                let decrypted = encryption::read_to_end(&reader_path)?;
+                let content_md5 = Self::content_md5(&decrypted);
+                Ok((Some(decrypted), content_md5))
+            } else {
+                let mut md5 = Md5::new();
+                io::copy(&mut File::open(&reader_path)?, &mut md5)?;
+                Ok((None, BASE64_STANDARD.encode(md5.finalize())))
+            }
+        })
+        .await
+        .map_err(|err| io_error(io::Error::other(err)))?
+        .map_err(io_error)?;
+        if let Some(decrypted) = decrypted {
+            let file_length = decrypted.len() as u64;
+            self.put_object(
+                key,
+                ByteStream::from(decrypted),
+                file_length,
+                Some(content_md5),
+            )
+            .await?;
+            Ok(file_length)
+        } else {
+            let byte_stream = ByteStream::from_path(file_path)
+                .await
+                .map_err(|err| io_error(io::Error::other(err)))?;
+            self.put_object(key, byte_stream, file_length, Some(content_md5))
+                .await?;
+            Ok(file_length)
+        }
+    }
+
+    async fn put_bytes(&self, key: &str, bytes: Vec<u8>) -> Result<(), StorageError> {
+        let file_length = bytes.len() as u64;
+        let content_md5 = Self::content_md5(&bytes);
+        self.put_object(key, ByteStream::from(bytes), file_length, Some(content_md5))
+            .await
+    }
+}
+
+// how much of a file we've read
+struct CountingReader<R: Read> {
+    inner: R,
+    bytes_read: Rc<Cell<u64>>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.bytes_read.set(self.bytes_read.get() + read as u64);
+        Ok(read)
+    }
+}
+
+pub struct LocalStorage {
+    directory: PathBuf,
+}
+
+impl LocalStorage {
+    fn path(&self, key: &str) -> PathBuf {
+        self.directory.join(key)
+    }
+
+    // renamed into place, so the target never reads a partial file.
+    // on a blocking thread, as it's all file io
+    async fn write<R, F>(&self, key: &str, open: F) -> Result<u64, StorageError>
+    where
+        R: Read,
+        F: FnOnce() -> Result<R, (PathBuf, io::Error)> + Send + 'static,
+    {
+        let path = self.path(key);
+        let partial_path = self.directory.join(format!("{}.partial", key));
+        tokio::task::spawn_blocking(move || {
+            let mut reader = open()?;
+            let io_error = |err| (path.clone(), err);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).map_err(io_error)?;
+            }
+            let mut partial_file = File::create(&partial_path).map_err(io_error)?;
+            let file_length = io::copy(&mut reader, &mut partial_file).map_err(io_error)?;
+            partial_file.sync_all().map_err(io_error)?;
+            fs::rename(&partial_path, &path).map_err(io_error)?;
+            Ok(file_length)
+        })
+        .await
+        .map_err(|err| (self.path(key), io::Error::other(err)))
+        .and_then(|result| result)
+        .map_err(|(path, err)| StorageError::IoError(path, err))
+    }
+}
+
+#[async_trait]
+impl StorageBackend for LocalStorage {
+    async fn put_file(&self, key: &str, file_path: &Path) -> Result<u64, StorageError> {
+        let file_path = file_path.to_path_buf();
+        self.write(key, move || {
+            File::open(&file_path)
+                .and_then(encryption::decrypting_reader)
+                .map_err(|err| (file_path, err))
+        })
+        .await
+    }
+
+    async fn put_bytes(&self, key: &str, bytes: Vec<u8>) -> Result<(), StorageError> {
+        self.write(key, move || Ok(io::Cursor::new(bytes)))
+            .await
+            .map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_are_laid_out_the_same_for_each_backend() {
+        let key = "re_dms/output_wal/1/1_public.users_insert.csv.gz";
+        let s3 = StorageLocation::parse("", || "bucket".to_owned(), || unreachable!());
+        assert_eq!(
+            s3.url(key),
+            "s3://bucket/re_dms/output_wal/1/1_public.users_insert.csv.gz"
+        );
+        let local =
+            StorageLocation::parse("local", || unreachable!(), || "/mnt/staging".to_owned());
+        assert_eq!(
+            local.url(key),
+            "/mnt/staging/re_dms/output_wal/1/1_public.users_insert.csv.gz"
+        );
+    }
+
+    #[test]
+    fn multipart_parts_are_sized_for_s3() {
+        assert_eq!(part_bytes(50_000_000, 1_000_000_000), 50_000_000);
+        assert_eq!(part_bytes(1_000, 1_000_000_000), S3_MIN_PART_BYTES);
+        assert_eq!(part_bytes(50_000_000, 1_000_000_000_000), 100_000_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown storage backend")]
+    fn unknown_backends_are_rejected() {
+        StorageLocation::parse("gcs", || unreachable!(), || unreachable!());
+    }
+}
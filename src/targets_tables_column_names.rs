@@ -1,5 +1,4 @@
 use deadpool_postgres::{Client, ManagerConfig, Pool, RecyclingMethod, Runtime};
-use lazy_static::lazy_static;
 use openssl::ssl::{SslConnector, SslMethod};
 use postgres_openssl::MakeTlsConnector;
 use serde::Deserialize;
@@ -14,10 +13,6 @@ use crate::{function, logger_debug, logger_error, logger_info, logger_panic};
 
 use crate::parser::{ColumnName, SchemaAndTable, TableName};
 
-lazy_static! {
-    static ref TARGET_SCHEMA_NAME: Option<String> = std::env::var("TARGET_SCHEMA_NAME").ok();
-}
-
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
 pub struct ColumnInfo {
     pub name: ColumnName,
@@ -29,11 +24,12 @@ pub struct Table {
     pub name: TableName,
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 struct TableHolder {
     tables: HashMap<TableName, Table>,
 }
 
+#[derive(Clone)]
 pub struct TargetsTablesColumnNames {
     connection_pool: Option<Pool>,
     table_holder: TableHolder,
@@ -117,7 +113,7 @@ impl TargetsTablesColumnNames {
 
     pub fn get_by_name(&self, table_name_with_schema: &TableName) -> Option<Table> {
         // Strip the schema name in order to match, as it's possible schemas will differ between source and target
-        let (_, table_name) = table_name_with_schema.schema_and_table_name();
+        let (_, table_name) = table_name_with_schema.original_schema_and_table_name();
         match self
             .table_holder
             .tables
@@ -132,7 +128,71 @@ impl TargetsTablesColumnNames {
         self.table_holder.tables.len()
     }
 
-    fn create_connection_pool() -> Pool {
+    // The methods below keep the cache in line with ddl we apply ourselves,
+    // so we don't have to refetch everything from the target after every change.
+    // Like get_by_name they key on the table name without the schema.
+    fn cache_key(table_name_with_schema: &TableName) -> TableName {
+        let (_, table_name) = table_name_with_schema.original_schema_and_table_name();
+        TableName::new(table_name.to_string())
+    }
+
+    pub fn add_table(&mut self, table_name_with_schema: &TableName, column_names: Vec<ColumnName>) {
+        let key = Self::cache_key(table_name_with_schema);
+        let column_info = column_names
+            .into_iter()
+            .map(|name| ColumnInfo { name })
+            .collect();
+        self.table_holder.tables.insert(
+            key.clone(),
+            Table {
+                name: key,
+                column_info,
+            },
+        );
+    }
+
+    pub fn remove_table(&mut self, table_name_with_schema: &TableName) {
+        self.table_holder
+            .tables
+            .remove(&Self::cache_key(table_name_with_schema));
+    }
+
+    pub fn rename_table(&mut self, old_table_name: &TableName, new_table_name: &TableName) {
+        if let Some(mut table) = self
+            .table_holder
+            .tables
+            .remove(&Self::cache_key(old_table_name))
+        {
+            let new_key = Self::cache_key(new_table_name);
+            table.name = new_key.clone();
+            self.table_holder.tables.insert(new_key, table);
+        }
+    }
+
+    pub fn rename_column(
+        &mut self,
+        table_name_with_schema: &TableName,
+        old_column_name: &ColumnName,
+        new_column_name: &ColumnName,
+    ) {
+        if let Some(table) = self
+            .table_holder
+            .tables
+            .get_mut(&Self::cache_key(table_name_with_schema))
+        {
+            let old_column_info = ColumnInfo {
+                name: old_column_name.clone(),
+            };
+            if table.column_info.remove(&old_column_info) {
+                table.column_info.insert(ColumnInfo {
+                    name: new_column_name.clone(),
+                });
+            }
+        }
+    }
+
+    // also used to read our checkpoints
+    pub fn create_connection_pool() -> Pool {
         // fail fast
         let mut cfg = Config::from_env().expect("Unable to build config from environment");
         cfg.pg.manager = Some(ManagerConfig {
@@ -167,21 +227,20 @@ impl TargetsTablesColumnNames {
         }
     }
 
+    // the tables in the target schema, or in every schema without one
     pub async fn refresh(
         &mut self,
+        target_schema: Option<&str>,
     ) -> Result<&mut TargetsTablesColumnNames, TargetsTablesColumnNamesError> {
         self.connection_pool = Some(TargetsTablesColumnNames::create_connection_pool());
         let client = self.get_connection_from_pool().await?;
 
-        let schema_filter = match &*TARGET_SCHEMA_NAME {
+        let schema_filter = match target_schema {
             Some(_) => "table_schema = $1",
             None => "$1 = $1",
         };
 
-        let schema_name = match &*TARGET_SCHEMA_NAME {
-            Some(schema_name) => schema_name,
-            None => "none",
-        };
+        let schema_name = target_schema.unwrap_or("none");
 
         let query = format!(
             "SELECT table_name, column_name
@@ -0,0 +1,504 @@
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::Error as S3Error;
+use backoff::Error as BackoffError;
+use glob::glob;
+use lazy_static::lazy_static;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::OnceCell;
+use tokio::task::JoinHandle;
+
+#[allow(unused_imports)]
+use crate::{function, logger_debug, logger_error, logger_info, logger_panic, logger_warning};
+
+use crate::encryption;
+use crate::exponential_backoff::*;
+use crate::storage_backend::S3Storage;
+use crate::wal_file_manager::{wal_file_number_from_path, WalCompression};
+
+lazy_static! {
+    // s3://bucket/prefix/ or a local directory. Archiving is off if it's not set
+    static ref WAL_ARCHIVE_LOCATION: Option<ArchiveLocation> =
+        std::env::var("WAL_ARCHIVE_LOCATION")
+            .ok()
+            .filter(|location| !location.is_empty())
+            .map(|location| ArchiveLocation::parse(&location));
+    // archived wal files older than this are removed. Kept forever if it's not set
+    static ref WAL_ARCHIVE_RETENTION_DAYS: Option<u64> =
+        std::env::var("WAL_ARCHIVE_RETENTION_DAYS").ok().map(|days| {
+            days.parse::<u64>()
+                .expect("WAL_ARCHIVE_RETENTION_DAYS is not a valid integer")
+        });
+    static ref ARCHIVE_TASKS: Mutex<Vec<JoinHandle<()>>> = Mutex::new(vec![]);
+    // shared by everything we archive, fetch and remove
+    static ref S3_CLIENT: OnceCell<aws_sdk_s3::Client> = OnceCell::new();
+    // when we last looked for expired wal files
+    static ref LAST_EXPIRED: Mutex<Option<Instant>> = Mutex::new(None);
+}
+
+// retention is in days, so there's no need to list the archive for every wal file
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+async fn s3_client() -> &'static aws_sdk_s3::Client {
+    S3_CLIENT.get_or_init(S3Storage::new_s3_client).await
+}
+
+// true at most once an interval, and records that we're expiring now
+fn expiry_due(last_expired: &mut Option<Instant>, interval: Duration) -> bool {
+    let due = last_expired.is_none_or(|last_expired| last_expired.elapsed() >= interval);
+    if due {
+        *last_expired = Some(Instant::now());
+    }
+    due
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum ArchiveLocation {
+    S3 { bucket: String, prefix: String },
+    Local(PathBuf),
+}
+
+impl ArchiveLocation {
+    fn parse(location: &str) -> ArchiveLocation {
+        match location.strip_prefix("s3://") {
+            Some(bucket_and_prefix) => {
+                let (bucket, prefix) = bucket_and_prefix
+                    .split_once('/')
+                    .unwrap_or((bucket_and_prefix, ""));
+                let prefix = if prefix.is_empty() || prefix.ends_with('/') {
+                    prefix.to_string()
+                } else {
+                    format!("{}/", prefix)
+                };
+                ArchiveLocation::S3 {
+                    bucket: bucket.to_string(),
+                    prefix,
+                }
+            }
+            None => ArchiveLocation::Local(PathBuf::from(location)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum WalArchiveError {
+    Io(std::io::Error),
+    S3(S3Error),
+    NotConfigured,
+}
+
+impl fmt::Display for WalArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WalArchiveError::Io(err) => write!(f, "Io error: {}", err),
+            WalArchiveError::S3(err) => write!(f, "S3 error: {}", err),
+            WalArchiveError::NotConfigured => write!(f, "WAL_ARCHIVE_LOCATION env is not set"),
+        }
+    }
+}
+
+impl Error for WalArchiveError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WalArchiveError::Io(err) => Some(err),
+            WalArchiveError::S3(err) => Some(err),
+            WalArchiveError::NotConfigured => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for WalArchiveError {
+    fn from(err: std::io::Error) -> Self {
+        WalArchiveError::Io(err)
+    }
+}
+
+impl From<S3Error> for WalArchiveError {
+    fn from(err: S3Error) -> Self {
+        WalArchiveError::S3(err)
+    }
+}
+
+type Result<T, E = WalArchiveError> = std::result::Result<T, E>;
+
+pub struct WalArchive {}
+
+impl WalArchive {
+    fn pending_directory(wal_directory: &Path) -> PathBuf {
+        wal_directory.join("archive_pending")
+    }
+
+    // called with a sealed wal file, before it can be removed.
+    // the copying happens in the background
+    pub fn archive(wal_file_path: &Path) {
+        if WAL_ARCHIVE_LOCATION.is_none() {
+            return;
+        }
+        let pending_directory = Self::pending_directory(
+            wal_file_path
+                .parent()
+                .expect("wal file should be in the wal directory"),
+        );
+        fs::create_dir_all(&pending_directory)
+            .expect("Unable to create wal archive pending directory");
+        let pending_path = pending_directory.join(
+            wal_file_path
+                .file_name()
+                .expect("wal file should have a file name"),
+        );
+        // already picked up by archive_pending on startup
+        if pending_path.exists() {
+            return;
+        }
+        // a hard link, so it's still there after the wal file is removed
+        fs::hard_link(wal_file_path, &pending_path).expect("Unable to link wal file for archiving");
+        Self::spawn_archive(pending_path);
+    }
+
+    // anything we didn't get to archive before we stopped last time
+    pub fn archive_pending(wal_directory: &Path) {
+        if WAL_ARCHIVE_LOCATION.is_none() {
+            return;
+        }
+        let pending_glob = Self::pending_directory(wal_directory).join("*.wal*");
+        let mut pending_paths: Vec<PathBuf> = glob(
+            pending_glob
+                .to_str()
+                .expect("Error creating wal archive pending glob string"),
+        )
+        .expect("Error running wal archive pending glob")
+        .filter_map(std::result::Result::ok)
+        .collect();
+        pending_paths.sort();
+        for pending_path in pending_paths {
+            Self::spawn_archive(pending_path);
+        }
+    }
+
+    fn spawn_archive(pending_path: PathBuf) {
+        let handle = tokio::spawn(async move {
+            if let Err(err) = Self::archive_pending_file(&pending_path).await {
+                // it stays pending, and we try again on restart
+                logger_error!(
+                    wal_file_number_from_path(&pending_path),
+                    None,
+                    &format!("wal_archive_failed path:{:?} error:{}", pending_path, err)
+                );
+            }
+        });
+        ARCHIVE_TASKS
+            .lock()
+            .expect("Error locking wal archive tasks")
+            .push(handle);
+    }
+
+    // waits for any archiving we've started
+    pub async fn finish() {
+        let handles: Vec<JoinHandle<()>> = ARCHIVE_TASKS
+            .lock()
+            .expect("Error locking wal archive tasks")
+            .drain(..)
+            .collect();
+        for handle in handles {
+            handle.await.expect("Error joining wal archive task");
+        }
+    }
+
+    async fn archive_pending_file(pending_path: &Path) -> Result<()> {
+        let location = WAL_ARCHIVE_LOCATION
+            .as_ref()
+            .ok_or(WalArchiveError::NotConfigured)?;
+        // compressing is cpu heavy, so keep it off the async threads
+        let uncompressed_path = pending_path.to_path_buf();
+        let compressed_path = tokio::task::spawn_blocking(move || compress(&uncompressed_path))
+            .await
+            .expect("Error joining wal archive compression")?;
+        let file_name = file_name(&compressed_path);
+        match location {
+            ArchiveLocation::Local(directory) => {
+                fs::create_dir_all(directory)?;
+                // renamed into place, so the archive never has a partial file
+                let partial_path = directory.join(format!("{}.partial", file_name));
+                fs::copy(&compressed_path, &partial_path)?;
+                fs::rename(&partial_path, directory.join(&file_name))?;
+            }
+            ArchiveLocation::S3 { bucket, prefix } => {
+                let s3_client = s3_client().await;
+                let key = format!("{}{}", prefix, file_name);
+                retry(default_exponential_backoff(), || async {
+                    let body = ByteStream::from_path(&compressed_path)
+                        .await
+                        .map_err(|err| BackoffError::permanent(WalArchiveError::Io(err.into())))?;
+                    S3Storage::with_server_side_encryption(s3_client.put_object())
+                        .bucket(bucket)
+                        .key(&key)
+                        .body(body)
+                        .send()
+                        .await
+                        .map_err(|err| BackoffError::transient(WalArchiveError::S3(err.into())))?;
+                    Ok::<(), BackoffError<WalArchiveError>>(())
+                })
+                .await?;
+            }
+        }
+        fs::remove_file(&compressed_path)?;
+        logger_info!(
+            wal_file_number_from_path(&compressed_path),
+            None,
+            &format!("archived_wal_file:{}", file_name)
+        );
+        Self::remove_expired(location).await
+    }
+
+    async fn remove_expired(location: &ArchiveLocation) -> Result<()> {
+        let retention_days = match *WAL_ARCHIVE_RETENTION_DAYS {
+            Some(retention_days) => retention_days,
+            None => return Ok(()),
+        };
+        if !expiry_due(
+            &mut LAST_EXPIRED
+                .lock()
+                .expect("Error locking wal archive expiry"),
+            EXPIRE_INTERVAL,
+        ) {
+            return Ok(());
+        }
+        let expires_before = SystemTime::now() - Duration::from_secs(retention_days * 24 * 60 * 60);
+        for archived in Self::list(location).await? {
+            if archived.modified < expires_before {
+                match location {
+                    ArchiveLocation::Local(directory) => {
+                        fs::remove_file(directory.join(&archived.file_name))?
+                    }
+                    ArchiveLocation::S3 { bucket, prefix } => {
+                        s3_client()
+                            .await
+                            .delete_object()
+                            .bucket(bucket)
+                            .key(format!("{}{}", prefix, archived.file_name))
+                            .send()
+                            .await
+                            .map_err(S3Error::from)?;
+                    }
+                }
+                logger_info!(
+                    Some(archived.wal_file_number),
+                    None,
+                    &format!("removed_expired_archived_wal_file:{}", archived.file_name)
+                );
+            }
+        }
+        Ok(())
+    }
+
+    async fn list(location: &ArchiveLocation) -> Result<Vec<ArchivedWalFile>> {
+        let mut archived = vec![];
+        match location {
+            ArchiveLocation::Local(directory) => {
+                if !directory.exists() {
+                    return Ok(archived);
+                }
+                for entry in fs::read_dir(directory)? {
+                    let entry = entry?;
+                    archived.extend(ArchivedWalFile::new(
+                        file_name(&entry.path()),
+                        entry.metadata()?.modified()?,
+                    ));
+                }
+            }
+            ArchiveLocation::S3 { bucket, prefix } => {
+                let s3_client = s3_client().await;
+                let mut continuation_token = None;
+                loop {
+                    let response = s3_client
+                        .list_objects_v2()
+                        .bucket(bucket)
+                        .prefix(prefix)
+                        .set_continuation_token(continuation_token)
+                        .send()
+                        .await
+                        .map_err(S3Error::from)?;
+                    for object in response.contents() {
+                        let modified = object
+                            .last_modified()
+                            .map(|modified| {
+                                SystemTime::UNIX_EPOCH
+                                    + Duration::from_secs(modified.secs().max(0) as u64)
+                            })
+                            .unwrap_or_else(SystemTime::now);
+                        let key = object.key().unwrap_or_default();
+                        archived.extend(ArchivedWalFile::new(
+                            key.strip_prefix(prefix.as_str()).unwrap_or(key).to_string(),
+                            modified,
+                        ));
+                    }
+                    continuation_token = response.next_continuation_token().map(str::to_string);
+                    if continuation_token.is_none() {
+                        break;
+                    }
+                }
+            }
+        }
+        archived.sort_by_key(|archived| archived.wal_file_number);
+        Ok(archived)
+    }
+
+    pub fn configured() -> bool {
+        WAL_ARCHIVE_LOCATION.is_some()
+    }
+
+    // copies the archived wal files numbered first to last into the directory, to be replayed,
+    // apart from those we already have. returns how many there were
+    pub async fn fetch(
+        first: u64,
+        last: u64,
+        directory: &Path,
+        already_fetched: &[u64],
+    ) -> Result<usize> {
+        let location = WAL_ARCHIVE_LOCATION
+            .as_ref()
+            .ok_or(WalArchiveError::NotConfigured)?;
+        fs::create_dir_all(directory)?;
+        let to_fetch: Vec<ArchivedWalFile> = Self::list(location)
+            .await?
+            .into_iter()
+            .filter(|archived| (first..=last).contains(&archived.wal_file_number))
+            .filter(|archived| !already_fetched.contains(&archived.wal_file_number))
+            .collect();
+        for archived in to_fetch.iter() {
+            let partial_path = directory.join(format!("{}.partial", archived.file_name));
+            match location {
+                ArchiveLocation::Local(archive_directory) => {
+                    fs::copy(archive_directory.join(&archived.file_name), &partial_path)?;
+                }
+                ArchiveLocation::S3 { bucket, prefix } => {
+                    let response = s3_client()
+                        .await
+                        .get_object()
+                        .bucket(bucket)
+                        .key(format!("{}{}", prefix, archived.file_name))
+                        .send()
+                        .await
+                        .map_err(S3Error::from)?;
+                    let mut body = response.body.into_async_read();
+                    let mut file = tokio::fs::File::create(&partial_path).await?;
+                    tokio::io::copy_buf(&mut body, &mut file).await?;
+                }
+            }
+            fs::rename(&partial_path, directory.join(&archived.file_name))?;
+            logger_info!(
+                Some(archived.wal_file_number),
+                None,
+                &format!("fetched_archived_wal_file:{}", archived.file_name)
+            );
+        }
+        Ok(to_fetch.len())
+    }
+}
+
+#[derive(Debug)]
+struct ArchivedWalFile {
+    file_name: String,
+    wal_file_number: u64,
+    modified: SystemTime,
+}
+
+impl ArchivedWalFile {
+    // None for anything that isn't an archived wal file
+    fn new(file_name: String, modified: SystemTime) -> Option<ArchivedWalFile> {
+        if file_name.ends_with(".partial") {
+            return None;
+        }
+        let wal_file_number = wal_file_number_from_path(Path::new(&file_name))?;
+        Some(ArchivedWalFile {
+            file_name,
+            wal_file_number,
+            modified,
+        })
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .and_then(|file_name| file_name.to_str())
+        .expect("wal file name should be utf-8")
+        .to_string()
+}
+
+// archived wal files are always compressed, with zstd unless they already were.
+// encrypted ones are archived as they are, as they don't compress
+fn compress(pending_path: &Path) -> std::io::Result<PathBuf> {
+    if WalCompression::from_path(pending_path) != WalCompression::None
+        || encryption::is_encrypted(pending_path)?
+    {
+        return Ok(pending_path.to_path_buf());
+    }
+    // renamed into place, so a partial file is never picked up as pending
+    let partial_path = pending_path.with_extension("partial");
+    let mut encoder = zstd::Encoder::new(File::create(&partial_path)?, 0)?;
+    std::io::copy(&mut File::open(pending_path)?, &mut encoder)?;
+    encoder.finish()?;
+    let compressed_path = pending_path.with_extension("wal.zst");
+    fs::rename(&partial_path, &compressed_path)?;
+    fs::remove_file(pending_path)?;
+    Ok(compressed_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn archive_locations() {
+        assert_eq!(
+            ArchiveLocation::parse("s3://bucket/wal_archive"),
+            ArchiveLocation::S3 {
+                bucket: "bucket".to_string(),
+                prefix: "wal_archive/".to_string()
+            }
+        );
+        assert_eq!(
+            ArchiveLocation::parse("s3://bucket"),
+            ArchiveLocation::S3 {
+                bucket: "bucket".to_string(),
+                prefix: "".to_string()
+            }
+        );
+        assert_eq!(
+            ArchiveLocation::parse("/var/re_dms/wal_archive"),
+            ArchiveLocation::Local(PathBuf::from("/var/re_dms/wal_archive"))
+        );
+    }
+
+    #[test]
+    fn pending_wal_files_are_compressed() {
+        let directory = PathBuf::from("/tmp/wal_archive_testing");
+        if directory.exists() {
+            fs::remove_dir_all(&directory).unwrap();
+        }
+        fs::create_dir_all(&directory).unwrap();
+        let pending_path = directory.join("000000000000001F.wal");
+        fs::write(&pending_path, "BEGIN 1\nCOMMIT 1\n").unwrap();
+        let compressed_path = compress(&pending_path).unwrap();
+        assert_eq!(compressed_path, directory.join("000000000000001F.wal.zst"));
+        assert!(!pending_path.exists());
+        assert_eq!(
+            zstd::decode_all(File::open(&compressed_path).unwrap()).unwrap(),
+            b"BEGIN 1\nCOMMIT 1\n"
+        );
+        // already compressed
+        assert_eq!(compress(&compressed_path).unwrap(), compressed_path);
+    }
+
+    #[test]
+    fn expiry_runs_at_most_once_an_interval() {
+        let mut last_expired = None;
+        assert!(expiry_due(&mut last_expired, Duration::from_secs(600)));
+        assert!(!expiry_due(&mut last_expired, Duration::from_secs(600)));
+        assert!(expiry_due(&mut last_expired, Duration::ZERO));
+    }
+}
@@ -0,0 +1,271 @@
+use glob::glob;
+use lazy_static::lazy_static;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+#[allow(unused_imports)]
+use crate::{function, logger_debug, logger_error, logger_info, logger_panic, logger_warning};
+
+use crate::database_writer::StatsdWrapper;
+use crate::shutdown_handler::ShutdownHandler;
+
+lazy_static! {
+    // percentages of the wal disk that's free
+    static ref WAL_DISK_FREE_PERCENT_WARNING: f64 =
+        free_percent_from_env("WAL_DISK_FREE_PERCENT_WARNING", 20.0);
+    static ref WAL_DISK_FREE_PERCENT_PAUSE: f64 =
+        free_percent_from_env("WAL_DISK_FREE_PERCENT_PAUSE", 10.0);
+    static ref WAL_DISK_FREE_PERCENT_SHUTDOWN: f64 =
+        free_percent_from_env("WAL_DISK_FREE_PERCENT_SHUTDOWN", 5.0);
+    // the wal files waiting to be applied. No limit if it's not set
+    static ref MAX_WAL_BACKLOG_BYTES: Option<u64> =
+        std::env::var("MAX_WAL_BACKLOG_BYTES")
+            .ok()
+            .filter(|bytes| !bytes.is_empty())
+            .map(|bytes| {
+                bytes
+                    .parse::<u64>()
+                    .expect("MAX_WAL_BACKLOG_BYTES is not a valid integer")
+            });
+    static ref SECONDS_BETWEEN_DISK_SPACE_CHECKS: u64 =
+        std::env::var("SECONDS_BETWEEN_DISK_SPACE_CHECKS")
+            .unwrap_or("10".to_string())
+            .parse::<u64>()
+            .expect("SECONDS_BETWEEN_DISK_SPACE_CHECKS is not a valid integer");
+}
+
+fn free_percent_from_env(name: &str, default: f64) -> f64 {
+    std::env::var(name)
+        .map(|percent| {
+            percent
+                .parse::<f64>()
+                .unwrap_or_else(|_| panic!("{} is not a valid number", name))
+        })
+        .unwrap_or(default)
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, PartialOrd, Ord)]
+pub enum DiskPressure {
+    Normal,
+    // log and carry on
+    Warning,
+    // stop reading input until the backlog is applied
+    Pause,
+    // too close to full to carry on
+    Shutdown,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct DiskUsage {
+    free_bytes: u64,
+    total_bytes: u64,
+    backlog_bytes: u64,
+    backlog_files: u64,
+}
+
+impl DiskUsage {
+    fn measure(wal_directory: &Path) -> std::io::Result<DiskUsage> {
+        let mut usage = DiskUsage {
+            free_bytes: fs2::available_space(wal_directory)?,
+            total_bytes: fs2::total_space(wal_directory)?,
+            ..Default::default()
+        };
+        // compressed or not
+        let wal_glob = wal_directory.join("*.wal*");
+        for path in glob(
+            wal_glob
+                .to_str()
+                .expect("Error creating wal backlog glob string"),
+        )
+        .expect("Error running wal backlog glob")
+        .filter_map(Result::ok)
+        {
+            usage.backlog_bytes += std::fs::metadata(path)?.len();
+            usage.backlog_files += 1;
+        }
+        Ok(usage)
+    }
+
+    fn free_percent(&self) -> f64 {
+        if self.total_bytes == 0 {
+            return 100.0;
+        }
+        self.free_bytes as f64 * 100.0 / self.total_bytes as f64
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Thresholds {
+    warning_free_percent: f64,
+    pause_free_percent: f64,
+    shutdown_free_percent: f64,
+    max_backlog_bytes: Option<u64>,
+}
+
+impl Thresholds {
+    fn from_env() -> Thresholds {
+        Thresholds {
+            warning_free_percent: *WAL_DISK_FREE_PERCENT_WARNING,
+            pause_free_percent: *WAL_DISK_FREE_PERCENT_PAUSE,
+            shutdown_free_percent: *WAL_DISK_FREE_PERCENT_SHUTDOWN,
+            max_backlog_bytes: *MAX_WAL_BACKLOG_BYTES,
+        }
+    }
+
+    fn pressure(&self, usage: &DiskUsage) -> DiskPressure {
+        let free_percent = usage.free_percent();
+        if free_percent < self.shutdown_free_percent {
+            DiskPressure::Shutdown
+        } else if free_percent < self.pause_free_percent
+            || self
+                .max_backlog_bytes
+                .is_some_and(|max_backlog_bytes| usage.backlog_bytes > max_backlog_bytes)
+        {
+            DiskPressure::Pause
+        } else if free_percent < self.warning_free_percent {
+            DiskPressure::Warning
+        } else {
+            DiskPressure::Normal
+        }
+    }
+}
+
+// keeps an eye on the disk the wal files are written to, so a slow target fills
+// the source's replication slot rather than our disk
+#[derive(Debug)]
+pub struct DiskSpaceGuard {
+    wal_directory: PathBuf,
+    thresholds: Thresholds,
+    last_checked: Option<Instant>,
+    pressure: DiskPressure,
+}
+
+impl DiskSpaceGuard {
+    pub fn new(wal_directory: &Path) -> DiskSpaceGuard {
+        DiskSpaceGuard {
+            wal_directory: wal_directory.to_path_buf(),
+            thresholds: Thresholds::from_env(),
+            last_checked: None,
+            pressure: DiskPressure::Normal,
+        }
+    }
+
+    fn check_interval() -> Duration {
+        Duration::from_secs(*SECONDS_BETWEEN_DISK_SPACE_CHECKS)
+    }
+
+    // only measures every SECONDS_BETWEEN_DISK_SPACE_CHECKS, as it's called for every line
+    pub fn check(&mut self) -> DiskPressure {
+        if self
+            .last_checked
+            .is_some_and(|last_checked| last_checked.elapsed() < Self::check_interval())
+        {
+            return self.pressure;
+        }
+        self.last_checked = Some(Instant::now());
+        let usage = match DiskUsage::measure(&self.wal_directory) {
+            Ok(usage) => usage,
+            Err(err) => {
+                logger_warning!(
+                    None,
+                    None,
+                    &format!("wal_disk_space_check_failed error:{}", err)
+                );
+                return self.pressure;
+            }
+        };
+        let pressure = self.thresholds.pressure(&usage);
+        Self::emit(&usage, pressure);
+        if pressure != self.pressure {
+            let message = format!(
+                "wal_disk_pressure:{:?} previous:{:?} free_bytes:{} free_percent:{:.1} backlog_bytes:{} backlog_files:{}",
+                pressure,
+                self.pressure,
+                usage.free_bytes,
+                usage.free_percent(),
+                usage.backlog_bytes,
+                usage.backlog_files
+            );
+            match pressure {
+                DiskPressure::Normal => logger_info!(None, None, &message),
+                DiskPressure::Warning | DiskPressure::Pause => {
+                    logger_warning!(None, None, &message)
+                }
+                DiskPressure::Shutdown => logger_error!(None, None, &message),
+            }
+        }
+        self.pressure = pressure;
+        pressure
+    }
+
+    fn emit(usage: &DiskUsage, pressure: DiskPressure) {
+        let statsd = StatsdWrapper::new();
+        let no_tags: [&str; 0] = [];
+        statsd.gauge("wal_disk_free_bytes", usage.free_bytes, no_tags);
+        statsd.gauge(
+            "wal_disk_free_percent",
+            format!("{:.1}", usage.free_percent()),
+            no_tags,
+        );
+        statsd.gauge("wal_backlog_bytes", usage.backlog_bytes, no_tags);
+        statsd.gauge("wal_backlog_files", usage.backlog_files, no_tags);
+        statsd.gauge(
+            "wal_backpressure_paused",
+            (pressure >= DiskPressure::Pause) as u8,
+            no_tags,
+        );
+    }
+
+    // waits until we're below the pause thresholds again, or shuts down if we're nearly full
+    pub async fn wait_for_disk_space(&mut self) {
+        loop {
+            match self.check() {
+                DiskPressure::Normal | DiskPressure::Warning => return,
+                DiskPressure::Pause => {
+                    if ShutdownHandler::shutting_down() {
+                        return;
+                    }
+                    tokio::time::sleep(Self::check_interval()).await;
+                }
+                DiskPressure::Shutdown => {
+                    if !ShutdownHandler::shutting_down() {
+                        logger_error!(None, None, "wal_disk_nearly_full shutting_down");
+                        ShutdownHandler::register_clean_shutdown();
+                    }
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THRESHOLDS: Thresholds = Thresholds {
+        warning_free_percent: 20.0,
+        pause_free_percent: 10.0,
+        shutdown_free_percent: 5.0,
+        max_backlog_bytes: Some(1000),
+    };
+
+    fn usage(free_percent: u64, backlog_bytes: u64) -> DiskUsage {
+        DiskUsage {
+            free_bytes: free_percent,
+            total_bytes: 100,
+            backlog_bytes,
+            backlog_files: 1,
+        }
+    }
+
+    #[test]
+    fn disk_pressure_thresholds() {
+        assert_eq!(THRESHOLDS.pressure(&usage(50, 0)), DiskPressure::Normal);
+        assert_eq!(THRESHOLDS.pressure(&usage(15, 0)), DiskPressure::Warning);
+        assert_eq!(THRESHOLDS.pressure(&usage(8, 0)), DiskPressure::Pause);
+        assert_eq!(THRESHOLDS.pressure(&usage(50, 1001)), DiskPressure::Pause);
+        assert_eq!(THRESHOLDS.pressure(&usage(4, 0)), DiskPressure::Shutdown);
+        assert_eq!(THRESHOLDS.pressure(&usage(4, 1001)), DiskPressure::Shutdown);
+    }
+}
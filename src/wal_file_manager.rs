@@ -1,3 +1,6 @@
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use glob::glob;
 use lazy_static::lazy_static;
 use std::fs::{self, File, OpenOptions};
@@ -5,13 +8,21 @@ use std::path::Path;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 
-use std::io::Write;
+use std::io::{BufRead, BufReader, Read, Write};
 use std::time::Duration;
 
+use crate::encryption::{self, EncryptingWriter};
+use crate::replication_client::ReplicationProgress;
 use crate::shutdown_handler::ShutdownHandler;
+use crate::wal_archive::WalArchive;
+use crate::wal_disk_space::{DiskPressure, DiskSpaceGuard};
+use crate::wal_fsync::WalFsync;
+use crate::wal_integrity::{escape_line, CommitFinder, WalChecksum};
+use crate::wal_manifest::WalManifest;
+use crate::wal_retention::WalRetention;
 
 #[allow(unused_imports)]
-use crate::{function, logger_debug, logger_error, logger_info, logger_panic};
+use crate::{function, logger_debug, logger_error, logger_info, logger_panic, logger_warning};
 
 #[cfg(test)]
 use mock_instant::{Instant, MockClock};
@@ -25,6 +36,144 @@ lazy_static! {
         .unwrap_or("1000000000".to_string()) // 1 GB default
         .parse::<usize>()
         .expect("MAX_BYTES_UNTIL_WAL_SWITCH is not a valid integer");
+    // none, gzip or zstd
+    static ref WAL_COMPRESSION: WalCompression = std::env::var("WAL_COMPRESSION")
+        .map(|compression| WalCompression::parse(&compression))
+        .unwrap_or(WalCompression::None);
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum WalCompression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl WalCompression {
+    fn parse(compression: &str) -> WalCompression {
+        match compression {
+            "none" | "" => WalCompression::None,
+            "gzip" => WalCompression::Gzip,
+            "zstd" => WalCompression::Zstd,
+            unknown => panic!("Unknown wal compression: {}", unknown),
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            WalCompression::None => "wal",
+            WalCompression::Gzip => "wal.gz",
+            WalCompression::Zstd => "wal.zst",
+        }
+    }
+
+    // from the file name rather than our config,
+    // so we can reprocess files written with a different setting
+    pub fn from_path(path: &Path) -> WalCompression {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("gz") => WalCompression::Gzip,
+            Some("zst") => WalCompression::Zstd,
+            _ => WalCompression::None,
+        }
+    }
+
+    fn writer(&self, file: File) -> WalWriter {
+        // compressed, then encrypted
+        let file = EncryptingWriter::new(file);
+        match self {
+            WalCompression::None => WalWriter::Plain(file),
+            WalCompression::Gzip => WalWriter::Gzip(GzEncoder::new(file, Compression::default())),
+            WalCompression::Zstd => WalWriter::Zstd(
+                zstd::Encoder::new(file, 0).expect("Unable to create zstd encoder for wal file"),
+            ),
+        }
+    }
+}
+
+// reads plain or compressed wal files, whatever they were written with
+pub fn open_wal_file_reader(wal_file_path: &Path) -> std::io::Result<Box<dyn BufRead>> {
+    // only a wal file we hadn't sealed can have been cut short by a crash
+    let sealed = is_sealed(wal_file_path);
+    // and encrypted or not
+    let file = if sealed {
+        encryption::decrypting_reader(File::open(wal_file_path)?)?
+    } else {
+        encryption::decrypting_reader_allowing_truncation(File::open(wal_file_path)?)?
+    };
+    Ok(match WalCompression::from_path(wal_file_path) {
+        WalCompression::None => Box::new(BufReader::new(file)),
+        WalCompression::Gzip => Box::new(BufReader::new(TruncatedStreamReader::new(
+            GzDecoder::new(file),
+            wal_file_path,
+            sealed,
+        ))),
+        WalCompression::Zstd => Box::new(BufReader::new(TruncatedStreamReader::new(
+            zstd::Decoder::new(file)?,
+            wal_file_path,
+            sealed,
+        ))),
+    })
+}
+
+// from the manifest next to it, which is saved once the sealed wal file has been finished
+pub fn is_sealed(wal_file_path: &Path) -> bool {
+    let wal_file_directory = wal_file_path
+        .file_name()
+        .and_then(|file_name| file_name.to_str())
+        .and_then(|file_name| file_name.split_once('.'))
+        .map(|(name, _extension)| wal_file_path.with_file_name(name));
+    match wal_file_directory.map(|directory| WalManifest::load(&directory)) {
+        Some(Ok(Some(manifest))) => manifest.sealed,
+        _ => false,
+    }
+}
+
+// a compressed wal file we didn't get to finish (e.g. we crashed) ends part way
+// through the stream. Everything up to the last flush is still there, so we read that
+// and treat the rest as the end of the file. A sealed one was finished, so it can't.
+struct TruncatedStreamReader<R: Read> {
+    inner: R,
+    path: PathBuf,
+    sealed: bool,
+    truncated: bool,
+}
+
+impl<R: Read> TruncatedStreamReader<R> {
+    fn new(inner: R, path: &Path, sealed: bool) -> TruncatedStreamReader<R> {
+        TruncatedStreamReader {
+            inner,
+            path: path.to_path_buf(),
+            sealed,
+            truncated: false,
+        }
+    }
+}
+
+impl<R: Read> Read for TruncatedStreamReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.truncated {
+            return Ok(0);
+        }
+        match self.inner.read(buf) {
+            Err(err) if !self.sealed && err.kind() != std::io::ErrorKind::Interrupted => {
+                logger_warning!(
+                    None,
+                    None,
+                    &format!("wal_file_truncated path:{:?} error:{}", self.path, err)
+                );
+                self.truncated = true;
+                Ok(0)
+            }
+            result => result,
+        }
+    }
+}
+
+// the number of a wal file from its name e.g. 000000000000001F.wal.zst
+pub fn wal_file_number_from_path(path: &Path) -> Option<u64> {
+    let file_name = path.file_name()?.to_str()?;
+    let (number, _extension) = file_name.split_once('.')?;
+    u64::from_str_radix(number, 16).ok()
 }
 
 #[cfg(not(test))]
@@ -40,26 +189,103 @@ pub struct WalFile {
     // for the directory associated with this wal file see
     // path_for_wal_directory
     pub wal_directory: PathBuf,
+    pub compression: WalCompression,
     // we have interior mutability of the file, and synchronise with a mutex
     // NOTE: it is unsafe to create two wal_files with the same file_number
     // (keep wal file creation single threaded!)
     file: Arc<Option<Mutex<WalFileInternal>>>,
 }
 
+enum WalWriter {
+    Plain(EncryptingWriter<File>),
+    Gzip(GzEncoder<EncryptingWriter<File>>),
+    Zstd(zstd::Encoder<'static, EncryptingWriter<File>>),
+    // the compressed stream has been ended, nothing more can be written
+    Finished,
+}
+
+impl WalWriter {
+    // finished wal files are always synced to disk
+    fn finish(&mut self) -> std::io::Result<()> {
+        match std::mem::replace(self, WalWriter::Finished) {
+            WalWriter::Plain(file) => {
+                file.finish()?.sync_data()?;
+            }
+            WalWriter::Gzip(encoder) => {
+                encoder.finish()?.finish()?.sync_data()?;
+            }
+            WalWriter::Zstd(encoder) => {
+                encoder.finish()?.finish()?.sync_data()?;
+            }
+            WalWriter::Finished => {}
+        }
+        Ok(())
+    }
+    fn sync_data(&mut self) -> std::io::Result<()> {
+        self.flush()?;
+        match self {
+            WalWriter::Plain(file) => file.get_ref().sync_data(),
+            WalWriter::Gzip(encoder) => encoder.get_ref().get_ref().sync_data(),
+            WalWriter::Zstd(encoder) => encoder.get_ref().get_ref().sync_data(),
+            WalWriter::Finished => Ok(()),
+        }
+    }
+}
+
+impl std::fmt::Debug for WalWriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WalWriter::Plain(file) => write!(f, "Plain({:?})", file),
+            WalWriter::Gzip(encoder) => write!(f, "Gzip({:?})", encoder.get_ref()),
+            WalWriter::Zstd(encoder) => write!(f, "Zstd({:?})", encoder.get_ref()),
+            WalWriter::Finished => write!(f, "Finished"),
+        }
+    }
+}
+
+impl std::io::Write for WalWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            WalWriter::Plain(file) => file.write(buf),
+            WalWriter::Gzip(encoder) => encoder.write(buf),
+            WalWriter::Zstd(encoder) => encoder.write(buf),
+            WalWriter::Finished => Err(std::io::Error::other("wal file has already been finished")),
+        }
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            WalWriter::Plain(file) => file.flush(),
+            WalWriter::Gzip(encoder) => encoder.flush(),
+            WalWriter::Zstd(encoder) => encoder.flush(),
+            WalWriter::Finished => Ok(()),
+        }
+    }
+}
+
 #[derive(Debug)]
 struct WalFileInternal {
-    file: File,
+    writer: WalWriter,
     // we want this to be locked by the mutex
     had_errors_loading: bool,
+    // uncompressed, so swapping wal files doesn't depend on the compression
     pub current_number_of_bytes: usize,
+    checksum: WalChecksum,
+    commit_finder: CommitFinder,
+    manifest: WalManifest,
+    // once the wal file is sealed (or we're reprocessing it), so the manifest is complete
+    save_manifest: bool,
 }
 
 impl WalFileInternal {
-    fn new(file: File) -> WalFileInternal {
+    fn new(writer: WalWriter) -> WalFileInternal {
         WalFileInternal {
-            file: file,
+            writer,
             had_errors_loading: false,
             current_number_of_bytes: 0,
+            checksum: WalChecksum::default(),
+            commit_finder: CommitFinder::new(),
+            manifest: WalManifest::default(),
+            save_manifest: false,
         }
     }
     fn register_error(&mut self) {
@@ -68,16 +294,58 @@ impl WalFileInternal {
     fn has_errors(&self) -> bool {
         self.had_errors_loading
     }
+    fn finish(&mut self) -> std::io::Result<()> {
+        self.writer.finish()
+    }
+    // once a write fails we stop writing, as the file would be missing lines
+    fn write_line(&mut self, line: &str) -> bool {
+        if self.has_errors() {
+            return false;
+        }
+        let result = self.write_all(format!("{}\n", line).as_bytes());
+        self.check_write(result)
+    }
+    // the manifest is only for debugging, so we carry on if we can't save it
+    fn save_manifest(&self, wal_file_directory: &Path) {
+        if !self.save_manifest {
+            return;
+        }
+        if let Err(err) = self.manifest.save(wal_file_directory) {
+            logger_warning!(
+                None,
+                None,
+                &format!(
+                    "wal_manifest_save_failed directory:{:?} error:{}",
+                    wal_file_directory, err
+                )
+            );
+        }
+    }
+    // a full disk shouldn't panic. we shut down, and keep the wal file to reprocess
+    fn check_write(&mut self, result: std::io::Result<()>) -> bool {
+        match result {
+            Ok(()) => true,
+            Err(err) => {
+                if !self.has_errors() {
+                    logger_error!(None, None, &format!("wal_file_write_failed error:{}", err));
+                    self.register_error();
+                    ShutdownHandler::register_messy_shutdown();
+                }
+                false
+            }
+        }
+    }
 }
 
-// just pass writes straight to the file
+// just pass writes straight to the file (via the compressor)
 impl std::io::Write for WalFileInternal {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        self.current_number_of_bytes += buf.len();
-        self.file.write(buf)
+        let written = self.writer.write(buf)?;
+        self.current_number_of_bytes += written;
+        Ok(written)
     }
     fn flush(&mut self) -> std::io::Result<()> {
-        self.file.flush()
+        self.writer.flush()
     }
 }
 
@@ -96,7 +364,11 @@ impl WalFile {
         wal_file_directory: &Path,
         wal_file_mode: WalFileMode,
     ) -> WalFile {
-        let path = Self::path_for_wal_file_class(wal_file_number, wal_file_directory);
+        let compression = match &wal_file_mode {
+            WalFileMode::Processing => *WAL_COMPRESSION,
+            WalFileMode::Reprocessing(path) => WalCompression::from_path(Path::new(path)),
+        };
+        let path = Self::path_for_wal_file_class(wal_file_number, wal_file_directory, compression);
         let directory_path =
             Self::path_for_wal_directory_class(wal_file_number, wal_file_directory);
         logger_info!(
@@ -117,7 +389,7 @@ impl WalFile {
             &format!("creating wal file {:?}", path)
         );
         let mut open_options = OpenOptions::new();
-        match wal_file_mode {
+        match &wal_file_mode {
             WalFileMode::Processing => {
                 // use atomic file creation. Bail if a file already exists
                 open_options.write(true).create_new(true);
@@ -130,27 +402,81 @@ impl WalFile {
             "Unable to create wal file: {}",
             path.to_str().unwrap_or("unprintable non-utf-8 path")
         ));
+        let writer = match wal_file_mode {
+            WalFileMode::Processing => compression.writer(file),
+            // never written to
+            WalFileMode::Reprocessing(_) => WalWriter::Plain(EncryptingWriter::new(file)),
+        };
+        let mut internal_file = WalFileInternal::new(writer);
+        internal_file.manifest =
+            Self::new_manifest(wal_file_number, &directory_path, &wal_file_mode);
+        // nothing more is written to a wal file we're reprocessing
+        internal_file.save_manifest = matches!(wal_file_mode, WalFileMode::Reprocessing(_));
         WalFile {
             file_number: wal_file_number,
-            file: Arc::new(Some(Mutex::new(WalFileInternal::new(file)))),
+            file: Arc::new(Some(Mutex::new(internal_file))),
             wal_directory: wal_file_directory.to_path_buf(),
+            compression,
         }
     }
+    // a reprocessed wal file starts a new manifest, as we make new csv files for it,
+    // but we log where the last attempt got to
+    fn new_manifest(
+        wal_file_number: u64,
+        directory_path: &Path,
+        wal_file_mode: &WalFileMode,
+    ) -> WalManifest {
+        let mut manifest = WalManifest::new(Self::name_for_wal_file(wal_file_number));
+        if let WalFileMode::Reprocessing(_) = wal_file_mode {
+            match WalManifest::load(directory_path) {
+                Ok(Some(previous_manifest)) => {
+                    logger_info!(
+                        Some(wal_file_number),
+                        None,
+                        &format!(
+                            "reprocessing_wal_file previous_manifest:{}",
+                            previous_manifest
+                        )
+                    );
+                    manifest.sealed = previous_manifest.sealed;
+                    manifest.reprocessed = previous_manifest.reprocessed + 1;
+                }
+                Ok(None) => {}
+                Err(err) => logger_warning!(
+                    Some(wal_file_number),
+                    None,
+                    &format!("wal_manifest_unreadable error:{}", err)
+                ),
+            }
+        }
+        manifest
+    }
     // 16 hex chars
-    fn name_for_wal_file(wal_file_number: u64) -> String {
+    pub fn name_for_wal_file(wal_file_number: u64) -> String {
         // hex uppercase padded to 16 chars
         format!("{:0>16X}", wal_file_number)
     }
     // class method needed in constructor
-    fn path_for_wal_file_class(wal_file_number: u64, wal_file_directory: &Path) -> PathBuf {
-        let mut name_without_extension = Self::name_for_wal_file(wal_file_number);
-        name_without_extension.push_str(".wal");
-        wal_file_directory.join(name_without_extension)
+    fn path_for_wal_file_class(
+        wal_file_number: u64,
+        wal_file_directory: &Path,
+        compression: WalCompression,
+    ) -> PathBuf {
+        let name = format!(
+            "{}.{}",
+            Self::name_for_wal_file(wal_file_number),
+            compression.extension()
+        );
+        wal_file_directory.join(name)
     }
 
     // for symmetry with directory
     pub fn path_for_wal_file(&self) -> PathBuf {
-        Self::path_for_wal_file_class(self.file_number, self.wal_directory.as_path())
+        Self::path_for_wal_file_class(
+            self.file_number,
+            self.wal_directory.as_path(),
+            self.compression,
+        )
     }
 
     // class method needed in constructor
@@ -163,21 +489,71 @@ impl WalFile {
         Self::path_for_wal_directory_class(self.file_number, self.wal_directory.as_path())
     }
 
-    fn write(&mut self, string: &str) {
-        self.with_locked_internal_file()
-            .write_all(format!("{}\n", string).as_bytes())
-            .expect("Unable to write line to wal_file");
+    // writes a line as it came from the source, with a commit marker after each COMMIT.
+    // true if it was the end of a transaction
+    pub fn write_line(&mut self, line: &str) -> bool {
+        let is_commit = self.write(line);
+        if is_commit {
+            self.write_commit_marker();
+        }
+        is_commit
     }
-    pub fn flush(&mut self) {
-        self.with_locked_internal_file()
-            .flush()
-            .expect("Unable to flush wal_file");
+    fn write(&mut self, string: &str) -> bool {
+        let mut internal_file = self.with_locked_internal_file();
+        let is_commit = internal_file.commit_finder.is_commit(string);
+        if internal_file.write_line(&escape_line(string)) {
+            internal_file.checksum.add_line(string);
+            internal_file.manifest.add_line(string);
+        }
+        is_commit
+    }
+    // checksums the transaction, so we can tell if it's complete and intact on restart
+    fn write_commit_marker(&mut self) {
+        let mut internal_file = self.with_locked_internal_file();
+        let marker = internal_file.checksum.commit_marker();
+        internal_file.write_line(&marker);
+    }
+    // marks the wal file as complete, unless we stopped part way through a transaction
+    // and saves its manifest
+    pub fn seal(&mut self) {
+        let mut internal_file = self.with_locked_internal_file();
+        if let Some(marker) = internal_file.checksum.sealed_marker() {
+            internal_file.manifest.sealed = internal_file.write_line(&marker);
+        }
+        // saved once it's finished, as a sealed wal file is read as a whole one
+        internal_file.save_manifest = true;
+    }
+    // for what we see line by line, which is saved with the rest of the manifest
+    pub fn record_in_manifest<F: FnOnce(&mut WalManifest)>(&self, record: F) {
+        record(&mut self.with_locked_internal_file().manifest);
+    }
+    // as the wal file's changes make their way to the target
+    pub fn update_manifest<F: FnOnce(&mut WalManifest)>(&self, update: F) {
+        let directory_path = self.path_for_wal_directory();
+        let mut internal_file = self.with_locked_internal_file();
+        update(&mut internal_file.manifest);
+        internal_file.save_manifest(&directory_path);
+    }
+    // so what we've written survives a power loss, see WAL_FSYNC_POLICY
+    pub fn sync(&mut self) {
+        let mut internal_file = self.with_locked_internal_file();
+        let result = internal_file.writer.sync_data();
+        internal_file.check_write(result);
+    }
+    // ends the compressed and encrypted streams, once we're done writing to the file
+    pub fn finish(&mut self) {
+        let directory_path = self.path_for_wal_directory();
+        let mut internal_file = self.with_locked_internal_file();
+        let result = internal_file.finish();
+        if internal_file.check_write(result) {
+            internal_file.save_manifest(&directory_path);
+        }
     }
     pub fn register_error(&mut self) {
         self.with_locked_internal_file().register_error();
     }
 
-    fn with_locked_internal_file(&mut self) -> std::sync::MutexGuard<'_, WalFileInternal> {
+    fn with_locked_internal_file(&self) -> std::sync::MutexGuard<'_, WalFileInternal> {
         self.file
             .as_ref() // tbh, I don't even know why we need two as_ref here, but we do
             .as_ref() // ref to option
@@ -213,9 +589,14 @@ impl WalFile {
                 return;
             }
             // We've locked our mutex, so we're safe from races
-            std::fs::remove_file(file_path).expect("Error removing wal file");
+            // or moves it to processed/ if we keep them for a while
+            WalRetention::new()
+                .retain_or_remove(&file_path)
+                .expect("Error removing wal file");
             std::fs::remove_dir_all(directory_path).expect("Error removing wal directory");
         }
+        // everything in this wal file is in the target now
+        ReplicationProgress::register_applied_wal_file(self.file_number);
 
         // borrow dropped by here
         // now we replace Arc value with None.
@@ -224,6 +605,9 @@ impl WalFile {
     pub fn current_bytes(&mut self) -> usize {
         self.with_locked_internal_file().current_number_of_bytes
     }
+    pub fn in_transaction(&mut self) -> bool {
+        self.with_locked_internal_file().checksum.in_transaction()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -249,12 +633,26 @@ pub struct WalFileManager {
     output_wal_directory: PathBuf,
     last_swapped_wal: Instant,
     wal_file_mode: WalFileMode,
+    disk_space_guard: DiskSpaceGuard,
+    fsync: WalFsync,
 }
 
 impl WalFileManager {
+    #[cfg(test)]
     pub fn new(output_wal_directory: &Path) -> WalFileManager {
-        let new_wal_file_number =
-            Self::get_next_wal_filenumber_from_filesystem(output_wal_directory);
+        Self::new_after(output_wal_directory, 0)
+    }
+
+    // carry on numbering after the last wal file applied to the target,
+    // even if it's gone from disk, so old checkpoints never match a new wal file
+    pub fn new_after(
+        output_wal_directory: &Path,
+        last_applied_wal_file_number: u64,
+    ) -> WalFileManager {
+        let new_wal_file_number = std::cmp::max(
+            Self::get_next_wal_filenumber_from_filesystem(output_wal_directory),
+            last_applied_wal_file_number + 1,
+        );
         let first_wal_file = WalFile::new(
             new_wal_file_number,
             output_wal_directory,
@@ -266,26 +664,16 @@ impl WalFileManager {
             output_wal_directory: output_wal_directory.to_path_buf(),
             last_swapped_wal: Instant::now(),
             wal_file_mode: WalFileMode::Processing,
+            disk_space_guard: DiskSpaceGuard::new(output_wal_directory),
+            fsync: WalFsync::new(),
         }
     }
 
     pub fn reprocess(output_wal_directory: &Path, wal_file_path: String) -> WalFileManager {
-        let file_name = Path::new(&wal_file_path)
-            .file_stem()
-            .expect(&format!(
-                "error getting path stem of wal file: {}",
-                wal_file_path
-            ))
-            .to_str()
-            .expect(&format!(
-                "error turning wal path stem to string: {}",
-                wal_file_path
-            ));
-
-        let wal_file_number = u64::from_str_radix(file_name, 16).expect(&format!(
-            "error parsing wal file name as u64 from: {}",
-            wal_file_path
-        ));
+        let wal_file_number =
+            wal_file_number_from_path(Path::new(&wal_file_path)).unwrap_or_else(|| {
+                panic!("error parsing wal file name as u64 from: {}", wal_file_path)
+            });
         let first_wal_file = WalFile::new(
             wal_file_number,
             output_wal_directory,
@@ -297,31 +685,52 @@ impl WalFileManager {
             output_wal_directory: output_wal_directory.to_path_buf(),
             last_swapped_wal: Instant::now(),
             wal_file_mode: WalFileMode::Reprocessing(wal_file_path),
+            disk_space_guard: DiskSpaceGuard::new(output_wal_directory),
+            fsync: WalFsync::new(),
         }
     }
 
     fn get_next_wal_filenumber_from_filesystem(wal_directory: &Path) -> u64 {
-        let wal_glob = wal_directory.join("*".to_owned() + ".wal");
-        glob(
+        Self::wal_filenumbers_on_filesystem(wal_directory)
+            .into_iter()
+            .fold(0, std::cmp::max)
+            + 1
+    }
+
+    pub fn first_wal_filenumber_on_filesystem(wal_directory: &Path) -> Option<u64> {
+        Self::wal_filenumbers_on_filesystem(wal_directory)
+            .into_iter()
+            .min()
+    }
+
+    fn wal_filenumbers_on_filesystem(wal_directory: &Path) -> Vec<u64> {
+        Self::wal_files_on_filesystem(wal_directory)
+            .into_iter()
+            .map(|(wal_file_number, _path)| wal_file_number)
+            .collect()
+    }
+
+    // in the order they'd be processed
+    pub fn wal_files_on_filesystem(wal_directory: &Path) -> Vec<(u64, PathBuf)> {
+        // compressed or not
+        let wal_glob = wal_directory.join("*".to_owned() + ".wal*");
+        let mut wal_files: Vec<(u64, PathBuf)> = glob(
             wal_glob
                 .to_str()
                 .expect("Error creating next wal file glob string"),
         )
         .expect("Error running wal glob pattern on directory")
         .map(|file_path| match file_path {
-            Ok(path) => {
-                let file_name = path
-                    .file_stem()
-                    .expect("error getting path stem of wal file")
-                    .to_str()
-                    .expect("error turning wal path stem to string");
-                u64::from_str_radix(file_name, 16).expect("error parsing wal file name as u64")
-            }
+            Ok(path) => (
+                wal_file_number_from_path(&path).expect("error parsing wal file name as u64"),
+                path,
+            ),
 
             Err(_e) => panic!("unreadable path. What did you do?"),
         })
-        .fold(0, std::cmp::max)
-            + 1
+        .collect();
+        wal_files.sort();
+        wal_files
     }
 
     pub fn current_wal(&self) -> WalFile {
@@ -338,7 +747,9 @@ impl WalFileManager {
                 self.last_swapped_wal,
             )
         );
-        self.current_wal_file.flush();
+        self.current_wal_file.seal();
+        self.finish_current_wal_file();
+        WalArchive::archive(&self.current_wal_file.path_for_wal_file());
         self.current_wal_file_number = self.current_wal_file_number + 1;
         self.last_swapped_wal = Instant::now();
         let next_wal = WalFile::new(
@@ -351,6 +762,35 @@ impl WalFileManager {
         self.current_wal_file = next_wal;
     }
 
+    // how long until we should swap the wal file if nothing else comes in.
+    // None if there's nothing to swap, or we're part way through a transaction
+    pub fn time_until_idle_swap(&mut self) -> Option<Duration> {
+        if let WalFileMode::Reprocessing(_) = self.wal_file_mode {
+            return None;
+        }
+        if self.current_wal_bytes() == 0 || self.current_wal_file.in_transaction() {
+            return None;
+        }
+        Some(
+            Duration::new(*SECONDS_UNTIL_WAL_SWITCH, 0)
+                .saturating_sub(self.last_swapped_wal.elapsed()),
+        )
+    }
+
+    // so quiet streams are applied without waiting for the next commit
+    pub fn swap_idle_wal(&mut self) -> Option<WalFile> {
+        if self.time_until_idle_swap()? > Duration::ZERO {
+            return None;
+        }
+        logger_info!(
+            Some(self.current_wal_file_number),
+            None,
+            "swapping_idle_wal"
+        );
+        self.swap_wal();
+        Some(self.current_wal())
+    }
+
     fn should_swap_wal(&mut self) -> bool {
         if let WalFileMode::Reprocessing(_) = self.wal_file_mode {
             false
@@ -378,7 +818,9 @@ impl WalFileManager {
                     &format!("current_wal_bytes:{:?}", current_wal_bytes)
                 )
             }
-            should_swap_wal_time || should_swap_wal_bytes
+            // send what we have on to the target before we pause, so the backlog goes down
+            let should_swap_wal_disk_space = self.disk_space_guard.check() >= DiskPressure::Pause;
+            should_swap_wal_time || should_swap_wal_bytes || should_swap_wal_disk_space
         }
     }
 
@@ -390,15 +832,38 @@ impl WalFileManager {
     // floating around. So we're doing this manually
     pub fn next_line(&mut self, next_line_string: &String) -> WalLineResult {
         if let WalFileMode::Reprocessing(_) = self.wal_file_mode {
+            self.current_wal_file
+                .record_in_manifest(|manifest| manifest.add_line(next_line_string));
             WalLineResult::WalLine()
         } else {
-            self.current_wal_file.write(next_line_string.as_str());
-            self.handle_next_line(next_line_string.clone())
+            let is_commit = self.current_wal_file.write_line(next_line_string.as_str());
+            if is_commit {
+                // compressed and encrypted wal files are only flushed when we sync (see
+                // WAL_FSYNC_POLICY), as flushing every commit ruins the compression and
+                // adds a frame each time. If we crash we lose the rest, which postgres
+                // sends again as we only confirm what's applied
+                if self.fsync.commit_written() {
+                    self.fsync.sync(&mut self.current_wal_file);
+                }
+            }
+            self.handle_next_line(is_commit)
+        }
+    }
+
+    // stops us reading more input while the wal disk is filling up, so the source holds on to it.
+    // only between wal files, as the changes in the current one won't be applied until we swap
+    pub async fn wait_for_disk_space(&mut self) {
+        if let WalFileMode::Reprocessing(_) = self.wal_file_mode {
+            return;
+        }
+        if self.current_wal_bytes() == 0 || self.disk_space_guard.check() == DiskPressure::Shutdown
+        {
+            self.disk_space_guard.wait_for_disk_space().await;
         }
     }
 
-    fn handle_next_line(&mut self, line: String) -> WalLineResult {
-        if self.should_swap_wal() && line.starts_with("COMMIT") {
+    fn handle_next_line(&mut self, is_commit: bool) -> WalLineResult {
+        if self.should_swap_wal() && is_commit {
             // this means the next time the iterator is called
             // we return SwapWal
             self.swap_wal();
@@ -409,9 +874,46 @@ impl WalFileManager {
     }
 
     pub fn clean_up_final_wal_file(&mut self) {
+        if let WalFileMode::Processing = self.wal_file_mode {
+            self.current_wal_file.seal();
+            self.finish_current_wal_file();
+            WalArchive::archive(&self.current_wal_file.path_for_wal_file());
+        } else {
+            self.current_wal_file.finish();
+        }
         self.current_wal_file.maybe_remove_wal_file()
     }
 
+    // finishing syncs the wal file
+    fn finish_current_wal_file(&mut self) {
+        let start = std::time::Instant::now();
+        self.current_wal_file.finish();
+        self.fsync.synced(start, "reason:swap");
+    }
+
+    // group commit: once we've caught up with the input, sync the commits since the last sync
+    pub fn sync_caught_up(&mut self) {
+        if let WalFileMode::Processing = self.wal_file_mode {
+            if self.fsync.should_sync_caught_up() {
+                self.fsync.sync(&mut self.current_wal_file);
+            }
+        }
+    }
+
+    // None if there's nothing waiting to be synced
+    pub fn time_until_sync(&self) -> Option<Duration> {
+        match self.wal_file_mode {
+            WalFileMode::Processing => self.fsync.time_until_sync(),
+            WalFileMode::Reprocessing(_) => None,
+        }
+    }
+
+    pub fn sync_if_due(&mut self) {
+        if self.time_until_sync() == Some(Duration::ZERO) {
+            self.fsync.sync(&mut self.current_wal_file);
+        }
+    }
+
     // mutable as we lock the internal file
     pub fn current_wal_bytes(&mut self) -> usize {
         self.current_wal_file.current_bytes()
@@ -427,6 +929,7 @@ pub enum WalLineResult {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::wal_integrity::{is_marker, open_validated_wal_file, validate_wal_file};
     use glob::{glob_with, MatchOptions};
     use std::io::{BufRead, BufReader};
 
@@ -437,6 +940,12 @@ mod tests {
         env_logger::init();
         // set required variable
         std::env::set_var("SECONDS_UNTIL_WAL_SWITCH", "600");
+        // before any test reads it, as it's only read once
+        std::env::set_var("MAX_BYTES_UNTIL_WAL_SWITCH", "939");
+        // so how full the disk running the tests is doesn't swap wal files
+        std::env::set_var("WAL_DISK_FREE_PERCENT_WARNING", "0");
+        std::env::set_var("WAL_DISK_FREE_PERCENT_PAUSE", "0");
+        std::env::set_var("WAL_DISK_FREE_PERCENT_SHUTDOWN", "0");
         std::fs::create_dir_all(TESTING_PATH).unwrap();
     }
 
@@ -475,6 +984,23 @@ mod tests {
         assert_eq!(wal_file_manager.current_wal_file.file_number, number + 1)
     }
 
+    #[test]
+    fn wal_file_manager_numbering_after_checkpoint() {
+        // own directory, so other tests' wal files don't interfere
+        let directory_path = PathBuf::from(format!("{}_checkpoint", TESTING_PATH));
+        if directory_path.exists() {
+            fs::remove_dir_all(directory_path.clone()).unwrap();
+        }
+        fs::create_dir_all(directory_path.clone()).unwrap();
+        WalFile::new(3, directory_path.as_path(), WalFileMode::Processing);
+        assert_eq!(
+            WalFileManager::first_wal_filenumber_on_filesystem(directory_path.as_path()),
+            Some(3)
+        );
+        let wal_file_manager = WalFileManager::new_after(directory_path.as_path(), 41);
+        assert_eq!(wal_file_manager.current_wal_file.file_number, 42)
+    }
+
     #[test]
     fn wal_file_directory() {
         let directory_path = PathBuf::from(TESTING_PATH);
@@ -489,13 +1015,73 @@ mod tests {
     #[test]
     fn wal_file_path() {
         let directory_path = PathBuf::from(TESTING_PATH);
-        let wal_file_path = WalFile::path_for_wal_file_class(1, directory_path.as_path());
+        let wal_file_path =
+            WalFile::path_for_wal_file_class(1, directory_path.as_path(), WalCompression::None);
         assert_eq!(
             wal_file_path,
             PathBuf::from("/tmp/wal_testing/0000000000000001.wal")
         )
     }
 
+    #[test]
+    fn compressed_wal_file_path() {
+        let directory_path = PathBuf::from(TESTING_PATH);
+        let wal_file_path =
+            WalFile::path_for_wal_file_class(31, directory_path.as_path(), WalCompression::Zstd);
+        assert_eq!(
+            wal_file_path,
+            PathBuf::from("/tmp/wal_testing/000000000000001F.wal.zst")
+        );
+        assert_eq!(
+            WalCompression::from_path(&wal_file_path),
+            WalCompression::Zstd
+        );
+        assert_eq!(wal_file_number_from_path(&wal_file_path), Some(31));
+    }
+
+    #[test]
+    fn compressed_wal_files_are_read_back() {
+        // own directory, so other tests' wal files don't interfere
+        let directory_path = PathBuf::from(format!("{}_compressed", TESTING_PATH));
+        if directory_path.exists() {
+            fs::remove_dir_all(directory_path.clone()).unwrap();
+        }
+        fs::create_dir_all(directory_path.clone()).unwrap();
+        let lines = [
+            "BEGIN 1",
+            "table public.foo: INSERT: id[bigint]:1",
+            "COMMIT 1",
+        ];
+        for compression in [WalCompression::Gzip, WalCompression::Zstd] {
+            for finished in [true, false] {
+                let path = directory_path.join(format!("{}.{}", finished, compression.extension()));
+                let mut wal_file_internal =
+                    WalFileInternal::new(compression.writer(File::create(&path).unwrap()));
+                for line in lines {
+                    wal_file_internal
+                        .write_all(format!("{}\n", line).as_bytes())
+                        .unwrap();
+                }
+                wal_file_internal.flush().unwrap();
+                if finished {
+                    wal_file_internal.finish().unwrap();
+                }
+                // counts what we were given, not what went to disk
+                assert_eq!(
+                    wal_file_internal.current_number_of_bytes,
+                    lines.iter().map(|line| line.len() + 1).sum::<usize>()
+                );
+                // an unfinished stream is what we're left with after a crash
+                let read_lines: Vec<String> = open_wal_file_reader(&path)
+                    .unwrap()
+                    .lines()
+                    .map(Result::unwrap)
+                    .collect();
+                assert_eq!(read_lines, lines);
+            }
+        }
+    }
+
     #[test]
     fn new_wal_file() {
         clear_testing_directory();
@@ -544,9 +1130,14 @@ mod tests {
 
     fn last_line_of_wal(wal_file: &mut WalFile) -> String {
         let path = wal_file.path_for_wal_file();
-        wal_file.flush();
+        wal_file.sync();
         let file = BufReader::new(File::open(path).unwrap());
-        let mut lines: Vec<_> = file.lines().map(|line| line.unwrap()).collect();
+        // without our checksum markers
+        let mut lines: Vec<_> = file
+            .lines()
+            .map(|line| line.unwrap())
+            .filter(|line| !is_marker(line))
+            .collect();
         lines.reverse();
         if let Some(line) = lines.first_mut() {
             line.clone()
@@ -594,9 +1185,138 @@ mod tests {
         }
     }
 
+    #[test]
+    fn idle_wal_files_are_swapped_between_transactions() {
+        // own directory, so other tests' wal files don't interfere
+        let directory_path = PathBuf::from(format!("{}_idle", TESTING_PATH));
+        if directory_path.exists() {
+            fs::remove_dir_all(directory_path.clone()).unwrap();
+        }
+        fs::create_dir_all(directory_path.clone()).unwrap();
+        let mut wal_file_manager = WalFileManager::new(directory_path.as_path());
+        let idle_wal_file = wal_file_manager.current_wal();
+        // nothing to swap yet
+        assert_eq!(wal_file_manager.time_until_idle_swap(), None);
+        let reader = BufReader::new(File::open("test/parser.txt").unwrap());
+        let mut lines = reader.lines();
+        wal_file_manager.next_line(&lines.next().unwrap().unwrap());
+        wal_file_manager.next_line(&lines.next().unwrap().unwrap());
+        // part way through a transaction
+        MockClock::advance(Duration::from_secs(600));
+        assert_eq!(wal_file_manager.time_until_idle_swap(), None);
+        assert_eq!(wal_file_manager.swap_idle_wal(), None);
+        // the commit swaps it, as we're past the time limit
+        wal_file_manager.next_line(&lines.next().unwrap().unwrap());
+        assert_ne!(wal_file_manager.current_wal(), idle_wal_file);
+        let idle_wal_file = wal_file_manager.current_wal();
+        // a transaction that's under MAX_BYTES_UNTIL_WAL_SWITCH
+        for line in lines.skip(3).take(3) {
+            wal_file_manager.next_line(&line.unwrap());
+        }
+        assert!(wal_file_manager.time_until_idle_swap().is_some());
+        MockClock::advance(Duration::from_secs(600));
+        assert_eq!(
+            wal_file_manager.time_until_idle_swap(),
+            Some(Duration::ZERO)
+        );
+        let swapped_wal_file = wal_file_manager.swap_idle_wal();
+        assert!(swapped_wal_file.is_some());
+        assert_ne!(swapped_wal_file.unwrap(), idle_wal_file);
+        assert!(
+            validate_wal_file(&idle_wal_file.path_for_wal_file())
+                .unwrap()
+                .sealed
+        );
+    }
+
+    #[test]
+    fn commits_in_multi_line_values_dont_end_the_transaction() {
+        // own directory, so other tests' wal files don't interfere
+        let directory_path = PathBuf::from(format!("{}_multi_line", TESTING_PATH));
+        if directory_path.exists() {
+            fs::remove_dir_all(directory_path.clone()).unwrap();
+        }
+        fs::create_dir_all(directory_path.clone()).unwrap();
+        let mut wal_file_manager = WalFileManager::new(directory_path.as_path());
+        let wal_file = wal_file_manager.current_wal();
+        let lines = [
+            "BEGIN 1",
+            "table public.foo: INSERT: id[bigint]:1 bar[text]:'first",
+            "COMMIT 1",
+            "#re_dms sealed commits:1 lines:3",
+            "last'",
+            "COMMIT 1",
+        ];
+        // due a swap, which waits for the end of the transaction
+        MockClock::advance(Duration::from_secs(600));
+        for line in &lines[..5] {
+            wal_file_manager.next_line(&line.to_string());
+        }
+        assert_eq!(wal_file_manager.current_wal(), wal_file);
+        wal_file_manager.next_line(&lines[5].to_string());
+        assert_ne!(wal_file_manager.current_wal(), wal_file);
+        let mut read_lines = open_validated_wal_file(&wal_file.path_for_wal_file()).unwrap();
+        assert_eq!(
+            read_lines.by_ref().map(Result::unwrap).collect::<Vec<_>>(),
+            lines
+        );
+        assert!(read_lines.validation().sealed);
+        assert_eq!(read_lines.validation().commits, 1);
+    }
+
+    #[test]
+    fn swapped_wal_files_are_sealed() {
+        // own directory, so other tests' wal files don't interfere
+        let directory_path = PathBuf::from(format!("{}_sealed", TESTING_PATH));
+        if directory_path.exists() {
+            fs::remove_dir_all(directory_path.clone()).unwrap();
+        }
+        fs::create_dir_all(directory_path.clone()).unwrap();
+        let mut wal_file_manager = WalFileManager::new(directory_path.as_path());
+        let sealed_wal_file = wal_file_manager.current_wal();
+        let reader = BufReader::new(File::open("test/parser.txt").unwrap());
+        for line in reader.lines().take(3) {
+            // swap on the commit
+            MockClock::advance(Duration::from_secs(600));
+            wal_file_manager.next_line(&line.unwrap());
+        }
+        assert_ne!(wal_file_manager.current_wal(), sealed_wal_file);
+        let validation = validate_wal_file(&sealed_wal_file.path_for_wal_file()).unwrap();
+        assert!(validation.sealed);
+        assert_eq!(validation.commits, 1);
+        assert_eq!(validation.lines, 3);
+        assert_eq!(validation.truncated_lines, 0);
+        // the manifest is saved once it's sealed and finished
+        let manifest = WalManifest::load(&sealed_wal_file.path_for_wal_directory())
+            .unwrap()
+            .unwrap();
+        assert!(manifest.sealed);
+        assert_eq!(manifest.lines, 3);
+        assert_eq!(manifest.reprocessed, 0);
+        // and read when it's reprocessed
+        let wal_file_path = sealed_wal_file.path_for_wal_file();
+        let reprocessing_wal_file_manager = WalFileManager::reprocess(
+            directory_path.as_path(),
+            wal_file_path.to_str().unwrap().to_string(),
+        );
+        reprocessing_wal_file_manager
+            .current_wal()
+            .update_manifest(|manifest| manifest.add_line("BEGIN 1"));
+        let manifest = WalManifest::load(&sealed_wal_file.path_for_wal_directory())
+            .unwrap()
+            .unwrap();
+        assert!(manifest.sealed);
+        assert_eq!(manifest.lines, 1);
+        assert_eq!(manifest.reprocessed, 1);
+
+        // so it's refused if it's been cut short since
+        let file = OpenOptions::new().write(true).open(&wal_file_path).unwrap();
+        file.set_len(file.metadata().unwrap().len() - 5).unwrap();
+        assert!(validate_wal_file(&wal_file_path).is_err());
+    }
+
     #[test]
     fn wal_file_byte_swap_integration_test() {
-        std::env::set_var("MAX_BYTES_UNTIL_WAL_SWITCH", "939");
         let directory_path = PathBuf::from(TESTING_PATH);
         let mut wal_file_manager = WalFileManager::new(directory_path.as_path());
 
@@ -0,0 +1,175 @@
+use lazy_static::lazy_static;
+use std::time::{Duration, Instant};
+
+#[allow(unused_imports)]
+use crate::{function, logger_debug, logger_error, logger_info, logger_panic, logger_warning};
+
+use crate::database_writer::StatsdWrapper;
+use crate::wal_file_manager::WalFile;
+
+lazy_static! {
+    // commit, interval or swap
+    static ref WAL_FSYNC_POLICY: FsyncPolicy = std::env::var("WAL_FSYNC_POLICY")
+        .map(|policy| FsyncPolicy::parse(&policy))
+        .unwrap_or(FsyncPolicy::Swap);
+    // how often we sync with the interval policy,
+    // and the longest a commit waits for others to sync with it with the commit policy
+    static ref WAL_FSYNC_INTERVAL_MS: u64 = std::env::var("WAL_FSYNC_INTERVAL_MS")
+        .unwrap_or("200".to_string())
+        .parse::<u64>()
+        .expect("WAL_FSYNC_INTERVAL_MS is not a valid integer");
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum FsyncPolicy {
+    // every commit, grouped with any others that come in at the same time
+    Commit,
+    // every WAL_FSYNC_INTERVAL_MS, if there's been a commit
+    Interval,
+    // only when we swap to the next wal file
+    Swap,
+}
+
+impl FsyncPolicy {
+    fn parse(policy: &str) -> FsyncPolicy {
+        match policy {
+            "commit" => FsyncPolicy::Commit,
+            "interval" => FsyncPolicy::Interval,
+            "swap" | "" => FsyncPolicy::Swap,
+            unknown => panic!("Unknown wal fsync policy: {}", unknown),
+        }
+    }
+
+    fn tag(&self) -> &'static str {
+        match self {
+            FsyncPolicy::Commit => "policy:commit",
+            FsyncPolicy::Interval => "policy:interval",
+            FsyncPolicy::Swap => "policy:swap",
+        }
+    }
+}
+
+// decides when to fsync the current wal file. Wal files are always synced when they're swapped
+pub struct WalFsync {
+    policy: FsyncPolicy,
+    interval: Duration,
+    last_synced: Instant,
+    // the first commit since we last synced
+    unsynced_since: Option<Instant>,
+    statsd: StatsdWrapper,
+}
+
+impl std::fmt::Debug for WalFsync {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WalFsync")
+            .field("policy", &self.policy)
+            .field("interval", &self.interval)
+            .field("last_synced", &self.last_synced)
+            .field("unsynced_since", &self.unsynced_since)
+            .finish()
+    }
+}
+
+impl WalFsync {
+    pub fn new() -> WalFsync {
+        Self::with_policy(
+            *WAL_FSYNC_POLICY,
+            Duration::from_millis(*WAL_FSYNC_INTERVAL_MS),
+        )
+    }
+
+    fn with_policy(policy: FsyncPolicy, interval: Duration) -> WalFsync {
+        WalFsync {
+            policy,
+            interval,
+            last_synced: Instant::now(),
+            unsynced_since: None,
+            statsd: StatsdWrapper::new(),
+        }
+    }
+
+    // after a COMMIT is written. true if we need to sync now, rather than wait for more
+    pub fn commit_written(&mut self) -> bool {
+        if self.policy == FsyncPolicy::Swap {
+            return false;
+        }
+        let unsynced_since = *self.unsynced_since.get_or_insert_with(Instant::now);
+        match self.policy {
+            FsyncPolicy::Commit => unsynced_since.elapsed() >= self.interval,
+            FsyncPolicy::Interval => self.last_synced.elapsed() >= self.interval,
+            FsyncPolicy::Swap => false,
+        }
+    }
+
+    // once we've caught up with the input, nothing else is about to join the group
+    pub fn should_sync_caught_up(&self) -> bool {
+        self.policy == FsyncPolicy::Commit && self.unsynced_since.is_some()
+    }
+
+    // None if there's nothing waiting to be synced
+    pub fn time_until_sync(&self) -> Option<Duration> {
+        let unsynced_since = self.unsynced_since?;
+        match self.policy {
+            FsyncPolicy::Commit => Some(self.interval.saturating_sub(unsynced_since.elapsed())),
+            FsyncPolicy::Interval => Some(self.interval.saturating_sub(self.last_synced.elapsed())),
+            FsyncPolicy::Swap => None,
+        }
+    }
+
+    pub fn sync(&mut self, wal_file: &mut WalFile) {
+        let start = Instant::now();
+        wal_file.sync();
+        self.synced(start, "reason:commit");
+    }
+
+    // records a sync, including the one when a wal file is finished
+    pub fn synced(&mut self, start: Instant, reason: &str) {
+        self.statsd.timing(
+            "wal_fsync",
+            start.elapsed().as_millis() as i64,
+            [self.policy.tag(), reason],
+        );
+        self.last_synced = Instant::now();
+        self.unsynced_since = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commits_are_synced_in_groups() {
+        let mut fsync = WalFsync::with_policy(FsyncPolicy::Commit, Duration::from_secs(600));
+        assert!(!fsync.should_sync_caught_up());
+        // more commits can join the group
+        assert!(!fsync.commit_written());
+        assert!(!fsync.commit_written());
+        assert!(fsync.should_sync_caught_up());
+        fsync.synced(Instant::now(), "reason:commit");
+        assert!(!fsync.should_sync_caught_up());
+        assert_eq!(fsync.time_until_sync(), None);
+        // unless the group's been waiting too long
+        let mut fsync = WalFsync::with_policy(FsyncPolicy::Commit, Duration::ZERO);
+        assert!(fsync.commit_written());
+    }
+
+    #[test]
+    fn commits_are_synced_on_an_interval() {
+        let mut fsync = WalFsync::with_policy(FsyncPolicy::Interval, Duration::from_secs(600));
+        assert!(!fsync.commit_written());
+        assert!(!fsync.should_sync_caught_up());
+        assert!(fsync.time_until_sync().unwrap() > Duration::ZERO);
+        let mut fsync = WalFsync::with_policy(FsyncPolicy::Interval, Duration::ZERO);
+        assert!(fsync.commit_written());
+        assert_eq!(fsync.time_until_sync(), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn commits_are_only_synced_on_swap() {
+        let mut fsync = WalFsync::with_policy(FsyncPolicy::Swap, Duration::ZERO);
+        assert!(!fsync.commit_written());
+        assert!(!fsync.should_sync_caught_up());
+        assert_eq!(fsync.time_until_sync(), None);
+    }
+}
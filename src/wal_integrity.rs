@@ -0,0 +1,672 @@
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+
+#[allow(unused_imports)]
+use crate::{function, logger_debug, logger_error, logger_info, logger_panic, logger_warning};
+
+use crate::parser::{ParsedLine, Parser};
+use crate::wal_file_manager::{is_sealed, open_wal_file_reader};
+
+// a change's lines start with BEGIN, COMMIT or table, but the rest of a multi-line text value
+// can start with anything. So we escape a line starting with # with another one, which leaves
+// our markers the only lines starting with a single #
+const MARKER_PREFIX: &str = "#re_dms ";
+const ESCAPE: char = '#';
+
+// we write a commit marker with the checksum of the lines since the last one after every COMMIT,
+// and a sealed marker once we're done with the wal file. e.g.
+// BEGIN 1234
+// table public.foo: INSERT: id[bigint]:1
+// COMMIT 1234
+// #re_dms commit lines:3 crc32:278c9ff9
+// #re_dms sealed commits:1 lines:3
+#[derive(Debug, Default)]
+pub struct WalChecksum {
+    hasher: crc32fast::Hasher,
+    lines: u64,
+    commits: u64,
+    committed_lines: u64,
+}
+
+impl WalChecksum {
+    pub fn add_line(&mut self, line: &str) {
+        self.hasher.update(line.as_bytes());
+        self.hasher.update(b"\n");
+        self.lines += 1;
+    }
+
+    pub fn commit_marker(&mut self) -> String {
+        let checksum = std::mem::take(&mut self.hasher).finalize();
+        let marker = format!(
+            "{}commit lines:{} crc32:{:08x}",
+            MARKER_PREFIX, self.lines, checksum
+        );
+        self.commits += 1;
+        self.committed_lines += self.lines;
+        self.lines = 0;
+        marker
+    }
+
+    // lines since the last COMMIT
+    pub fn in_transaction(&self) -> bool {
+        self.lines != 0
+    }
+
+    // None if we're part way through a transaction, as the file isn't complete
+    pub fn sealed_marker(&self) -> Option<String> {
+        if self.in_transaction() {
+            return None;
+        }
+        Some(format!(
+            "{}sealed commits:{} lines:{}",
+            MARKER_PREFIX, self.commits, self.committed_lines
+        ))
+    }
+}
+
+// a line starting with COMMIT is only the end of a transaction if it's not part of a text value,
+// which takes the parser to tell
+pub struct CommitFinder {
+    parser: Parser,
+}
+
+impl CommitFinder {
+    pub fn new() -> CommitFinder {
+        CommitFinder {
+            parser: Parser::new(false),
+        }
+    }
+
+    // every line of the wal file goes through here, in order
+    pub fn is_commit(&mut self, line: &str) -> bool {
+        matches!(
+            self.parser.parse(&line.to_string()),
+            Ok(ParsedLine::Commit(..))
+        )
+    }
+}
+
+impl fmt::Debug for CommitFinder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CommitFinder").finish_non_exhaustive()
+    }
+}
+
+// the line as we write it to the wal file, so it can't be mistaken for a marker
+pub fn escape_line(line: &str) -> std::borrow::Cow<'_, str> {
+    if line.starts_with(ESCAPE) {
+        format!("{}{}", ESCAPE, line).into()
+    } else {
+        line.into()
+    }
+}
+
+fn unescape_line(line: &str) -> &str {
+    match line.strip_prefix(ESCAPE) {
+        Some(unescaped) if unescaped.starts_with(ESCAPE) => unescaped,
+        _ => line,
+    }
+}
+
+pub fn is_marker(line: &str) -> bool {
+    line.starts_with(MARKER_PREFIX)
+}
+
+#[derive(Debug, Eq, PartialEq)]
+enum Marker {
+    Commit { lines: u64, checksum: u32 },
+    Sealed { commits: u64, lines: u64 },
+}
+
+impl Marker {
+    fn parse(line: &str) -> Option<Marker> {
+        let mut words = line.strip_prefix(MARKER_PREFIX)?.split(' ');
+        let kind = words.next()?;
+        let mut field = |name: &str| words.next()?.strip_prefix(name);
+        match kind {
+            "commit" => Some(Marker::Commit {
+                lines: field("lines:")?.parse().ok()?,
+                checksum: u32::from_str_radix(field("crc32:")?, 16).ok()?,
+            }),
+            "sealed" => Some(Marker::Sealed {
+                commits: field("commits:")?.parse().ok()?,
+                lines: field("lines:")?.parse().ok()?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum WalIntegrityError {
+    Io(std::io::Error),
+    MalformedMarker {
+        line_number: u64,
+        line: String,
+    },
+    ChecksumMismatch {
+        line_number: u64,
+        expected: u32,
+        actual: u32,
+    },
+    LineCountMismatch {
+        line_number: u64,
+        expected: u64,
+        actual: u64,
+    },
+    // the sealed marker doesn't agree with the commits before it
+    BadSeal {
+        line_number: u64,
+        expected_commits: u64,
+        commits: u64,
+    },
+    DataAfterSeal {
+        line_number: u64,
+    },
+    // the manifest says we sealed it, so it can't have been cut short
+    MissingSeal,
+}
+
+impl fmt::Display for WalIntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WalIntegrityError::Io(err) => write!(f, "Unable to read wal file: {}", err),
+            WalIntegrityError::MalformedMarker { line_number, line } => {
+                write!(f, "Malformed marker at line {}: {}", line_number, line)
+            }
+            WalIntegrityError::ChecksumMismatch {
+                line_number,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Checksum mismatch for the transaction ending at line {}: expected {:08x} got {:08x}",
+                line_number, expected, actual
+            ),
+            WalIntegrityError::LineCountMismatch {
+                line_number,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Line count mismatch for the transaction ending at line {}: expected {} got {}",
+                line_number, expected, actual
+            ),
+            WalIntegrityError::BadSeal {
+                line_number,
+                expected_commits,
+                commits,
+            } => write!(
+                f,
+                "Sealed marker at line {} expects {} commits, found {}",
+                line_number, expected_commits, commits
+            ),
+            WalIntegrityError::DataAfterSeal { line_number } => {
+                write!(f, "Data after the sealed marker at line {}", line_number)
+            }
+            WalIntegrityError::MissingSeal => {
+                write!(f, "Wal file was sealed, but ends without its sealed marker")
+            }
+        }
+    }
+}
+
+impl Error for WalIntegrityError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WalIntegrityError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for WalIntegrityError {
+    fn from(err: std::io::Error) -> Self {
+        WalIntegrityError::Io(err)
+    }
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct WalValidation {
+    // changes we can safely replay, from the start of the file
+    pub lines: u64,
+    pub commits: u64,
+    pub sealed: bool,
+    // written before we had markers, so we only check it ends with a COMMIT
+    pub unverified: bool,
+    // an incomplete transaction (or partial line) at the end, that we won't replay
+    pub truncated_lines: u64,
+}
+
+impl fmt::Display for WalValidation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "lines:{} commits:{} sealed:{} unverified:{} truncated_lines:{}",
+            self.lines, self.commits, self.sealed, self.unverified, self.truncated_lines
+        )
+    }
+}
+
+// the lines of a wal file we can replay, without our markers or anything incomplete at the end.
+// we hold on to each transaction until its commit marker checks out, so the file is only read once,
+// and a corrupted transaction is an error where it would have been replayed
+pub struct WalLines {
+    reader: Box<dyn BufRead>,
+    // None when we're only validating, so we don't hold on to the lines
+    transaction: Option<Vec<String>>,
+    replayable: VecDeque<String>,
+    checksum: WalChecksum,
+    validation: WalValidation,
+    line_number: u64,
+    // until we see a marker, in case the file was written before we had them
+    commit_finder: Option<CommitFinder>,
+    seen_marker: bool,
+    // a COMMIT without a marker after it yet
+    unmarked_commit: bool,
+    // lines since the transaction before
+    held_lines: u64,
+    // the manifest says we sealed it
+    sealed_in_manifest: bool,
+    path: Option<PathBuf>,
+    finished: bool,
+}
+
+impl WalLines {
+    fn new(reader: Box<dyn BufRead>, keep_lines: bool) -> WalLines {
+        WalLines {
+            reader,
+            transaction: keep_lines.then(Vec::new),
+            replayable: VecDeque::new(),
+            checksum: WalChecksum::default(),
+            validation: WalValidation::default(),
+            line_number: 0,
+            commit_finder: Some(CommitFinder::new()),
+            seen_marker: false,
+            unmarked_commit: false,
+            held_lines: 0,
+            sealed_in_manifest: false,
+            path: None,
+            finished: false,
+        }
+    }
+
+    // complete once we've read all the lines
+    pub fn validation(&self) -> &WalValidation {
+        &self.validation
+    }
+
+    // None at the end of the file. A line cut short can only be the last one
+    fn read_line(&mut self) -> Result<Option<(String, bool)>, WalIntegrityError> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let complete = line.ends_with('\n');
+        if complete {
+            line.pop();
+            if line.ends_with('\r') {
+                line.pop();
+            }
+        }
+        Ok(Some((line, complete)))
+    }
+
+    fn hold_line(&mut self, line: String) {
+        if let Some(transaction) = self.transaction.as_mut() {
+            transaction.push(line);
+        }
+        self.held_lines += 1;
+    }
+
+    fn release_transaction(&mut self, escaped: bool) {
+        if let Some(transaction) = self.transaction.as_mut() {
+            self.replayable
+                .extend(transaction.drain(..).map(|line| match escaped {
+                    true => unescape_line(&line).to_string(),
+                    false => line,
+                }));
+        }
+        self.validation.commits += 1;
+        self.validation.lines += self.held_lines;
+        self.held_lines = 0;
+    }
+
+    fn add_line(&mut self, line: String, complete: bool) -> Result<(), WalIntegrityError> {
+        let line_number = self.line_number;
+        if self.validation.sealed {
+            return Err(WalIntegrityError::DataAfterSeal { line_number });
+        }
+        if !is_marker(&line) {
+            // a wal file from before we had markers is replayable up to its last COMMIT
+            if std::mem::take(&mut self.unmarked_commit) {
+                self.release_transaction(false);
+            }
+            self.checksum.add_line(unescape_line(&line));
+            if let Some(commit_finder) = self.commit_finder.as_mut() {
+                // the parser can't make sense of a line that's been cut short
+                self.unmarked_commit = complete && commit_finder.is_commit(unescape_line(&line));
+            }
+            self.hold_line(line);
+            return Ok(());
+        }
+        self.seen_marker = true;
+        self.commit_finder = None;
+        self.unmarked_commit = false;
+        // cut short as we wrote it, so the transaction before it is incomplete
+        if !complete {
+            return Ok(());
+        }
+        match Marker::parse(&line) {
+            Some(Marker::Commit {
+                lines,
+                checksum: expected,
+            }) => {
+                if lines != self.checksum.lines || lines != self.held_lines {
+                    return Err(WalIntegrityError::LineCountMismatch {
+                        line_number,
+                        expected: lines,
+                        actual: self.checksum.lines,
+                    });
+                }
+                let actual = std::mem::take(&mut self.checksum.hasher).finalize();
+                if actual != expected {
+                    return Err(WalIntegrityError::ChecksumMismatch {
+                        line_number,
+                        expected,
+                        actual,
+                    });
+                }
+                self.checksum.lines = 0;
+                self.release_transaction(true);
+            }
+            Some(Marker::Sealed { commits, lines }) => {
+                if commits != self.validation.commits
+                    || lines != self.validation.lines
+                    || self.held_lines != 0
+                {
+                    return Err(WalIntegrityError::BadSeal {
+                        line_number,
+                        expected_commits: commits,
+                        commits: self.validation.commits,
+                    });
+                }
+                self.validation.sealed = true;
+            }
+            None => return Err(WalIntegrityError::MalformedMarker { line_number, line }),
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), WalIntegrityError> {
+        if std::mem::take(&mut self.unmarked_commit) {
+            self.release_transaction(false);
+        }
+        self.validation.unverified = !self.seen_marker;
+        self.validation.truncated_lines = self.held_lines;
+        if !self.validation.sealed && self.sealed_in_manifest {
+            return Err(WalIntegrityError::MissingSeal);
+        }
+        if let Some(path) = self.path.as_ref() {
+            let file_name = path.to_str().unwrap_or("unprintable non-utf-8 path");
+            if self.validation.truncated_lines > 0 {
+                logger_warning!(
+                    None,
+                    None,
+                    &format!(
+                        "wal_file_incomplete_at_end path:{} {}",
+                        file_name, self.validation
+                    )
+                );
+            } else {
+                logger_info!(
+                    None,
+                    None,
+                    &format!("wal_file_validated path:{} {}", file_name, self.validation)
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn read_next(&mut self) -> Result<(), WalIntegrityError> {
+        match self.read_line()? {
+            Some((line, complete)) => {
+                self.line_number += 1;
+                self.add_line(line, complete)
+            }
+            None => {
+                self.finished = true;
+                self.finish()
+            }
+        }
+    }
+}
+
+impl Iterator for WalLines {
+    type Item = Result<String, WalIntegrityError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(line) = self.replayable.pop_front() {
+                return Some(Ok(line));
+            }
+            if self.finished {
+                return None;
+            }
+            if let Err(err) = self.read_next() {
+                // nothing after a corrupted line can be replayed
+                self.finished = true;
+                return Some(Err(err));
+            }
+        }
+    }
+}
+
+// drops any incomplete transaction at the end, and errors on anything corrupted as we get to it
+pub fn open_validated_wal_file(wal_file_path: &Path) -> Result<WalLines, WalIntegrityError> {
+    let mut lines = WalLines::new(open_wal_file_reader(wal_file_path)?, true);
+    lines.sealed_in_manifest = is_sealed(wal_file_path);
+    lines.path = Some(wal_file_path.to_path_buf());
+    Ok(lines)
+}
+
+// reads the whole wal file without keeping its lines
+#[cfg(test)]
+pub fn validate_wal_file(wal_file_path: &Path) -> Result<WalValidation, WalIntegrityError> {
+    let mut lines = open_validated_wal_file(wal_file_path)?;
+    lines.transaction = None;
+    for line in lines.by_ref() {
+        line?;
+    }
+    Ok(lines.validation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn lines_from(lines: &[String]) -> WalLines {
+        WalLines::new(
+            Box::new(Cursor::new(
+                lines
+                    .iter()
+                    .map(|line| format!("{}\n", line))
+                    .collect::<String>(),
+            )),
+            true,
+        )
+    }
+
+    fn validate(lines: &[String]) -> Result<WalValidation, WalIntegrityError> {
+        let mut lines = lines_from(lines);
+        for line in lines.by_ref() {
+            line?;
+        }
+        Ok(lines.validation)
+    }
+
+    fn replay(lines: &[String]) -> Vec<String> {
+        lines_from(lines).map(|line| line.unwrap()).collect()
+    }
+
+    // as the wal file manager writes them
+    fn with_markers(transactions: &[&[&str]], sealed: bool) -> Vec<String> {
+        let mut checksum = WalChecksum::default();
+        let mut commit_finder = CommitFinder::new();
+        let mut lines = vec![];
+        for transaction in transactions {
+            for line in transaction.iter() {
+                checksum.add_line(line);
+                lines.push(escape_line(line).to_string());
+                if commit_finder.is_commit(line) {
+                    lines.push(checksum.commit_marker());
+                }
+            }
+        }
+        if let Some(marker) = checksum.sealed_marker().filter(|_| sealed) {
+            lines.push(marker);
+        }
+        lines
+    }
+
+    fn to_strings(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|line| line.to_string()).collect()
+    }
+
+    const TRANSACTION: &[&str] = &[
+        "BEGIN 1",
+        "table public.foo: INSERT: id[bigint]:1",
+        "COMMIT 1",
+    ];
+
+    // a text value with lines that look like our markers and commits
+    const MULTI_LINE_TRANSACTION: &[&str] = &[
+        "BEGIN 2",
+        "table public.foo: INSERT: id[bigint]:2 bar[text]:'first",
+        "#re_dms commit lines:1 crc32:00000000",
+        "COMMIT 2",
+        "#re_dms sealed commits:1 lines:3",
+        "## heading",
+        "last'",
+        "COMMIT 2",
+    ];
+
+    #[test]
+    fn sealed_wal_file_is_valid() {
+        let lines = with_markers(&[TRANSACTION, TRANSACTION], true);
+        assert_eq!(
+            validate(&lines).unwrap(),
+            WalValidation {
+                lines: 6,
+                commits: 2,
+                sealed: true,
+                unverified: false,
+                truncated_lines: 0
+            }
+        );
+        assert_eq!(
+            replay(&lines),
+            to_strings(&[TRANSACTION, TRANSACTION].concat())
+        );
+    }
+
+    #[test]
+    fn incomplete_transaction_is_truncated() {
+        let mut lines = with_markers(&[TRANSACTION], false);
+        lines.push("BEGIN 2".to_string());
+        lines.push("table public.foo: INS".to_string());
+        let validation = validate(&lines).unwrap();
+        assert_eq!(validation.lines, 3);
+        assert_eq!(validation.truncated_lines, 2);
+        assert!(!validation.sealed);
+        assert_eq!(replay(&lines), to_strings(TRANSACTION));
+    }
+
+    #[test]
+    fn multi_line_values_are_replayed_as_they_were() {
+        let lines = with_markers(&[TRANSACTION, MULTI_LINE_TRANSACTION], true);
+        // only a marker after each real COMMIT, and the heading escaped
+        assert_eq!(lines.iter().filter(|line| is_marker(line)).count(), 3);
+        assert!(lines.contains(&"### heading".to_string()));
+        let validation = validate(&lines).unwrap();
+        assert_eq!(validation.commits, 2);
+        assert_eq!(validation.lines, 11);
+        assert!(validation.sealed);
+        assert_eq!(
+            replay(&lines),
+            to_strings(&[TRANSACTION, MULTI_LINE_TRANSACTION].concat())
+        );
+    }
+
+    #[test]
+    fn transaction_cut_short_in_a_multi_line_value_is_truncated() {
+        // up to the COMMIT in the value, which isn't the end of the transaction
+        let mut lines = with_markers(&[TRANSACTION, &MULTI_LINE_TRANSACTION[..4]], false);
+        assert_eq!(lines.iter().filter(|line| is_marker(line)).count(), 1);
+        let validation = validate(&lines).unwrap();
+        assert_eq!(validation.lines, 3);
+        assert_eq!(validation.truncated_lines, 4);
+        assert_eq!(replay(&lines), to_strings(TRANSACTION));
+        // and the same without markers, from before we had them
+        lines.retain(|line| !is_marker(line));
+        let validation = validate(&lines).unwrap();
+        assert!(validation.unverified);
+        assert_eq!(validation.lines, 3);
+        assert_eq!(replay(&lines), to_strings(TRANSACTION));
+    }
+
+    #[test]
+    fn corrupted_transaction_is_refused() {
+        let mut lines = with_markers(&[TRANSACTION, TRANSACTION], true);
+        lines[4] = "table public.foo: INSERT: id[bigint]:2".to_string();
+        assert!(matches!(
+            validate(&lines),
+            Err(WalIntegrityError::ChecksumMismatch { line_number: 8, .. })
+        ));
+        // after the transaction before it
+        let mut replayed = lines_from(&lines);
+        for line in TRANSACTION {
+            assert_eq!(&replayed.next().unwrap().unwrap(), line);
+        }
+        assert!(replayed.next().unwrap().is_err());
+        assert!(replayed.next().is_none());
+    }
+
+    #[test]
+    fn wal_file_without_markers_is_truncated_to_the_last_commit() {
+        let lines = to_strings(&[
+            "BEGIN 1",
+            "table public.foo: INSERT: id[bigint]:1",
+            "COMMIT 1",
+            "BEGIN 2",
+        ]);
+        let validation = validate(&lines).unwrap();
+        assert!(validation.unverified);
+        assert_eq!(validation.lines, 3);
+        assert_eq!(validation.truncated_lines, 1);
+        assert_eq!(replay(&lines), to_strings(TRANSACTION));
+    }
+
+    #[test]
+    fn marker_cut_short_leaves_its_transaction_out() {
+        let mut lines = with_markers(&[TRANSACTION, TRANSACTION], false);
+        let mut file = lines.join("\n");
+        lines.pop();
+        file.truncate(file.len() - 5);
+        let mut wal_lines = WalLines::new(Box::new(Cursor::new(file)), true);
+        assert_eq!(
+            wal_lines
+                .by_ref()
+                .map(|line| line.unwrap())
+                .collect::<Vec<_>>(),
+            to_strings(TRANSACTION)
+        );
+        assert_eq!(wal_lines.validation().commits, 1);
+        assert_eq!(wal_lines.validation().truncated_lines, 3);
+    }
+}
@@ -0,0 +1,277 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::parser::ChangeKind;
+
+const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+#[derive(Debug)]
+pub enum WalManifestError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for WalManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WalManifestError::Io(err) => write!(f, "Io error: {}", err),
+            WalManifestError::Json(err) => write!(f, "Json error: {}", err),
+        }
+    }
+}
+
+impl Error for WalManifestError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WalManifestError::Io(err) => Some(err),
+            WalManifestError::Json(err) => Some(err),
+        }
+    }
+}
+
+impl From<std::io::Error> for WalManifestError {
+    fn from(err: std::io::Error) -> Self {
+        WalManifestError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for WalManifestError {
+    fn from(err: serde_json::Error) -> Self {
+        WalManifestError::Json(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CsvFileStatus {
+    Written,
+    Uploaded,
+    Loaded,
+    Failed,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct CsvFileManifest {
+    pub file_name: String,
+    pub kind: String,
+    pub s3_key: Option<String>,
+    pub status: CsvFileStatus,
+}
+
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TableManifest {
+    pub inserts: u64,
+    pub updates: u64,
+    pub deletes: u64,
+    pub csv_files: Vec<CsvFileManifest>,
+}
+
+// what's in a wal file, and how far through the pipeline it's got.
+// kept next to the wal file's csv files, so we don't have to grep the wal file
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WalManifest {
+    pub wal_file: String,
+    pub first_xid: Option<i64>,
+    pub last_xid: Option<i64>,
+    // only if the replication slot includes them
+    pub first_commit_timestamp: Option<String>,
+    pub last_commit_timestamp: Option<String>,
+    pub commits: u64,
+    // the lines from the source, not counting our markers
+    pub lines: u64,
+    pub bytes: u64,
+    pub sealed: bool,
+    // how many times we've reprocessed the wal file after a restart
+    pub reprocessed: u64,
+    pub tables: BTreeMap<String, TableManifest>,
+}
+
+impl WalManifest {
+    pub fn new(wal_file: String) -> WalManifest {
+        WalManifest {
+            wal_file,
+            ..Default::default()
+        }
+    }
+
+    pub fn path(wal_file_directory: &Path) -> PathBuf {
+        wal_file_directory.join(MANIFEST_FILE_NAME)
+    }
+
+    // None if the wal file doesn't have one, e.g. it was written before we had manifests
+    pub fn load(wal_file_directory: &Path) -> Result<Option<WalManifest>, WalManifestError> {
+        match fs::read(Self::path(wal_file_directory)) {
+            Ok(contents) => Ok(Some(serde_json::from_slice(&contents)?)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // replaces the file in one go, so tooling never reads half a manifest
+    pub fn save(&self, wal_file_directory: &Path) -> Result<(), WalManifestError> {
+        let path = Self::path(wal_file_directory);
+        let temporary_path = path.with_extension("json.tmp");
+        fs::write(&temporary_path, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(temporary_path, path)?;
+        Ok(())
+    }
+
+    pub fn add_line(&mut self, line: &str) {
+        self.lines += 1;
+        // and the newline
+        self.bytes += line.len() as u64 + 1;
+    }
+
+    pub fn add_commit(&mut self, xid: i64, commit_timestamp: Option<&str>) {
+        self.commits += 1;
+        self.first_xid = self.first_xid.or(Some(xid));
+        self.last_xid = Some(xid);
+        if let Some(commit_timestamp) = commit_timestamp {
+            if self.first_commit_timestamp.is_none() {
+                self.first_commit_timestamp = Some(commit_timestamp.to_string());
+            }
+            self.last_commit_timestamp = Some(commit_timestamp.to_string());
+        }
+    }
+
+    pub fn add_row(&mut self, table_name: &str, kind: ChangeKind) {
+        let table = self.tables.entry(table_name.to_string()).or_default();
+        match kind {
+            ChangeKind::Insert => table.inserts += 1,
+            ChangeKind::Update => table.updates += 1,
+            ChangeKind::Delete => table.deletes += 1,
+        }
+    }
+
+    pub fn add_csv_file(&mut self, table_name: &str, kind: ChangeKind, file_name: &Path) {
+        self.tables
+            .entry(table_name.to_string())
+            .or_default()
+            .csv_files
+            .push(CsvFileManifest {
+                file_name: file_name.to_string_lossy().to_string(),
+                kind: kind.to_string(),
+                s3_key: None,
+                status: CsvFileStatus::Written,
+            });
+    }
+
+    pub fn csv_file_uploaded(&mut self, file_name: &Path, s3_key: &str) {
+        let file_name = file_name.to_string_lossy();
+        if let Some(csv_file) = self
+            .csv_files_mut()
+            .find(|csv_file| csv_file.file_name == file_name)
+        {
+            csv_file.s3_key = Some(s3_key.to_string());
+            csv_file.status = CsvFileStatus::Uploaded;
+        }
+    }
+
+    pub fn csv_file_upload_failed(&mut self, file_name: &Path) {
+        let file_name = file_name.to_string_lossy();
+        if let Some(csv_file) = self
+            .csv_files_mut()
+            .find(|csv_file| csv_file.file_name == file_name)
+        {
+            csv_file.status = CsvFileStatus::Failed;
+        }
+    }
+
+    // loads only know the s3 key
+    pub fn csv_file_loaded(&mut self, s3_key: &str, status: CsvFileStatus) {
+        if let Some(csv_file) = self
+            .csv_files_mut()
+            .find(|csv_file| csv_file.s3_key.as_deref() == Some(s3_key))
+        {
+            csv_file.status = status;
+        }
+    }
+
+    fn csv_files_mut(&mut self) -> impl Iterator<Item = &mut CsvFileManifest> {
+        self.tables
+            .values_mut()
+            .flat_map(|table| table.csv_files.iter_mut())
+    }
+
+    pub fn csv_files(&self) -> impl Iterator<Item = &CsvFileManifest> {
+        self.tables
+            .values()
+            .flat_map(|table| table.csv_files.iter())
+    }
+
+    pub fn csv_files_with_status(&self, status: CsvFileStatus) -> usize {
+        self.csv_files()
+            .filter(|csv_file| csv_file.status == status)
+            .count()
+    }
+}
+
+impl fmt::Display for WalManifest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let xids = match (self.first_xid, self.last_xid) {
+            (Some(first), Some(last)) => format!("{}-{}", first, last),
+            _ => "-".to_string(),
+        };
+        write!(
+            f,
+            "xids:{} commits:{} lines:{} tables:{} csv_files:{} loaded:{} failed:{} reprocessed:{}",
+            xids,
+            self.commits,
+            self.lines,
+            self.tables.len(),
+            self.csv_files().count(),
+            self.csv_files_with_status(CsvFileStatus::Loaded),
+            self.csv_files_with_status(CsvFileStatus::Failed),
+            self.reprocessed
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TESTING_PATH: &str = "/tmp/wal_manifest_testing";
+
+    #[test]
+    fn manifests_follow_csv_files_through_the_pipeline() {
+        let directory = Path::new(TESTING_PATH);
+        let _ = fs::remove_dir_all(directory);
+        fs::create_dir_all(directory).unwrap();
+        assert_eq!(WalManifest::load(directory).unwrap(), None);
+
+        let mut manifest = WalManifest::new("0000000000000001".to_string());
+        manifest.add_line("BEGIN 10");
+        manifest.add_line("COMMIT 10");
+        manifest.add_commit(10, None);
+        manifest.add_commit(12, Some("2024-01-01 10:00:00+00"));
+        manifest.add_row("public.users", ChangeKind::Insert);
+        manifest.add_row("public.users", ChangeKind::Delete);
+        let insert_file = Path::new("/tmp/1_public.users_insert.csv.gz");
+        let delete_file = Path::new("/tmp/1_public.users_delete.csv.gz");
+        manifest.add_csv_file("public.users", ChangeKind::Insert, insert_file);
+        manifest.add_csv_file("public.users", ChangeKind::Delete, delete_file);
+        manifest.csv_file_uploaded(insert_file, "folder/1_public.users_insert.csv.gz");
+        manifest.csv_file_loaded("folder/1_public.users_insert.csv.gz", CsvFileStatus::Loaded);
+        manifest.csv_file_upload_failed(delete_file);
+        manifest.save(directory).unwrap();
+
+        let loaded = WalManifest::load(directory).unwrap().unwrap();
+        assert_eq!(loaded, manifest);
+        assert_eq!((loaded.first_xid, loaded.last_xid), (Some(10), Some(12)));
+        assert_eq!(loaded.first_commit_timestamp, loaded.last_commit_timestamp);
+        assert_eq!((loaded.lines, loaded.bytes), (2, 19));
+        let users = &loaded.tables["public.users"];
+        assert_eq!((users.inserts, users.updates, users.deletes), (1, 0, 1));
+        assert_eq!(
+            loaded.to_string(),
+            "xids:10-12 commits:2 lines:2 tables:1 csv_files:2 loaded:1 failed:1 reprocessed:0"
+        );
+    }
+}
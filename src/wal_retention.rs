@@ -0,0 +1,180 @@
+use lazy_static::lazy_static;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+#[allow(unused_imports)]
+use crate::{function, logger_debug, logger_error, logger_info, logger_panic, logger_warning};
+
+use crate::wal_file_manager::{wal_file_number_from_path, WalFileManager};
+
+lazy_static! {
+    // processed wal files are kept for this long, to be replayed
+    static ref WAL_RETENTION_HOURS: Option<u64> =
+        std::env::var("WAL_RETENTION_HOURS").ok().filter(|hours| !hours.is_empty()).map(|hours| {
+            hours.parse::<u64>()
+                .expect("WAL_RETENTION_HOURS is not a valid integer")
+        });
+    // and the oldest are removed once they add up to more than this
+    static ref WAL_RETENTION_MAX_BYTES: Option<u64> =
+        std::env::var("WAL_RETENTION_MAX_BYTES").ok().filter(|bytes| !bytes.is_empty()).map(|bytes| {
+            bytes.parse::<u64>()
+                .expect("WAL_RETENTION_MAX_BYTES is not a valid integer")
+        });
+}
+
+// keeps wal files around once everything in them is applied, rather than removing them straight away.
+// they're moved out of the wal directory, so they're never picked up to be reprocessed
+pub struct WalRetention {
+    hours: Option<u64>,
+    max_bytes: Option<u64>,
+}
+
+impl WalRetention {
+    pub fn new() -> WalRetention {
+        WalRetention {
+            hours: *WAL_RETENTION_HOURS,
+            max_bytes: *WAL_RETENTION_MAX_BYTES,
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.hours.is_some() || self.max_bytes.is_some()
+    }
+
+    pub fn processed_directory(wal_directory: &Path) -> PathBuf {
+        wal_directory.join("processed")
+    }
+
+    // called once everything in the wal file is applied
+    pub fn retain_or_remove(&self, wal_file_path: &Path) -> std::io::Result<()> {
+        if !self.enabled() {
+            return fs::remove_file(wal_file_path);
+        }
+        let wal_directory = wal_file_path
+            .parent()
+            .expect("wal file should be in the wal directory");
+        let processed_directory = Self::processed_directory(wal_directory);
+        fs::create_dir_all(&processed_directory)?;
+        let retained_path = processed_directory.join(
+            wal_file_path
+                .file_name()
+                .expect("wal file should have a file name"),
+        );
+        fs::rename(wal_file_path, &retained_path)?;
+        // retention counts from when it was processed, not when it was written
+        fs::File::options()
+            .write(true)
+            .open(&retained_path)?
+            .set_modified(SystemTime::now())?;
+        logger_info!(
+            wal_file_number_from_path(&retained_path),
+            None,
+            &format!("retained_wal_file:{:?}", retained_path)
+        );
+        self.remove_expired(&processed_directory)
+    }
+
+    fn remove_expired(&self, processed_directory: &Path) -> std::io::Result<()> {
+        let now = SystemTime::now();
+        let mut retained = vec![];
+        for (_wal_file_number, path) in WalFileManager::wal_files_on_filesystem(processed_directory)
+        {
+            let metadata = fs::metadata(&path)?;
+            retained.push((path, metadata.len(), metadata.modified()?));
+        }
+        let mut retained_bytes: u64 = retained.iter().map(|(_path, bytes, _modified)| bytes).sum();
+        // oldest first
+        for (path, bytes, modified) in retained {
+            let expired = self.hours.is_some_and(|hours| {
+                now.duration_since(modified).unwrap_or_default()
+                    > Duration::from_secs(hours * 60 * 60)
+            });
+            let over_max_bytes = self
+                .max_bytes
+                .is_some_and(|max_bytes| retained_bytes > max_bytes);
+            if !expired && !over_max_bytes {
+                continue;
+            }
+            fs::remove_file(&path)?;
+            retained_bytes -= bytes;
+            logger_info!(
+                wal_file_number_from_path(&path),
+                None,
+                &format!(
+                    "removed_retained_wal_file:{:?} expired:{} retained_bytes:{}",
+                    path, expired, retained_bytes
+                )
+            );
+        }
+        Ok(())
+    }
+
+    // copies the retained wal files numbered first to last into the directory, to be replayed.
+    // returns their numbers
+    pub fn fetch(
+        wal_directory: &Path,
+        first: u64,
+        last: u64,
+        directory: &Path,
+    ) -> std::io::Result<Vec<u64>> {
+        fs::create_dir_all(directory)?;
+        let mut fetched = vec![];
+        let retained =
+            WalFileManager::wal_files_on_filesystem(&Self::processed_directory(wal_directory));
+        for (wal_file_number, path) in retained {
+            if !(first..=last).contains(&wal_file_number) {
+                continue;
+            }
+            let file_name = path.file_name().expect("wal file should have a file name");
+            // renamed into place, so a partial file is never replayed
+            let partial_path = directory.join(format!("{}.partial", file_name.to_string_lossy()));
+            fs::copy(&path, &partial_path)?;
+            fs::rename(&partial_path, directory.join(file_name))?;
+            logger_info!(
+                Some(wal_file_number),
+                None,
+                &format!("fetched_retained_wal_file:{:?}", path)
+            );
+            fetched.push(wal_file_number);
+        }
+        Ok(fetched)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TESTING_PATH: &str = "/tmp/wal_retention_testing";
+
+    #[test]
+    fn processed_wal_files_are_retained_up_to_max_bytes() {
+        let wal_directory = Path::new(TESTING_PATH);
+        let _ = fs::remove_dir_all(wal_directory);
+        fs::create_dir_all(wal_directory).unwrap();
+        let retention = WalRetention {
+            hours: Some(1),
+            max_bytes: Some(20),
+        };
+        for wal_file_name in ["0000000000000001.wal", "0000000000000002.wal"] {
+            let wal_file_path = wal_directory.join(wal_file_name);
+            fs::write(&wal_file_path, "BEGIN 1\nCOMMIT 1\n").unwrap();
+            retention.retain_or_remove(&wal_file_path).unwrap();
+            assert!(!wal_file_path.exists());
+        }
+        // the first is removed to stay under max bytes
+        let processed_directory = WalRetention::processed_directory(wal_directory);
+        assert_eq!(
+            WalFileManager::wal_files_on_filesystem(&processed_directory),
+            vec![(2, processed_directory.join("0000000000000002.wal"))]
+        );
+
+        let replay_directory = wal_directory.join("replay");
+        assert_eq!(
+            WalRetention::fetch(wal_directory, 1, u64::MAX, &replay_directory).unwrap(),
+            vec![2]
+        );
+        assert!(replay_directory.join("0000000000000002.wal").exists());
+    }
+}
@@ -0,0 +1,591 @@
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+#[allow(unused_imports)]
+use crate::{function, logger_debug, logger_error, logger_info, logger_panic, logger_warning};
+
+use crate::parser::{ParsedLine, Parser};
+use crate::shutdown_handler::{RuntimeType, ShutdownHandler};
+use crate::wal_file_manager::{wal_file_number_from_path, WalFile, WalFileManager, WalFileMode};
+use crate::wal_integrity::{open_validated_wal_file, WalIntegrityError, WalLines, WalValidation};
+use crate::wal_manifest::{CsvFileStatus, WalManifest, WalManifestError};
+
+// `re_dms wal ...`, for looking after wal files that are left over
+pub fn subcommand() -> App<'static, 'static> {
+    let directory = || {
+        Arg::with_name("directory")
+            .long("directory")
+            .takes_value(true)
+            .help("The directory with the wal files, defaults to OUTPUT_WAL_DIRECTORY")
+    };
+    let output = || {
+        Arg::with_name("output")
+            .long("output")
+            .takes_value(true)
+            .required(true)
+            .help("The directory to write the new wal files to")
+    };
+    SubCommand::with_name("wal")
+        .about("Lists, merges, splits and verifies wal files, and shows their manifests")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("Lists the wal files with their size, age, xid range and csv files loaded")
+                .arg(directory()),
+        )
+        .subcommand(
+            SubCommand::with_name("manifest")
+                .about("Prints a wal file's manifest")
+                .arg(directory())
+                .arg(
+                    Arg::with_name("wal_file_number")
+                        .required(true)
+                        .help("The (hex) number of the wal file"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("merge")
+                .about("Combines every N consecutive wal files into one, numbered after the last of them")
+                .arg(directory())
+                .arg(output())
+                .arg(
+                    Arg::with_name("count")
+                        .long("count")
+                        .takes_value(true)
+                        .required(true)
+                        .help("How many wal files to combine"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("split")
+                .about("Splits a wal file into wal files of at most --max-bytes (uncompressed)")
+                .arg(output())
+                .arg(
+                    Arg::with_name("max-bytes")
+                        .long("max-bytes")
+                        .takes_value(true)
+                        .required(true)
+                        .help("The most uncompressed bytes per wal file, unless a single transaction is bigger"),
+                )
+                .arg(
+                    Arg::with_name("first-number")
+                        .long("first-number")
+                        .takes_value(true)
+                        .help("The (hex) number of the first new wal file, defaults to the wal file's own"),
+                )
+                .arg(Arg::with_name("wal_file").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Checks the wal files' checksums and that every line parses")
+                .arg(directory())
+                .arg(Arg::with_name("wal_files").multiple(true)),
+        )
+}
+
+#[derive(Debug)]
+pub enum WalToolkitError {
+    Io(std::io::Error),
+    Usage(String),
+    Manifest(WalManifestError),
+    Integrity {
+        path: PathBuf,
+        err: WalIntegrityError,
+    },
+    Parse {
+        path: PathBuf,
+        line_number: u64,
+        message: String,
+    },
+    // write errors on the new wal files are logged as they happen
+    WriteFailed,
+    VerifyFailed {
+        failed: usize,
+    },
+}
+
+impl fmt::Display for WalToolkitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WalToolkitError::Io(err) => write!(f, "Io error: {}", err),
+            WalToolkitError::Usage(message) => write!(f, "{}", message),
+            WalToolkitError::Manifest(err) => write!(f, "Unable to read manifest: {}", err),
+            WalToolkitError::Integrity { path, err } => write!(f, "{:?}: {}", path, err),
+            WalToolkitError::Parse {
+                path,
+                line_number,
+                message,
+            } => write!(
+                f,
+                "{:?}: unable to parse line {}: {}",
+                path, line_number, message
+            ),
+            WalToolkitError::WriteFailed => write!(f, "Unable to write the new wal files"),
+            WalToolkitError::VerifyFailed { failed } => {
+                write!(f, "{} wal files failed verification", failed)
+            }
+        }
+    }
+}
+
+impl Error for WalToolkitError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WalToolkitError::Io(err) => Some(err),
+            WalToolkitError::Manifest(err) => Some(err),
+            WalToolkitError::Integrity { err, .. } => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for WalToolkitError {
+    fn from(err: std::io::Error) -> Self {
+        WalToolkitError::Io(err)
+    }
+}
+
+impl From<WalManifestError> for WalToolkitError {
+    fn from(err: WalManifestError) -> Self {
+        WalToolkitError::Manifest(err)
+    }
+}
+
+type Result<T, E = WalToolkitError> = std::result::Result<T, E>;
+
+pub fn run(arg_matches: &ArgMatches) -> Result<()> {
+    // a failed write registers a shut down, rather than panicking
+    ShutdownHandler::register_shutdown_handler(RuntimeType::File);
+    let result = match arg_matches.subcommand() {
+        ("list", Some(arg_matches)) => list(&directory(arg_matches)?),
+        ("manifest", Some(arg_matches)) => manifest(
+            &directory(arg_matches)?,
+            number_arg(arg_matches, "wal_file_number", 16)?,
+        ),
+        ("merge", Some(arg_matches)) => merge(
+            &directory(arg_matches)?,
+            &output(arg_matches)?,
+            number_arg(arg_matches, "count", 10)? as usize,
+        ),
+        ("split", Some(arg_matches)) => {
+            let wal_file = PathBuf::from(arg_matches.value_of("wal_file").unwrap_or_default());
+            split(
+                &wal_file,
+                &output(arg_matches)?,
+                number_arg(arg_matches, "max-bytes", 10)? as usize,
+                arg_matches
+                    .value_of("first-number")
+                    .map(|_| number_arg(arg_matches, "first-number", 16))
+                    .transpose()?,
+            )
+        }
+        ("verify", Some(arg_matches)) => match arg_matches.values_of("wal_files") {
+            Some(wal_files) => verify(wal_files.map(PathBuf::from).collect()),
+            None => verify(
+                WalFileManager::wal_files_on_filesystem(&directory(arg_matches)?)
+                    .into_iter()
+                    .map(|(_wal_file_number, path)| path)
+                    .collect(),
+            ),
+        },
+        (subcommand, _) => Err(WalToolkitError::Usage(format!(
+            "Unknown wal subcommand: {}",
+            subcommand
+        ))),
+    };
+    if result.is_ok() && ShutdownHandler::shutting_down_messily() {
+        return Err(WalToolkitError::WriteFailed);
+    }
+    result
+}
+
+fn directory(arg_matches: &ArgMatches) -> Result<PathBuf> {
+    arg_matches
+        .value_of("directory")
+        .map(str::to_string)
+        .or_else(|| std::env::var("OUTPUT_WAL_DIRECTORY").ok())
+        .map(PathBuf::from)
+        .ok_or_else(|| {
+            WalToolkitError::Usage(
+                "Either pass --directory, or set OUTPUT_WAL_DIRECTORY".to_string(),
+            )
+        })
+}
+
+// the new wal files go somewhere else, as their numbers can clash with the existing ones
+fn output(arg_matches: &ArgMatches) -> Result<PathBuf> {
+    let output = PathBuf::from(arg_matches.value_of("output").unwrap_or_default());
+    fs::create_dir_all(&output)?;
+    if !WalFileManager::wal_files_on_filesystem(&output).is_empty() {
+        return Err(WalToolkitError::Usage(format!(
+            "{:?} already has wal files in it",
+            output
+        )));
+    }
+    Ok(output)
+}
+
+fn number_arg(arg_matches: &ArgMatches, name: &str, radix: u32) -> Result<u64> {
+    let value = arg_matches.value_of(name).unwrap_or_default();
+    u64::from_str_radix(value, radix)
+        .map_err(|_| WalToolkitError::Usage(format!("--{} is not a valid number: {}", name, value)))
+}
+
+// the lines of a committed transaction, as they're written in the wal file
+#[derive(Debug, Default)]
+struct Transaction {
+    lines: Vec<String>,
+    // uncompressed, as MAX_BYTES_UNTIL_WAL_SWITCH counts them
+    bytes: usize,
+    xid: Option<i64>,
+}
+
+// the complete transactions in a wal file. We go by what the parser says rather than
+// what a line starts with, as a value can have a line starting with COMMIT in it
+struct Transactions {
+    path: PathBuf,
+    lines: WalLines,
+    parser: Parser,
+    line_number: u64,
+}
+
+impl Transactions {
+    fn open(path: &Path) -> Result<Transactions> {
+        let lines = open_validated_wal_file(path).map_err(|err| WalToolkitError::Integrity {
+            path: path.to_path_buf(),
+            err,
+        })?;
+        Ok(Transactions {
+            path: path.to_path_buf(),
+            lines,
+            parser: Parser::new(true),
+            line_number: 0,
+        })
+    }
+
+    // once we've been through them all
+    fn validation(&self) -> &WalValidation {
+        self.lines.validation()
+    }
+}
+
+impl Iterator for Transactions {
+    type Item = Result<Transaction>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut transaction = Transaction::default();
+        for line in self.lines.by_ref() {
+            let line = match line {
+                Ok(line) => line,
+                Err(err) => {
+                    return Some(Err(WalToolkitError::Integrity {
+                        path: self.path.clone(),
+                        err,
+                    }))
+                }
+            };
+            self.line_number += 1;
+            let parsed_line = match self.parser.parse(&line) {
+                Ok(parsed_line) => parsed_line,
+                Err(err) => {
+                    return Some(Err(WalToolkitError::Parse {
+                        path: self.path.clone(),
+                        line_number: self.line_number,
+                        message: format!("{:?}", err),
+                    }))
+                }
+            };
+            transaction.bytes += line.len() + 1;
+            transaction.lines.push(line);
+            if let ParsedLine::Commit(xid, _commit_timestamp) = parsed_line {
+                transaction.xid = Some(xid);
+                return Some(Ok(transaction));
+            }
+        }
+        // validated wal files end on a commit
+        None
+    }
+}
+
+fn list(directory: &Path) -> Result<()> {
+    println!(
+        "{:<18} {:>14} {:>10} {:>10} {:>8} {:>25} sealed",
+        "wal_file", "bytes", "age", "loaded", "commits", "xids"
+    );
+    for (wal_file_number, path) in WalFileManager::wal_files_on_filesystem(directory) {
+        let metadata = fs::metadata(&path)?;
+        let age = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+            .map(format_age)
+            .unwrap_or_else(|| "-".to_string());
+        // the csv files that have made it to the target, from the manifest
+        let loaded =
+            WalManifest::load(&directory.join(WalFile::name_for_wal_file(wal_file_number)))
+                .ok()
+                .flatten()
+                .map(|manifest| {
+                    format!(
+                        "{}/{}",
+                        manifest.csv_files_with_status(CsvFileStatus::Loaded),
+                        manifest.csv_files().count()
+                    )
+                })
+                .unwrap_or_else(|| "-".to_string());
+        let summary = match Transactions::open(&path) {
+            Ok(mut transactions) => {
+                let mut commits = 0;
+                let mut xids = (None, None);
+                for transaction in transactions.by_ref() {
+                    let transaction = transaction?;
+                    commits += 1;
+                    xids.0 = xids.0.or(transaction.xid);
+                    xids.1 = transaction.xid;
+                }
+                let xids = match xids {
+                    (Some(first), Some(last)) => format!("{}-{}", first, last),
+                    _ => "-".to_string(),
+                };
+                format!(
+                    "{:>8} {:>25} {}",
+                    commits,
+                    xids,
+                    transactions.validation().sealed
+                )
+            }
+            Err(err) => format!("corrupted: {}", err),
+        };
+        println!(
+            "{:<18} {:>14} {:>10} {:>10} {}",
+            WalFile::name_for_wal_file(wal_file_number),
+            metadata.len(),
+            age,
+            loaded,
+            summary
+        );
+    }
+    Ok(())
+}
+
+fn manifest(directory: &Path, wal_file_number: u64) -> Result<()> {
+    let wal_file_directory = directory.join(WalFile::name_for_wal_file(wal_file_number));
+    match WalManifest::load(&wal_file_directory)? {
+        Some(manifest) => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&manifest).map_err(WalManifestError::from)?
+            );
+            Ok(())
+        }
+        None => Err(WalToolkitError::Usage(format!(
+            "No manifest in {:?}, the wal file is either applied or not sealed yet",
+            wal_file_directory
+        ))),
+    }
+}
+
+fn format_age(age: Duration) -> String {
+    let minutes = age.as_secs() / 60;
+    match minutes {
+        0..=59 => format!("{}m", minutes),
+        60..=1439 => format!("{}h{}m", minutes / 60, minutes % 60),
+        _ => format!("{}d{}h", minutes / 1440, minutes % 1440 / 60),
+    }
+}
+
+// written the same way as re_dms writes them, so they have checksums and are sealed
+fn write_wal_file(
+    wal_file_number: u64,
+    output: &Path,
+    transactions: &[Transaction],
+) -> Result<PathBuf> {
+    let mut wal_file = WalFile::new(wal_file_number, output, WalFileMode::Processing);
+    for transaction in transactions {
+        for line in transaction.lines.iter() {
+            wal_file.write_line(line);
+        }
+    }
+    wal_file.seal();
+    wal_file.finish();
+    // we don't need the directory for the wal file's changes, or the manifest sealing left in it
+    fs::remove_dir_all(wal_file.path_for_wal_directory())?;
+    let path = wal_file.path_for_wal_file();
+    println!(
+        "{:?} commits:{} bytes:{}",
+        path,
+        transactions.len(),
+        transactions
+            .iter()
+            .map(|transaction| transaction.bytes)
+            .sum::<usize>()
+    );
+    Ok(path)
+}
+
+// numbered after the last wal file in each group, so a checkpoint for any of them
+// doesn't skip the changes from the later ones
+fn merge(directory: &Path, output: &Path, count: usize) -> Result<()> {
+    if count == 0 {
+        return Err(WalToolkitError::Usage(
+            "--count needs to be at least 1".to_string(),
+        ));
+    }
+    let wal_files = WalFileManager::wal_files_on_filesystem(directory);
+    for group in wal_files.chunks(count) {
+        let mut transactions = vec![];
+        for (_wal_file_number, path) in group {
+            let wal_file_transactions = Transactions::open(path)?;
+            for transaction in wal_file_transactions {
+                transactions.push(transaction?);
+            }
+        }
+        let (last_wal_file_number, _path) = group.last().expect("chunks are never empty");
+        write_wal_file(*last_wal_file_number, output, &transactions)?;
+    }
+    Ok(())
+}
+
+fn split(
+    wal_file: &Path,
+    output: &Path,
+    max_bytes: usize,
+    first_wal_file_number: Option<u64>,
+) -> Result<()> {
+    let mut wal_file_number = match first_wal_file_number {
+        Some(wal_file_number) => wal_file_number,
+        None => wal_file_number_from_path(wal_file).ok_or_else(|| {
+            WalToolkitError::Usage(format!("{:?} isn't named like a wal file", wal_file))
+        })?,
+    };
+    let transactions = Transactions::open(wal_file)?;
+    let mut batch: Vec<Transaction> = vec![];
+    let mut batch_bytes = 0;
+    for transaction in transactions {
+        let transaction = transaction?;
+        if !batch.is_empty() && batch_bytes + transaction.bytes > max_bytes {
+            write_wal_file(wal_file_number, output, &batch)?;
+            wal_file_number += 1;
+            batch.clear();
+            batch_bytes = 0;
+        }
+        batch_bytes += transaction.bytes;
+        batch.push(transaction);
+    }
+    if !batch.is_empty() {
+        write_wal_file(wal_file_number, output, &batch)?;
+    }
+    Ok(())
+}
+
+fn verify(wal_files: Vec<PathBuf>) -> Result<()> {
+    let mut failed = 0;
+    for path in wal_files {
+        let verified = Transactions::open(&path).and_then(|mut transactions| {
+            for transaction in transactions.by_ref() {
+                transaction?;
+            }
+            Ok(transactions.validation().clone())
+        });
+        match verified {
+            Ok(validation) => println!("ok {:?} {}", path, validation),
+            Err(err) => {
+                failed += 1;
+                println!("FAILED {}", err);
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(WalToolkitError::VerifyFailed { failed });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TESTING_PATH: &str = "/tmp/wal_toolkit_testing";
+
+    fn testing_directory(name: &str) -> PathBuf {
+        let directory = Path::new(TESTING_PATH).join(name);
+        if directory.exists() {
+            fs::remove_dir_all(&directory).unwrap();
+        }
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    fn transaction(xid: i64) -> Transaction {
+        // a value with a line that looks like a commit
+        let lines: Vec<String> = vec![
+            format!("BEGIN {}", xid),
+            format!(
+                "table public.foo: INSERT: id[bigint]:{} bar[text]:'multi",
+                xid
+            ),
+            format!("COMMIT {}'", xid),
+            format!("COMMIT {}", xid),
+        ];
+        Transaction {
+            bytes: lines.iter().map(|line| line.len() + 1).sum(),
+            lines,
+            xid: Some(xid),
+        }
+    }
+
+    fn xids(path: &Path) -> Vec<i64> {
+        let mut transactions = Transactions::open(path).unwrap();
+        let xids = transactions
+            .by_ref()
+            .map(|transaction| transaction.unwrap().xid.unwrap())
+            .collect();
+        assert!(transactions.validation().sealed);
+        xids
+    }
+
+    #[test]
+    fn wal_files_are_merged_on_transaction_boundaries() {
+        let input = testing_directory("merge_input");
+        let output = testing_directory("merge_output");
+        for wal_file_number in 1..=3 {
+            write_wal_file(
+                wal_file_number,
+                &input,
+                &[transaction(wal_file_number as i64 * 10)],
+            )
+            .unwrap();
+        }
+        merge(&input, &output, 2).unwrap();
+        let merged = WalFileManager::wal_files_on_filesystem(&output);
+        assert_eq!(
+            merged.iter().map(|(number, _)| *number).collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+        assert_eq!(xids(&merged[0].1), vec![10, 20]);
+        assert_eq!(xids(&merged[1].1), vec![30]);
+    }
+
+    #[test]
+    fn wal_files_are_split_on_transaction_boundaries() {
+        let input = testing_directory("split_input");
+        let output = testing_directory("split_output");
+        let transactions: Vec<Transaction> = (1..=5).map(transaction).collect();
+        let max_bytes = transactions[0].bytes * 2;
+        let wal_file = write_wal_file(7, &input, &transactions).unwrap();
+        split(&wal_file, &output, max_bytes, None).unwrap();
+        let split = WalFileManager::wal_files_on_filesystem(&output);
+        assert_eq!(
+            split.iter().map(|(number, _)| *number).collect::<Vec<_>>(),
+            vec![7, 8, 9]
+        );
+        assert_eq!(xids(&split[0].1), vec![1, 2]);
+        assert_eq!(xids(&split[1].1), vec![3, 4]);
+        assert_eq!(xids(&split[2].1), vec![5]);
+        verify(split.into_iter().map(|(_, path)| path).collect()).unwrap();
+    }
+}